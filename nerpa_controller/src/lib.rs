@@ -53,14 +53,6 @@ use differential_datalog::record::{
 
 use dp2ddlog::{digest_to_ddlog, packet_in_to_ddlog};
 
-use futures::{SinkExt, StreamExt};
-use grpcio::{
-    ChannelBuilder,
-    ClientDuplexReceiver,
-    EnvBuilder,
-    StreamingCallSink
-};
-
 use num::BigInt;
 
 use p4ext::{
@@ -73,25 +65,19 @@ use p4ext::{
 use proto::p4runtime::{
     Action,
     Action_Param,
+    DigestList,
     FieldMatch,
-    MasterArbitrationUpdate,
-    StreamMessageRequest,
-    StreamMessageResponse,
+    PacketIn,
     TableAction,
 };
-use proto::p4runtime_grpc::P4RuntimeClient;
-use protobuf::Message;
 
 use std::{
     borrow::Cow,
     collections::{HashMap, HashSet},
-    ffi::OsStr,
-    fmt,
-    fs::File,
+    path::Path,
     sync::Arc,
 };
 use tokio::sync::{oneshot, mpsc};
-use tokio::time::{Duration, sleep};
 use tracing::{debug, error, instrument};
 
 /// Public handle for the Tokio tasks.
@@ -203,45 +189,17 @@ impl ControllerProgram {
     }
 }
 
-/// P4 Runtime client.
-//
-// This is a "newtype" style struct, so we can define `Debug` on it.
-pub struct P4RC(P4RuntimeClient);
-
-impl fmt::Debug for P4RC {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("P4RuntimeClient")
-         .finish()
-    }
-}
-
-/// Sink to send messages using the P4 Runtime API over StreamChannel.
-//
-//  This is a "newtype" style struct, so we can define `Debug` on it.
-pub struct PacketSink(StreamingCallSink<StreamMessageRequest>);
-
-impl fmt::Debug for PacketSink {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("PacketSink")
-         .finish()
-    }
-}
-
 /// Sends messages to the P4 Runtime switch.
 #[derive(Debug)]
 pub struct SwitchClient {
     // Includes necessary information to configure the switch and to send packets to the switch without unnecessary extra computation.
     //
-    /// The P4 Runtime Client as a newtype for debugging.
-    pub client: P4RC,
+    /// The P4 Runtime session with the switch.
+    pub session: p4ext::Session,
     p4info: String,
-    device_id: u64,
-    role_id: u64,
-    target: String,
     // Using P4 Info, map each PacketMetadata field to its id.
     // This is used as a cache for metadata for P4 Runtime PacketOuts.
     packet_meta_field_to_id: HashMap<String, u32>,
-    packet_sink: PacketSink,
     client_id: BigInt,
 }
 
@@ -249,41 +207,24 @@ impl SwitchClient {
     /// Return a P4 Runtime switch client, with extra information for easier communication.
     ///
     /// # Arguments
-    /// * `client` - P4 Runtime client.
+    /// * `session` - P4 Runtime session with the switch.
     /// * `p4info` - Filepath for P4info binary file.
     /// * `json` - Filepath for JSON representation of compiled P4 program.
     /// * `cookie` - Metadata used by the control plane to identify a forwarding pipeline configuration.
     /// * `action` - Configuration action for the forwarding pipeline.
-    /// * `device_id` - ID of the P4-enabled device.
-    /// * `role_id` - the desired role ID for the controller
-    /// * `target` - hardware/software entity hosting P4 Runtime (e.g., "localhost:50051"). Used for logging.
+    /// * `client_id` - The client ID, corresponding to its OVSDB UUID.
     pub async fn new(
-        client: P4RuntimeClient,
+        session: p4ext::Session,
         p4info: String,
         json: String,
         cookie: String,
         action: String,
-        device_id: u64,
-        role_id: u64,
-        target: String,
         client_id: BigInt,
-    ) -> Self {
-        p4ext::set_pipeline_config(
-            &p4info,
-            &json,
-            &cookie,
-            &action,
-            device_id,
-            role_id,
-            &target,
-            &client
-        );
+    ) -> Result<Self, p4ext::P4Error> {
+        session.set_pipeline(&p4info, &json, &cookie, &action).await?;
 
         // Load a P4info struct from file to cache any necessary data structures.
-        let mut p4info_file = File::open(OsStr::new(&p4info))
-            .unwrap_or_else(|err| panic!("{}: could not open P4Info ({})", p4info, err));
-        let p4info_struct: proto::p4info::P4Info = Message::parse_from_reader(&mut p4info_file)
-            .unwrap_or_else(|err| panic!("{}: could not read P4Info ({})", p4info, err));
+        let p4info_struct = p4ext::load_p4info(&p4info)?;
 
         // Map packet metadata field names to packet_ids.
         // We do this in the constructor, to avoid computation per packet sent to the dataplane.
@@ -299,57 +240,12 @@ impl SwitchClient {
             }
         }
 
-        // Establish a connection to the switch to send packets.
-        let (mut sink, _receiver) = client.stream_channel().unwrap();
-        // Send a master arbitration update to establish this as backup with election id 1.
-        // The Tokio actor handling messages from the dataplane has a StreamChannel with election id 0.
-        use proto::p4runtime::Uint128;
-        let mut election_id = Uint128::new();
-        election_id.set_high(0);
-        election_id.set_low(1);
-
-        let mut upd = MasterArbitrationUpdate::new();
-        upd.set_device_id(device_id);
-        upd.set_election_id(election_id);
-
-        let mut req = StreamMessageRequest::new();
-        req.set_arbitration(upd);
-
-        // Send the master arbitration update request to the switch.
-        // Retry using exponential backoff.
-        // TODO: Decompose this retry into a separate function.
-        let mut retries = 5;
-        let mut wait = 1000; // milliseconds
-        loop {
-            match sink.send((req.clone(), grpcio::WriteFlags::default())).await {
-                Err(e) => {
-                    if retries > 0 {
-                        error!("failed to configure backup stream through master arbitration: {:#?}", e);
-
-                        retries -= 1;
-                        sleep(Duration::from_secs(wait)).await;
-                        wait *= 2;
-                    }
-                },
-                Ok(_) => break,
-            }
-        };
-
-        // Wrap types from external crates in newtypes.
-        let p4rc = P4RC(client);
-        let packet_sink = PacketSink(sink);
-
-
-        Self {
-            client: p4rc,
+        Ok(Self {
+            session,
             p4info,
-            device_id,
-            role_id,
-            target,
             packet_meta_field_to_id,
-            packet_sink,
             client_id,
-        }
+        })
     }
 
     /// Configure the digest notification level on the switch.
@@ -367,11 +263,7 @@ impl SwitchClient {
         ack_timeout_ns: i64,
     ) -> Result<(), p4ext::P4Error> {
         // Read P4Info from file.
-        let p4info_str: &str = &self.p4info;
-        let mut p4info_file = File::open(OsStr::new(p4info_str))
-            .unwrap_or_else(|err| panic!("{}: could not open P4Info ({})", p4info_str, err));
-        let p4info: proto::p4info::P4Info = Message::parse_from_reader(&mut p4info_file)
-            .unwrap_or_else(|err| panic!("{}: could not read P4Info ({})", p4info_str, err));
+        let p4info = p4ext::load_p4info(&self.p4info)?;
 
         // Write updates for each digest.
         let mut digest_updates = Vec::new();
@@ -386,13 +278,7 @@ impl SwitchClient {
             );
         }
 
-        let digest_res = p4ext::write(
-            digest_updates,
-            self.device_id,
-            self.role_id,
-            &self.target,
-            &self.client.0
-        );
+        let digest_res = self.session.write(digest_updates).await;
 
         if digest_res.is_err() {
             let e = digest_res.err().unwrap(); // safe because of `is_err` check
@@ -410,11 +296,11 @@ impl SwitchClient {
     pub async fn ddlog_outputs_to_dataplane(
         &mut self,
         delta: &DeltaMap<DDValue>
-    ) -> (Vec<proto::p4runtime::Update>, Vec<proto::p4runtime::PacketOut>) {
+    ) -> Result<(Vec<proto::p4runtime::Update>, Vec<proto::p4runtime::PacketOut>), p4ext::P4Error> {
         let mut updates = Vec::new();
         let mut packet_outs = Vec::new();
 
-        let pipeline = p4ext::get_pipeline_config(self.device_id, &self.target, &self.client.0);
+        let pipeline = self.session.get_pipeline().await?;
         let switch: p4ext::Switch = pipeline.get_p4info().into();
 
         for (_, output_map) in (*delta).clone().into_iter() {
@@ -597,7 +483,7 @@ impl SwitchClient {
             }
         }
 
-        (updates, packet_outs)
+        Ok((updates, packet_outs))
     }
 
     /// Push the dataplane outputs (P4 table entries and packets) to the switch.
@@ -610,13 +496,7 @@ impl SwitchClient {
         updates: Vec<proto::p4runtime::Update>,
         packet_outs: Vec<proto::p4runtime::PacketOut>,
     ) -> Result<(), p4ext::P4Error> {
-        let write_res = p4ext::write(
-            updates,
-            self.device_id,
-            self.role_id,
-            &self.target,
-            &self.client.0,
-        );
+        let write_res = self.session.write(updates).await;
         if write_res.is_err() {
             error!("could not write updates to P4 Runtime: {:#?}",  write_res.as_ref().err());
             return write_res;
@@ -626,12 +506,9 @@ impl SwitchClient {
         if !packet_outs.is_empty() {
             // Send packets to the switch.
             for packet_out in packet_outs {
-                let mut req = StreamMessageRequest::new();
-                req.set_packet(packet_out);
-
-                let req_res = self.packet_sink.0.send((req, grpcio::WriteFlags::default())).await;
+                let req_res = self.session.send_packet(packet_out);
                 if req_res.is_err() {
-                    error!("failed to send request over stream channel: {:#?}", req_res.err());
+                    error!("failed to send packet over stream channel: {:#?}", req_res.err());
                 }
             }
         }
@@ -646,10 +523,24 @@ impl SwitchClient {
     /// * `delta` - DDlog output relations.
     #[instrument]
     pub async fn push_ddlog_outputs(&mut self, delta: &DeltaMap<DDValue>) -> Result<(), p4ext::P4Error> {
-        let (updates, packet_outs) = self.ddlog_outputs_to_dataplane(delta).await;
+        let (updates, packet_outs) = self.ddlog_outputs_to_dataplane(delta).await?;
         self.push_dataplane_outputs(updates, packet_outs).await
     }
 
+    /// Translates the `simple_switch_CLI` commands in the file at `commands` into P4Runtime
+    /// updates and writes them to the switch.
+    ///
+    /// # Arguments
+    /// * `commands` - Filepath for the commands file.
+    pub async fn apply_commands(&mut self, commands: &str) -> Result<(), p4ext::P4Error> {
+        let pipeline = self.session.get_pipeline().await?;
+        let switch: p4ext::Switch = pipeline.get_p4info().into();
+
+        let updates = p4ext::commands::parse_commands_file(&switch, commands)
+            .map_err(|e| p4ext::P4Error::InvalidArgument(format!("{:#}", e)))?;
+        self.push_dataplane_outputs(updates, Vec::new()).await
+    }
+
     /// Update the multicast group entry using P4 Runtime.
     ///
     /// # Arguments
@@ -700,12 +591,13 @@ impl SwitchClient {
 
         // We read all current multicast entities using group id 0.
         // We then find the replicas for the desired multicast group.
-        // Since this search is wild-carded, we can safely unwrap the result.
-        let mcast_entries = p4ext::read(
-            vec![p4ext::build_multicast_read(0)],
-            self.device_id,
-            &self.client.0,
-        ).await.unwrap();
+        let mcast_entries = match self.session.read(vec![p4ext::build_multicast_read(0)]).await {
+            Ok(mcast_entries) => mcast_entries,
+            Err(e) => {
+                error!("could not read multicast groups from switch: {:#?}", e);
+                return;
+            }
+        };
 
         // We find the replicas for the current multicast group.
         let mut replicas = Vec::new();
@@ -763,13 +655,7 @@ impl SwitchClient {
             replicas,
         );
 
-        let write_res = p4ext::write(
-            vec![mcast_update],
-            self.device_id,
-            self.role_id,
-            &self.target,
-            &self.client.0,
-        );
+        let write_res = self.session.write(vec![mcast_update]).await;
         if write_res.is_err() {
             error!("could not push multicast update to switch: {:#?}", write_res.err());
         }
@@ -1324,32 +1210,40 @@ impl ControllerActor {
 
         // Create a SwitchClient for each config from the management plane.
         for config in configs {
-            let env = Arc::new(EnvBuilder::new().build());
-            let ch = ChannelBuilder::new(env).connect(config.target.as_str());
-            let client = P4RuntimeClient::new(ch);
+            // Connect to the switch. A primary controller uses a higher election ID than a backup.
+            let election_id = if config.is_primary { 2 } else { 1 };
+            let session = match p4ext::Session::connect(
+                &config.target,
+                config.device_id,
+                config.role_id,
+                election_id,
+            ).await {
+                Ok(session) => session,
+                Err(e) => {
+                    error!("could not connect to switch {}: {:#?}", config.target, e);
+                    continue;
+                }
+            };
+            if config.is_primary && !session.is_primary() {
+                error!("controller did not become primary for switch {}", config.target);
+            }
 
             // Create a SwitchClient.
             // Handles communication with the switch.
-            let mut sc = SwitchClient::new(
-                client,
+            let mut sc = match SwitchClient::new(
+                session,
                 self.common_state.p4info.clone(),
                 self.common_state.json.clone(),
                 self.common_state.cookie.clone(),
                 self.common_state.action.clone(),
-                config.device_id,
-                config.role_id,
-                config.target.clone(),
                 config.client_id.clone(),
-            ).await;
-
-            // If primary, set the controller as primary using P4Runtime.
-            // This enables use of the StreamChannel RPC.
-            if config.is_primary {
-                let mau_res = p4ext::master_arbitration_update(config.device_id, &sc.client.0).await;
-                if mau_res.is_err() {
-                    panic!("could not set master arbitration on switch: {:#?}", mau_res.err());
+            ).await {
+                Ok(sc) => sc,
+                Err(e) => {
+                    error!("could not set up switch client for {}: {:#?}", config.target, e);
+                    continue;
                 }
-            }
+            };
 
             // Start streaming messages from the dataplane.
             // Set the configuration as a notification per-digest.
@@ -1360,24 +1254,33 @@ impl ControllerActor {
             }
 
             // Start the dataplane response for the client.
-            let (sink, receiver) = sc.client.0.stream_channel().unwrap();
             let digest_actor_tx = mpsc::Sender::clone(&client_tx);
 
             let dp_resp_metadata = DataplaneResponseMetadata {
                 client_id: config.client_id,
-                device_id: config.device_id,
             };
 
             let mut digest_actor = DataplaneResponseActor::new(
-                sink,
-                receiver,
+                sc.session.subscribe_digests(),
+                sc.session.subscribe_packets(),
                 digest_actor_tx,
                 dp_resp_metadata
             );
             tokio::spawn(async move { digest_actor.run().await });
 
+            // Apply the static configuration from the commands file, if any.
+            if Path::new(&self.common_state.commands).exists() {
+                let commands_res = sc.apply_commands(&self.common_state.commands).await;
+                if commands_res.is_err() {
+                    error!("could not apply commands: {:#?}", commands_res.err());
+                }
+            }
+
             // Push initial contents to the switch.
-            sc.push_ddlog_outputs(&self.common_state.initial_contents).await.unwrap();
+            let push_res = sc.push_ddlog_outputs(&self.common_state.initial_contents).await;
+            if push_res.is_err() {
+                error!("could not push initial contents to switch: {:#?}", push_res.err());
+            }
 
             // Add the client to the vector for return.
             clients.push(sc);
@@ -1391,16 +1294,14 @@ impl ControllerActor {
 struct DataplaneResponseMetadata {
     /// UUID of the configuration of the client switch in OVSDB.
     pub client_id: BigInt,
-    /// ID of the P4-enabled device
-    pub device_id: u64,
 }
 
 /// Actor that processes responses from the dataplane.
 struct DataplaneResponseActor {
-    /// Sends message to the data plane.
-    to_data_plane: StreamingCallSink<StreamMessageRequest>,
-    /// Receives messages from the data plane.
-    receiver: ClientDuplexReceiver<StreamMessageResponse>,
+    /// Receives digests from the data plane.
+    digests: mpsc::UnboundedReceiver<DigestList>,
+    /// Receives packets from the data plane.
+    packets: mpsc::UnboundedReceiver<PacketIn>,
     /// Sends DDlog updates to the controller actor.
     to_controller: mpsc::Sender<Option<Vec<Update<DDValue>>>>,
     /// Metadata for relations sent from the dataplane.
@@ -1411,84 +1312,61 @@ impl DataplaneResponseActor {
     /// Return actor that processes responses from the data plane.
     ///
     /// # Arguments
-    /// * `to_data_plane` - sends messages to the data plane.
-    /// * `receiver` - receives messages from the data plane.
+    /// * `digests` - receives digests from the data plane.
+    /// * `packets` - receives packets from the data plane.
     /// * `to_controller` - sends DDlog updates to the controller actor.
     /// * `metadata` - metadata used in sending messages to/from the data plane.
     fn new(
-        to_data_plane: StreamingCallSink<StreamMessageRequest>,
-        receiver: ClientDuplexReceiver<StreamMessageResponse>,
+        digests: mpsc::UnboundedReceiver<DigestList>,
+        packets: mpsc::UnboundedReceiver<PacketIn>,
         to_controller: mpsc::Sender<Option<Vec<Update<DDValue>>>>,
         metadata: DataplaneResponseMetadata,
     ) -> Self {
         Self {
-            to_data_plane,
-            receiver,
+            digests,
+            packets,
             to_controller,
             metadata,
         }
     }
 
-    /// Run the actor indefinitely. Handle each received message. 
+    /// Run the actor until the switch's session goes away. Handle each received message.
     async fn run(&mut self) {
-        // Send a master arbitration update. This lets the actor properly stream responses from the dataplane.
-        let mut update = MasterArbitrationUpdate::new();
-        update.set_device_id(self.metadata.device_id);
-
-        let mut smr = StreamMessageRequest::new();
-        smr.set_arbitration(update);
-        let req_result = self.to_data_plane.send((smr, grpcio::WriteFlags::default())).await;
-        if req_result.is_err() {
-            panic!("failed to configure stream channel with master arbitration update: {:#?}", req_result.err());
+        loop {
+            tokio::select! {
+                Some(d) = self.digests.recv() => self.handle_digest(d).await,
+                Some(p) = self.packets.recv() => self.handle_packet_in(p).await,
+                else => break,
+            }
         }
+    }
 
-        while let Some(result) = self.receiver.next().await {
-            self.handle_dataplane_message(result).await;
+    /// Convert a received digest into DDlog inputs. Send inputs to the controller.
+    ///
+    /// # Arguments
+    /// * `d` - digest list from the dataplane.
+    pub async fn handle_digest(&self, d: DigestList) {
+        for data in d.get_data().iter() {
+            let dd_update_opt = digest_to_ddlog(d.get_digest_id(), data);
+
+            let channel_res = self.to_controller.send(dd_update_opt).await;
+            if channel_res.is_err() {
+                error!("could not send response over channel: {:#?}", channel_res);
+            }
         }
     }
 
-    /// Handle dataplane messages. Convert received digests into DDlog inputs. Send inputs to the controller.
+    /// Convert a received packet into DDlog inputs. Send inputs to the controller.
     ///
     /// # Arguments
-    /// * `res` - result from the dataplane.
-    pub async fn handle_dataplane_message(&self, res: Result<StreamMessageResponse, grpcio::Error>) {
-        match res {
-            Err(e) => error!("received GRPC error from p4runtime streaming channel: {:#?}", e),
-            Ok(r) => {
-                let p4_update_opt = r.update;
-                if p4_update_opt.is_none() {
-                    debug!("received empty response from p4runtime streaming channel");
-                    return;
-                }
-
-                use proto::p4runtime::StreamMessageResponse_oneof_update::*;
-
-                // unwrap() is safe because of none check
-                match p4_update_opt.unwrap() {
-                    digest(d) => {
-                        for data in d.get_data().iter() {
-                            let dd_update_opt = digest_to_ddlog(d.get_digest_id(), data);
-                            
-                            let channel_res = self.to_controller.send(dd_update_opt).await;
-                            if channel_res.is_err() {
-                                error!("could not send response over channel: {:#?}", channel_res);
-                            }
-                        }
-                    },
-                    packet(p) => {
-                        let dd_update_opt = packet_in_to_ddlog(p, self.metadata.client_id.clone());
-                        debug!("received packetin update: {:#?}", dd_update_opt);
-
-                        let channel_res = self.to_controller.send(dd_update_opt).await;
-                        if channel_res.is_err() {
-                            error!("could not send response over channel: {:#?}", channel_res);
-                        }
-                    }
-                    error(e) => error!("received error from p4runtime streaming channel: {:#?}", e),
-                    // no action for arbitration, idle timeout, or other
-                    m => debug!("received message from p4runtime streaming channel: {:#?}", m),
-                };
-            }
+    /// * `p` - packet from the dataplane.
+    pub async fn handle_packet_in(&self, p: PacketIn) {
+        let dd_update_opt = packet_in_to_ddlog(p, self.metadata.client_id.clone());
+        debug!("received packetin update: {:#?}", dd_update_opt);
+
+        let channel_res = self.to_controller.send(dd_update_opt).await;
+        if channel_res.is_err() {
+            error!("could not send response over channel: {:#?}", channel_res);
         }
     }
 }
@@ -1520,4 +1398,7 @@ pub struct SwitchClientCommonState {
     pub cookie: String,
     /// Configuration action for the forwarding pipeline. 
     pub action: String,
+    /// Filepath for `simple_switch_CLI` commands to apply to each switch before the initial
+    /// contents.  Ignored if the file does not exist.
+    pub commands: String,
 }
\ No newline at end of file
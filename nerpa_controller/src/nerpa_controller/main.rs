@@ -106,6 +106,7 @@ async fn run_controller(
     let json = format!("{}/{}.json", file_dir, file_name);
     let cookie = String::from("");
     let action = String::from("verify-and-commit");
+    let commands = format!("{}/commands.txt", file_dir);
 
     let common_state = SwitchClientCommonState {
         initial_contents,
@@ -113,6 +114,7 @@ async fn run_controller(
         json,
         cookie,
         action,
+        commands,
     };
 
     // Instantiate controller.
@@ -0,0 +1,319 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+
+//! P4Runtime action profiles and action selectors.
+//!
+//! An entry in a table with an action profile doesn't specify an action.  Instead, it refers to a
+//! member of the profile, which is an action with arguments, or, if the profile has a selector,
+//! to a group of members, from which the selector chooses one for each packet.  ofp4 keeps the
+//! members and groups of each profile and passes them to the translation, for each table that
+//! uses the profile, in the table's `<Table>Member` and `<Table>GroupMember` input relations.  A
+//! translation typically turns each table entry that refers to a group into an OpenFlow `select`
+//! group with a bucket for each member.
+
+use anyhow::{Context, Result};
+
+use grpcio::RpcStatusCode;
+
+use p4ext::server::validate_write;
+use p4ext::{ActionProfileGroup, ActionProfileId, ActionProfileMember, ActionProfileRef, Error, Table, TableAction};
+
+use proto::p4info::{ActionProfile, P4Info};
+use proto::p4runtime::Update_Type;
+
+use std::collections::{BTreeMap, HashMap};
+
+/// The members and groups of every action profile.
+#[derive(Default)]
+pub struct ActionProfiles {
+    // Members' actions, indexed by action profile ID and member ID.
+    members: BTreeMap<(ActionProfileId, u32), TableAction>,
+
+    // Groups, indexed by action profile ID and group ID.
+    groups: BTreeMap<(ActionProfileId, u32), ActionProfileGroup>,
+}
+
+/// Returns the action profile with ID `id` in `p4info`.
+fn action_profile(p4info: &P4Info, id: ActionProfileId) -> Result<&ActionProfile> {
+    match p4info.get_action_profiles().iter().find(|ap| ap.get_preamble().id == id) {
+        Some(ap) => Ok(ap),
+        None => Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("unknown action profile {}", id)),
+    }
+}
+
+impl ActionProfiles {
+    /// Checks whether `op` may be applied to `member`.  `table_schemas` must include the tables
+    /// that use the member's action profile.  Returns the member's new action, or `None` if `op`
+    /// deletes it.
+    ///
+    /// The caller must check that no table entry refers to a member that `op` deletes.
+    pub fn validate_member(&self, op: Update_Type, member: &ActionProfileMember, p4info: &P4Info,
+                           table_schemas: &HashMap<u32, Table>) -> Result<Option<TableAction>> {
+        let profile = action_profile(p4info, member.action_profile_id)?;
+        let profile_id = member.action_profile_id;
+        validate_write(op, self.members.contains_key(&(profile_id, member.member_id)))?;
+        if op == Update_Type::DELETE {
+            if let Some(group) = self.groups.values().find(|group| group.action_profile_id == profile_id
+                                                         && group.members.contains_key(&member.member_id)) {
+                Err(Error(RpcStatusCode::FAILED_PRECONDITION))
+                    .context(format!("member {} is in group {}", member.member_id, group.group_id))?;
+            }
+            return Ok(None);
+        }
+
+        if op == Update_Type::INSERT && profile.size > 0 {
+            let n_members = self.members.range((profile_id, 0)..=(profile_id, u32::MAX)).count();
+            if n_members as i64 >= profile.size {
+                Err(Error(RpcStatusCode::RESOURCE_EXHAUSTED))
+                    .context(format!("action profile {} is full ({} members)", profile.get_preamble().name, profile.size))?;
+            }
+        }
+
+        let action = match &member.action {
+            Some(action) => action,
+            None => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("member {} lacks an action", member.member_id))?,
+        };
+
+        // The tables that share an action profile have the same actions, so any of them will do
+        // for checking the action.
+        let table = match profile.table_ids.iter().find_map(|table_id| table_schemas.get(table_id)) {
+            Some(table) => table,
+            None => Err(Error(RpcStatusCode::FAILED_PRECONDITION))
+                .context(format!("action profile {} has no tables", profile.get_preamble().name))?,
+        };
+        action.validate(table, false)?;
+        Ok(Some(action.clone()))
+    }
+
+    /// Checks whether `op` may be applied to `group`.  Returns the group's new value, or `None`
+    /// if `op` deletes it.
+    ///
+    /// The caller must check that no table entry refers to a group that `op` deletes.
+    pub fn validate_group(&self, op: Update_Type, group: &ActionProfileGroup, p4info: &P4Info)
+                          -> Result<Option<ActionProfileGroup>> {
+        let profile = action_profile(p4info, group.action_profile_id)?;
+        let profile_id = group.action_profile_id;
+        if !profile.with_selector {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("action profile {} has no selector, so it cannot have groups", profile.get_preamble().name))?;
+        }
+        let old_group = self.groups.get(&(profile_id, group.group_id));
+        validate_write(op, old_group.is_some())?;
+        if op == Update_Type::DELETE {
+            return Ok(None);
+        }
+
+        if let Some(member_id) = group.members.keys().find(|&&member_id| !self.members.contains_key(&(profile_id, member_id))) {
+            Err(Error(RpcStatusCode::NOT_FOUND))
+                .context(format!("group {} refers to unknown member {}", group.group_id, member_id))?;
+        }
+        if let Some(old_group) = old_group.filter(|old_group| old_group.max_size != group.max_size) {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("group {} max_size may not change from {}", group.group_id, old_group.max_size))?;
+        }
+        if profile.max_group_size > 0 && group.max_size > profile.max_group_size {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("group {} max_size {} exceeds action profile {} max_group_size {}",
+                                 group.group_id, group.max_size, profile.get_preamble().name, profile.max_group_size))?;
+        }
+        let max_size = if group.max_size > 0 { group.max_size } else { profile.max_group_size };
+        if max_size > 0 && group.members.len() > max_size as usize {
+            Err(Error(RpcStatusCode::RESOURCE_EXHAUSTED))
+                .context(format!("group {} has {} members, more than its maximum of {}",
+                                 group.group_id, group.members.len(), max_size))?;
+        }
+        Ok(Some(group.clone()))
+    }
+
+    /// Returns the action of member `member_id` of action profile `profile_id`, if it exists.
+    pub fn get_member(&self, profile_id: ActionProfileId, member_id: u32) -> Option<&TableAction> {
+        self.members.get(&(profile_id, member_id))
+    }
+
+    /// Returns group `group_id` of action profile `profile_id`, if it exists.
+    pub fn get_group(&self, profile_id: ActionProfileId, group_id: u32) -> Option<&ActionProfileGroup> {
+        self.groups.get(&(profile_id, group_id))
+    }
+
+    /// Returns true if action profile `profile_id` has the member or group that `apr` refers to.
+    pub fn contains(&self, profile_id: ActionProfileId, apr: ActionProfileRef) -> bool {
+        match apr {
+            ActionProfileRef::Member(member_id) => self.members.contains_key(&(profile_id, member_id)),
+            ActionProfileRef::Group(group_id) => self.groups.contains_key(&(profile_id, group_id)),
+        }
+    }
+
+    /// Returns the action profile ID, member ID, and action of every member.
+    pub fn members(&self) -> impl Iterator<Item = (ActionProfileId, u32, &TableAction)> {
+        self.members.iter().map(|(&(profile_id, member_id), action)| (profile_id, member_id, action))
+    }
+
+    /// Returns every group.
+    pub fn groups(&self) -> impl Iterator<Item = &ActionProfileGroup> {
+        self.groups.values()
+    }
+
+    /// Sets the action of member `member_id` of action profile `profile_id` to `action`, or
+    /// deletes the member if `action` is `None`.  Returns the old action.
+    pub fn set_member(&mut self, profile_id: ActionProfileId, member_id: u32, action: Option<TableAction>)
+                      -> Option<TableAction> {
+        match action {
+            Some(action) => self.members.insert((profile_id, member_id), action),
+            None => self.members.remove(&(profile_id, member_id)),
+        }
+    }
+
+    /// Sets group `group_id` of action profile `profile_id` to `group`, or deletes the group if
+    /// `group` is `None`.  Returns the old group.
+    pub fn set_group(&mut self, profile_id: ActionProfileId, group_id: u32, group: Option<ActionProfileGroup>)
+                     -> Option<ActionProfileGroup> {
+        match group {
+            Some(group) => self.groups.insert((profile_id, group_id), group),
+            None => self.groups.remove(&(profile_id, group_id)),
+        }
+    }
+
+    /// Implements the P4Runtime `Read` operation for action profile members.  An
+    /// `action_profile_id` or `member_id` of 0 in `target` acts as a wildcard.
+    pub fn read_members(&self, target: &ActionProfileMember, p4info: &P4Info) -> Result<Vec<ActionProfileMember>> {
+        if target.action_profile_id != 0 {
+            action_profile(p4info, target.action_profile_id)?;
+        }
+        Ok(self.members()
+            .filter(|&(profile_id, member_id, _)| (target.action_profile_id == 0 || target.action_profile_id == profile_id)
+                    && (target.member_id == 0 || target.member_id == member_id))
+            .map(|(action_profile_id, member_id, action)| ActionProfileMember {
+                action_profile_id, member_id, action: Some(action.clone())
+            })
+            .collect())
+    }
+
+    /// Implements the P4Runtime `Read` operation for action profile groups.  An
+    /// `action_profile_id` or `group_id` of 0 in `target` acts as a wildcard.
+    pub fn read_groups(&self, target: &ActionProfileGroup, p4info: &P4Info) -> Result<Vec<ActionProfileGroup>> {
+        if target.action_profile_id != 0 {
+            action_profile(p4info, target.action_profile_id)?;
+        }
+        Ok(self.groups()
+            .filter(|group| (target.action_profile_id == 0 || target.action_profile_id == group.action_profile_id)
+                    && (target.group_id == 0 || target.group_id == group.group_id))
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use p4ext::server::error_code;
+
+    const PROFILE_ID: ActionProfileId = 1;
+    const MAX_GROUP_SIZE: i32 = 2;
+
+    /// Returns a P4Info with action profile `ap`, with a selector and groups of at most
+    /// `MAX_GROUP_SIZE` members.
+    fn p4info() -> P4Info {
+        let mut profile = ActionProfile::new();
+        profile.mut_preamble().set_id(PROFILE_ID);
+        profile.mut_preamble().set_name("ap".into());
+        profile.with_selector = true;
+        profile.max_group_size = MAX_GROUP_SIZE;
+
+        let mut p4info = P4Info::new();
+        p4info.mut_action_profiles().push(profile);
+        p4info
+    }
+
+    fn action() -> TableAction {
+        TableAction { action_id: 1, params: Vec::new() }
+    }
+
+    /// Returns group `group_id` of action profile `profile_id` with weight-1 members `members`.
+    fn group(profile_id: ActionProfileId, group_id: u32, members: &[u32], max_size: i32) -> ActionProfileGroup {
+        ActionProfileGroup {
+            action_profile_id: profile_id,
+            group_id,
+            members: members.iter().map(|&member_id| (member_id, 1)).collect(),
+            max_size
+        }
+    }
+
+    #[test]
+    fn validate_group() {
+        let p4info = p4info();
+        let mut profiles = ActionProfiles::default();
+        profiles.set_member(PROFILE_ID, 1, Some(action()));
+        profiles.set_member(PROFILE_ID, 2, Some(action()));
+        profiles.set_member(PROFILE_ID, 3, Some(action()));
+
+        let g = group(PROFILE_ID, 1, &[1, 2], 0);
+        assert_eq!(profiles.validate_group(Update_Type::INSERT, &g, &p4info).unwrap(), Some(g.clone()));
+        profiles.set_group(PROFILE_ID, 1, Some(g.clone()));
+        assert_eq!(profiles.validate_group(Update_Type::DELETE, &g, &p4info).unwrap(), None);
+
+        for &(op, ref g, code) in &[
+            (Update_Type::INSERT, group(PROFILE_ID, 1, &[1], 0), RpcStatusCode::ALREADY_EXISTS),
+            (Update_Type::MODIFY, group(PROFILE_ID, 2, &[1], 0), RpcStatusCode::NOT_FOUND),
+            (Update_Type::INSERT, group(PROFILE_ID + 1, 2, &[1], 0), RpcStatusCode::NOT_FOUND),
+            (Update_Type::INSERT, group(PROFILE_ID, 2, &[4], 0), RpcStatusCode::NOT_FOUND),
+            (Update_Type::INSERT, group(PROFILE_ID, 2, &[1, 2, 3], 0), RpcStatusCode::RESOURCE_EXHAUSTED),
+            (Update_Type::INSERT, group(PROFILE_ID, 2, &[1], MAX_GROUP_SIZE + 1), RpcStatusCode::INVALID_ARGUMENT),
+            (Update_Type::MODIFY, group(PROFILE_ID, 1, &[1], 1), RpcStatusCode::INVALID_ARGUMENT),
+        ] {
+            let error = profiles.validate_group(op, g, &p4info).unwrap_err();
+            assert_eq!(error_code(&error), code, "{:?} {:?}", op, g);
+        }
+
+        // A member can't be deleted while a group contains it.
+        let member = ActionProfileMember { action_profile_id: PROFILE_ID, member_id: 1, action: None };
+        let error = profiles.validate_member(Update_Type::DELETE, &member, &p4info, &HashMap::new()).unwrap_err();
+        assert_eq!(error_code(&error), RpcStatusCode::FAILED_PRECONDITION);
+        let member = ActionProfileMember { member_id: 3, ..member };
+        assert_eq!(profiles.validate_member(Update_Type::DELETE, &member, &p4info, &HashMap::new()).unwrap(), None);
+    }
+
+    #[test]
+    fn read() {
+        let p4info = p4info();
+        let mut profiles = ActionProfiles::default();
+        profiles.set_member(PROFILE_ID, 1, Some(action()));
+        profiles.set_member(PROFILE_ID, 2, Some(action()));
+        profiles.set_group(PROFILE_ID, 1, Some(group(PROFILE_ID, 1, &[1, 2], 0)));
+
+        let member_ids = |members: Vec<ActionProfileMember>| -> Vec<u32> {
+            members.iter().map(|m| m.member_id).collect()
+        };
+        let target = ActionProfileMember { action_profile_id: 0, member_id: 0, action: None };
+        assert_eq!(member_ids(profiles.read_members(&target, &p4info).unwrap()), vec![1, 2]);
+        let target = ActionProfileMember { action_profile_id: PROFILE_ID, member_id: 2, action: None };
+        assert_eq!(profiles.read_members(&target, &p4info).unwrap(),
+                   vec![ActionProfileMember { action_profile_id: PROFILE_ID, member_id: 2, action: Some(action()) }]);
+
+        assert_eq!(profiles.read_groups(&group(0, 0, &[], 0), &p4info).unwrap(),
+                   vec![group(PROFILE_ID, 1, &[1, 2], 0)]);
+        assert_eq!(profiles.read_groups(&group(PROFILE_ID, 2, &[], 0), &p4info).unwrap(), Vec::new());
+
+        let error = profiles.read_groups(&group(PROFILE_ID + 1, 0, &[], 0), &p4info).unwrap_err();
+        assert_eq!(error_code(&error), RpcStatusCode::NOT_FOUND);
+    }
+}
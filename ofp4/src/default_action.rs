@@ -0,0 +1,291 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! P4Runtime default actions.
+//!
+//! Each P4 table has a default action, which applies to packets that miss every entry.  ofp4
+//! keeps track of it apart from the table's entries.  Initially, it is the one that the P4 program
+//! specifies, which ofp4 takes from the bmv2 JSON in the forwarding pipeline configuration, and
+//! which the translation builds into the table's miss flow.  The controller may change it with a
+//! MODIFY, unless the program declares it `const`, and restore the initial default with a MODIFY
+//! that lacks an action.  ofp4 passes the changed default action to the translation in the
+//! table's `<Table>DefaultAction` input relation.
+
+use anyhow::{Context, Result};
+
+use grpcio::RpcStatusCode;
+
+use p4ext::{ActionParam, Error, FieldValue, Table, TableAction, TableEntry, TableKey, TableValue};
+
+use proto::p4runtime::{Entity, Update_Type};
+
+use serde::Deserialize;
+
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Deserialize)]
+struct Bmv2Program {
+    actions: Vec<Bmv2Action>,
+    pipelines: Vec<Bmv2Pipeline>,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Action {
+    name: String,
+    id: u32,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Pipeline {
+    tables: Vec<Bmv2Table>,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Table {
+    name: String,
+    default_entry: Option<Bmv2DefaultEntry>,
+}
+
+#[derive(Deserialize)]
+struct Bmv2DefaultEntry {
+    action_id: u32,
+    action_data: Vec<String>,
+}
+
+/// Default actions, indexed by table ID.
+#[derive(Default)]
+pub struct DefaultActions {
+    // The default actions that the P4 program specifies.
+    initial: HashMap<u32, TableAction>,
+
+    // The default actions that the controller changed.
+    changed: HashMap<u32, TableAction>,
+}
+
+/// Returns the default action for `table` that bmv2 JSON `program` specifies, if any.
+fn bmv2_default_action(program: &Bmv2Program, table: &Table) -> Option<TableAction> {
+    let default_entry = program.pipelines.iter()
+        .flat_map(|pipeline| pipeline.tables.iter())
+        .find(|t| t.name == table.preamble.name)?
+        .default_entry.as_ref()?;
+    let name = &program.actions.iter().find(|a| a.id == default_entry.action_id)?.name;
+    let action = &table.actions.iter().find(|ar| &ar.action.preamble.name == name)?.action;
+    if action.params.len() != default_entry.action_data.len() {
+        return None;
+    }
+    let params = action.params.iter().zip(default_entry.action_data.iter())
+        .map(|(p, data)| {
+            let value = u128::from_str_radix(data.strip_prefix("0x")?, 16).ok()?;
+            Some(ActionParam { param_id: p.preamble.id, value: FieldValue(value) })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(TableAction { action_id: action.preamble.id, params })
+}
+
+impl DefaultActions {
+    /// Sets the initial default actions for `tables`, taking them from `device_config`, the bmv2
+    /// JSON for the P4 program, or from the P4Info for tables that it doesn't cover.  Like table
+    /// entries, the default actions that the controller changed stay in place.
+    pub fn configure(&mut self, tables: &HashMap<u32, Table>, device_config: &[u8]) {
+        let program: Option<Bmv2Program> = serde_json::from_slice(device_config).ok();
+        self.initial = tables.iter()
+            .filter_map(|(&table_id, table)| {
+                let action = program.as_ref()
+                    .and_then(|program| bmv2_default_action(program, table))
+                    .or_else(|| {
+                        // Without the bmv2 JSON, we know a const default action, although not
+                        // its arguments, and otherwise P4 defaults to NoAction.
+                        let action_id = match &table.const_default_action {
+                            Some(action) => action.preamble.id,
+                            None => table.action_by_name("NoAction")?.action.preamble.id,
+                        };
+                        Some(TableAction { action_id, params: Vec::new() })
+                    })?;
+                Some((table_id, action))
+            })
+            .collect();
+    }
+
+    /// Returns the default action for table `table_id`, if it is known.
+    pub fn get(&self, table_id: u32) -> Option<&TableAction> {
+        self.changed.get(&table_id).or_else(|| self.initial.get(&table_id))
+    }
+
+    /// Returns the default action for table `table_id` if the controller changed it.
+    pub fn get_changed(&self, table_id: u32) -> Option<&TableAction> {
+        self.changed.get(&table_id)
+    }
+
+    /// Checks whether `op` may be applied to `entry`, which must be the default entry for
+    /// `table`.  Returns the table's new default action, or `None` if `op` restores the initial
+    /// default action.
+    pub fn validate(&self, op: Update_Type, entry: &TableEntry, table: &Table) -> Result<Option<TableAction>> {
+        if op != Update_Type::MODIFY {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("{:?} of the default action for table {}", op, table.base_name()))?;
+        }
+        if table.const_default_action.is_some() {
+            Err(Error(RpcStatusCode::PERMISSION_DENIED))
+                .context(format!("table {} has a const default action", table.base_name()))?;
+        }
+        if entry.value.action.is_none() && entry.value.action_profile_ref.is_none() {
+            return Ok(None);
+        }
+        entry.validate(table)?;
+        Ok(entry.value.action.clone().filter(|action| self.initial.get(&table.preamble.id) != Some(action)))
+    }
+
+    /// Sets the default action for table `table_id` to `action`, or to the initial default
+    /// action if `action` is `None`.  Returns the previous changed default action, if any.
+    pub fn set(&mut self, table_id: u32, action: Option<TableAction>) -> Option<TableAction> {
+        match action {
+            Some(action) => self.changed.insert(table_id, action),
+            None => self.changed.remove(&table_id),
+        }
+    }
+
+    /// Returns the default entries that `target` selects, which is the default entry for its
+    /// table or, if its table ID is 0, for every table.
+    pub fn read(&self, target: &proto::p4runtime::TableEntry) -> Vec<Entity> {
+        let defaults: BTreeMap<u32, &TableAction> = self.initial.keys().chain(self.changed.keys())
+            .filter(|&&table_id| target.table_id == 0 || target.table_id == table_id)
+            .filter_map(|&table_id| Some((table_id, self.get(table_id)?)))
+            .collect();
+        defaults.into_iter()
+            .map(|(table_id, action)| {
+                let te = TableEntry {
+                    key: TableKey { table_id, matches: Vec::new(), priority: 0, is_default_action: true },
+                    value: TableValue {
+                        action: Some(action.clone()),
+                        action_profile_ref: None,
+                        controller_metadata: 0,
+                        metadata: Vec::new(),
+                        idle_timeout_ns: 0,
+                    },
+                };
+                let mut entity = Entity::new();
+                entity.set_table_entry((&te).into());
+                entity
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use p4ext::{Action, ActionRef, Param, Preamble, Scope};
+
+    const TABLE_ID: u32 = 1;
+    const SET_PORT_ID: u32 = 10;
+    const NO_ACTION_ID: u32 = 20;
+
+    fn preamble(id: u32, name: &str) -> Preamble {
+        Preamble { id, name: name.into(), ..Default::default() }
+    }
+
+    fn action_ref(action: Action) -> ActionRef {
+        ActionRef { action, scope: Scope::TableAndDefault, annotations: Default::default() }
+    }
+
+    /// Returns table `ingress.t`, whose actions are `ingress.set_port(port)` and `NoAction`.
+    fn table() -> Table {
+        let set_port = Action {
+            preamble: preamble(SET_PORT_ID, "ingress.set_port"),
+            params: vec![Param { preamble: preamble(1, "port"), bit_width: 9, type_name: None }],
+        };
+        let no_action = Action { preamble: preamble(NO_ACTION_ID, "NoAction"), params: Vec::new() };
+
+        let mut table = Table::default();
+        table.preamble = preamble(TABLE_ID, "ingress.t");
+        table.actions = vec![action_ref(set_port), action_ref(no_action)];
+        table
+    }
+
+    /// Returns a bmv2 program whose table `table_name` has a default entry that invokes the
+    /// action with bmv2 ID `action_id` with `action_data`.  The program's action with bmv2 ID 0
+    /// is `ingress.set_port` and the one with ID 1 is `NoAction`.
+    fn program(table_name: &str, action_id: u32, action_data: &[&str]) -> Bmv2Program {
+        let json = serde_json::json!({
+            "actions": [
+                { "name": "ingress.set_port", "id": 0 },
+                { "name": "NoAction", "id": 1 },
+            ],
+            "pipelines": [{
+                "tables": [{
+                    "name": table_name,
+                    "default_entry": { "action_id": action_id, "action_data": action_data },
+                }],
+            }],
+        });
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn set_port(port: u128) -> TableAction {
+        TableAction { action_id: SET_PORT_ID, params: vec![ActionParam { param_id: 1, value: FieldValue(port) }] }
+    }
+
+    #[test]
+    fn bmv2() {
+        let table = table();
+        assert_eq!(bmv2_default_action(&program("ingress.t", 0, &["0x0005"]), &table), Some(set_port(5)));
+        assert_eq!(bmv2_default_action(&program("ingress.t", 1, &[]), &table),
+                   Some(TableAction { action_id: NO_ACTION_ID, params: Vec::new() }));
+
+        // A different table, an unknown action, and malformed action data all yield nothing.
+        assert_eq!(bmv2_default_action(&program("ingress.u", 0, &["0x0005"]), &table), None);
+        assert_eq!(bmv2_default_action(&program("ingress.t", 2, &[]), &table), None);
+        assert_eq!(bmv2_default_action(&program("ingress.t", 0, &["5"]), &table), None);
+        assert_eq!(bmv2_default_action(&program("ingress.t", 0, &["0xzz"]), &table), None);
+
+        // So does action data with too few or too many arguments for the action.
+        assert_eq!(bmv2_default_action(&program("ingress.t", 0, &[]), &table), None);
+        assert_eq!(bmv2_default_action(&program("ingress.t", 0, &["0x0005", "0x0006"]), &table), None);
+    }
+
+    #[test]
+    fn configure() {
+        let tables: HashMap<u32, Table> = vec![(TABLE_ID, table())].into_iter().collect();
+        let mut defaults = DefaultActions::default();
+
+        let json = serde_json::to_vec(&serde_json::json!({
+            "actions": [{ "name": "ingress.set_port", "id": 0 }],
+            "pipelines": [{
+                "tables": [{
+                    "name": "ingress.t",
+                    "default_entry": { "action_id": 0, "action_data": ["0x7"] },
+                }],
+            }],
+        })).unwrap();
+        defaults.configure(&tables, &json);
+        assert_eq!(defaults.get(TABLE_ID), Some(&set_port(7)));
+
+        // Without the bmv2 JSON, the default is NoAction.
+        defaults.configure(&tables, b"not json");
+        assert_eq!(defaults.get(TABLE_ID), Some(&TableAction { action_id: NO_ACTION_ID, params: Vec::new() }));
+
+        // A changed default action overrides the initial one until it is restored.
+        assert_eq!(defaults.set(TABLE_ID, Some(set_port(3))), None);
+        assert_eq!(defaults.get(TABLE_ID), Some(&set_port(3)));
+        assert_eq!(defaults.set(TABLE_ID, None), Some(set_port(3)));
+        assert_eq!(defaults.get(TABLE_ID), Some(&TableAction { action_id: NO_ACTION_ID, params: Vec::new() }));
+    }
+}
@@ -0,0 +1,415 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! P4Runtime digests.
+//!
+//! The OpenFlow translation of a P4 `digest()` call loads the members of the digest's struct, in
+//! order, into 64-bit registers `xreg4`, `xreg5`, and so on, and then sends the packet to ofp4
+//! with a `controller(userdata=...)` action whose userdata is the name of the digest in ASCII.
+//! ofp4 decodes each such packet-in into digest data and batches the data into `DigestList`s as
+//! the controller configured with a `DigestEntry`.  Until the controller acknowledges a list, or
+//! the acknowledgment timeout passes, ofp4 drops new data identical to data in the list.
+
+use anyhow::{Context, Result};
+
+use grpcio::RpcStatusCode;
+
+use ovs::ofp_packet::{N_XREGS, PacketIn};
+
+use p4ext::Error;
+use p4ext::server::validate_write;
+
+use super::packet::encode_bitstring;
+
+use proto::p4data::{P4Data, P4StructLike};
+use proto::p4info::{Digest, P4Info};
+use proto::p4runtime::{DigestEntry, DigestEntry_Config, DigestList, DigestListAck, Update_Type};
+
+use protobuf::{RepeatedField, SingularPtrField};
+
+use std::collections::HashMap;
+use std::str;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The 64-bit register that holds the first member of a digest.
+pub const DIGEST_XREG_BASE: usize = 4;
+
+/// Digest configuration and state, indexed by digest ID.
+#[derive(Default)]
+pub struct Digests {
+    queues: HashMap<u32, DigestQueue>,
+    next_list_id: u64,
+}
+
+#[derive(Default)]
+struct DigestQueue {
+    config: DigestEntry_Config,
+
+    // Data not yet sent, and when the oldest of it arrived.
+    pending: Vec<P4Data>,
+    oldest: Option<Instant>,
+
+    // Lists sent but not yet acknowledged, indexed by list ID, with the time they were sent.
+    unacked: HashMap<u64, (Instant, Vec<P4Data>)>,
+}
+
+fn nanos(ns: i64) -> Duration {
+    Duration::from_nanos(ns as u64)
+}
+
+impl DigestQueue {
+    /// Returns true if some of the pending data should be sent at `now`.
+    fn is_due(&self, now: Instant) -> bool {
+        match self.oldest {
+            None => false,
+            Some(oldest) => self.is_full() || now >= oldest + nanos(self.config.max_timeout_ns),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.config.max_list_size > 0 && self.pending.len() >= self.config.max_list_size as usize
+    }
+
+    /// Returns true if `data` is already pending or awaiting acknowledgment.
+    fn contains(&self, data: &P4Data) -> bool {
+        self.pending.contains(data) || self.unacked.values().any(|(_, list)| list.contains(data))
+    }
+}
+
+impl Digests {
+    /// Checks whether `op` may be applied to the configuration for the digest in `entry`.  Returns
+    /// the digest's new configuration, or `None` if `op` deletes it.
+    pub fn validate(&self, op: Update_Type, entry: &DigestEntry, p4info: &P4Info) -> Result<Option<DigestEntry_Config>> {
+        if !p4info.get_digests().iter().any(|d| d.get_preamble().id == entry.digest_id) {
+            Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("unknown digest {}", entry.digest_id))?;
+        }
+        validate_write(op, self.queues.contains_key(&entry.digest_id))?;
+
+        if op == Update_Type::DELETE {
+            return Ok(None);
+        }
+        let config = entry.get_config();
+        if config.max_timeout_ns < 0 || config.max_list_size < 0 || config.ack_timeout_ns < 0 {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context("digest configuration must not be negative")?;
+        }
+        Ok(Some(config.clone()))
+    }
+
+    /// Sets the configuration for digest `digest_id` to `config`, or deletes the digest's
+    /// configuration and any data queued for it if `config` is `None`.  Returns the old
+    /// configuration.
+    pub fn configure(&mut self, digest_id: u32, config: Option<DigestEntry_Config>) -> Option<DigestEntry_Config> {
+        match config {
+            Some(config) => match self.queues.get_mut(&digest_id) {
+                Some(queue) => Some(std::mem::replace(&mut queue.config, config)),
+                None => {
+                    self.queues.insert(digest_id, DigestQueue { config, ..Default::default() });
+                    None
+                }
+            },
+            None => self.queues.remove(&digest_id).map(|queue| queue.config),
+        }
+    }
+
+    /// Implements the P4Runtime `Read` operation for digest entries.  A `digest_id` of 0 reads
+    /// all of them.
+    pub fn read(&self, digest_id: u32) -> Vec<DigestEntry> {
+        self.queues.iter()
+            .filter(|(&id, _)| digest_id == 0 || digest_id == id)
+            .map(|(&id, queue)| DigestEntry {
+                digest_id: id,
+                config: SingularPtrField::some(queue.config.clone()),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Queues the digest data in `pin`, which arrived at `now`.  Ignores packet-ins that don't
+    /// carry userdata, because those aren't digests, as well as digests that the controller
+    /// hasn't configured.
+    pub fn packet_in(&mut self, p4info: &P4Info, pin: &PacketIn, now: Instant) -> Result<()> {
+        if pin.userdata.is_empty() {
+            return Ok(());
+        }
+        let name = str::from_utf8(&pin.userdata)
+            .map_err(|_| Error(RpcStatusCode::INVALID_ARGUMENT))
+            .context("packet-in userdata is not a digest name")?;
+        let digest = match p4info.get_digests().iter().find(|d| {
+            let preamble = d.get_preamble();
+            preamble.get_name() == name || preamble.get_alias() == name
+        }) {
+            Some(digest) => digest,
+            None => Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("unknown digest {}", name))?,
+        };
+        let queue = match self.queues.get_mut(&digest.get_preamble().id) {
+            Some(queue) => queue,
+            None => return Ok(()),
+        };
+
+        let data = decode_digest(p4info, digest, pin)?;
+        if !queue.contains(&data) {
+            queue.pending.push(data);
+            queue.oldest.get_or_insert(now);
+        }
+        Ok(())
+    }
+
+    /// Marks the list that `ack` acknowledges as received by the controller.
+    pub fn ack(&mut self, ack: &DigestListAck) {
+        if let Some(queue) = self.queues.get_mut(&ack.digest_id) {
+            queue.unacked.remove(&ack.list_id);
+        }
+    }
+
+    /// Returns the digest lists due to be sent at `now`, and stops waiting for acknowledgment of
+    /// lists whose acknowledgment timeout has passed.
+    pub fn run(&mut self, now: Instant) -> Vec<DigestList> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as i64;
+        let mut lists = Vec::new();
+        for (&digest_id, queue) in &mut self.queues {
+            let ack_timeout = nanos(queue.config.ack_timeout_ns);
+            queue.unacked.retain(|_, (sent, _)| now < *sent + ack_timeout);
+
+            while queue.is_due(now) {
+                let n = match queue.config.max_list_size {
+                    0 => queue.pending.len(),
+                    max => queue.pending.len().min(max as usize),
+                };
+                let data: Vec<P4Data> = queue.pending.drain(..n).collect();
+                if queue.pending.is_empty() {
+                    queue.oldest = None;
+                }
+
+                self.next_list_id += 1;
+                lists.push(DigestList {
+                    digest_id,
+                    list_id: self.next_list_id,
+                    data: RepeatedField::from_vec(data.clone()),
+                    timestamp,
+                    ..Default::default()
+                });
+                queue.unacked.insert(self.next_list_id, (now, data));
+            }
+        }
+        lists
+    }
+
+    /// Returns the next time at which [`Self::run`] will have something to do, if any.
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        self.queues.values()
+            .flat_map(|queue| {
+                let send = queue.oldest.map(|oldest| oldest + nanos(queue.config.max_timeout_ns));
+                let expire = queue.unacked.values().map(move |(sent, _)| *sent + nanos(queue.config.ack_timeout_ns));
+                send.into_iter().chain(expire)
+            })
+            .min()
+    }
+}
+
+/// Decodes the data for `digest` from the registers in `pin`.
+fn decode_digest(p4info: &P4Info, digest: &Digest, pin: &PacketIn) -> Result<P4Data> {
+    let struct_name = digest.get_type_spec().get_field_struct().get_name();
+    let members = match p4info.get_type_info().get_structs().get(struct_name) {
+        Some(struct_spec) => struct_spec.get_members(),
+        None => Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context(format!("digest {} is not a struct", struct_name))?,
+    };
+    if members.len() > N_XREGS - DIGEST_XREG_BASE {
+        Err(Error(RpcStatusCode::UNIMPLEMENTED)).context(format!("digest {} has too many members", struct_name))?;
+    }
+
+    let mut fields = Vec::with_capacity(members.len());
+    for (i, member) in members.iter().enumerate() {
+        let type_spec = member.get_type_spec();
+        let bitwidth = type_spec.get_bitstring().get_bit().get_bitwidth();
+        if !type_spec.get_bitstring().has_bit() || bitwidth <= 0 || bitwidth > 64 {
+            Err(Error(RpcStatusCode::UNIMPLEMENTED))
+                .context(format!("digest member {}.{} is not bit<1> through bit<64>", struct_name, member.get_name()))?;
+        }
+
+        let mut field = P4Data::new();
+        field.set_bitstring(encode_bitstring(pin.xreg(DIGEST_XREG_BASE + i), bitwidth));
+        fields.push(field);
+    }
+
+    let mut struct_like = P4StructLike::new();
+    struct_like.set_members(RepeatedField::from_vec(fields));
+    let mut data = P4Data::new();
+    data.set_field_struct(struct_like);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use p4ext::server::error_code;
+
+    use proto::p4types::{P4StructTypeSpec, P4StructTypeSpec_Member};
+
+    const DIGEST_ID: u32 = 1;
+
+    /// Returns a P4Info with digest `learn` of a struct with a 48-bit and a 9-bit member.
+    fn p4info() -> P4Info {
+        let mut digest = Digest::new();
+        digest.mut_preamble().set_id(DIGEST_ID);
+        digest.mut_preamble().set_name("learn".into());
+        digest.mut_type_spec().mut_field_struct().set_name("learn_t".into());
+
+        let mut spec = P4StructTypeSpec::new();
+        for &(name, bitwidth) in &[("mac", 48), ("port", 9)] {
+            let mut member = P4StructTypeSpec_Member::new();
+            member.set_name(name.into());
+            member.mut_type_spec().mut_bitstring().mut_bit().set_bitwidth(bitwidth);
+            spec.mut_members().push(member);
+        }
+
+        let mut p4info = P4Info::new();
+        p4info.mut_digests().push(digest);
+        p4info.mut_type_info().mut_structs().insert("learn_t".into(), spec);
+        p4info
+    }
+
+    fn config(max_timeout_ns: i64, max_list_size: i32, ack_timeout_ns: i64) -> DigestEntry_Config {
+        DigestEntry_Config { max_timeout_ns, max_list_size, ack_timeout_ns, ..Default::default() }
+    }
+
+    /// Returns a packet-in for digest `name` whose members are `mac` and `port`.
+    fn packet_in(name: &str, mac: u64, port: u64) -> PacketIn {
+        let mut regs = vec![0; 2 * N_XREGS];
+        for (i, &value) in [mac, port].iter().enumerate() {
+            let n = DIGEST_XREG_BASE + i;
+            regs[2 * n] = (value >> 32) as u32;
+            regs[2 * n + 1] = value as u32;
+        }
+        PacketIn { packet: Vec::new(), table_id: 0, cookie: 0, userdata: name.as_bytes().to_vec(), in_port: 1, regs }
+    }
+
+    fn list_sizes(lists: &[DigestList]) -> Vec<usize> {
+        lists.iter().map(|list| list.data.len()).collect()
+    }
+
+    #[test]
+    fn decode() {
+        let p4info = p4info();
+        let mut digests = Digests::default();
+        digests.configure(DIGEST_ID, Some(config(0, 0, 1_000_000_000)));
+        let now = Instant::now();
+        digests.packet_in(&p4info, &packet_in("learn", 0x0000_5e00_5301, 0x1ff), now).unwrap();
+
+        let lists = digests.run(now);
+        assert_eq!(list_sizes(&lists), vec![1]);
+        assert_eq!(lists[0].digest_id, DIGEST_ID);
+        let members = lists[0].data[0].get_field_struct().get_members();
+        assert_eq!(members[0].get_bitstring(), &[0x00, 0x00, 0x5e, 0x00, 0x53, 0x01][..]);
+        assert_eq!(members[1].get_bitstring(), &[0x01, 0xff][..]);
+    }
+
+    #[test]
+    fn batching() {
+        let p4info = p4info();
+        let mut digests = Digests::default();
+        digests.configure(DIGEST_ID, Some(config(1_000_000, 2, 1_000_000_000)));
+        let now = Instant::now();
+        for mac in 0..5 {
+            digests.packet_in(&p4info, &packet_in("learn", mac, 1), now).unwrap();
+        }
+
+        // Full lists go out right away, but the remainder waits for the timeout.
+        assert_eq!(list_sizes(&digests.run(now)), vec![2, 2]);
+        assert_eq!(digests.next_wakeup(), Some(now + Duration::from_millis(1)));
+        assert!(digests.run(now).is_empty());
+        assert_eq!(list_sizes(&digests.run(now + Duration::from_millis(1))), vec![1]);
+    }
+
+    #[test]
+    fn unlimited_list_size() {
+        let p4info = p4info();
+        let mut digests = Digests::default();
+        digests.configure(DIGEST_ID, Some(config(1_000_000, 0, 1_000_000_000)));
+        let now = Instant::now();
+        for mac in 0..5 {
+            digests.packet_in(&p4info, &packet_in("learn", mac, 1), now).unwrap();
+        }
+        assert!(digests.run(now).is_empty());
+        assert_eq!(list_sizes(&digests.run(now + Duration::from_millis(1))), vec![5]);
+    }
+
+    #[test]
+    fn deduplication() {
+        let p4info = p4info();
+        let mut digests = Digests::default();
+        digests.configure(DIGEST_ID, Some(config(0, 0, 1_000_000_000)));
+        let now = Instant::now();
+
+        // Data identical to pending data is dropped.
+        digests.packet_in(&p4info, &packet_in("learn", 1, 1), now).unwrap();
+        digests.packet_in(&p4info, &packet_in("learn", 1, 1), now).unwrap();
+        digests.packet_in(&p4info, &packet_in("learn", 1, 2), now).unwrap();
+        let lists = digests.run(now);
+        assert_eq!(list_sizes(&lists), vec![2]);
+
+        // So is data identical to data in an unacknowledged list.
+        digests.packet_in(&p4info, &packet_in("learn", 1, 1), now).unwrap();
+        assert!(digests.run(now).is_empty());
+
+        // After the acknowledgment, it is new again.
+        let ack = DigestListAck { digest_id: DIGEST_ID, list_id: lists[0].list_id, ..Default::default() };
+        digests.ack(&ack);
+        digests.packet_in(&p4info, &packet_in("learn", 1, 1), now).unwrap();
+        assert_eq!(list_sizes(&digests.run(now)), vec![1]);
+    }
+
+    #[test]
+    fn ack_timeout() {
+        let p4info = p4info();
+        let mut digests = Digests::default();
+        digests.configure(DIGEST_ID, Some(config(0, 0, 1_000_000)));
+        let now = Instant::now();
+        digests.packet_in(&p4info, &packet_in("learn", 1, 1), now).unwrap();
+        assert_eq!(list_sizes(&digests.run(now)), vec![1]);
+        assert_eq!(digests.next_wakeup(), Some(now + Duration::from_millis(1)));
+
+        // Once the acknowledgment timeout passes, the same data is new again.
+        let later = now + Duration::from_millis(1);
+        assert!(digests.run(later).is_empty());
+        assert_eq!(digests.next_wakeup(), None);
+        digests.packet_in(&p4info, &packet_in("learn", 1, 1), later).unwrap();
+        assert_eq!(list_sizes(&digests.run(later)), vec![1]);
+    }
+
+    #[test]
+    fn ignored_packet_ins() {
+        let p4info = p4info();
+        let mut digests = Digests::default();
+        let now = Instant::now();
+
+        // Digests that the controller hasn't configured are dropped.
+        digests.packet_in(&p4info, &packet_in("learn", 1, 1), now).unwrap();
+        digests.configure(DIGEST_ID, Some(config(0, 0, 1_000_000_000)));
+        assert!(digests.run(now).is_empty());
+
+        // So are packet-ins without userdata, which aren't digests at all.
+        digests.packet_in(&p4info, &packet_in("", 1, 1), now).unwrap();
+        assert!(digests.run(now).is_empty());
+
+        let error = digests.packet_in(&p4info, &packet_in("unknown", 1, 1), now).unwrap_err();
+        assert_eq!(error_code(&error), RpcStatusCode::NOT_FOUND);
+    }
+}
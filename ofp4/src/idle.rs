@@ -0,0 +1,204 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! P4Runtime idle timeouts.
+//!
+//! A table entry in a table with idle notification may have an idle timeout.  When the entry
+//! doesn't match any packets for that long, the server notifies the controller, but unlike an
+//! OpenFlow idle timeout, the entry stays in place until the controller deletes it.  ofp4 finds
+//! out whether an entry matched packets by periodically asking the switch for the statistics of
+//! the entry's flows, by cookie, and checking whether their packet count changed.
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use super::EntryStats;
+
+/// How often to check the switch's flow statistics for table entries that went idle.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks whether a table entry with an idle timeout is in use.
+struct IdleEntry {
+    // The entry's idle timeout.
+    timeout: Duration,
+
+    // The entry's packet count in the last flow statistics, if there have been any since the
+    // entry's idle timeout was set.
+    packets: Option<u64>,
+
+    // When the entry last matched a packet, as far as we know, or when its idle timeout was set.
+    last_hit: Instant,
+
+    // Whether the entry has been reported idle since `last_hit`.
+    expired: bool,
+}
+
+/// Table entries with idle timeouts.
+pub struct IdleTimeouts {
+    // Table entries with an idle timeout, indexed by entry ID.
+    entries: HashMap<u32, IdleEntry>,
+
+    // IDs of table entries that went idle, to notify the controller about.
+    expired: BTreeSet<u32>,
+
+    // When to next check the switch's flow statistics.
+    next_poll: Instant,
+}
+
+impl IdleTimeouts {
+    pub fn new() -> IdleTimeouts {
+        IdleTimeouts { entries: HashMap::new(), expired: BTreeSet::new(), next_poll: Instant::now() }
+    }
+
+    /// Sets the idle timeout of the table entry with ID `entry_id` to `timeout_ns` nanoseconds,
+    /// or removes it if `timeout_ns` isn't positive.  Setting or changing an idle timeout restarts
+    /// it at `now`.
+    pub fn set(&mut self, entry_id: u32, timeout_ns: i64, now: Instant) {
+        if timeout_ns <= 0 {
+            self.remove(entry_id);
+            return;
+        }
+        let timeout = Duration::from_nanos(timeout_ns as u64);
+        if self.entries.get(&entry_id).map_or(true, |idle| idle.timeout != timeout) {
+            self.entries.insert(entry_id, IdleEntry { timeout, packets: None, last_hit: now, expired: false });
+            self.expired.remove(&entry_id);
+        }
+    }
+
+    /// Forgets the idle timeout of the table entry with ID `entry_id`, if it has one.
+    pub fn remove(&mut self, entry_id: u32) {
+        self.entries.remove(&entry_id);
+        self.expired.remove(&entry_id);
+    }
+
+    /// Returns the IDs of the table entries that have idle timeouts.
+    pub fn entry_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.entries.keys().copied()
+    }
+
+    /// Returns when to next check the switch's flow statistics, or `None` if no table entry has
+    /// an idle timeout.
+    pub fn next_poll(&self) -> Option<Instant> {
+        if self.entries.is_empty() {
+            None
+        } else {
+            Some(self.next_poll)
+        }
+    }
+
+    /// Returns true if it is time to check the switch's flow statistics as of `now`.
+    pub fn poll_due(&self, now: Instant) -> bool {
+        self.next_poll().map_or(false, |next_poll| now >= next_poll)
+    }
+
+    /// Records that the switch's flow statistics were requested at `now`, so that the next check
+    /// is due [`POLL_INTERVAL`] later.
+    pub fn polled(&mut self, now: Instant) {
+        self.next_poll = now + POLL_INTERVAL;
+    }
+
+    /// Updates the table entries with idle timeouts from flow statistics `stats`, taken at `now`,
+    /// which must cover all of them.  An entry whose packet count changed was hit since the last
+    /// statistics.  One that hasn't been hit for its idle timeout is reported by
+    /// [`Self::take_expired`], once, until it is hit again.
+    pub fn update(&mut self, stats: &EntryStats, now: Instant) {
+        for (&entry_id, idle) in self.entries.iter_mut() {
+            let packets = stats.get(&entry_id).map_or(0, |&(packets, _)| packets);
+            if idle.packets.replace(packets).map_or(false, |old| old != packets) {
+                idle.last_hit = now;
+                idle.expired = false;
+            } else if !idle.expired && now.saturating_duration_since(idle.last_hit) >= idle.timeout {
+                idle.expired = true;
+                self.expired.insert(entry_id);
+            }
+        }
+    }
+
+    /// Returns the IDs of the table entries that went idle since the last call.
+    pub fn take_expired(&mut self) -> BTreeSet<u32> {
+        std::mem::take(&mut self.expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(entries: &[(u32, u64)]) -> EntryStats {
+        entries.iter().map(|&(entry_id, packets)| (entry_id, (packets, packets * 100))).collect()
+    }
+
+    #[test]
+    fn update() {
+        let start = Instant::now();
+        let seconds = |n| start + Duration::from_secs(n);
+        let mut idle = IdleTimeouts::new();
+        idle.set(1, 2_000_000_000, start);
+        idle.set(2, 5_000_000_000, start);
+
+        // Entry 1 goes idle after 2 seconds without packets.  Entry 2 keeps matching packets.
+        idle.update(&stats(&[(1, 10), (2, 10)]), seconds(1));
+        assert!(idle.take_expired().is_empty());
+        idle.update(&stats(&[(1, 10), (2, 20)]), seconds(2));
+        assert_eq!(idle.take_expired(), vec![1].into_iter().collect());
+
+        // It is only reported once, until it is hit again and goes idle again.
+        idle.update(&stats(&[(1, 10), (2, 30)]), seconds(3));
+        assert!(idle.take_expired().is_empty());
+        idle.update(&stats(&[(1, 11), (2, 40)]), seconds(4));
+        assert!(idle.take_expired().is_empty());
+        idle.update(&stats(&[(1, 11), (2, 50)]), seconds(6));
+        assert_eq!(idle.take_expired(), vec![1].into_iter().collect());
+
+        // An entry without flows in the statistics has no packets, so it goes idle too.
+        idle.update(&stats(&[(1, 11)]), seconds(7));
+        idle.update(&stats(&[(1, 11)]), seconds(12));
+        assert_eq!(idle.take_expired(), vec![2].into_iter().collect());
+    }
+
+    #[test]
+    fn set_and_poll() {
+        let mut idle = IdleTimeouts::new();
+        let start = Instant::now();
+        assert_eq!(idle.next_poll(), None);
+        assert!(!idle.poll_due(start));
+
+        idle.set(1, 1_000_000_000, start);
+        assert!(idle.poll_due(start));
+        idle.polled(start);
+        assert!(!idle.poll_due(start));
+        assert!(idle.poll_due(start + POLL_INTERVAL));
+        assert_eq!(idle.entry_ids().collect::<Vec<_>>(), vec![1]);
+
+        // Setting the same timeout again doesn't restart it, but changing it does.
+        idle.update(&stats(&[]), start);
+        idle.set(1, 1_000_000_000, start + Duration::from_secs(1));
+        idle.update(&stats(&[]), start + Duration::from_secs(1));
+        assert_eq!(idle.take_expired(), vec![1].into_iter().collect());
+        idle.set(1, 3_000_000_000, start + Duration::from_secs(1));
+        idle.update(&stats(&[]), start + Duration::from_secs(2));
+        assert!(idle.take_expired().is_empty());
+
+        // Removing the timeout forgets the entry.
+        idle.set(1, 0, start);
+        assert_eq!(idle.next_poll(), None);
+        assert!(idle.take_expired().is_empty());
+    }
+}
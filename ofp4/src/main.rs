@@ -21,18 +21,39 @@ SOFTWARE.
 //! `ofp4` provides a P4Runtime interface to Open vSwitch.  It accepts P4Runtime connections from a
 //! controller and connects to an Open vSwitch instance over OpenFlow and OVSDB.
 
+mod action_profile;
+mod default_action;
+mod digest;
+mod idle;
+mod meter;
+mod packet;
+mod ports;
+
 use anyhow::{anyhow, Context, Result};
 
 use clap::{App, Arg};
 
 use differential_datalog::DeltaMap;
 use differential_datalog::api::HDDlog;
-use differential_datalog::ddval::{DDValConvert, DDValue};
-use differential_datalog::program::{IdxId, RelId, Update};
+use differential_datalog::ddval::DDValue;
 use differential_datalog::record::{RelIdentifier, UpdCmd};
-use differential_datalog::{DDlog, DDlogDynamic, DDlogInventory};
+use differential_datalog::{DDlog, DDlogDynamic};
+
+use action_profile::ActionProfiles;
+
+use default_action::DefaultActions;
+
+use digest::Digests;
 
-use futures_util::{FutureExt, SinkExt, TryFutureExt, TryStreamExt};
+use idle::IdleTimeouts;
+
+use meter::Meters;
+
+use ports::{Interface, PortMonitor};
+
+use futures_channel::{mpsc, oneshot};
+use futures_util::future::{self, Either};
+use futures_util::{FutureExt, SinkExt, StreamExt, TryFutureExt, TryStreamExt};
 
 use grpcio::{
     ChannelBuilder,
@@ -44,6 +65,7 @@ use grpcio::{
     ServerBuilder,
     ServerStreamingSink,
     UnarySink,
+    WriteFlags,
 };
 
 use log::error;
@@ -52,52 +74,97 @@ use ovs::{
     self,
     latch::Latch,
     ofpbuf::Ofpbuf,
-    ofp_flow::{FlowMod, FlowModCommand}
+    ofp_bundle::{BundleCtrlMsg, BundleSequence, OFPBCT_COMMIT_REPLY, OFPBF_ATOMIC, OFPBF_ORDERED},
+    ofp_errors,
+    ofp_flow::{FlowMod, FlowModCommand, FlowStats},
+    ofp_group::{GroupMod, GroupModCommand},
+    ofp_meter::{MeterConfig, MeterMod, MeterModCommand},
+    ofp_msgs::{self, OFPTYPE_BUNDLE_CONTROL, OFPTYPE_ERROR, OFPTYPE_FLOW_STATS_REPLY,
+               OFPTYPE_METER_CONFIG_STATS_REPLY, OFPTYPE_METER_MOD},
+    ofp_packet::{PacketIn, PacketInFormat},
 };
 
 use p4ext::*;
+use p4ext::server::{
+    Capabilities,
+    Entities,
+    error_code,
+    server_streaming_fail,
+    server_streaming_success,
+    unary_fail,
+    unary_success,
+    validate_table_write,
+    validate_write,
+    write_status,
+};
+use p4ext::session::{election_id_to_u128, u128_to_election_id};
+use p4ext::textproto;
+use p4ext::translation::Translation;
 
+use proto::ofp4::PortStatus;
 use proto::p4info::P4Info;
 use proto::p4runtime::{
     CapabilitiesRequest,
     CapabilitiesResponse,
+    CounterData,
+    DigestEntry_Config,
+    DirectCounterEntry,
+    DirectMeterEntry,
     Entity,
     Entity_oneof_entity,
     ForwardingPipelineConfig,
     ForwardingPipelineConfig_Cookie,
     GetForwardingPipelineConfigRequest,
     GetForwardingPipelineConfigResponse,
+    IdleTimeoutNotification,
+    MasterArbitrationUpdate,
+    MeterConfig,
+    PacketOut,
+    PacketOutError,
     PacketReplicationEngineEntry,
     PacketReplicationEngineEntry_oneof_type,
     ReadRequest,
     ReadResponse,
     SetForwardingPipelineConfigRequest,
+    SetForwardingPipelineConfigRequest_Action,
     SetForwardingPipelineConfigResponse,
+    StreamError,
     StreamMessageRequest,
+    StreamMessageRequest_oneof_update,
     StreamMessageResponse,
+    Uint128,
     Update_Type,
     WriteRequest,
+    WriteRequest_Atomicity,
     WriteResponse,
 };
 use proto::p4runtime_grpc::{P4Runtime, create_p4_runtime};
 
-use protobuf::{Message, SingularPtrField, well_known_types::Any};
+use protobuf::{Message, RepeatedField, SingularPtrField};
+use protobuf::well_known_types::Any;
 
-use snvs_ddlog::typedefs::{Flow, MulticastGroup};
-use snvs_ddlog::{Indexes, Relations};
+use serde::Deserialize;
 
-use std::collections::{BTreeSet, HashMap};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::default::Default;
 use std::convert::TryInto;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const OFP_PROTOCOL: ovs::ofp_protocol::Protocol = ovs::ofp_protocol::Protocol::OF15_OXM;
 const OFP_VERSION: ovs::ofp_protocol::Version = ovs::ofp_protocol::Version::OFP15;
 
+/// How long writes wait for a disconnected switch to come back before they fail.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 struct State {
+    translation: Box<dyn Translation>,
     hddlog: HDDlog,
     latch: Latch,
-    pending_flow_mods: Vec<Ofpbuf>,
+    pending_bundles: Vec<PendingBundle>,
+    pending_packet_outs: Vec<Ofpbuf>,
 
     // Configuration state.
     device_id: u64,
@@ -105,433 +172,2421 @@ struct State {
     cookie: u64,
     table_schemas: HashMap<u32, Table>,
 
-    // Table state.
-    multicast_groups: HashMap<MulticastGroupId, BTreeSet<Replica>>,
-    table_entries: HashMap<TableKey, TableValue>
+    // A configuration that passed verification and awaits a `COMMIT`.
+    saved_config: Option<ForwardingPipelineConfig>,
+
+    // The device's ports in the Open vSwitch database, indexed by P4 port number.
+    ports: BTreeMap<u16, Interface>,
+
+    // Table state.  A multicast group is present only if it has at least one replica.
+    entities: Entities,
+    default_actions: DefaultActions,
+    digests: Digests,
+    meters: Meters,
+    action_profiles: ActionProfiles,
+
+    // The ID of each table entry, for the translation to put in the cookies of its flows, and
+    // the reverse.
+    entry_ids: HashMap<TableKey, u32>,
+    entry_keys: HashMap<u32, TableKey>,
+
+    // The number of entries in each table that has any, indexed by table ID, for enforcing
+    // `max_entries`.
+    n_entries: HashMap<u32, u64>,
+    next_entry_id: u32,
+
+    // The flows in the translation's `Flow` relation, indexed by the upper 32 bits of their
+    // cookies (see [`FlowSource`]), each with its cookie.  Two flows' cookies can collide, so the
+    // set includes the flow text.
+    flows: HashMap<u32, BTreeSet<(u64, String)>>,
+
+    // Table entries with an idle timeout.
+    idle: IdleTimeouts,
+
+    // Reads waiting for flow statistics from the switch.
+    stats_requests: Vec<oneshot::Sender<EntryStats>>,
+
+    // Controllers, indexed by stream.
+    controllers: HashMap<u64, Controller>,
+
+    // The highest election ID that any controller has sent for each role, indexed by role ID.
+    election_ids: HashMap<u64, u128>,
 }
 
-impl State {
-    fn new(hddlog: HDDlog) -> State {
-        let (device_id, pending_flow_mods, p4info, cookie, table_schemas, multicast_groups,
-             table_entries)
-            = Default::default();
-        let latch = Latch::new(); 
-        State {
-            hddlog, latch, pending_flow_mods, device_id, p4info, cookie, table_schemas,
-            multicast_groups, table_entries,
-        }
-    }
+/// What ofp4 supports, for the `Capabilities` RPC and the start of each stream channel.
+///
+/// ofp4 accepts `DATAPLANE_ATOMIC` writes only if they don't change meters, so it doesn't
+/// advertise that mode.
+fn capabilities() -> Capabilities {
+    Capabilities::new(&["table-entry", "multicast-group-entry", "digest-entry", "meter-entry",
+                        "direct-meter-entry", "direct-counter-entry", "action-profile-member",
+                        "action-profile-group"],
+                      &[WriteRequest_Atomicity::CONTINUE_ON_ERROR,
+                        WriteRequest_Atomicity::ROLLBACK_ON_ERROR])
+}
 
-    /// Implements the P4Runtime `read` operation for the specified `multicast_group_id`, including
-    /// the P4Runtime behavior that a zero or missing multicast group acts as a wildcard.  Returns
-    /// the entities to send back to the P4Runtime client.
-    fn read_multicast_groups(&self, multicast_group_id: u32) -> Vec<Entity> {
-        let group_ids: Vec<MulticastGroupId> = if multicast_group_id == 0 {
-            self.multicast_groups.keys().cloned().collect()
-        } else {
-            vec![multicast_group_id]
-        };
+/// A controller connected over the stream channel.
+struct Controller {
+    role_id: u64,
+    election_id: u128,
+    sender: mpsc::UnboundedSender<StreamMessageResponse>,
+}
+
+/// Flow mods waiting to be sent to the switch together in an atomic bundle.
+struct PendingBundle {
+    flow_mods: Vec<Ofpbuf>,
+
+    // Writes waiting for the bundle to commit.
+    replies: Vec<oneshot::Sender<Result<()>>>,
+}
+
+/// A bundle sent to the switch whose commit hasn't been confirmed yet.
+struct SentBundle {
+    bundle_id: u32,
+
+    // Transaction IDs of the messages that make up the bundle, to match up errors.
+    xids: HashSet<u32>,
+
+    // Writes waiting for the bundle to commit.
+    replies: Vec<oneshot::Sender<Result<()>>>,
+}
 
-        let mut entities = Vec::new();
-        for multicast_group_id in group_ids {
-            if let Some(mg_replicas) = self.multicast_groups.get(&multicast_group_id) {
-                let p_mge: proto::p4runtime::MulticastGroupEntry = (&MulticastGroupEntry {
-                    multicast_group_id, replicas: mg_replicas.clone()
-                }).into();
-                let entity = Entity {
-                    entity: Some(Entity_oneof_entity::packet_replication_engine_entry(PacketReplicationEngineEntry {
-                        field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(p_mge)), ..Default::default()})), ..Default::default()};
-                entities.push(entity);
+impl SentBundle {
+    /// Sends `flow_mods` to the switch on `rconn` as atomic bundle `bundle_id`.  Meter mods can't
+    /// be bundled, so the ones at the beginning of `flow_mods` are sent just before the bundle and
+    /// the rest just after it.
+    fn send(rconn: &mut ovs::rconn::Rconn, bundle_id: u32, flow_mods: impl Iterator<Item = Ofpbuf>,
+            replies: Vec<oneshot::Sender<Result<()>>>) -> SentBundle {
+        let is_meter_mod = |msg: &Ofpbuf| ofp_msgs::decode_type(msg.as_slice()).ok() == Some(OFPTYPE_METER_MOD);
+        let mut before = Vec::new();
+        let mut bundled = Vec::new();
+        let mut after = Vec::new();
+        for msg in flow_mods {
+            if !is_meter_mod(&msg) {
+                bundled.push(msg);
+            } else if bundled.is_empty() {
+                before.push(msg);
+            } else {
+                after.push(msg);
             }
         }
-        entities
+
+        let mut xids = HashSet::new();
+        let bundle = BundleSequence::new(bundle_id, OFPBF_ATOMIC | OFPBF_ORDERED, OFP_VERSION, bundled.into_iter());
+        for msg in before.into_iter().chain(bundle).chain(after) {
+            xids.insert(ofp_msgs::xid(msg.as_slice()));
+            rconn.send(msg).unwrap();
+        }
+        SentBundle { bundle_id, xids, replies }
     }
 
-    /// Implements the P4Runtime `read` operation for table entries that match all of the fields in
-    /// `target`.  As P4Runtime specifies, any field in `target` that is zero or missing acts as a
-    /// wildcard.  Returns the entities to send back to the P4Runtime client.
-    fn read_table_entries(&self, target: &proto::p4runtime::TableEntry) -> Vec<Entity> {
-        let target: TableEntry = match target.try_into() {
-            Ok(target) => target,
-            Err(error) => {
-                eprintln!("bad TableEntry {:?} for read operation ({:?})", target, error);
-                return Vec::new();
-            }
-        };
-            
-        let mut entities = Vec::new();
-        for (key, value) in &self.table_entries {
-            if target.key.table_id != 0 && target.key.table_id != key.table_id {
-                continue;
-            }
-            if !target.key.matches.is_empty() && target.key.matches != key.matches {
-                continue;
-            }
-            if target.key.priority != 0 && target.key.priority != key.priority {
-                continue;
-            }
-            if target.key.is_default_action && !key.is_default_action {
-                continue;
-            }
-            if target.value.controller_metadata != 0 && target.value.controller_metadata != value.controller_metadata {
-                continue;
-            }
-            if !target.value.metadata.is_empty() && target.value.metadata != value.metadata {
-                continue;
-            }
-            // XXX meter_config
-            // XXX counter_data
-            // XXX idle_timeout_ns?
-            // XXX time_since_last_hit?
-            let (unknown_fields, cached_size) = Default::default();
-            let te = TableEntry { key: key.clone(), value: value.clone() }; 
-            entities.push(Entity {
-                entity: Some(Entity_oneof_entity::table_entry((&te).into())),
-                unknown_fields, cached_size });
+    /// Tells the writes waiting for this bundle about `result`.
+    fn finish(self, result: &Result<()>) {
+        for reply in self.replies {
+            let _ = reply.send(copy_result(result));
         }
-        entities
     }
 }
 
-#[derive(Clone)]
-struct P4RuntimeService {
-    state: Arc<Mutex<State>>,
+/// A resynchronization of the switch's flow table with the `Flow` index, and of its meters with the
+/// `Meter` index, after reconnecting.
+struct Resync {
+    // Transaction ID of the flow stats request that dumps the switch's flows.
+    xid: u32,
+
+    // Cookies of the flows that the switch has, so far.
+    cookies: HashSet<u64>,
+
+    // Transaction ID of the meter config request that dumps the switch's meters.
+    meter_xid: u32,
+
+    // Configurations of the meters that the switch has, so far, indexed by meter ID.
+    meters: HashMap<u32, MeterConfig>,
+
+    // Writes waiting for the resync to commit.
+    replies: Vec<oneshot::Sender<Result<()>>>,
+
+    // Whether each dump is complete, and whether either one failed.
+    done: bool,
+    meters_done: bool,
+    failed: bool,
 }
 
-impl P4RuntimeService {
-    fn new(state: Arc<Mutex<State>>) -> P4RuntimeService {
-        P4RuntimeService { state }
-    }
+/// A dump of flow statistics from the switch, to answer reads of counters or to check table
+/// entries with idle timeouts.
+struct StatsDump {
+    // Transaction IDs of the flow stats requests that the switch hasn't finished answering.
+    xids: Vec<u32>,
+
+    // Statistics so far.
+    stats: EntryStats,
+
+    // Reads waiting for the statistics.
+    replies: Vec<oneshot::Sender<EntryStats>>,
 }
 
-fn unary_fail<T>(ctx: &RpcContext, sink: UnarySink<T>, status: grpcio::RpcStatus) {
-    let f = sink.fail(status)
-        .map_err(|e| error!("failed to send error: {:?}", e))
-        .map(|_| ());
-    ctx.spawn(f);
+/// Packet and byte counts for table entries, summed across each entry's flows and indexed by
+/// entry ID.
+type EntryStats = HashMap<u32, (u64, u64)>;
+
+/// A change to our internal representation of P4Runtime entities, made alongside the
+/// corresponding DDlog updates.
+enum Change {
+    /// Sets the replicas in a multicast group.  No replicas deletes the group.
+    MulticastGroup { id: MulticastGroupId, replicas: BTreeSet<Replica> },
+
+    /// Sets, along with the entry's ID, or with `None` deletes a table entry.
+    TableEntry { key: TableKey, value: Option<(TableValue, u32)> },
+
+    /// Sets or, with `None`, restores the initial default action for a table.
+    DefaultAction { table_id: u32, action: Option<TableAction> },
+
+    /// Sets or, with `None`, deletes a digest configuration.
+    Digest { id: u32, config: Option<DigestEntry_Config> },
+
+    /// Sets or, with `None`, resets the configuration of a cell in an indirect meter.
+    Meter { meter_id: u32, index: i64, config: Option<MeterConfig> },
+
+    /// Sets or, with `None`, resets the configuration of a table entry's direct meter.
+    DirectMeter { key: TableKey, config: Option<MeterConfig> },
+
+    /// Sets the action of or, with `None`, deletes a member of an action profile.
+    ActionProfileMember { profile_id: ActionProfileId, member_id: u32, action: Option<TableAction> },
+
+    /// Sets or, with `None`, deletes a group of an action profile.
+    ActionProfileGroup { profile_id: ActionProfileId, group_id: u32, group: Option<ActionProfileGroup> },
 }
 
-fn unary_success<T>(ctx: &RpcContext, sink: UnarySink<T>, reply: T) {
-    let f = sink
-        .success(reply)
-        .map_err(|e: grpcio::Error| error!("write failed: {:?}", e))
-        .map(|_| ());
-    ctx.spawn(f);
+/// Returns the cookie for `flow`, which the translation derived from the table entry with ID
+/// `entry_id` (0 if none).  The upper 32 bits are the entry ID, so that ofp4 can find all of an
+/// entry's flows by cookie, and the lower 32 bits are an FNV-1a hash of the flow's text, so that
+/// after reconnecting ofp4 can tell which flows in the switch are still correct.  The hash doesn't
+/// change from one run of ofp4 to the next.
+fn flow_cookie(flow: &str, entry_id: u32) -> u64 {
+    let hash = flow.bytes().fold(0xcbf29ce484222325u64, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3));
+    ((entry_id as u64) << 32) | (hash & 0xffff_ffff)
 }
 
-fn server_streaming_fail<T>(ctx: &RpcContext, sink: ServerStreamingSink<T>, code: RpcStatusCode) {
-    let f = sink.fail(grpcio::RpcStatus::new(code))
-        .map_err(|e| error!("failed to send error: {:?}", e))
-        .map(|_| ());
-    ctx.spawn(f);
+/// The translation marks the flows that it derives from a multicast group by setting this bit in
+/// the group's ID and using that as their cookie.  Table entry IDs stay below it.
+const MULTICAST_COOKIE: u32 = 0x8000_0000;
+
+/// The P4Runtime entity that a flow came from, according to its cookie.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FlowSource {
+    /// The table entry with the given ID.
+    TableEntry(u32),
+
+    /// The multicast group with the given ID.
+    MulticastGroup(MulticastGroupId),
 }
 
-fn server_streaming_success<T: Send + 'static>(ctx: &RpcContext, mut sink: ServerStreamingSink<T>,
-                               reply: Vec<T>) {
-    let f = async move {
-        for msg in reply {
-            sink.send((msg, Default::default())).await?;
+impl FlowSource {
+    /// Returns the entity that the flows with the given `cookie` came from, or `None` if they
+    /// don't belong to any entity.
+    fn from_cookie(cookie: u64) -> Option<FlowSource> {
+        match (cookie >> 32) as u32 {
+            0 => None,
+            id if id & MULTICAST_COOKIE != 0 => Some(FlowSource::MulticastGroup(id & !MULTICAST_COOKIE)),
+            id => Some(FlowSource::TableEntry(id)),
         }
-        sink.close().await?;
-        Ok(())
     }
-    .map_err(|e: grpcio::Error| error!("failed to stream response: {:?}", e))
-        .map(|_| ());
-    ctx.spawn(f);
-}
 
-impl P4RuntimeService {
-    fn validate_write(op: Update_Type, entity_exists: bool) -> Result<()> {
-        match (op, entity_exists) {
-            (Update_Type::UNSPECIFIED, _) => Err(Error(RpcStatusCode::INVALID_ARGUMENT))?,
-            (Update_Type::INSERT, true) => Err(Error(RpcStatusCode::ALREADY_EXISTS))?,
-            (Update_Type::MODIFY, false) => Err(Error(RpcStatusCode::NOT_FOUND))?,
-            (Update_Type::DELETE, false) => Err(Error(RpcStatusCode::NOT_FOUND))?,
-            _ => Ok(()),
+    /// Returns the upper 32 bits of the cookies of the flows that came from this entity.
+    fn cookie_id(self) -> u32 {
+        match self {
+            FlowSource::TableEntry(entry_id) => entry_id,
+            FlowSource::MulticastGroup(id) => id | MULTICAST_COOKIE,
         }
     }
+}
 
-    fn write_entity(op: Update_Type, entity: Option<&Entity>, state: &mut State) -> Result<()> {
-        match entity {
-            None => Err(Error(RpcStatusCode::INVALID_ARGUMENT))?,
-            Some(Entity {
-                entity: Some(Entity_oneof_entity::packet_replication_engine_entry(
-                    PacketReplicationEngineEntry {
-                        field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(
-                            mge)), ..})), ..
-            }) => {
-                let mge: MulticastGroupEntry = mge.into();
-                if mge.multicast_group_id == 0 {
-                    Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context(format!("multicast_group_id must not be zero"))?;
-                }
-
-                // Validate the operation.
-                let no_values = BTreeSet::new();
-                let old_value = state.multicast_groups.get(&mge.multicast_group_id).unwrap_or(&no_values);
-                Self::validate_write(op, !old_value.is_empty())?;
-
-                let new_value = match op {
-                    Update_Type::UNSPECIFIED => unreachable!(),
-                    Update_Type::INSERT | Update_Type::MODIFY => &mge.replicas,
-                    Update_Type::DELETE => &no_values,
-                };
-
-                // Commit the operation to DDlog.
-                let mut commands = Vec::with_capacity(2);
-                for insertion in new_value.difference(old_value) {
-                    commands.push(Update::Insert {
-                        relid: Relations::MulticastGroup as RelId,
-                        v: MulticastGroup {
-                            mcast_id: mge.multicast_group_id as u16,
-                            port: insertion.egress_port as u16
-                        }.into_ddvalue()
-                    });
-                }
-                for deletion in old_value.difference(new_value) {
-                    commands.push(Update::DeleteValue {
-                        relid: Relations::MulticastGroup as RelId,
-                        v: MulticastGroup {
-                            mcast_id: mge.multicast_group_id as u16,
-                            port: deletion.egress_port as u16
-                        }.into_ddvalue()
-                    });
-                }
-                let delta = {
-                    let hddlog = &state.hddlog;
-
-                    hddlog.transaction_start().ddlog_map_error()?;
-                    hddlog.apply_updates(&mut commands.into_iter()).ddlog_map_error()?;
-                    hddlog.transaction_commit_dump_changes().ddlog_map_error()?
-                };
-                delta_to_flow_mods(&delta, &mut state.pending_flow_mods);
-                state.latch.set();
+/// Returns the ID of the table entry whose flows have the given `cookie`, or 0 if the flows
+/// don't belong to a table entry.
+fn cookie_entry_id(cookie: u64) -> u32 {
+    match FlowSource::from_cookie(cookie) {
+        Some(FlowSource::TableEntry(entry_id)) => entry_id,
+        _ => 0,
+    }
+}
 
-                // Commit the operation to our internal representation.
-                if new_value.is_empty() {
-                    state.multicast_groups.remove(&mge.multicast_group_id);
-                } else {
-                    state.multicast_groups.insert(mge.multicast_group_id, mge.replicas);
-                }
-                Ok(())
-            },
-            Some(Entity { entity: Some(Entity_oneof_entity::table_entry(te)), .. }) => {
-                let te: TableEntry = te.try_into()?;
+/// Parses `flow`, a record in the `Flow` relation, into a flow mod with `command`.  Replaces the
+/// cookie that the translation specified, which is the ID of the table entry that the flow came
+/// from, by the one from [`flow_cookie`].  A flow mod that modifies or deletes flows affects only
+/// the flow with that cookie.  The syntax doesn't allow specifying a cookie that way for commands
+/// other than adding, so this parses every flow as an addition and then changes the command.
+fn parse_flow(flow: &str, command: FlowModCommand) -> Option<FlowMod> {
+    match FlowMod::parse(flow, Some(FlowModCommand::Add)) {
+        Ok((mut flow_mod, _)) => {
+            let cookie = flow_cookie(flow, flow_mod.cookie() as u32);
+            if matches!(command, FlowModCommand::Add) {
+                flow_mod.set_cookie(cookie);
+            } else {
+                flow_mod.set_command(command);
+                flow_mod.set_cookie_match(cookie);
+            }
+            Some(flow_mod)
+        },
+        Err(s) => {
+            eprintln!("{}: {}", flow, s);
+            None
+        }
+    }
+}
 
-                // Look up the table schema and get its DDlog relation ID.
-                let table = match state.table_schemas.get(&te.key.table_id) {
-                    Some(table) => table,
-                    None => Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("unknown table {}", te.key.table_id))?
-                };
-                let relid = state.hddlog.inventory.get_table_id(table.base_name()).ddlog_map_error()? as RelId;
+/// Parses `group` into a group mod with `command`.
+fn parse_group(group: &str, command: GroupModCommand) -> Option<GroupMod> {
+    match GroupMod::parse(group, command) {
+        Ok((group_mod, _)) => Some(group_mod),
+        Err(s) => {
+            eprintln!("{}: {}", group, s);
+            None
+        }
+    }
+}
 
-                // Validate the operation.
-                let old_value = state.table_entries.get(&te.key);
-                Self::validate_write(op, old_value.is_some())?;
+/// Parses `meter` into a meter mod with `command`.
+fn parse_meter(meter: &str, command: MeterModCommand) -> Option<MeterMod> {
+    match MeterMod::parse(meter, command) {
+        Ok((meter_mod, _)) => Some(meter_mod),
+        Err(s) => {
+            eprintln!("{}: {}", meter, s);
+            None
+        }
+    }
+}
 
-                // Commit the operation to DDlog.
-                let mut commands = Vec::with_capacity(2);
-                if let Some(old_value) = old_value {
-                    let old_te = TableEntry { key: te.key.clone(), value: old_value.clone() };
-                    let old_record = old_te.to_record(table).unwrap();
-                    commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), old_record));
-                }
-                if op != Update_Type::DELETE {
-                    let new_record = te.to_record(table).unwrap();
-                    commands.push(UpdCmd::Insert(RelIdentifier::RelId(relid), new_record));
-                }
-                eprintln!("len={} {:?}", commands.len(), commands);
-                let delta = {
-                    let hddlog = &state.hddlog;
+/// Returns the table schemas for the tables in `p4info`, indexed by table ID.
+fn table_schemas(p4info: &P4Info) -> HashMap<u32, Table> {
+    // Actions are referenced by id, so make a map.
+    let action_by_id: HashMap<u32, p4ext::Action> = p4info
+        .get_actions()
+        .iter()
+        .map(|a| (a.get_preamble().id, a.into()))
+        .collect();
+    p4info.get_tables().iter()
+        .map(|table| p4ext::Table::new_from_proto(table, &action_by_id))
+        .map(|table| (table.preamble.id, table))
+        .collect()
+}
 
-                    hddlog.transaction_start().ddlog_map_error()?;
-                    hddlog.apply_updates_dynamic(&mut commands.into_iter()).ddlog_map_error()?;
-                    hddlog.transaction_commit_dump_changes().ddlog_map_error()?
-                };
-                delta_to_flow_mods(&delta, &mut state.pending_flow_mods);
-                state.latch.set();
+/// Returns the parts of `p4info` that define table `table_id`, if it has one: the table itself,
+/// its actions, its direct meters, and its action profile.  If a table's definition is the same in
+/// two P4Infos, then its entries mean the same thing under both of them.
+#[allow(clippy::type_complexity)]
+fn table_definition(p4info: &P4Info, table_id: u32)
+                    -> Option<(&proto::p4info::Table, Vec<&proto::p4info::Action>, Vec<&proto::p4info::DirectMeter>,
+                               Option<&proto::p4info::ActionProfile>)> {
+    let table = p4info.get_tables().iter().find(|t| t.get_preamble().id == table_id)?;
+    let actions = table.get_action_refs().iter()
+        .filter_map(|ar| p4info.get_actions().iter().find(|a| a.get_preamble().id == ar.id))
+        .collect();
+    let direct_meters = p4info.get_direct_meters().iter()
+        .filter(|dm| dm.direct_table_id == table_id)
+        .collect();
+    let action_profile = p4info.get_action_profiles().iter()
+        .find(|ap| table.implementation_id != 0 && ap.get_preamble().id == table.implementation_id);
+    Some((table, actions, direct_meters, action_profile))
+}
 
-                // Commit the operation to our internal representation.
-                if op == Update_Type::DELETE {
-                    state.table_entries.remove(&te.key);
-                } else {
-                    state.table_entries.insert(te.key, te.value);
-                }
+/// Returns the value of numeric field `name` in `s`, an OpenFlow flow, group, or meter in
+/// `ovs-ofctl` syntax, if `s` specifies it.
+fn ofctl_field(s: &str, name: &str) -> Option<u64> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .find_map(|field| field.strip_prefix(name)?.strip_prefix('=')?.parse().ok())
+}
 
-                Ok(())
-            },
-            _ => Err(Error(RpcStatusCode::UNIMPLEMENTED))?
-        }
+/// Returns a copy of `result`.  The copy of an error has the same status code and message.
+fn copy_result(result: &Result<()>) -> Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(Error(error_code(e))).context(format!("{:#}", e)),
     }
 }
 
-impl<'a> P4Runtime for P4RuntimeService {
-    fn write(&mut self,
-             ctx: RpcContext,
-             req: WriteRequest,
-             sink: UnarySink<WriteResponse>) {
-        println!("write {:?}", req);
-        let mut state = self.state.lock().unwrap();
-        if req.device_id != state.device_id {
-            unary_fail(&ctx, sink, grpcio::RpcStatus::new(RpcStatusCode::NOT_FOUND));
-            return;
+impl State {
+    fn new(device_id: u64, translation: Box<dyn Translation>, hddlog: HDDlog) -> State {
+        State {
+            translation,
+            hddlog,
+            latch: Latch::new(),
+            pending_bundles: Vec::new(),
+            pending_packet_outs: Vec::new(),
+            device_id,
+            p4info: P4Info::new(),
+            cookie: 0,
+            table_schemas: HashMap::new(),
+            saved_config: None,
+            ports: BTreeMap::new(),
+            entities: Entities::default(),
+            default_actions: DefaultActions::default(),
+            digests: Digests::default(),
+            meters: Meters::default(),
+            action_profiles: ActionProfiles::default(),
+            entry_ids: HashMap::new(),
+            entry_keys: HashMap::new(),
+            n_entries: HashMap::new(),
+            next_entry_id: 1,
+            flows: HashMap::new(),
+            idle: IdleTimeouts::new(),
+            stats_requests: Vec::new(),
+            controllers: HashMap::new(),
+            election_ids: HashMap::new(),
         }
+    }
 
-        // XXX role
-        // XXX election_id
-        // XXX atomicity
+    /// Checks that the translation can implement the pipeline that `p4info` describes: that its
+    /// DDlog program has input relations for the pipeline's tables, with the same fields, and for
+    /// its meters if it has any.  Returns the pipeline's table schemas.
+    fn verify_config(&self, p4info: &P4Info) -> Result<HashMap<u32, Table>> {
+        let table_schemas = table_schemas(p4info);
+        for table in table_schemas.values() {
+            self.translation.check_table(&self.hddlog, table)?;
+        }
+        if !p4info.get_meters().is_empty() && self.translation.meter_config_relation(&self.hddlog).is_err() {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context("P4Info has meters but the translation has no MeterConfig relation")?;
+        }
+        if !p4info.get_direct_meters().is_empty() && self.translation.direct_meter_config_relation(&self.hddlog).is_err() {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context("P4Info has direct meters but the translation has no DirectMeterConfig relation")?;
+        }
+        Ok(table_schemas)
+    }
 
-        let mut errors = Vec::with_capacity(req.updates.len());
-        for proto::p4runtime::Update { field_type: op, entity, .. } in req.updates {
-            let code = match Self::write_entity(op, entity.as_ref(), &mut state) {
-                Err(error) => {
-                    eprintln!("{:?}", error);
-                    match error.downcast_ref::<Error>() {
-                        Some(Error(code)) => *code,
-                        _ => RpcStatusCode::UNKNOWN
-                    }
-                },
-                Ok(()) => RpcStatusCode::OK,
-            };
-            errors.push(code);
+    /// Implements the P4Runtime `SetForwardingPipelineConfig` operation for `req`.  A pipeline
+    /// saved with `VERIFY_AND_SAVE` doesn't take effect, for reads and writes either, until a
+    /// `COMMIT`.  Returns a receiver for the result of committing the flow mods that remove
+    /// entities that the new pipeline doesn't keep, if there are any.
+    fn set_pipeline_config(&mut self, req: &SetForwardingPipelineConfigRequest)
+                           -> Result<Option<oneshot::Receiver<Result<()>>>> {
+        use SetForwardingPipelineConfigRequest_Action::*;
+        if req.action == UNSPECIFIED {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context("action must be specified")?;
         }
-        if errors.iter().all(|&code| code == RpcStatusCode::OK) {
-            unary_success(&ctx, sink, WriteResponse::new());
-        } else {
-            let (message, unknown_fields, cached_size) = Default::default();
-            let details = proto::status::Status {
-                code: RpcStatusCode::UNKNOWN.into(),
-                details: errors.iter().map(|&canonical_code| {
-                    let (message, space, code, details, unknown_fields, cached_size) = Default::default();
-                    Any::pack(&proto::p4runtime::Error {
-                        canonical_code: canonical_code.into(),
-                        message, space, code, details, unknown_fields, cached_size
-                    }).unwrap()}).collect(),
-                message, unknown_fields, cached_size
+        if req.action == COMMIT {
+            let config = match self.saved_config.take() {
+                Some(config) => config,
+                None => Err(Error(RpcStatusCode::FAILED_PRECONDITION)).context("no saved forwarding pipeline config")?,
             };
-            unary_fail(&ctx, sink, grpcio::RpcStatus::with_details(RpcStatusCode::UNKNOWN, Default::default(), details.write_to_bytes().unwrap()));
+            let table_schemas = table_schemas(config.get_p4info());
+            return self.commit_config(config, table_schemas, false);
+        }
+        if !req.has_config() || !req.get_config().has_p4info() {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context("config must include P4Info")?;
+        }
+        let config = req.get_config();
+        let table_schemas = self.verify_config(config.get_p4info())?;
+        match req.action {
+            VERIFY => Ok(None),
+            VERIFY_AND_SAVE => {
+                self.saved_config = Some(config.clone());
+                Ok(None)
+            },
+            VERIFY_AND_COMMIT => self.commit_config(config.clone(), table_schemas, false),
+            RECONCILE_AND_COMMIT => self.commit_config(config.clone(), table_schemas, true),
+            UNSPECIFIED | COMMIT => unreachable!(),
         }
     }
 
-    fn read(&mut self,
-            ctx: RpcContext,
-            req: ReadRequest,
-            sink: ServerStreamingSink<ReadResponse>) {
-        println!("read {:?}", req);
-        let state = self.state.lock().unwrap();
-        if req.device_id != state.device_id {
-            server_streaming_fail(&ctx, sink, RpcStatusCode::NOT_FOUND);
-            return;
+    /// Makes `config`, whose P4Info has `table_schemas`, the device's pipeline.  If `reconcile` is
+    /// true, keeps the entities that mean the same thing in the new pipeline as in the old one;
+    /// otherwise, deletes all of them.  Returns a receiver like [`Self::set_pipeline_config`].
+    fn commit_config(&mut self, config: ForwardingPipelineConfig, table_schemas: HashMap<u32, Table>, reconcile: bool)
+                     -> Result<Option<oneshot::Receiver<Result<()>>>> {
+        let flow_mods = self.clear_entities(if reconcile { Some(config.get_p4info()) } else { None })?;
+        self.p4info = config.get_p4info().clone();
+        self.cookie = config.get_cookie().get_cookie();
+        self.table_schemas = table_schemas;
+        let State { default_actions, table_schemas, .. } = &mut *self;
+        default_actions.configure(table_schemas, config.get_p4_device_config());
+        Ok(self.queue_flow_mods(flow_mods))
+    }
+
+    /// Deletes, from DDlog and our internal state, the entities that don't carry over to a
+    /// pipeline with `p4info`: the entries and changed default actions of tables whose
+    /// definitions change, the members and groups of action profiles that change or that such a
+    /// table uses, and the configuration of meters and digests that change.  If `p4info`
+    /// is `None`, deletes every entity, including multicast groups.  Returns flow mods that bring
+    /// the switch up to date.
+    fn clear_entities(&mut self, p4info: Option<&P4Info>) -> Result<Vec<Ofpbuf>> {
+        let old_p4info = &self.p4info;
+        let keep_table = |table_id| p4info.map_or(false, |p4info| {
+            table_definition(old_p4info, table_id) == table_definition(p4info, table_id)
+        });
+        let keep_meter = |meter_id| p4info.map_or(false, |p4info| {
+            let meter = |p4info: &P4Info| p4info.get_meters().iter().find(|m| m.get_preamble().id == meter_id).cloned();
+            meter(old_p4info) == meter(p4info)
+        });
+        let keep_digest = |digest_id| p4info.map_or(false, |p4info| {
+            let digest = |p4info: &P4Info| p4info.get_digests().iter().find(|d| d.get_preamble().id == digest_id).cloned();
+            digest(old_p4info) == digest(p4info)
+        });
+        let keep_profile = |profile_id| p4info.map_or(false, |p4info| {
+            let profile = |p4info: &P4Info| p4info.get_action_profiles().iter().find(|ap| ap.get_preamble().id == profile_id).cloned();
+            let new_profile = profile(p4info);
+            profile(old_p4info) == new_profile
+                && new_profile.map_or(false, |ap| ap.table_ids.iter().all(|&table_id| keep_table(table_id)))
+        });
+
+        let mut commands = Vec::new();
+        let mut changes = Vec::new();
+        for (key, value) in self.entities.table_entries.iter().filter(|(key, _)| !keep_table(key.table_id)) {
+            let table = &self.table_schemas[&key.table_id];
+            let entry_id = self.entry_ids[key];
+            let relid = self.translation.table_relation(&self.hddlog, table.base_name()).ddlog_map_error()?;
+            let te = TableEntry { key: key.clone(), value: value.clone() };
+            let record = self.translation.table_record(table, &te, entry_id)?;
+            commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), record));
+            if self.meters.get_direct(key).is_some() {
+                commands.extend(self.direct_meter_commands(key, entry_id, None)?);
+                changes.push(Change::DirectMeter { key: key.clone(), config: None });
+            }
+            changes.push(Change::TableEntry { key: key.clone(), value: None });
+        }
+        for (&table_id, table) in self.table_schemas.iter().filter(|&(&table_id, _)| !keep_table(table_id)) {
+            if let Some(action) = self.default_actions.get_changed(table_id) {
+                let relid = self.translation.default_action_relation(&self.hddlog, table).ddlog_map_error()?;
+                let record = self.translation.default_action_record(table, action)?;
+                commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), record));
+                changes.push(Change::DefaultAction { table_id, action: None });
+            }
+        }
+        for group in self.action_profiles.groups().filter(|group| !keep_profile(group.action_profile_id)) {
+            let (profile_id, group_id) = (group.action_profile_id, group.group_id);
+            commands.extend(self.group_commands(profile_id, group_id, &group.members, &BTreeMap::new())?);
+            changes.push(Change::ActionProfileGroup { profile_id, group_id, group: None });
+        }
+        for (profile_id, member_id, action) in self.action_profiles.members().filter(|&(profile_id, _, _)| !keep_profile(profile_id)) {
+            commands.extend(self.member_commands(profile_id, member_id, Some(action), None)?);
+            changes.push(Change::ActionProfileMember { profile_id, member_id, action: None });
+        }
+        for (meter_id, index, config) in self.meters.cells().filter(|&(meter_id, _, _)| !keep_meter(meter_id)) {
+            let relid = self.translation.meter_config_relation(&self.hddlog).ddlog_map_error()?;
+            let record = self.translation.meter_config_record(meter_id, index as u64, config);
+            commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), record));
+            changes.push(Change::Meter { meter_id, index, config: None });
+        }
+        for de in self.digests.read(0).into_iter().filter(|de| !keep_digest(de.digest_id)) {
+            changes.push(Change::Digest { id: de.digest_id, config: None });
+        }
+        if p4info.is_none() {
+            for (&id, replicas) in &self.entities.multicast_groups {
+                let relid = self.translation.multicast_relation(&self.hddlog).ddlog_map_error()?;
+                for replica in replicas {
+                    // `prepare` only accepts groups whose ID and ports fit in 16 bits.
+                    let record = self.translation.multicast_record(id as u16, replica.egress_port as u16);
+                    commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), record));
+                }
+                changes.push(Change::MulticastGroup { id, replicas: BTreeSet::new() });
+            }
         }
 
-        let mut responses = Vec::new();
-        for rq_entity in req.entities {
-            let rpy_entities = match rq_entity {
-                Entity {
-                    entity: Some(Entity_oneof_entity::packet_replication_engine_entry(PacketReplicationEngineEntry {
-                        field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(mge)), ..})), ..}
-                => state.read_multicast_groups(mge.multicast_group_id),
+        let flow_mods = if commands.is_empty() {
+            Vec::new()
+        } else {
+            let delta = self.commit(commands)?;
+            self.delta_to_flow_mods(&delta)
+        };
+        for change in changes {
+            self.apply(change);
+        }
+        Ok(flow_mods)
+    }
 
-                Entity { entity: Some(Entity_oneof_entity::table_entry(te)), .. }
-                => state.read_table_entries(&te),
+    /// Returns the primary controller for `role_id`, which is the one whose election ID is the
+    /// highest that any controller has sent for the role.  Returns `None` if that controller has
+    /// disconnected or moved to a lower election ID, in which case the role has no primary until
+    /// some controller sends an election ID at least that high.
+    fn primary(&self, role_id: u64) -> Option<&Controller> {
+        let election_id = *self.election_ids.get(&role_id)?;
+        self.controllers.values().find(|c| c.role_id == role_id && c.election_id == election_id)
+    }
 
-                _ => Vec::new(),
-            };
-            responses.push(ReadResponse { entities: rpy_entities.into(), ..Default::default() });
+    /// Checks that a request for `role_id` with `election_id` comes from the primary controller.
+    fn check_primary(&self, role_id: u64, election_id: Option<&Uint128>) -> Result<()> {
+        let election_id = election_id.map_or(0, election_id_to_u128);
+        match self.primary(role_id) {
+            None => Err(Error(RpcStatusCode::PERMISSION_DENIED))
+                .context(format!("role {} has no primary controller", role_id))?,
+            Some(primary) if election_id != primary.election_id => Err(Error(RpcStatusCode::PERMISSION_DENIED))
+                .context(format!("election ID {} is not primary for role {}", election_id, role_id))?,
+            Some(_) => (),
         }
+        Ok(())
+    }
 
-        server_streaming_success(&ctx, sink, responses);
+    /// Sends an arbitration update to the controller on `stream_id`, reporting whether it is the
+    /// primary for its role, or whether the role has no primary, along with the highest election
+    /// ID seen for the role.
+    fn send_arbitration(&self, stream_id: u64) {
+        let controller = &self.controllers[&stream_id];
+        let highest_election_id = self.election_ids.get(&controller.role_id).copied().unwrap_or(0);
+
+        let mut status = proto::status::Status::new();
+        status.set_code(match self.primary(controller.role_id) {
+            Some(primary) if primary.election_id == controller.election_id => RpcStatusCode::OK,
+            Some(_) => RpcStatusCode::ALREADY_EXISTS,
+            None => RpcStatusCode::NOT_FOUND,
+        }.into());
+
+        let mut update = MasterArbitrationUpdate::new();
+        update.set_device_id(self.device_id);
+        update.mut_role().set_id(controller.role_id);
+        update.set_election_id(u128_to_election_id(highest_election_id));
+        update.set_status(status);
+
+        let mut response = StreamMessageResponse::new();
+        response.set_arbitration(update);
+        let _ = controller.sender.unbounded_send(response);
     }
 
-    fn set_forwarding_pipeline_config(
-        &mut self,
-        ctx: RpcContext,
-        req: SetForwardingPipelineConfigRequest,
-        sink: UnarySink<SetForwardingPipelineConfigResponse>) {
-        println!("set_forwarding_pipeline_config");
-        let config = req.get_config();
+    /// Sends an arbitration update to every controller for `role_id`.
+    fn notify_role(&self, role_id: u64) {
+        for (&stream_id, controller) in &self.controllers {
+            if controller.role_id == role_id {
+                self.send_arbitration(stream_id);
+            }
+        }
+    }
 
-        let mut state = self.state.lock().unwrap();
-        state.p4info = config.get_p4info().clone();
-        state.cookie = config.get_cookie().get_cookie();
+    /// Handles `request`, received on stream `stream_id`, whose replies go to `sender`.  Returns
+    /// an error if the stream should be closed.
+    fn stream_request(&mut self, stream_id: u64, sender: &mpsc::UnboundedSender<StreamMessageResponse>,
+                      request: StreamMessageRequest) -> Result<()> {
+        match request.update {
+            Some(StreamMessageRequest_oneof_update::arbitration(mau)) => {
+                if mau.device_id != self.device_id {
+                    Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("unknown device {}", mau.device_id))?;
+                }
+                let role_id = mau.get_role().id;
+                let election_id = election_id_to_u128(mau.get_election_id());
+                if self.controllers.iter().any(|(&id, c)| id != stream_id && c.role_id == role_id
+                                               && c.election_id == election_id) {
+                    Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                        .context(format!("election ID {} is already in use for role {}", election_id, role_id))?;
+                }
 
-        // Actions are referenced by id, so make a map.
-        let action_by_id: HashMap<u32, p4ext::Action> = state.p4info
-            .get_actions()
-            .iter()
-            .map(|a| (a.get_preamble().id, a.into()))
-            .collect();
-        state.table_schemas = state.p4info.get_tables().iter()
-            .map(|table| p4ext::Table::new_from_proto(table, &action_by_id))
-            .map(|table| (table.preamble.id, table))
-            .collect();
-        unary_success(&ctx, sink, SetForwardingPipelineConfigResponse::new());
+                // If the primary changes, every controller for the role hears about it.  Otherwise,
+                // only the controller that sent the update gets a reply.
+                let old_primary = self.primary(role_id).map(|c| c.election_id);
+                let old_role = self.controllers.insert(stream_id, Controller {
+                    role_id, election_id, sender: sender.clone()
+                }).map(|c| c.role_id);
+                let highest = self.election_ids.entry(role_id).or_insert(election_id);
+                *highest = (*highest).max(election_id);
+                if let Some(old_role) = old_role.filter(|&r| r != role_id) {
+                    self.notify_role(old_role);
+                }
+                if self.primary(role_id).map(|c| c.election_id) != old_primary {
+                    self.notify_role(role_id);
+                } else {
+                    self.send_arbitration(stream_id);
+                }
+                if old_role.is_none() {
+                    // A new stream hears about our capabilities after its arbitration reply.
+                    let _ = sender.unbounded_send(capabilities().to_stream_response());
+                }
+            },
+            Some(StreamMessageRequest_oneof_update::digest_ack(ack)) => self.digests.ack(&ack),
+            Some(StreamMessageRequest_oneof_update::packet(packet_out)) => {
+                if let Err(e) = self.packet_out(stream_id, &packet_out) {
+                    let mut packet_out_error = PacketOutError::new();
+                    packet_out_error.set_packet_out(packet_out);
+                    let mut error = StreamError::new();
+                    error.set_canonical_code(p4ext::server::error_code(&e).into());
+                    error.set_message(format!("{:#}", e));
+                    error.set_packet_out(packet_out_error);
+
+                    let mut reply = StreamMessageResponse::new();
+                    reply.set_error(error);
+                    let _ = sender.unbounded_send(reply);
+                }
+            },
+            _ => (),
+        }
+        Ok(())
     }
 
-    fn get_forwarding_pipeline_config(&mut self, ctx: RpcContext, req: GetForwardingPipelineConfigRequest, sink: UnarySink<GetForwardingPipelineConfigResponse>) {
-        println!("get_forwarding_pipeline_config");
-        let state = self.state.lock().unwrap();
-        if req.device_id != state.device_id {
-            unary_fail(&ctx, sink, grpcio::RpcStatus::new(RpcStatusCode::NOT_FOUND));
-            return;
+    /// Forgets the controller on stream `stream_id`, which closed.  If it was the primary for its
+    /// role, the role has no primary until another controller sends an election ID at least as
+    /// high, so this tells the remaining controllers for the role.
+    fn stream_closed(&mut self, stream_id: u64) {
+        if let Some(controller) = self.controllers.remove(&stream_id) {
+            if self.election_ids.get(&controller.role_id) == Some(&controller.election_id) {
+                self.notify_role(controller.role_id);
+            }
         }
-        let reply = GetForwardingPipelineConfigResponse {
-            config: SingularPtrField::some(ForwardingPipelineConfig {
-                p4info: SingularPtrField::some(state.p4info.clone()),
-                cookie: SingularPtrField::some(ForwardingPipelineConfig_Cookie {
-                    cookie: state.cookie, ..Default::default()}),
-                ..Default::default()}),
-            ..Default::default()};
-        unary_success(&ctx, sink, reply);
     }
 
-    fn stream_channel(
-        &mut self,
-        ctx: RpcContext,
-        mut stream: RequestStream<StreamMessageRequest>,
-        mut sink: DuplexSink<StreamMessageResponse>) {
-        let f = async move {
-            while let Some(n) = stream.try_next().await? {
-                println!("stream_channel");
-                let mut reply = StreamMessageResponse::new();
-                reply.set_arbitration(n.get_arbitration().clone());
-                sink.send((reply, grpcio::WriteFlags::default())).await?;
-            }
-            sink.close().await?;
-            Ok(())
+    /// Queues `packet_out`, received on stream `stream_id`, to be sent to the switch.
+    fn packet_out(&mut self, stream_id: u64, packet_out: &PacketOut) -> Result<()> {
+        let is_primary = self.controllers.get(&stream_id)
+            .map_or(false, |c| self.primary(c.role_id).map(|p| p.election_id) == Some(c.election_id));
+        if !is_primary {
+            Err(Error(RpcStatusCode::PERMISSION_DENIED)).context("only the primary controller may send packets")?;
         }
-        .map_err(|e: grpcio::Error| error!("stream_channel failed: {:?}", e))
-        .map(|_| ());
-        ctx.spawn(f)
+        let packet_out = packet::packet_out_to_openflow(&self.p4info, packet_out)?;
+        self.pending_packet_outs.push(packet_out.encode(OFP_PROTOCOL));
+        self.latch.set();
+        Ok(())
     }
 
-    fn capabilities(&mut self,
-                    _ctx: RpcContext,
-                    _req: CapabilitiesRequest,
-                    _sink: UnarySink<CapabilitiesResponse>) {
-        println!("capabilities");
+    /// Sends `message` to the primary controller for each role.
+    fn send_to_primary(&self, message: StreamMessageResponse) {
+        let roles: BTreeSet<u64> = self.controllers.values().map(|c| c.role_id).collect();
+        for role_id in roles {
+            if let Some(primary) = self.primary(role_id) {
+                let _ = primary.sender.unbounded_send(message.clone());
+            }
+        }
     }
-}
 
-trait DdlogMapError<T> {
-    fn ddlog_map_error(self) -> Result<T>;
-}
-impl<T> DdlogMapError<T> for std::result::Result<T, String> {
-    fn ddlog_map_error(self) -> Result<T> {
-        self.map_err(|s| anyhow!("DDlog error: {}", s))
+    /// Sends the digest lists that are due to the primary controllers, if there are any.
+    fn send_digests(&mut self) {
+        if self.controllers.is_empty() {
+            return;
+        }
+        for list in self.digests.run(Instant::now()) {
+            let mut message = StreamMessageResponse::new();
+            message.set_digest(list);
+            self.send_to_primary(message);
+        }
     }
-}
 
-fn main() -> Result<()> {
-    const OVS_REMOTE: &str = "ovs-remote";
+    /// Sends the controller a notification for the table entries whose flows went idle since the
+    /// last call, if there are any, to the primary controllers.
+    fn send_idle_timeouts(&mut self) {
+        let entry_ids = self.idle.take_expired();
+        if self.controllers.is_empty() {
+            return;
+        }
+        let table_entries: Vec<proto::p4runtime::TableEntry> = entry_ids.iter()
+            .filter_map(|entry_id| {
+                let key = self.entry_keys.get(entry_id)?;
+                let value = self.entities.table_entries.get(key)?;
+                Some((&TableEntry { key: key.clone(), value: value.clone() }).into())
+            })
+            .collect();
+        if table_entries.is_empty() {
+            return;
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as i64;
+        let mut message = StreamMessageResponse::new();
+        message.set_idle_timeout_notification(IdleTimeoutNotification {
+            table_entry: RepeatedField::from_vec(table_entries),
+            timestamp,
+            ..Default::default()
+        });
+        self.send_to_primary(message);
+    }
+
+    /// Updates the device's ports to `ports`, as discovered in the Open vSwitch database.  If the
+    /// translation has a `Port` relation, this passes it the OpenFlow port number of each port and
+    /// queues the resulting flow changes.  It also tells the controllers about each port that went
+    /// up or down.
+    fn set_ports(&mut self, ports: BTreeMap<u16, Interface>) {
+        if let Ok(relid) = self.translation.port_relation(&self.hddlog) {
+            let mut commands = Vec::new();
+            for (&p4_port, old) in &self.ports {
+                if ports.get(&p4_port).map(|new| new.ofport) != Some(old.ofport) {
+                    let record = self.translation.port_record(p4_port, old.ofport);
+                    commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), record));
+                }
+            }
+            for (&p4_port, new) in &ports {
+                if self.ports.get(&p4_port).map(|old| old.ofport) != Some(new.ofport) {
+                    let record = self.translation.port_record(p4_port, new.ofport);
+                    commands.push(UpdCmd::Insert(RelIdentifier::RelId(relid), record));
+                }
+            }
+            if !commands.is_empty() {
+                match self.commit(commands) {
+                    Ok(delta) => {
+                        let flow_mods = self.delta_to_flow_mods(&delta);
+                        self.queue_flow_mods(flow_mods);
+                    },
+                    Err(e) => eprintln!("ports: {:#}", e),
+                }
+            }
+        }
+
+        for (&p4_port, new) in &ports {
+            if self.ports.get(&p4_port).map_or(false, |old| old.up) != new.up {
+                self.send_port_status(p4_port, new);
+            }
+        }
+        for (&p4_port, old) in &self.ports {
+            if old.up && !ports.contains_key(&p4_port) {
+                self.send_port_status(p4_port, &Interface { up: false, ..old.clone() });
+            }
+        }
+        self.ports = ports;
+    }
+
+    /// Tells every controller that P4 port `port`, implemented by `interface`, is up or down.
+    /// P4Runtime has no message for this, so it goes in the `other` member of the stream message.
+    fn send_port_status(&self, port: u16, interface: &Interface) {
+        let status = PortStatus {
+            device_id: self.device_id,
+            port: port.into(),
+            name: interface.name.clone(),
+            up: interface.up,
+            ..Default::default()
+        };
+        let mut other = Any::new();
+        other.set_type_url("type.googleapis.com/ofp4.PortStatus".into());
+        other.set_value(status.write_to_bytes().unwrap());
+
+        let mut message = StreamMessageResponse::new();
+        message.set_other(other);
+        for controller in self.controllers.values() {
+            let _ = controller.sender.unbounded_send(message.clone());
+        }
+    }
+
+    /// Converts the `delta` of changes to DDlog output relations (particularly `Flow`, `Group`,
+    /// and `Meter`) into OpenFlow [`FlowMod`], [`GroupMod`], and [`MeterMod`] messages.  Meters
+    /// and groups are added and modified before the flow mods and deleted after them, so that no
+    /// flow ever refers to a missing meter or group.
+    fn delta_to_flow_mods(&self, delta: &DeltaMap<DDValue>) -> Vec<Ofpbuf> {
+        let mut flow_mods = Vec::new();
+        let flow_relid = match self.translation.flow_relation(&self.hddlog) {
+            Ok(relid) => relid,
+            Err(s) => {
+                eprintln!("{}", s);
+                return flow_mods;
+            }
+        };
+        let group_relid = self.translation.group_relation(&self.hddlog).ok();
+        let mut added_groups = BTreeMap::new();
+        let mut deleted_groups = BTreeSet::new();
+        let meter_relid = self.translation.meter_relation(&self.hddlog).ok();
+        let mut added_meters = BTreeMap::new();
+        let mut deleted_meters = BTreeSet::new();
+        for (&rel, changes) in delta.iter() {
+            if rel == flow_relid {
+                for (val, &weight) in changes.iter() {
+                    let command = match weight {
+                        1 => FlowModCommand::Add,
+                        -1 => FlowModCommand::Delete { strict: true },
+                        _ => unreachable!()
+                    };
+
+                    let flow = match self.translation.flow_string(val) {
+                        Some(flow) => flow,
+                        None => continue,
+                    };
+                    if let Some(flow_mod) = parse_flow(&flow, command) {
+                        flow_mods.push(flow_mod.encode(OFP_PROTOCOL));
+                    }
+                }
+            } else if Some(rel) == group_relid {
+                for (val, &weight) in changes.iter() {
+                    let group = match self.translation.group_string(val) {
+                        Some(group) => group,
+                        None => continue,
+                    };
+                    if let Some(group_mod) = parse_group(&group, GroupModCommand::Add) {
+                        match weight {
+                            1 => { added_groups.insert(group_mod.group_id(), (group, group_mod)); },
+                            -1 => { deleted_groups.insert(group_mod.group_id()); },
+                            _ => unreachable!()
+                        }
+                    }
+                }
+            } else if Some(rel) == meter_relid {
+                for (val, &weight) in changes.iter() {
+                    let meter = match self.translation.meter_string(val) {
+                        Some(meter) => meter,
+                        None => continue,
+                    };
+                    if let Some(meter_mod) = parse_meter(&meter, MeterModCommand::Add) {
+                        match weight {
+                            1 => { added_meters.insert(meter_mod.meter_id(), (meter, meter_mod)); },
+                            -1 => { deleted_meters.insert(meter_mod.meter_id()); },
+                            _ => unreachable!()
+                        }
+                    }
+                }
+            }
+        }
+
+        // A meter that is both deleted and added changed its bands, so modify it in place.
+        let mut msgs = Vec::new();
+        for (meter_id, (meter, meter_mod)) in added_meters {
+            let meter_mod = if deleted_meters.remove(&meter_id) {
+                match parse_meter(&meter, MeterModCommand::Modify) {
+                    Some(meter_mod) => meter_mod,
+                    None => continue,
+                }
+            } else {
+                meter_mod
+            };
+            msgs.push(meter_mod.encode(OFP_VERSION));
+        }
+
+        // Likewise for groups and their buckets.
+        for (group_id, (group, group_mod)) in added_groups {
+            let group_mod = if deleted_groups.remove(&group_id) {
+                match parse_group(&group, GroupModCommand::Modify) {
+                    Some(group_mod) => group_mod,
+                    None => continue,
+                }
+            } else {
+                group_mod
+            };
+            msgs.push(group_mod.encode(OFP_VERSION));
+        }
+        msgs.extend(flow_mods);
+        msgs.extend(deleted_groups.into_iter()
+            .filter_map(|group_id| parse_group(&format!("group_id={}", group_id), GroupModCommand::Delete))
+            .map(|group_mod| group_mod.encode(OFP_VERSION)));
+        msgs.extend(deleted_meters.into_iter()
+            .filter_map(|meter_id| parse_meter(&format!("meter={}", meter_id), MeterModCommand::Delete))
+            .map(|meter_mod| meter_mod.encode(OFP_VERSION)));
+        msgs
+    }
+
+    /// Returns group mods that add or modify every group in the `Group` index.
+    fn resync_group_mods(&self) -> Vec<Ofpbuf> {
+        let group_index = match self.translation.group_index(&self.hddlog) {
+            Ok(group_index) => group_index,
+            Err(_) => return Vec::new(),
+        };
+        self.hddlog.dump_index(group_index).unwrap().into_iter()
+            .filter_map(|record| self.translation.group_string(&record))
+            .filter_map(|group| parse_group(&group, GroupModCommand::AddOrModify))
+            .map(|group_mod| group_mod.encode(OFP_VERSION))
+            .collect()
+    }
+
+    /// Returns flow mods that change the switch from the flows with the cookies and the meters in
+    /// `existing` to the flows in the `Flow` index and the meters in the `Meter` index.  If
+    /// `existing` is `None`, or if two of our flows have the same cookie, so that the switch's
+    /// cookies don't say which flows it has, the flow mods delete all of the switch's flows,
+    /// groups, and meters and then add all of ours.
+    ///
+    /// Deleting a meter deletes the flows that use it, so the incremental case adds and modifies
+    /// meters first and deletes the ones we don't want last, after no flow refers to them.
+    fn resync_flow_mods(&self, existing: Option<(&HashSet<u64>, &HashMap<u32, MeterConfig>)>) -> Vec<Ofpbuf> {
+        let meters: Vec<(String, MeterMod)> = match self.translation.meter_index(&self.hddlog) {
+            Ok(meter_index) => self.hddlog.dump_index(meter_index).unwrap().into_iter()
+                .filter_map(|record| self.translation.meter_string(&record))
+                .filter_map(|meter| parse_meter(&meter, MeterModCommand::Add).map(|mm| (meter, mm)))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        let flow_index = self.translation.flow_index(&self.hddlog).unwrap();
+        let flows: Vec<FlowMod> = self.hddlog.dump_index(flow_index).unwrap().into_iter()
+            .filter_map(|record| self.translation.flow_string(&record))
+            .filter_map(|flow| parse_flow(&flow, FlowModCommand::Add))
+            .collect();
+        let mut our_cookies = HashSet::with_capacity(flows.len());
+        let existing = if flows.iter().all(|flow_mod| our_cookies.insert(flow_mod.cookie())) {
+            existing
+        } else {
+            eprintln!("flows have colliding cookies, replacing all of the switch's flows");
+            None
+        };
+
+        // Delete flows before adding, because the switch might have a flow with the same match as
+        // one of ours but different actions.
+        let mut flow_mods = Vec::new();
+        match existing {
+            None => flow_mods.push(FlowMod::parse("", Some(FlowModCommand::Delete { strict: false })).unwrap().0),
+            Some((cookies, _)) => flow_mods.extend(cookies.iter()
+                .filter(|cookie| !our_cookies.contains(cookie))
+                .filter_map(|cookie| FlowMod::parse(&format!("cookie={:#x}/-1", cookie),
+                                                    Some(FlowModCommand::Delete { strict: false })).ok())
+                .map(|(flow_mod, _)| flow_mod)),
+        }
+        flow_mods.extend(flows.into_iter()
+            .filter(|flow_mod| existing.map_or(true, |(cookies, _)| !cookies.contains(&flow_mod.cookie()))));
+
+        // Meters and groups go first, so that the flows we add can refer to them.  The switch
+        // doesn't report which groups it has along with its flows, so in the incremental case we
+        // just bring all of ours up to date and leave any extras in place.  They are harmless
+        // because no flow refers to them.
+        let mut msgs = Vec::new();
+        let mut meter_deletes = Vec::new();
+        match existing {
+            None => {
+                if !meters.is_empty() {
+                    msgs.push(parse_meter("meter=all", MeterModCommand::Delete).unwrap().encode(OFP_VERSION));
+                    msgs.extend(meters.iter().map(|(_, mm)| mm.encode(OFP_VERSION)));
+                }
+                msgs.push(parse_group("group_id=all", GroupModCommand::Delete).unwrap().encode(OFP_VERSION));
+            },
+            Some((_, switch_meters)) => {
+                for (meter, mm) in &meters {
+                    match switch_meters.get(&mm.meter_id()) {
+                        None => msgs.push(mm.encode(OFP_VERSION)),
+                        Some(config) if *config != mm.config() => {
+                            // Modifying a meter, unlike deleting and re-adding it, keeps its flows.
+                            if let Some(mm) = parse_meter(meter, MeterModCommand::Modify) {
+                                msgs.push(mm.encode(OFP_VERSION));
+                            }
+                        },
+                        Some(_) => (),
+                    }
+                }
+                let ours: HashSet<u32> = meters.iter().map(|(_, mm)| mm.meter_id()).collect();
+                meter_deletes.extend(switch_meters.keys()
+                    .filter(|meter_id| !ours.contains(meter_id))
+                    .filter_map(|meter_id| parse_meter(&format!("meter={}", meter_id), MeterModCommand::Delete))
+                    .map(|mm| mm.encode(OFP_VERSION)));
+            },
+        }
+        msgs.extend(self.resync_group_mods());
+        msgs.extend(flow_mods.into_iter().map(|fm| fm.encode(OFP_PROTOCOL)));
+        msgs.extend(meter_deletes);
+        msgs
+    }
+
+    /// Implements the P4Runtime `Read` operation for `targets`.  If `stats` is present, it is used
+    /// for counter data.
+    fn read(&self, targets: &[Entity], stats: Option<&EntryStats>) -> Result<Vec<ReadResponse>> {
+        let mut responses = Vec::with_capacity(targets.len());
+        for target in targets {
+            let entities = match &target.entity {
+                Some(Entity_oneof_entity::digest_entry(de)) => self.digests.read(de.digest_id).into_iter()
+                    .map(|de| Entity { entity: Some(Entity_oneof_entity::digest_entry(de)), ..Default::default() })
+                    .collect(),
+                Some(Entity_oneof_entity::direct_counter_entry(dce)) => self.entities
+                    .read_table_entries(dce.get_table_entry())?
+                    .into_iter()
+                    .filter_map(|entity| {
+                        let te = entity.get_table_entry();
+                        let data = self.counter_data(te, stats)?;
+                        let dce = DirectCounterEntry {
+                            table_entry: SingularPtrField::some(te.clone()),
+                            data: SingularPtrField::some(data),
+                            ..Default::default()
+                        };
+                        Some(Entity { entity: Some(Entity_oneof_entity::direct_counter_entry(dce)), ..Default::default() })
+                    })
+                    .collect(),
+                Some(Entity_oneof_entity::direct_meter_entry(dme)) => self.entities
+                    .read_table_entries(dme.get_table_entry())?
+                    .into_iter()
+                    .filter(|entity| meter::direct_meter_id(&self.p4info, entity.get_table_entry().table_id).is_some())
+                    .map(|entity| {
+                        let te = entity.get_table_entry();
+                        let dme = DirectMeterEntry {
+                            table_entry: SingularPtrField::some(te.clone()),
+                            config: SingularPtrField::from_option(self.meter_config(te)),
+                            ..Default::default()
+                        };
+                        Entity { entity: Some(Entity_oneof_entity::direct_meter_entry(dme)), ..Default::default() }
+                    })
+                    .collect(),
+                Some(Entity_oneof_entity::meter_entry(me)) => self.meters.read(me, &self.p4info)?.into_iter()
+                    .map(|me| Entity { entity: Some(Entity_oneof_entity::meter_entry(me)), ..Default::default() })
+                    .collect(),
+                Some(Entity_oneof_entity::action_profile_member(apm)) => {
+                    let apm: ActionProfileMember = apm.try_into()?;
+                    self.action_profiles.read_members(&apm, &self.p4info)?.iter()
+                        .map(|apm| Entity { entity: Some(Entity_oneof_entity::action_profile_member(apm.into())), ..Default::default() })
+                        .collect()
+                },
+                Some(Entity_oneof_entity::action_profile_group(apg)) => {
+                    let apg: ActionProfileGroup = apg.try_into()?;
+                    self.action_profiles.read_groups(&apg, &self.p4info)?.iter()
+                        .map(|apg| Entity { entity: Some(Entity_oneof_entity::action_profile_group(apg.into())), ..Default::default() })
+                        .collect()
+                },
+                // Wildcard reads don't include default entries.
+                Some(Entity_oneof_entity::table_entry(te)) if te.is_default_action => self.default_actions.read(te),
+                Some(Entity_oneof_entity::table_entry(te)) => {
+                    let mut entities = self.entities.read(target)?;
+                    for entity in &mut entities {
+                        if te.has_counter_data() {
+                            if let Some(data) = self.counter_data(entity.get_table_entry(), stats) {
+                                entity.mut_table_entry().set_counter_data(data);
+                            }
+                        }
+                        if let Some(config) = self.meter_config(entity.get_table_entry()) {
+                            entity.mut_table_entry().set_meter_config(config);
+                        }
+                    }
+                    entities
+                },
+                _ => self.entities.read(target)?,
+            };
+            responses.push(ReadResponse { entities: entities.into(), ..Default::default() });
+        }
+        Ok(responses)
+    }
+
+    /// Returns the counter data for table entry `te` from `stats`, or `None` if `te`'s table
+    /// doesn't have a direct counter.
+    fn counter_data(&self, te: &proto::p4runtime::TableEntry, stats: Option<&EntryStats>) -> Option<CounterData> {
+        if !self.p4info.get_direct_counters().iter().any(|dc| dc.direct_table_id == te.table_id) {
+            return None;
+        }
+        let te: TableEntry = te.try_into().ok()?;
+        let entry_id = self.entry_ids.get(&te.key)?;
+        let (packets, bytes) = stats?.get(entry_id).copied().unwrap_or_default();
+        Some(CounterData { packet_count: packets as i64, byte_count: bytes as i64, ..Default::default() })
+    }
+
+    /// Returns the configuration of the direct meter for table entry `te`, or `None` if `te`'s
+    /// table doesn't have a direct meter or it isn't configured.
+    fn meter_config(&self, te: &proto::p4runtime::TableEntry) -> Option<MeterConfig> {
+        let te: TableEntry = te.try_into().ok()?;
+        self.meters.get_direct(&te.key).cloned()
+    }
+
+    /// Returns true if writing `entity` might add, modify, or delete an OpenFlow meter: if it is a
+    /// meter entry or a direct meter entry, or a table entry that has or had a meter
+    /// configuration.
+    fn changes_meters(&self, entity: Option<&Entity>) -> bool {
+        match entity.and_then(|entity| entity.entity.as_ref()) {
+            Some(Entity_oneof_entity::meter_entry(_)) | Some(Entity_oneof_entity::direct_meter_entry(_)) => true,
+            Some(Entity_oneof_entity::table_entry(te)) => te.has_meter_config() || self.meter_config(te).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Returns the OpenFlow meters, groups, and flows that the translation currently specifies,
+    /// in `ovs-ofctl` syntax.  Meters and groups are sorted by ID and flows by table, then by
+    /// descending priority, then by text, so that the same entities always yield the same output.
+    fn flow_table(&self) -> Vec<String> {
+        let mut meters: Vec<String> = match self.translation.meter_index(&self.hddlog) {
+            Ok(meter_index) => self.hddlog.dump_index(meter_index).unwrap().into_iter()
+                .filter_map(|record| self.translation.meter_string(&record))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        meters.sort_by_key(|meter| (ofctl_field(meter, "meter"), meter.clone()));
+
+        let mut groups: Vec<String> = match self.translation.group_index(&self.hddlog) {
+            Ok(group_index) => self.hddlog.dump_index(group_index).unwrap().into_iter()
+                .filter_map(|record| self.translation.group_string(&record))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        groups.sort_by_key(|group| (ofctl_field(group, "group_id"), group.clone()));
+
+        let flow_index = self.translation.flow_index(&self.hddlog).unwrap();
+        let mut flows: Vec<String> = self.hddlog.dump_index(flow_index).unwrap().into_iter()
+            .filter_map(|record| self.translation.flow_string(&record))
+            .collect();
+        flows.sort_by_key(|flow| (ofctl_field(flow, "table").unwrap_or(0),
+                                  Reverse(ofctl_field(flow, "priority").unwrap_or(32768)),
+                                  flow.clone()));
+
+        meters.into_iter().chain(groups).chain(flows).collect()
+    }
+
+    /// Replays `input`, DDlog commands in the syntax of the `replay.txt` file that ofp4 records,
+    /// against the translation.  Only the commands that start, commit, and roll back
+    /// transactions, update relations, and clear relations have any effect.
+    fn replay(&self, input: &[u8]) -> Result<()> {
+        use cmd_parser::Command;
+        let hddlog = &self.hddlog;
+        let mut input = input;
+        while input.iter().any(|c| !c.is_ascii_whitespace()) {
+            let (rest, command) = match cmd_parser::parse_command(input) {
+                Ok(result) => result,
+                Err(e) => return Err(anyhow!("invalid DDlog command: {:?}", e)),
+            };
+            input = rest;
+            let result = match command {
+                Command::Start => hddlog.transaction_start(),
+                Command::Commit(_) => hddlog.transaction_commit(),
+                Command::Rollback => hddlog.transaction_rollback(),
+                Command::Update(command, _) => hddlog.apply_updates_dynamic(&mut std::iter::once(command)),
+                Command::Clear(relation) => self.translation.table_relation(hddlog, &relation)
+                    .and_then(|relid| hddlog.clear_relation(relid)),
+                _ => Ok(()),
+            };
+            result.ddlog_map_error()?;
+        }
+        Ok(())
+    }
+
+    /// Queues `flow_mods` to be sent to the switch in a bundle of their own.  Returns a receiver
+    /// for the result of committing the bundle, or `None` if there are no flow mods.
+    fn queue_flow_mods(&mut self, flow_mods: Vec<Ofpbuf>) -> Option<oneshot::Receiver<Result<()>>> {
+        if flow_mods.is_empty() {
+            return None;
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.pending_bundles.push(PendingBundle { flow_mods, replies: vec![sender] });
+        self.latch.set();
+        Some(receiver)
+    }
+
+    /// Checks whether `op` may be applied to `entity`.  If so, returns the DDlog commands that
+    /// implement it and the corresponding changes to our internal state, without making either.
+    fn prepare(&self, op: Update_Type, entity: Option<&Entity>) -> Result<(Vec<UpdCmd>, Vec<Change>)> {
+        match entity {
+            None => Err(Error(RpcStatusCode::INVALID_ARGUMENT))?,
+            Some(Entity {
+                entity: Some(Entity_oneof_entity::packet_replication_engine_entry(
+                    PacketReplicationEngineEntry {
+                        field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(
+                            mge)), ..})), ..
+            }) => {
+                let mge: MulticastGroupEntry = mge.into();
+                if mge.multicast_group_id == 0 {
+                    Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context(format!("multicast_group_id must not be zero"))?;
+                }
+
+                // The translation's multicast group IDs and ports are 16 bits wide.
+                if mge.multicast_group_id > u16::MAX.into() {
+                    Err(Error(RpcStatusCode::OUT_OF_RANGE))
+                        .context(format!("multicast_group_id {} exceeds maximum {}", mge.multicast_group_id, u16::MAX))?;
+                }
+                if let Some(replica) = mge.replicas.iter().find(|replica| replica.egress_port > u16::MAX.into()) {
+                    Err(Error(RpcStatusCode::OUT_OF_RANGE))
+                        .context(format!("egress_port {} exceeds maximum {}", replica.egress_port, u16::MAX))?;
+                }
+
+                // Validate the operation.
+                let no_values = BTreeSet::new();
+                let old_value = self.entities.multicast_groups.get(&mge.multicast_group_id).unwrap_or(&no_values);
+                validate_write(op, !old_value.is_empty())?;
+
+                let new_value = match op {
+                    Update_Type::UNSPECIFIED => unreachable!(),
+                    Update_Type::INSERT | Update_Type::MODIFY => &mge.replicas,
+                    Update_Type::DELETE => &no_values,
+                };
+
+                let relid = self.translation.multicast_relation(&self.hddlog).ddlog_map_error()?;
+                // The group ID and the ports of the old and new replicas were all checked above, or
+                // when they were inserted, so they fit in 16 bits.
+                let record = |replica: &Replica| self.translation.multicast_record(
+                    mge.multicast_group_id as u16, replica.egress_port as u16);
+                let mut commands = Vec::with_capacity(2);
+                for insertion in new_value.difference(old_value) {
+                    commands.push(UpdCmd::Insert(RelIdentifier::RelId(relid), record(insertion)));
+                }
+                for deletion in old_value.difference(new_value) {
+                    commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), record(deletion)));
+                }
+                let change = Change::MulticastGroup { id: mge.multicast_group_id, replicas: new_value.clone() };
+                Ok((commands, vec![change]))
+            },
+            Some(Entity { entity: Some(Entity_oneof_entity::digest_entry(de)), .. }) => {
+                let config = self.digests.validate(op, de, &self.p4info)?;
+                Ok((Vec::new(), vec![Change::Digest { id: de.digest_id, config }]))
+            },
+            Some(Entity { entity: Some(Entity_oneof_entity::meter_entry(me)), .. }) => {
+                let config = self.meters.validate(op, me, &self.p4info)?;
+                let index = me.get_index().index;
+                let relid = self.translation.meter_config_relation(&self.hddlog).ddlog_map_error()?;
+                let record = |config| self.translation.meter_config_record(me.meter_id, index as u64, config);
+                let mut commands = Vec::with_capacity(2);
+                if let Some(old_config) = self.meters.get(me.meter_id, index) {
+                    commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), record(old_config)));
+                }
+                if let Some(config) = &config {
+                    commands.push(UpdCmd::Insert(RelIdentifier::RelId(relid), record(config)));
+                }
+                Ok((commands, vec![Change::Meter { meter_id: me.meter_id, index, config }]))
+            },
+            Some(Entity { entity: Some(Entity_oneof_entity::direct_meter_entry(dme)), .. }) => {
+                if op != Update_Type::MODIFY {
+                    Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context("direct meter entries may only be modified")?;
+                }
+                let te: TableEntry = dme.get_table_entry().try_into()?;
+                let entry_id = match self.entry_ids.get(&te.key) {
+                    Some(&entry_id) => entry_id,
+                    None => Err(Error(RpcStatusCode::NOT_FOUND)).context("direct meter entry's table entry does not exist")?,
+                };
+                let config = if dme.has_config() { Some(dme.get_config().clone()) } else { None };
+                let commands = self.direct_meter_commands(&te.key, entry_id, config.as_ref())?;
+                Ok((commands, vec![Change::DirectMeter { key: te.key, config }]))
+            },
+            Some(Entity { entity: Some(Entity_oneof_entity::table_entry(proto_te)), .. }) => {
+                let te: TableEntry = proto_te.try_into()?;
+
+                // Look up the table schema and get its DDlog relation ID.
+                let table = match self.table_schemas.get(&te.key.table_id) {
+                    Some(table) => table,
+                    None => Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("unknown table {}", te.key.table_id))?
+                };
+                if te.key.is_default_action {
+                    return self.prepare_default_action(op, &te, table);
+                }
+                let relid = self.translation.table_relation(&self.hddlog, table.base_name()).ddlog_map_error()?;
+
+                // Validate the entry against the schema before the translation sees it.
+                let old_value = self.entities.table_entries.get(&te.key);
+                let n_entries = self.n_entries.get(&te.key.table_id).copied().unwrap_or(0);
+                validate_table_write(op, &te, table, old_value, n_entries)?;
+
+                // An entry in a table with an action profile must refer to a member or group that
+                // exists.
+                if let (Some(apr), Some(profile_id)) = (te.value.action_profile_ref, table.action_profile_id()) {
+                    if op != Update_Type::DELETE && !self.action_profiles.contains(profile_id, apr) {
+                        Err(Error(RpcStatusCode::NOT_FOUND))
+                            .context(format!("table entry refers to nonexistent {} of action profile {}", apr, profile_id))?;
+                    }
+                }
+
+                // A modified entry keeps its ID.
+                let entry_id = self.entry_ids.get(&te.key).copied().unwrap_or(self.next_entry_id);
+                if entry_id >= MULTICAST_COOKIE {
+                    Err(Error(RpcStatusCode::RESOURCE_EXHAUSTED)).context("out of table entry IDs")?;
+                }
+
+                let mut commands = Vec::with_capacity(2);
+                if let Some(old_value) = old_value {
+                    let old_te = TableEntry { key: te.key.clone(), value: old_value.clone() };
+                    let old_record = self.translation.table_record(table, &old_te, entry_id)?;
+                    commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), old_record));
+                }
+                if op != Update_Type::DELETE {
+                    let new_record = self.translation.table_record(table, &te, entry_id)?;
+                    commands.push(UpdCmd::Insert(RelIdentifier::RelId(relid), new_record));
+                }
+                eprintln!("len={} {:?}", commands.len(), commands);
+
+                // The entry's direct meter, if it has one, takes the entry's meter configuration,
+                // or the default if it doesn't have one.
+                let mut changes = Vec::with_capacity(2);
+                let has_direct_meter = meter::direct_meter_id(&self.p4info, te.key.table_id).is_some();
+                if has_direct_meter {
+                    let config = if op != Update_Type::DELETE && proto_te.has_meter_config() {
+                        Some(proto_te.get_meter_config().clone())
+                    } else {
+                        None
+                    };
+                    commands.extend(self.direct_meter_commands(&te.key, entry_id, config.as_ref())?);
+                    changes.push(Change::DirectMeter { key: te.key.clone(), config });
+                } else if proto_te.has_meter_config() {
+                    Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                        .context(format!("table {} has no direct meter", table.base_name()))?;
+                }
+
+                let value = if op == Update_Type::DELETE { None } else { Some((te.value, entry_id)) };
+                changes.push(Change::TableEntry { key: te.key, value });
+                Ok((commands, changes))
+            },
+            Some(Entity { entity: Some(Entity_oneof_entity::action_profile_member(proto_apm)), .. }) => {
+                let apm: ActionProfileMember = proto_apm.try_into()?;
+                let (profile_id, member_id) = (apm.action_profile_id, apm.member_id);
+                let action = self.action_profiles.validate_member(op, &apm, &self.p4info, &self.table_schemas)?;
+                if action.is_none() && self.refers_to(profile_id, ActionProfileRef::Member(member_id)) {
+                    Err(Error(RpcStatusCode::FAILED_PRECONDITION))
+                        .context(format!("a table entry refers to member {}", member_id))?;
+                }
+                let old_action = self.action_profiles.get_member(profile_id, member_id);
+                let commands = self.member_commands(profile_id, member_id, old_action, action.as_ref())?;
+                Ok((commands, vec![Change::ActionProfileMember { profile_id, member_id, action }]))
+            },
+            Some(Entity { entity: Some(Entity_oneof_entity::action_profile_group(proto_apg)), .. }) => {
+                let apg: ActionProfileGroup = proto_apg.try_into()?;
+                let (profile_id, group_id) = (apg.action_profile_id, apg.group_id);
+                let group = self.action_profiles.validate_group(op, &apg, &self.p4info)?;
+                if group.is_none() && self.refers_to(profile_id, ActionProfileRef::Group(group_id)) {
+                    Err(Error(RpcStatusCode::FAILED_PRECONDITION))
+                        .context(format!("a table entry refers to group {}", group_id))?;
+                }
+                let no_members = BTreeMap::new();
+                let old_members = self.action_profiles.get_group(profile_id, group_id).map_or(&no_members, |g| &g.members);
+                let new_members = group.as_ref().map_or(&no_members, |g| &g.members);
+                let commands = self.group_commands(profile_id, group_id, old_members, new_members)?;
+                Ok((commands, vec![Change::ActionProfileGroup { profile_id, group_id, group }]))
+            },
+            _ => Err(Error(RpcStatusCode::UNIMPLEMENTED))?
+        }
+    }
+
+    /// Returns the DDlog commands that change the configuration of the direct meter for the table
+    /// entry with `key` and ID `entry_id` to `config`, or to the default if `config` is `None`.
+    fn direct_meter_commands(&self, key: &TableKey, entry_id: u32, config: Option<&MeterConfig>) -> Result<Vec<UpdCmd>> {
+        let meter_id = match meter::direct_meter_id(&self.p4info, key.table_id) {
+            Some(meter_id) => meter_id,
+            None => Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context(format!("table {} has no direct meter", key.table_id))?,
+        };
+        if let Some(config) = config {
+            meter::validate_config(config)?;
+        }
+        let old_config = self.meters.get_direct(key);
+        if old_config.is_none() && config.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let relid = self.translation.direct_meter_config_relation(&self.hddlog).ddlog_map_error()?;
+        let record = |config| self.translation.direct_meter_config_record(meter_id, entry_id, config);
+        let mut commands = Vec::with_capacity(2);
+        if let Some(old_config) = old_config {
+            commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), record(old_config)));
+        }
+        if let Some(config) = config {
+            commands.push(UpdCmd::Insert(RelIdentifier::RelId(relid), record(config)));
+        }
+        Ok(commands)
+    }
+
+    /// Returns the tables that use action profile `profile_id`.
+    fn profile_tables(&self, profile_id: ActionProfileId) -> impl Iterator<Item = &Table> {
+        self.table_schemas.values().filter(move |table| table.action_profile_id() == Some(profile_id))
+    }
+
+    /// Returns true if a table entry refers to `apr` in action profile `profile_id`.
+    fn refers_to(&self, profile_id: ActionProfileId, apr: ActionProfileRef) -> bool {
+        self.entities.table_entries.iter().any(|(key, value)| {
+            value.action_profile_ref == Some(apr)
+                && self.table_schemas.get(&key.table_id).and_then(|table| table.action_profile_id()) == Some(profile_id)
+        })
+    }
+
+    /// Returns the DDlog commands that change the action of member `member_id` of action profile
+    /// `profile_id` from `old` to `new`, in the member relation of each table that uses the
+    /// profile.  `None` means that the member doesn't exist.
+    fn member_commands(&self, profile_id: ActionProfileId, member_id: u32, old: Option<&TableAction>,
+                       new: Option<&TableAction>) -> Result<Vec<UpdCmd>> {
+        let mut commands = Vec::new();
+        for table in self.profile_tables(profile_id) {
+            let relid = self.translation.member_relation(&self.hddlog, table).ddlog_map_error()?;
+            if let Some(old) = old {
+                let record = self.translation.member_record(table, member_id, old)?;
+                commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), record));
+            }
+            if let Some(new) = new {
+                let record = self.translation.member_record(table, member_id, new)?;
+                commands.push(UpdCmd::Insert(RelIdentifier::RelId(relid), record));
+            }
+        }
+        Ok(commands)
+    }
+
+    /// Returns the DDlog commands that change the members of group `group_id` of action profile
+    /// `profile_id`, with their weights, from `old` to `new`, in the group member relation of each
+    /// table that uses the profile.
+    fn group_commands(&self, profile_id: ActionProfileId, group_id: u32, old: &BTreeMap<u32, u32>,
+                      new: &BTreeMap<u32, u32>) -> Result<Vec<UpdCmd>> {
+        let old: BTreeSet<(u32, u32)> = old.iter().map(|(&member_id, &weight)| (member_id, weight)).collect();
+        let new: BTreeSet<(u32, u32)> = new.iter().map(|(&member_id, &weight)| (member_id, weight)).collect();
+        let mut commands = Vec::new();
+        for table in self.profile_tables(profile_id) {
+            let relid = self.translation.group_member_relation(&self.hddlog, table).ddlog_map_error()?;
+            let record = |&(member_id, weight): &(u32, u32)| {
+                self.translation.group_member_record(table, group_id, member_id, weight)
+            };
+            commands.extend(old.difference(&new).map(|m| UpdCmd::Delete(RelIdentifier::RelId(relid), record(m))));
+            commands.extend(new.difference(&old).map(|m| UpdCmd::Insert(RelIdentifier::RelId(relid), record(m))));
+        }
+        Ok(commands)
+    }
+
+    /// Checks whether `op` may be applied to `te`, the default entry for `table`.  If so, returns
+    /// the DDlog commands that implement it and the corresponding change to our internal state.
+    fn prepare_default_action(&self, op: Update_Type, te: &TableEntry, table: &Table) -> Result<(Vec<UpdCmd>, Vec<Change>)> {
+        let table_id = table.preamble.id;
+        let action = self.default_actions.validate(op, te, table)?;
+        let old_action = self.default_actions.get_changed(table_id);
+
+        let mut commands = Vec::with_capacity(2);
+        if old_action != action.as_ref() {
+            if !table.has_default_action_relation() {
+                Err(Error(RpcStatusCode::UNIMPLEMENTED))
+                    .context(format!("the translation cannot change the default action for table {}", table.base_name()))?;
+            }
+            let relid = self.translation.default_action_relation(&self.hddlog, table).ddlog_map_error()?;
+            if let Some(old_action) = old_action {
+                let record = self.translation.default_action_record(table, old_action)?;
+                commands.push(UpdCmd::Delete(RelIdentifier::RelId(relid), record));
+            }
+            if let Some(action) = &action {
+                let record = self.translation.default_action_record(table, action)?;
+                commands.push(UpdCmd::Insert(RelIdentifier::RelId(relid), record));
+            }
+        }
+        Ok((commands, vec![Change::DefaultAction { table_id, action }]))
+    }
+
+    /// Makes `change` to our internal state.  Returns the change that undoes it.
+    fn apply(&mut self, change: Change) -> Change {
+        match change {
+            Change::MulticastGroup { id, replicas } => {
+                let old = if replicas.is_empty() {
+                    self.entities.multicast_groups.remove(&id)
+                } else {
+                    self.entities.multicast_groups.insert(id, replicas)
+                };
+                Change::MulticastGroup { id, replicas: old.unwrap_or_default() }
+            },
+            Change::TableEntry { key, value } => {
+                let old = match value {
+                    Some((value, entry_id)) => {
+                        self.next_entry_id = self.next_entry_id.max(entry_id + 1);
+                        let old_id = self.entry_ids.insert(key.clone(), entry_id);
+                        self.entry_keys.insert(entry_id, key.clone());
+                        if old_id.is_none() {
+                            *self.n_entries.entry(key.table_id).or_default() += 1;
+                        }
+
+                        self.idle.set(entry_id, value.idle_timeout_ns, Instant::now());
+                        self.entities.table_entries.insert(key.clone(), value).zip(old_id)
+                    },
+                    None => {
+                        let old_id = self.entry_ids.remove(&key);
+                        if let Some(old_id) = old_id {
+                            self.entry_keys.remove(&old_id);
+                            self.idle.remove(old_id);
+                            if let Some(n) = self.n_entries.get_mut(&key.table_id) {
+                                *n -= 1;
+                                if *n == 0 {
+                                    self.n_entries.remove(&key.table_id);
+                                }
+                            }
+                        }
+                        self.entities.table_entries.remove(&key).zip(old_id)
+                    },
+                };
+                Change::TableEntry { key, value: old }
+            },
+            Change::DefaultAction { table_id, action } => {
+                Change::DefaultAction { table_id, action: self.default_actions.set(table_id, action) }
+            },
+            Change::Digest { id, config } => Change::Digest { id, config: self.digests.configure(id, config) },
+            Change::Meter { meter_id, index, config } => {
+                Change::Meter { meter_id, index, config: self.meters.configure(meter_id, index, config) }
+            },
+            Change::DirectMeter { key, config } => {
+                let old = self.meters.configure_direct(key.clone(), config);
+                Change::DirectMeter { key, config: old }
+            },
+            Change::ActionProfileMember { profile_id, member_id, action } => {
+                let old = self.action_profiles.set_member(profile_id, member_id, action);
+                Change::ActionProfileMember { profile_id, member_id, action: old }
+            },
+            Change::ActionProfileGroup { profile_id, group_id, group } => {
+                let old = self.action_profiles.set_group(profile_id, group_id, group);
+                Change::ActionProfileGroup { profile_id, group_id, group: old }
+            },
+        }
+    }
+
+    /// Applies `commands` to DDlog in a single transaction.  Returns the resulting changes to the
+    /// DDlog output relations, or rolls back the transaction on error.
+    fn commit(&mut self, commands: Vec<UpdCmd>) -> Result<DeltaMap<DDValue>> {
+        let hddlog = &self.hddlog;
+        hddlog.transaction_start().ddlog_map_error()?;
+        let result = hddlog.apply_updates_dynamic(&mut commands.into_iter())
+            .and_then(|_| hddlog.transaction_commit_dump_changes());
+        if result.is_err() {
+            let _ = hddlog.transaction_rollback();
+        }
+        let delta = result.ddlog_map_error()?;
+        self.index_flows(&delta);
+        Ok(delta)
+    }
+
+    /// Updates `self.flows` with the changes to the `Flow` relation in `delta`.
+    fn index_flows(&mut self, delta: &DeltaMap<DDValue>) {
+        let flow_relid = match self.translation.flow_relation(&self.hddlog) {
+            Ok(relid) => relid,
+            Err(_) => return,
+        };
+        let changes = match delta.iter().find(|&(&rel, _)| rel == flow_relid) {
+            Some((_, changes)) => changes,
+            None => return,
+        };
+        for (val, &weight) in changes.iter() {
+            let flow = match self.translation.flow_string(val) {
+                Some(flow) => flow,
+                None => continue,
+            };
+            let cookie = match FlowMod::parse(&flow, Some(FlowModCommand::Add)) {
+                Ok((flow_mod, _)) => flow_cookie(&flow, flow_mod.cookie() as u32),
+                Err(_) => continue,
+            };
+            let id = (cookie >> 32) as u32;
+            if weight > 0 {
+                self.flows.entry(id).or_default().insert((cookie, flow));
+            } else if let Some(flows) = self.flows.get_mut(&id) {
+                flows.remove(&(cookie, flow));
+                if flows.is_empty() {
+                    self.flows.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// Describes the P4Runtime entity that the flows with `cookie` came from, followed by those
+    /// flows, for the `ofp4/cookie` command.
+    fn describe_cookie(&self, cookie: u64) -> std::result::Result<String, String> {
+        let source = FlowSource::from_cookie(cookie)
+            .ok_or_else(|| format!("cookie {:#x} does not belong to a P4Runtime entity", cookie))?;
+        let mut s = match source {
+            FlowSource::TableEntry(entry_id) => {
+                let key = self.entry_keys.get(&entry_id)
+                    .ok_or_else(|| format!("no table entry has ID {}", entry_id))?;
+                let te = TableEntry { key: key.clone(), value: self.entities.table_entries[key].clone() };
+                let table_name = self.table_schemas.get(&key.table_id)
+                    .map_or_else(|| key.table_id.to_string(), |table| table.base_name().into());
+                format!("table entry {} in table {}: {}\n",
+                        entry_id, table_name,
+                        protobuf::text_format::print_to_string(&proto::p4runtime::TableEntry::from(&te)))
+            },
+            FlowSource::MulticastGroup(id) => format!("multicast group {}\n", id),
+        };
+        s.push_str(&self.describe_flows(source));
+        Ok(s)
+    }
+
+    /// Lists the flows that came from `source`, one per line, each preceded by its cookie.
+    fn describe_flows(&self, source: FlowSource) -> String {
+        let mut s = String::new();
+        for (cookie, flow) in self.flows.get(&source.cookie_id()).into_iter().flatten() {
+            s.push_str(&format!("{:#018x} {}\n", cookie, flow));
+        }
+        s
+    }
+
+    /// Applies all of `updates` to DDlog, in a single transaction, and to our internal state, or
+    /// none of them if any of them fails.  Returns the result for each update and the flow mods
+    /// that bring the switch up to date.
+    fn write_atomically(&mut self, updates: &[(Update_Type, Option<Entity>)]) -> (Vec<Result<()>>, Vec<Ofpbuf>) {
+        let mut results = Vec::with_capacity(updates.len());
+        let mut commands = Vec::new();
+        let mut undo = Vec::with_capacity(updates.len());
+        for (op, entity) in updates {
+            // Later updates in the batch must see the effects of earlier ones, so make each change
+            // to our internal state as we go.
+            let result = self.prepare(*op, entity.as_ref()).map(|(update_commands, changes)| {
+                commands.extend(update_commands);
+                for change in changes {
+                    undo.push(self.apply(change));
+                }
+            });
+            if let Err(error) = &result {
+                eprintln!("{:?}", error);
+            }
+            results.push(result);
+        }
+
+        if results.iter().all(|result| result.is_ok()) {
+            if commands.is_empty() {
+                return (results, Vec::new());
+            }
+            match self.commit(commands) {
+                Ok(delta) => return (results, self.delta_to_flow_mods(&delta)),
+                Err(error) => {
+                    eprintln!("{:?}", error);
+                    let result = Err(error);
+                    results = updates.iter().map(|_| copy_result(&result)).collect();
+                }
+            }
+        }
+
+        // Undo the changes to our internal state.
+        for change in undo.into_iter().rev() {
+            self.apply(change);
+        }
+        for result in &mut results {
+            if result.is_ok() {
+                *result = Err(Error(RpcStatusCode::ABORTED)).context("not applied because another update failed");
+            }
+        }
+        (results, Vec::new())
+    }
+
+    /// Applies `updates` according to `atomicity`.  Returns the result of each update so far.  An
+    /// update that succeeded so far might still fail when the switch commits its flow mods, so
+    /// also returns receivers for those results, each with the indexes of the updates in
+    /// `updates` that it covers.
+    fn write(&mut self, atomicity: WriteRequest_Atomicity, updates: &[(Update_Type, Option<Entity>)])
+             -> (Vec<Result<()>>, Vec<(Vec<usize>, oneshot::Receiver<Result<()>>)>) {
+        let mut waits = Vec::new();
+        let results = match atomicity {
+            WriteRequest_Atomicity::CONTINUE_ON_ERROR => updates.iter().enumerate()
+                .map(|(i, update)| {
+                    let (mut results, flow_mods) = self.write_atomically(std::slice::from_ref(update));
+                    if let Some(receiver) = self.queue_flow_mods(flow_mods) {
+                        waits.push((vec![i], receiver));
+                    }
+                    results.pop().unwrap()
+                })
+                .collect(),
+            // Meter mods can't go in a bundle, so a write that changes meters can't be atomic in
+            // the dataplane.
+            WriteRequest_Atomicity::DATAPLANE_ATOMIC
+                if updates.iter().any(|(_, entity)| self.changes_meters(entity.as_ref())) => updates.iter()
+                .map(|_| Err(Error(RpcStatusCode::UNIMPLEMENTED))
+                     .context("DATAPLANE_ATOMIC writes may not change meters"))
+                .collect(),
+            WriteRequest_Atomicity::ROLLBACK_ON_ERROR | WriteRequest_Atomicity::DATAPLANE_ATOMIC => {
+                let (results, flow_mods) = self.write_atomically(updates);
+                if let Some(receiver) = self.queue_flow_mods(flow_mods) {
+                    waits.push(((0..updates.len()).collect(), receiver));
+                }
+                results
+            },
+        };
+        (results, waits)
+    }
+}
+
+#[derive(Clone)]
+struct P4RuntimeService {
+    // The state of each device, indexed by device ID.
+    devices: Arc<HashMap<u64, Arc<Mutex<State>>>>,
+
+    // Stream IDs are unique across devices, since a stream only binds to a device when it
+    // arbitrates.
+    next_stream_id: Arc<AtomicU64>,
+}
+
+impl P4RuntimeService {
+    fn new(devices: HashMap<u64, Arc<Mutex<State>>>) -> P4RuntimeService {
+        P4RuntimeService { devices: Arc::new(devices), next_stream_id: Arc::new(AtomicU64::new(1)) }
+    }
+
+    /// Returns the state of device `device_id`, or an error if there is no such device.
+    fn device(&self, device_id: u64) -> Result<Arc<Mutex<State>>> {
+        match self.devices.get(&device_id) {
+            Some(state) => Ok(state.clone()),
+            None => Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("unknown device {}", device_id)),
+        }
+    }
+
+    /// Returns the state of the device that `request`, received on a stream, is for.  `device` is
+    /// the device that the stream is bound to, if any.  A stream binds to a device with its first
+    /// arbitration update, which has to come before any other message.
+    fn bind_stream(&self, device: &mut Option<(u64, Arc<Mutex<State>>)>, request: &StreamMessageRequest)
+                   -> Result<Arc<Mutex<State>>> {
+        let arbitration_device_id = match &request.update {
+            Some(StreamMessageRequest_oneof_update::arbitration(mau)) => Some(mau.device_id),
+            _ => None,
+        };
+        match (device.as_ref(), arbitration_device_id) {
+            (Some((device_id, _)), Some(id)) if id != *device_id => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("arbitration for device {} on a stream for device {}", id, device_id)),
+            (Some((_, state)), _) => Ok(state.clone()),
+            (None, Some(id)) => {
+                let state = self.device(id)?;
+                *device = Some((id, state.clone()));
+                Ok(state)
+            },
+            (None, None) => Err(Error(RpcStatusCode::FAILED_PRECONDITION))
+                .context("stream must start with an arbitration update"),
+        }
+    }
+}
+
+impl<'a> P4Runtime for P4RuntimeService {
+    fn write(&mut self,
+             ctx: RpcContext,
+             req: WriteRequest,
+             sink: UnarySink<WriteResponse>) {
+        println!("write {:?}", req);
+        let device = match self.device(req.device_id) {
+            Ok(device) => device,
+            Err(e) => {
+                unary_fail(&ctx, sink, grpcio::RpcStatus::with_message(error_code(&e), format!("{:#}", e)));
+                return;
+            }
+        };
+        let mut state = device.lock().unwrap();
+        if let Err(e) = state.check_primary(req.role_id, req.election_id.as_ref()) {
+            unary_fail(&ctx, sink, grpcio::RpcStatus::with_message(error_code(&e), format!("{:#}", e)));
+            return;
+        }
+
+        let updates: Vec<_> = req.updates.into_iter()
+            .map(|update| (update.field_type, update.entity.into_option()))
+            .collect();
+        let (mut results, waits) = state.write(req.atomicity, &updates);
+
+        // Reply only after the switch commits the flow mods for every update.
+        let f = async move {
+            for (indexes, receiver) in waits {
+                let result = match receiver.await {
+                    Ok(result) => result,
+                    Err(oneshot::Canceled) => Err(Error(RpcStatusCode::INTERNAL))
+                        .context("gave up waiting for the switch"),
+                };
+                for i in indexes {
+                    results[i] = copy_result(&result);
+                }
+            }
+            match write_status(&results) {
+                Some(status) => sink.fail(status).await,
+                None => sink.success(WriteResponse::new()).await,
+            }
+        }
+        .map_err(|e: grpcio::Error| eprintln!("write failed: {:?}", e))
+        .map(|_| ());
+        ctx.spawn(f);
+    }
+
+    fn read(&mut self,
+            ctx: RpcContext,
+            req: ReadRequest,
+            sink: ServerStreamingSink<ReadResponse>) {
+        println!("read {:?}", req);
+        let device = match self.device(req.device_id) {
+            Ok(device) => device,
+            Err(e) => {
+                server_streaming_fail(&ctx, sink, error_code(&e));
+                return;
+            }
+        };
+        let mut state = device.lock().unwrap();
+
+        let needs_stats = req.entities.iter().any(|e| e.has_direct_counter_entry()
+                                                  || (e.has_table_entry() && e.get_table_entry().has_counter_data()));
+        if !needs_stats {
+            match state.read(&req.entities, None) {
+                Ok(responses) => server_streaming_success(&ctx, sink, responses),
+                Err(error) => {
+                    eprintln!("{:?}", error);
+                    server_streaming_fail(&ctx, sink, error_code(&error));
+                }
+            }
+            return;
+        }
+
+        // Counters come from flow statistics, which we have to get from the switch.
+        let (sender, receiver) = oneshot::channel();
+        state.stats_requests.push(sender);
+        state.latch.set();
+        drop(state);
+
+        let state = device;
+        let f = async move {
+            let result = match receiver.await {
+                Ok(stats) => state.lock().unwrap().read(&req.entities, Some(&stats)),
+                Err(oneshot::Canceled) => Err(Error(RpcStatusCode::UNAVAILABLE))
+                    .context("could not get flow statistics from the switch"),
+            };
+            match result {
+                Ok(responses) => {
+                    for response in responses {
+                        sink.send((response, WriteFlags::default())).await?;
+                    }
+                    sink.close().await
+                },
+                Err(error) => {
+                    eprintln!("{:?}", error);
+                    sink.fail(grpcio::RpcStatus::new(error_code(&error))).await
+                }
+            }
+        }
+        .map_err(|e: grpcio::Error| eprintln!("read failed: {:?}", e))
+        .map(|_| ());
+        ctx.spawn(f);
+    }
+
+    fn set_forwarding_pipeline_config(
+        &mut self,
+        ctx: RpcContext,
+        req: SetForwardingPipelineConfigRequest,
+        sink: UnarySink<SetForwardingPipelineConfigResponse>) {
+        println!("set_forwarding_pipeline_config");
+        let device = match self.device(req.device_id) {
+            Ok(device) => device,
+            Err(e) => {
+                unary_fail(&ctx, sink, grpcio::RpcStatus::with_message(error_code(&e), format!("{:#}", e)));
+                return;
+            }
+        };
+        let mut state = device.lock().unwrap();
+        if let Err(e) = state.check_primary(req.role_id, req.election_id.as_ref()) {
+            unary_fail(&ctx, sink, grpcio::RpcStatus::with_message(error_code(&e), format!("{:#}", e)));
+            return;
+        }
+        let receiver = match state.set_pipeline_config(&req) {
+            Ok(receiver) => receiver,
+            Err(e) => {
+                eprintln!("{:?}", e);
+                unary_fail(&ctx, sink, grpcio::RpcStatus::with_message(error_code(&e), format!("{:#}", e)));
+                return;
+            }
+        };
+
+        // Reply only after the switch deletes the flows for the entities that the new pipeline
+        // doesn't keep.
+        let f = async move {
+            let result = match receiver {
+                None => Ok(()),
+                Some(receiver) => match receiver.await {
+                    Ok(result) => result,
+                    Err(oneshot::Canceled) => Err(Error(RpcStatusCode::INTERNAL))
+                        .context("gave up waiting for the switch"),
+                },
+            };
+            match result {
+                Ok(()) => sink.success(SetForwardingPipelineConfigResponse::new()).await,
+                Err(e) => sink.fail(grpcio::RpcStatus::with_message(error_code(&e), format!("{:#}", e))).await,
+            }
+        }
+        .map_err(|e: grpcio::Error| eprintln!("set_forwarding_pipeline_config failed: {:?}", e))
+        .map(|_| ());
+        ctx.spawn(f);
+    }
+
+    fn get_forwarding_pipeline_config(&mut self, ctx: RpcContext, req: GetForwardingPipelineConfigRequest, sink: UnarySink<GetForwardingPipelineConfigResponse>) {
+        println!("get_forwarding_pipeline_config");
+        let device = match self.device(req.device_id) {
+            Ok(device) => device,
+            Err(e) => {
+                unary_fail(&ctx, sink, grpcio::RpcStatus::with_message(error_code(&e), format!("{:#}", e)));
+                return;
+            }
+        };
+        let state = device.lock().unwrap();
+        let reply = GetForwardingPipelineConfigResponse {
+            config: SingularPtrField::some(ForwardingPipelineConfig {
+                p4info: SingularPtrField::some(state.p4info.clone()),
+                cookie: SingularPtrField::some(ForwardingPipelineConfig_Cookie {
+                    cookie: state.cookie, ..Default::default()}),
+                ..Default::default()}),
+            ..Default::default()};
+        unary_success(&ctx, sink, reply);
+    }
+
+    fn stream_channel(
+        &mut self,
+        ctx: RpcContext,
+        mut stream: RequestStream<StreamMessageRequest>,
+        mut sink: DuplexSink<StreamMessageResponse>) {
+        let (sender, mut receiver) = mpsc::unbounded();
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+
+        // The stream binds to the device named in its first arbitration update.
+        let service = self.clone();
+        let mut device: Option<(u64, Arc<Mutex<State>>)> = None;
+        let f = async move {
+            let result = async {
+                loop {
+                    match future::select(stream.try_next(), receiver.next()).await {
+                        Either::Left((request, _)) => match request? {
+                            Some(request) => {
+                                println!("stream_channel");
+                                let result = service.bind_stream(&mut device, &request).and_then(|state| {
+                                    let mut state = state.lock().unwrap();
+                                    state.stream_request(stream_id, &sender, request)
+                                });
+                                if let Err(e) = result {
+                                    let status = grpcio::RpcStatus::with_message(error_code(&e), format!("{:#}", e));
+                                    return sink.fail(status).await;
+                                }
+                            },
+                            None => break,
+                        },
+                        Either::Right((Some(response), _)) => sink.send((response, WriteFlags::default())).await?,
+                        Either::Right((None, _)) => break,
+                    }
+                }
+                sink.close().await
+            }.await;
+            if let Some((_, state)) = device {
+                state.lock().unwrap().stream_closed(stream_id);
+            }
+            result
+        }
+        .map_err(|e: grpcio::Error| error!("stream_channel failed: {:?}", e))
+        .map(|_| ());
+        ctx.spawn(f)
+    }
+
+    fn capabilities(&mut self,
+                    ctx: RpcContext,
+                    _req: CapabilitiesRequest,
+                    sink: UnarySink<CapabilitiesResponse>) {
+        println!("capabilities");
+        unary_success(&ctx, sink, capabilities().to_response());
+    }
+}
+
+trait DdlogMapError<T> {
+    fn ddlog_map_error(self) -> Result<T>;
+}
+impl<T> DdlogMapError<T> for std::result::Result<T, String> {
+    fn ddlog_map_error(self) -> Result<T> {
+        self.map_err(|s| anyhow!("DDlog error: {}", s))
+    }
+}
+
+/// The OpenFlow connection to the Open vSwitch bridge that implements a device.
+struct Bridge {
+    state: Arc<Mutex<State>>,
+    name: Option<String>,
+    rconn: ovs::rconn::Rconn,
+    last_seqno: u32,
+    bundle_id: u32,
+    sent_bundles: Vec<SentBundle>,
+    resync: Option<Resync>,
+    stats_dump: Option<StatsDump>,
+
+    // When the connection to the switch went down, if it is down.
+    disconnected_since: Option<Instant>,
+}
+
+impl Bridge {
+    /// Creates a connection to `ovs_remote` for the device with `state`.  `name` is the bridge's
+    /// name in the Open vSwitch database, if known.
+    fn new(state: Arc<Mutex<State>>, ovs_remote: &str, name: Option<String>) -> Bridge {
+        let mut rconn = ovs::rconn::Rconn::new(0, 0, ovs::rconn::DSCP_DEFAULT, OFP_VERSION.into());
+        rconn.connect(ovs_remote, None);
+        Bridge {
+            state,
+            name,
+            rconn,
+            last_seqno: 0,
+            bundle_id: 0,
+            sent_bundles: Vec::new(),
+            resync: None,
+            stats_dump: None,
+            disconnected_since: None,
+        }
+    }
+
+    /// Processes messages from the switch and sends it whatever the device has queued.
+    fn run(&mut self) {
+        let Bridge { state, rconn, last_seqno, bundle_id, sent_bundles, resync, stats_dump, disconnected_since, .. } = self;
+        rconn.run();
+        loop {
+            let p = rconn.recv();
+            match p {
+                None => break,
+                Some(message) if ofp_msgs::decode_type(message.as_slice()).ok() == Some(OFPTYPE_BUNDLE_CONTROL) => {
+                    match BundleCtrlMsg::decode(message.as_slice()) {
+                        Ok(bcm) if bcm.type_ == OFPBCT_COMMIT_REPLY => {
+                            if let Some(i) = sent_bundles.iter().position(|b| b.bundle_id == bcm.bundle_id) {
+                                sent_bundles.remove(i).finish(&Ok(()));
+                            }
+                        },
+                        Ok(_) => (),
+                        Err(e) => eprintln!("bundle control: {:#}", e),
+                    }
+                },
+                Some(message) if ofp_msgs::decode_type(message.as_slice()).ok() == Some(OFPTYPE_FLOW_STATS_REPLY)
+                    && resync.as_ref().map(|r| r.xid) == Some(ofp_msgs::xid(message.as_slice())) => {
+                    let r = resync.as_mut().unwrap();
+                    match FlowStats::decode_reply(message.as_slice()) {
+                        Ok(stats) => r.cookies.extend(stats.iter().map(|fs| fs.cookie)),
+                        Err(e) => {
+                            // Fall back to replacing all the flows.
+                            eprintln!("flow stats reply: {:#}", e);
+                            r.failed = true;
+                        }
+                    }
+                    if !ofp_msgs::more(message.as_slice()) {
+                        r.done = true;
+                    }
+                },
+                Some(message) if ofp_msgs::decode_type(message.as_slice()).ok() == Some(OFPTYPE_METER_CONFIG_STATS_REPLY)
+                    && resync.as_ref().map(|r| r.meter_xid) == Some(ofp_msgs::xid(message.as_slice())) => {
+                    let r = resync.as_mut().unwrap();
+                    match MeterConfig::decode_reply(message.as_slice()) {
+                        Ok(configs) => r.meters.extend(configs.into_iter().map(|mc| (mc.meter_id, mc))),
+                        Err(e) => {
+                            // Fall back to replacing all the flows and meters.
+                            eprintln!("meter config reply: {:#}", e);
+                            r.failed = true;
+                        }
+                    }
+                    if !ofp_msgs::more(message.as_slice()) {
+                        r.meters_done = true;
+                    }
+                },
+                Some(message) if ofp_msgs::decode_type(message.as_slice()).ok() == Some(OFPTYPE_FLOW_STATS_REPLY)
+                    && stats_dump.as_ref().map_or(false, |d| d.xids.contains(&ofp_msgs::xid(message.as_slice()))) => {
+                    let d = stats_dump.as_mut().unwrap();
+                    match FlowStats::decode_reply(message.as_slice()) {
+                        Ok(stats) => for fs in stats.iter().filter(|fs| cookie_entry_id(fs.cookie) != 0) {
+                            let (packets, bytes) = d.stats.entry(cookie_entry_id(fs.cookie)).or_default();
+                            *packets += fs.packet_count;
+                            *bytes += fs.byte_count;
+                        },
+                        Err(e) => {
+                            // Dropping the replies makes the reads fail.
+                            eprintln!("flow stats reply: {:#}", e);
+                            *stats_dump = None;
+                            continue;
+                        }
+                    }
+                    if !ofp_msgs::more(message.as_slice()) {
+                        let xid = ofp_msgs::xid(message.as_slice());
+                        d.xids.retain(|&x| x != xid);
+                        if !d.xids.is_empty() {
+                            continue;
+                        }
+                        let d = stats_dump.take().unwrap();
+                        state.lock().unwrap().idle.update(&d.stats, Instant::now());
+                        for reply in d.replies {
+                            let _ = reply.send(d.stats.clone());
+                        }
+                    }
+                },
+                Some(message) if ofp_msgs::decode_type(message.as_slice()).ok() == Some(OFPTYPE_ERROR) => {
+                    let xid = ofp_msgs::xid(message.as_slice());
+                    let error = match ofp_errors::decode_msg(message.as_slice()) {
+                        Some(error) => error.to_string(),
+                        None => String::from("unknown error"),
+                    };
+                    let details = ovs::ofp_print::Printer(message.as_slice()).to_string();
+                    eprintln!("received error {}", details);
+
+                    // If the switch can't dump its flows or meters, fall back to replacing all of them.
+                    if let Some(r) = resync.as_mut().filter(|r| r.xid == xid) {
+                        r.failed = true;
+                        r.done = true;
+                    }
+                    if let Some(r) = resync.as_mut().filter(|r| r.meter_xid == xid) {
+                        r.failed = true;
+                        r.meters_done = true;
+                    }
+                    if stats_dump.as_ref().map_or(false, |d| d.xids.contains(&xid)) {
+                        *stats_dump = None;
+                    }
+
+                    // The switch discards a bundle that has an error, so it won't send a commit
+                    // reply for it.  A flow mod inside a bundle has the same xid as the message
+                    // that added it to the bundle, so the error identifies the bundle, and the
+                    // error's details include the flow mod that the switch rejected.
+                    if let Some(i) = sent_bundles.iter().position(|b| b.xids.contains(&xid)) {
+                        let result = Err(Error(RpcStatusCode::INTERNAL))
+                            .context(format!("switch rejected flow table update with {}: {}", error, details.trim_end()));
+                        sent_bundles.remove(i).finish(&result);
+                    }
+                },
+                Some(message) => match PacketIn::decode(&message) {
+                    Ok(Some(pin)) if pin.userdata.is_empty() => {
+                        // A packet sent to the CPU port.
+                        let state = state.lock().unwrap();
+                        match packet::packet_in_to_p4runtime(&state.p4info, &pin) {
+                            Ok(packet_in) => {
+                                let mut message = StreamMessageResponse::new();
+                                message.set_packet(packet_in);
+                                state.send_to_primary(message);
+                            },
+                            Err(e) => eprintln!("packet-in: {:#}", e),
+                        }
+                    },
+                    Ok(Some(pin)) => {
+                        let mut state = state.lock().unwrap();
+                        let state = &mut *state;
+                        if let Err(e) = state.digests.packet_in(&state.p4info, &pin, Instant::now()) {
+                            eprintln!("packet-in: {:#}", e);
+                        }
+                    },
+                    _ => println!("received message {}", ovs::ofp_print::Printer(message.as_slice()))
+                }
+            }
+        }
+        state.lock().unwrap().send_digests();
+        state.lock().unwrap().send_idle_timeouts();
+
+        state.lock().unwrap().latch.poll();
+        if rconn.connected() {
+            let mut state = state.lock().unwrap();
+            *disconnected_since = None;
+
+            if rconn.connection_seqno() != *last_seqno {
+                // We just reconnected.  Any bundle we sent on the old connection might or might
+                // not have been committed, so writes waiting for them wait for the resync instead.
+                let mut replies: Vec<_> = resync.take().map(|r| r.replies).unwrap_or_default();
+                replies.extend(sent_bundles.drain(..).flat_map(|b| b.replies));
+
+                // Digests need the userdata and registers that only NXT_PACKET_IN2 includes.
+                let format = ovs::ofp_packet::encode_set_packet_in_format(OFP_VERSION, PacketInFormat::Nxt2);
+                rconn.send(format).unwrap();
+
+                // Find out what flows the switch already has, by cookie, and what meters.
+                let request = FlowStats::encode_dump_request(OFP_PROTOCOL);
+                let xid = ofp_msgs::xid(request.as_slice());
+                rconn.send(request).unwrap();
+                let request = MeterConfig::encode_dump_request(OFP_VERSION);
+                let meter_xid = ofp_msgs::xid(request.as_slice());
+                rconn.send(request).unwrap();
+                *resync = Some(Resync { xid, cookies: HashSet::new(), meter_xid, meters: HashMap::new(), replies,
+                                        done: false, meters_done: false, failed: false });
+
+                // Reads waiting for statistics from the old connection have to ask again.
+                *stats_dump = None;
+
+                *last_seqno = rconn.connection_seqno();
+            }
+
+            if let Some(r) = resync.as_mut() {
+                // The resync will include pending flow mods, so discard them.
+                r.replies.extend(state.pending_bundles.drain(..).flat_map(|b| b.replies));
+            }
+            if resync.as_ref().map_or(false, |r| r.done && r.meters_done) {
+                // Send only the differences between the switch's flows and meters and the ones we
+                // want, in an atomic bundle so that packets in the middle don't see a partial flow
+                // table.
+                let r = resync.take().unwrap();
+                let flow_mods = state.resync_flow_mods(if r.failed { None } else { Some((&r.cookies, &r.meters)) });
+                if flow_mods.is_empty() {
+                    for reply in r.replies {
+                        let _ = reply.send(Ok(()));
+                    }
+                } else {
+                    *bundle_id += 1;
+                    sent_bundles.push(SentBundle::send(rconn, *bundle_id, flow_mods.into_iter(), r.replies));
+                }
+            }
+            if resync.is_none() {
+                // Send pending flow mods, if any, each bundle separately so that writes waiting
+                // on one don't wait on the others.
+                for bundle in std::mem::take(&mut state.pending_bundles) {
+                    *bundle_id += 1;
+                    sent_bundles.push(SentBundle::send(rconn, *bundle_id, bundle.flow_mods.into_iter(),
+                                                       bundle.replies));
+                }
+
+                // Send packets from the controller, now that the flows are up to date.
+                for msg in state.pending_packet_outs.drain(..) {
+                    rconn.send(msg).unwrap();
+                }
+            }
+
+            // Dump flow statistics for reads that need them, which also covers table entries with
+            // idle timeouts.  Otherwise, periodically request just the statistics for the flows of
+            // the entries with idle timeouts, by cookie, to find the ones that went idle.  Reads
+            // that arrive while a dump is in progress wait for the next one, since the current one
+            // might be out of date.
+            let now = Instant::now();
+            if stats_dump.is_none() && (!state.stats_requests.is_empty() || state.idle.poll_due(now)) {
+                state.idle.polled(now);
+                let requests: Vec<Ofpbuf> = if state.stats_requests.is_empty() {
+                    state.idle.entry_ids()
+                        .map(|entry_id| FlowStats::encode_cookie_dump_request(
+                            OFP_PROTOCOL, (entry_id as u64) << 32, 0xffff_ffff_0000_0000))
+                        .collect()
+                } else {
+                    vec![FlowStats::encode_dump_request(OFP_PROTOCOL)]
+                };
+                let xids = requests.iter().map(|request| ofp_msgs::xid(request.as_slice())).collect();
+                for request in requests {
+                    rconn.send(request).unwrap();
+                }
+                *stats_dump = Some(StatsDump { xids, stats: HashMap::new(), replies: std::mem::take(&mut state.stats_requests) });
+            }
+        } else {
+            // We're disconnected.  We can't send pending flow mods.  When we reconnect, we'll send
+            // everything, so keep only the writes waiting for them.  Packets from the controller
+            // are lost.
+            let mut state = state.lock().unwrap();
+            for bundle in &mut state.pending_bundles {
+                bundle.flow_mods.clear();
+            }
+            state.pending_packet_outs.clear();
+
+            // Reads that need statistics fail.
+            state.stats_requests.clear();
+            *stats_dump = None;
+
+            // Once the switch has been gone for a while, writes waiting for it fail, and so do
+            // new writes until it comes back.  Their changes stay in DDlog, so the switch gets
+            // them when it reconnects.
+            let since = *disconnected_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= DISCONNECT_TIMEOUT {
+                let error: Result<()> = Err(Error(RpcStatusCode::UNAVAILABLE)).context("switch is disconnected");
+                let replies = state.pending_bundles.drain(..).flat_map(|b| b.replies)
+                    .chain(sent_bundles.drain(..).flat_map(|b| b.replies))
+                    .chain(resync.take().into_iter().flat_map(|r| r.replies));
+                for reply in replies {
+                    let _ = reply.send(copy_result(&error));
+                }
+            }
+        }
+    }
+
+    /// Arranges for `ovs::poll_loop::block()` to wake up when `run()` has work to do.
+    fn wait(&mut self) {
+        let state = self.state.lock().unwrap();
+        state.latch.wait();
+        if let Some(wakeup) = state.digests.next_wakeup() {
+            let delay = wakeup.saturating_duration_since(Instant::now());
+            ovs::poll_loop::timer_wait(((delay.as_micros() + 999) / 1000) as i64);
+        }
+        if let Some(next_poll) = state.idle.next_poll().filter(|_| self.rconn.connected()) {
+            let delay = next_poll.saturating_duration_since(Instant::now());
+            ovs::poll_loop::timer_wait(((delay.as_micros() + 999) / 1000) as i64);
+        }
+        if let Some(since) = self.disconnected_since {
+            // After the timeout, the latch wakes us up for new writes.
+            if since.elapsed() <= DISCONNECT_TIMEOUT {
+                let delay = (since + DISCONNECT_TIMEOUT).saturating_duration_since(Instant::now());
+                ovs::poll_loop::timer_wait(((delay.as_micros() + 999) / 1000) as i64);
+            }
+        }
+        self.rconn.run_wait();
+        self.rconn.recv_wait();
+    }
+}
+
+/// A device to serve, as listed in the file passed to `--devices`.
+#[derive(Deserialize)]
+struct DeviceConfig {
+    device_id: u64,
+    ovs_remote: String,
+
+    // The bridge's name in the Open vSwitch database.  By default, for an `ovs_remote` like
+    // `unix:/path/to/br0.mgmt`, it is `br0`.
+    #[serde(default)]
+    bridge: Option<String>,
+}
+
+impl DeviceConfig {
+    fn bridge_name(&self) -> Option<String> {
+        self.bridge.clone().or_else(|| {
+            let path = std::path::Path::new(self.ovs_remote.strip_prefix("unix:")?);
+            if path.extension()? != "mgmt" {
+                return None;
+            }
+            Some(path.file_stem()?.to_str()?.into())
+        })
+    }
+}
+
+/// Parses `arg` as a P4Runtime device ID and returns the device's state.
+fn unixctl_device(devices: &HashMap<u64, Arc<Mutex<State>>>, arg: &str) -> std::result::Result<Arc<Mutex<State>>, String> {
+    let device_id = arg.parse::<u64>().map_err(|_| format!("{}: invalid device ID", arg))?;
+    devices.get(&device_id).cloned().ok_or_else(|| format!("unknown device {}", device_id))
+}
+
+/// Registers the `ovs-appctl` commands for finding the P4Runtime entity that a flow came from and
+/// the flows that came from an entity.
+fn register_unixctl_commands(devices: &HashMap<u64, Arc<Mutex<State>>>) {
+    let d = devices.clone();
+    ovs::unixctl::register_command("ofp4/cookie", "DEVICE COOKIE", 2, 2, move |args| {
+        let state = unixctl_device(&d, args[0])?;
+        let cookie = match args[1].strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => args[1].parse::<u64>(),
+        }.map_err(|_| format!("{}: invalid cookie", args[1]))?;
+        let state = state.lock().unwrap();
+        state.describe_cookie(cookie)
+    });
+
+    let d = devices.clone();
+    ovs::unixctl::register_command("ofp4/entry-flows", "DEVICE TABLE_ENTRY", 2, 2, move |args| {
+        let state = unixctl_device(&d, args[0])?;
+        let te: proto::p4runtime::TableEntry = textproto::parse_text(args[1]).map_err(|e| format!("{:#}", e))?;
+        let te: TableEntry = (&te).try_into().map_err(|e| format!("{:#}", e))?;
+        let state = state.lock().unwrap();
+        let entry_id = *state.entry_ids.get(&te.key).ok_or("no such table entry")?;
+        Ok(state.describe_flows(FlowSource::TableEntry(entry_id)))
+    });
+
+    let d = devices.clone();
+    ovs::unixctl::register_command("ofp4/group-flows", "DEVICE GROUP_ID", 2, 2, move |args| {
+        let state = unixctl_device(&d, args[0])?;
+        let id = args[1].parse::<MulticastGroupId>().map_err(|_| format!("{}: invalid group ID", args[1]))?;
+        let state = state.lock().unwrap();
+        if !state.entities.multicast_groups.contains_key(&id) {
+            return Err(format!("no multicast group {}", id));
+        }
+        Ok(state.describe_flows(FlowSource::MulticastGroup(id)))
+    });
+}
+
+/// Runs ofp4 without Open vSwitch or a P4Runtime server, to test a translation.  If `p4info` is
+/// given, configures the pipeline with it and `bmv2_json` and then applies the P4Runtime
+/// `WriteRequest`s in `files`, each of which is a JSON array of requests if its name ends in
+/// `.json` and otherwise a single request in the protobuf text format.  Otherwise, replays the
+/// DDlog commands in `files`, such as a `replay.txt` that ofp4 recorded.  Either way, prints the
+/// resulting OpenFlow meters, groups, and flows.  Fails if any update failed.
+fn run_offline(files: &[&str], p4info: Option<&str>, bmv2_json: Option<&str>) -> Result<()> {
+    let translation = Box::new(ofp4_program::Program);
+    let hddlog = translation.run(1).ddlog_map_error()?;
+    let mut state = State::new(0, translation, hddlog);
+
+    let mut n_errors = 0;
+    match p4info {
+        Some(p4info) => {
+            let mut config = ForwardingPipelineConfig::new();
+            config.set_p4info(p4ext::load_p4info(p4info)?);
+            if let Some(bmv2_json) = bmv2_json {
+                config.set_p4_device_config(std::fs::read(bmv2_json).with_context(|| format!("{}: read failed", bmv2_json))?);
+            }
+            let mut request = SetForwardingPipelineConfigRequest::new();
+            request.set_action(SetForwardingPipelineConfigRequest_Action::VERIFY_AND_COMMIT);
+            request.set_config(config);
+            state.set_pipeline_config(&request).with_context(|| format!("{}: invalid pipeline", p4info))?;
+
+            for &file in files {
+                let input = std::fs::read_to_string(file).with_context(|| format!("{}: read failed", file))?;
+                let requests: Vec<WriteRequest> = if file.ends_with(".json") {
+                    textproto::parse_json_list(&input)
+                } else {
+                    textproto::parse_text(&input).map(|request| vec![request])
+                }.with_context(|| format!("{}: parse failed", file))?;
+                for (i, request) in requests.into_iter().enumerate() {
+                    let updates: Vec<_> = request.updates.into_iter()
+                        .map(|update| (update.field_type, update.entity.into_option()))
+                        .collect();
+                    let (results, _) = state.write(request.atomicity, &updates);
+                    for (j, result) in results.iter().enumerate() {
+                        if let Err(e) = result {
+                            eprintln!("{}: request {}, update {}: {:#}", file, i, j, e);
+                            n_errors += 1;
+                        }
+                    }
+                }
+            }
+        },
+        None => for &file in files {
+            let input = std::fs::read(file).with_context(|| format!("{}: read failed", file))?;
+            state.replay(&input).with_context(|| format!("{}: replay failed", file))?;
+        },
+    }
+
+    for line in state.flow_table() {
+        println!("{}", line);
+    }
+    if n_errors > 0 {
+        return Err(anyhow!("{} updates failed", n_errors));
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    const OVS_REMOTE: &str = "ovs-remote";
+    const DEVICE_ID: &str = "device-id";
+    const DEVICES: &str = "devices";
+    const BRIDGE: &str = "bridge";
+    const OVSDB_REMOTE: &str = "ovsdb-remote";
     const P4_PORT: &str = "p4-port";
     const P4_ADDR: &str = "p4-addr";
+    const OFFLINE: &str = "offline";
+    const P4INFO: &str = "p4info";
+    const BMV2_JSON: &str = "bmv2-json";
+    const UNIXCTL: &str = "unixctl";
 
     let matches = App::new("ofp4")
         .version(env!("CARGO_PKG_VERSION"))
         .arg(Arg::with_name(OVS_REMOTE)
              .help("OVS remote to connect, e.g. \"unix:/path/to/ovs/tutorial/sandbox/br0.mgmt\"")
-             .required(true)
+             .required_unless_one(&[DEVICES, OFFLINE])
+             .conflicts_with_all(&[DEVICES, OFFLINE])
              .index(1))
+        .arg(Arg::with_name(DEVICE_ID)
+             .long(DEVICE_ID)
+             .help("P4Runtime device ID for the OVS remote")
+             .takes_value(true)
+             .default_value("0"))
+        .arg(Arg::with_name(BRIDGE)
+             .long(BRIDGE)
+             .help("Name of the OVS bridge in the Open vSwitch database (default: from the OVS remote)")
+             .takes_value(true)
+             .conflicts_with(DEVICES))
+        .arg(Arg::with_name(OVSDB_REMOTE)
+             .long(OVSDB_REMOTE)
+             .help("Open vSwitch database to discover ports from, e.g. \"unix:/path/to/ovs/tutorial/sandbox/db.sock\"")
+             .takes_value(true))
+        .arg(Arg::with_name(DEVICES)
+             .long(DEVICES)
+             .help("JSON file listing the devices to serve, e.g. [{\"device_id\": 1, \"ovs_remote\": \"unix:br0.mgmt\"}]")
+             .takes_value(true))
         .arg(Arg::with_name(P4_PORT)
              .long(P4_PORT)
              .help("P4Runtime connection listening port")
@@ -542,18 +2597,82 @@ fn main() -> Result<()> {
              .help("P4Runtime connection bind address")
              .takes_value(true)
              .default_value("127.0.0.1"))
+        .arg(Arg::with_name(OFFLINE)
+             .long(OFFLINE)
+             .help("Instead of connecting to OVS, apply P4Runtime writes (with --p4info) or DDlog commands from the given files and print the resulting flows")
+             .takes_value(true)
+             .multiple(true)
+             .conflicts_with(DEVICES))
+        .arg(Arg::with_name(P4INFO)
+             .long(P4INFO)
+             .help("Binary P4Info file for the pipeline that --offline writes use")
+             .takes_value(true)
+             .requires(OFFLINE))
+        .arg(Arg::with_name(BMV2_JSON)
+             .long(BMV2_JSON)
+             .help("bmv2 JSON file for the pipeline that --offline writes use, for its default actions")
+             .takes_value(true)
+             .requires(P4INFO))
+        .arg(Arg::with_name(UNIXCTL)
+             .long(UNIXCTL)
+             .help("Unix domain socket to listen on for ovs-appctl commands that map between flows and P4Runtime entities")
+             .takes_value(true)
+             .conflicts_with(OFFLINE))
         .get_matches();
 
-    let ovs_remote = matches.value_of(OVS_REMOTE).unwrap();
+    if let Some(files) = matches.values_of(OFFLINE) {
+        let files: Vec<&str> = files.collect();
+        return run_offline(&files, matches.value_of(P4INFO), matches.value_of(BMV2_JSON));
+    }
+
+    let device_configs: Vec<DeviceConfig> = match matches.value_of(DEVICES) {
+        Some(file_name) => {
+            let file = std::fs::File::open(file_name).with_context(|| format!("{}: open failed", file_name))?;
+            serde_json::from_reader(file).with_context(|| format!("{}: parse failed", file_name))?
+        },
+        None => vec![DeviceConfig {
+            device_id: matches.value_of(DEVICE_ID).unwrap().parse::<u64>()?,
+            ovs_remote: matches.value_of(OVS_REMOTE).unwrap().into(),
+            bridge: matches.value_of(BRIDGE).map(String::from),
+        }],
+    };
+    if device_configs.is_empty() {
+        return Err(anyhow!("no devices to serve"));
+    }
     let p4_port = matches.value_of(P4_PORT).unwrap().parse::<u16>().unwrap();
     let p4_addr = matches.value_of(P4_ADDR).unwrap();
 
+    // Each device has its own translation instance and bridge.
+    let mut devices = HashMap::new();
+    let mut bridges = Vec::new();
+    for config in &device_configs {
+        let translation = Box::new(ofp4_program::Program);
+        let mut hddlog = translation.run(1).ddlog_map_error()?;
+        let replay = if device_configs.len() == 1 {
+            String::from("replay.txt")
+        } else {
+            format!("replay-{}.txt", config.device_id)
+        };
+        let mut record = Some(std::fs::File::create(replay)?);
+        hddlog.record_commands(&mut record);
+
+        let state = Arc::new(Mutex::new(State::new(config.device_id, translation, hddlog)));
+        if devices.insert(config.device_id, state.clone()).is_some() {
+            return Err(anyhow!("device {} is listed more than once", config.device_id));
+        }
+        bridges.push(Bridge::new(state, &config.ovs_remote, config.bridge_name()));
+    }
+    let mut port_monitor = matches.value_of(OVSDB_REMOTE).map(PortMonitor::new);
+    let mut unixctl = match matches.value_of(UNIXCTL) {
+        Some(path) => {
+            register_unixctl_commands(&devices);
+            Some(ovs::unixctl::UnixctlServer::new(path).with_context(|| format!("{}: listen failed", path))?)
+        },
+        None => None,
+    };
+
     let env = Arc::new(Environment::new(1));
-    let (mut hddlog, _init_state) = snvs_ddlog::run(1, false).ddlog_map_error()?;
-    let mut record = Some(std::fs::File::create("replay.txt")?);
-    hddlog.record_commands(&mut record);
-    let state = Arc::new(Mutex::new(State::new(hddlog)));
-    let service = create_p4_runtime(P4RuntimeService::new(state.clone()));
+    let service = create_p4_runtime(P4RuntimeService::new(devices));
     let ch_builder = ChannelBuilder::new(env.clone());
     let mut server = ServerBuilder::new(env)
         .register_service(service)
@@ -563,99 +2682,32 @@ fn main() -> Result<()> {
         .unwrap();
     server.start();
 
-    let mut rconn = ovs::rconn::Rconn::new(0, 0, ovs::rconn::DSCP_DEFAULT, OFP_VERSION.into());
-
-    rconn.connect(ovs_remote, None);
-    let mut last_seqno = 0;
-    let mut bundle_id = 0;
     loop {
-        rconn.run();
-        loop {
-            let p = rconn.recv();
-            match p {
-                None => break,
-                Some(message) => println!("received message {}", ovs::ofp_print::Printer(message.as_slice()))
-            }
-        }
-
-        state.lock().unwrap().latch.poll();
-        if rconn.connected() {
-            let mut state = state.lock().unwrap();
-
-            let flags = ovs::ofp_bundle::OFPBF_ATOMIC | ovs::ofp_bundle::OFPBF_ORDERED;
-            if rconn.connection_seqno() == last_seqno {
-                // Send pending flow mods, if any.
-                if !state.pending_flow_mods.is_empty() {
-                    bundle_id += 1;
-                    let bundle = ovs::ofp_bundle::BundleSequence::new(bundle_id, flags, OFP_VERSION,
-                                                                      state.pending_flow_mods.drain(..));
-                    for msg in bundle {
-                        rconn.send(msg).unwrap();
+        if let Some(port_monitor) = port_monitor.as_mut() {
+            if port_monitor.run() {
+                for bridge in &bridges {
+                    if let Some(name) = &bridge.name {
+                        bridge.state.lock().unwrap().set_ports(port_monitor.ports(name));
                     }
                 }
-            } else {
-                // We just reconnected.  Send all the flows.  Discard pending flow mods, if any,
-                // because the full collection of flows includes them.
-                state.pending_flow_mods.clear();
-
-                // Compose a sequence of flow_mods starting with one to delete all the existing
-                // flows, then add in all the flows we do want.  We're going to put all of these
-                // together into an atomic bundle, so we shouldn't change the treatment of all the
-                // packets in the middle.
-                let del_flows = FlowMod::parse("", Some(FlowModCommand::Delete { strict: false })).unwrap().0;
-                let add_flows = state.hddlog.dump_index(Indexes::Flow as IdxId).unwrap().into_iter()
-                    .filter_map(|record| {
-                        let flow: &Flow = Flow::from_ddvalue_ref(&record);
-                        match FlowMod::parse(&flow.s, Some(FlowModCommand::Add)) {
-                            Ok((flow, _)) => Some(flow),
-                            Err(s) => {
-                                eprintln!("{}: {}", flow.s, s);
-                                None
-                            }
-                        }
-                    });
-                let flow_mods = std::iter::once(del_flows).chain(add_flows).map(|fm| fm.encode(OFP_PROTOCOL));
-
-                bundle_id += 1;
-                let bundle = ovs::ofp_bundle::BundleSequence::new(bundle_id, flags, OFP_VERSION, flow_mods);
-                for msg in bundle {
-                    rconn.send(msg).unwrap();
-                }
-
-                last_seqno = rconn.connection_seqno();
             }
-        } else {
-            // We're disconnected.  We can't send pending flow mods.  When we reconnect, we'll send
-            // everything.
-            let mut state = state.lock().unwrap();
-            state.pending_flow_mods.clear();
+        }
+        for bridge in &mut bridges {
+            bridge.run();
+        }
+        if let Some(unixctl) = unixctl.as_mut() {
+            unixctl.run();
         }
 
-        state.lock().unwrap().latch.wait();
-        rconn.run_wait();
-        rconn.recv_wait();
-        ovs::poll_loop::block();
-    }
-}
-
-/// Converts the `delta` of changes to DDlog output relations (particularly `Flow`) into OpenFlow
-/// [`FlowMod`] messages and appends those messages to `flow_mods`.
-fn delta_to_flow_mods(delta: &DeltaMap<DDValue>, flow_mods: &mut Vec<Ofpbuf>) {
-    for (&rel, changes) in delta.iter() {
-        if rel == Relations::Flow as RelId {
-            for (val, &weight) in changes.iter() {
-                let command = match weight {
-                    1 => FlowModCommand::Add,
-                    -1 => FlowModCommand::Delete { strict: true },
-                    _ => unreachable!()
-                };
-
-                let flow0: &Flow = Flow::from_ddvalue_ref(val);
-                match FlowMod::parse(&flow0.s, Some(command)) {
-                    Ok((flow_mod, _)) => flow_mods.push(flow_mod.encode(OFP_PROTOCOL)),
-                    Err(s) => eprintln!("{}: {}", flow0.s, s)
-                };
-            }
+        if let Some(port_monitor) = port_monitor.as_mut() {
+            port_monitor.wait();
+        }
+        if let Some(unixctl) = unixctl.as_mut() {
+            unixctl.wait();
         }
+        for bridge in &mut bridges {
+            bridge.wait();
+        }
+        ovs::poll_loop::block();
     }
 }
@@ -0,0 +1,253 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+
+//! P4Runtime meters.
+//!
+//! ofp4 keeps the configuration of each configured cell of an indirect meter and of each table
+//! entry's direct meter, and passes them to the translation in its `MeterConfig` and
+//! `DirectMeterConfig` input relations.  The translation turns them into OpenFlow meters in its
+//! `Meter` output relation.  A P4 meter colors packets and leaves it to the program to act on the
+//! color, but an OpenFlow meter can only drop packets, so a translation typically drops packets
+//! that exceed the peak rate, which P4 would color red, and passes the rest.  A meter cell or
+//! direct meter that isn't configured passes every packet.
+
+use anyhow::{Context, Result};
+
+use grpcio::RpcStatusCode;
+
+use p4ext::{Error, TableKey};
+
+use proto::p4info::P4Info;
+use proto::p4runtime::{MeterConfig, MeterEntry, Update_Type};
+
+use protobuf::SingularPtrField;
+
+use std::collections::HashMap;
+
+/// Meter configuration.
+#[derive(Default)]
+pub struct Meters {
+    // Configured cells of indirect meters, indexed by meter ID and index.
+    cells: HashMap<(u32, i64), MeterConfig>,
+
+    // Configured direct meters, indexed by table entry.
+    direct: HashMap<TableKey, MeterConfig>,
+}
+
+/// Returns the ID of the direct meter for table `table_id`, if it has one.
+pub fn direct_meter_id(p4info: &P4Info, table_id: u32) -> Option<u32> {
+    p4info.get_direct_meters().iter()
+        .find(|dm| dm.direct_table_id == table_id)
+        .map(|dm| dm.get_preamble().id)
+}
+
+/// Checks that `config` makes sense as a meter configuration.
+pub fn validate_config(config: &MeterConfig) -> Result<()> {
+    if config.cir < 0 || config.cburst < 0 || config.pir < 0 || config.pburst < 0 {
+        Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context("meter configuration must not be negative")?;
+    }
+    if config.cir > config.pir {
+        Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+            .context(format!("meter committed rate {} exceeds peak rate {}", config.cir, config.pir))?;
+    }
+    Ok(())
+}
+
+impl Meters {
+    /// Checks whether `op` may be applied to the meter cell in `entry`.  Returns the cell's new
+    /// configuration, or `None` if `op` resets it to the default.
+    pub fn validate(&self, op: Update_Type, entry: &MeterEntry, p4info: &P4Info) -> Result<Option<MeterConfig>> {
+        let meter = match p4info.get_meters().iter().find(|m| m.get_preamble().id == entry.meter_id) {
+            Some(meter) => meter,
+            None => Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("unknown meter {}", entry.meter_id))?,
+        };
+        if op != Update_Type::MODIFY {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context("meter entries may only be modified")?;
+        }
+        if !entry.has_index() {
+            Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context("meter entry lacks index")?;
+        }
+        let index = entry.get_index().index;
+        if index < 0 || index >= meter.size {
+            Err(Error(RpcStatusCode::OUT_OF_RANGE))
+                .context(format!("index {} is out of range for meter {} of size {}",
+                                 index, meter.get_preamble().name, meter.size))?;
+        }
+
+        if !entry.has_config() {
+            return Ok(None);
+        }
+        validate_config(entry.get_config())?;
+        Ok(Some(entry.get_config().clone()))
+    }
+
+    /// Returns the configuration of cell `index` in meter `meter_id`, if it has been configured.
+    pub fn get(&self, meter_id: u32, index: i64) -> Option<&MeterConfig> {
+        self.cells.get(&(meter_id, index))
+    }
+
+    /// Returns the meter ID, index, and configuration of each configured cell of an indirect
+    /// meter.
+    pub fn cells(&self) -> impl Iterator<Item = (u32, i64, &MeterConfig)> {
+        self.cells.iter().map(|(&(meter_id, index), config)| (meter_id, index, config))
+    }
+
+    /// Returns the configuration of the direct meter for the table entry with `key`, if it has
+    /// been configured.
+    pub fn get_direct(&self, key: &TableKey) -> Option<&MeterConfig> {
+        self.direct.get(key)
+    }
+
+    /// Sets the configuration for cell `index` in meter `meter_id` to `config`, or resets it to
+    /// the default if `config` is `None`.  Returns the old configuration.
+    pub fn configure(&mut self, meter_id: u32, index: i64, config: Option<MeterConfig>) -> Option<MeterConfig> {
+        match config {
+            Some(config) => self.cells.insert((meter_id, index), config),
+            None => self.cells.remove(&(meter_id, index)),
+        }
+    }
+
+    /// Sets the configuration for the direct meter for the table entry with `key` to `config`, or
+    /// resets it to the default if `config` is `None`.  Returns the old configuration.
+    pub fn configure_direct(&mut self, key: TableKey, config: Option<MeterConfig>) -> Option<MeterConfig> {
+        match config {
+            Some(config) => self.direct.insert(key, config),
+            None => self.direct.remove(&key),
+        }
+    }
+
+    /// Implements the P4Runtime `Read` operation for meter entries.  A `meter_id` of 0 reads
+    /// every meter, and a missing index reads every cell.  Cells that haven't been configured
+    /// are included, without a configuration.
+    pub fn read(&self, entry: &MeterEntry, p4info: &P4Info) -> Result<Vec<MeterEntry>> {
+        if entry.meter_id != 0 && !p4info.get_meters().iter().any(|m| m.get_preamble().id == entry.meter_id) {
+            Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("unknown meter {}", entry.meter_id))?;
+        }
+
+        let mut entries = Vec::new();
+        for meter in p4info.get_meters().iter().filter(|m| entry.meter_id == 0 || entry.meter_id == m.get_preamble().id) {
+            let meter_id = meter.get_preamble().id;
+            let indexes = if entry.has_index() {
+                let index = entry.get_index().index;
+                index.max(0)..(index + 1).min(meter.size)
+            } else {
+                0..meter.size
+            };
+            for index in indexes {
+                let mut me = MeterEntry { meter_id, ..Default::default() };
+                me.mut_index().index = index;
+                me.config = SingularPtrField::from_option(self.get(meter_id, index).cloned());
+                entries.push(me);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use p4ext::server::error_code;
+
+    use proto::p4info::Meter;
+
+    const METER_ID: u32 = 1;
+    const METER_SIZE: i64 = 4;
+
+    /// Returns a P4Info with meter `m` of `METER_SIZE` cells.
+    fn p4info() -> P4Info {
+        let mut meter = Meter::new();
+        meter.mut_preamble().set_id(METER_ID);
+        meter.mut_preamble().set_name("m".into());
+        meter.size = METER_SIZE;
+
+        let mut p4info = P4Info::new();
+        p4info.mut_meters().push(meter);
+        p4info
+    }
+
+    fn config(cir: i64, pir: i64) -> MeterConfig {
+        MeterConfig { cir, cburst: 10, pir, pburst: 10, ..Default::default() }
+    }
+
+    /// Returns a meter entry for cell `index` of meter `meter_id`, or for every cell if `index` is
+    /// `None`.
+    fn entry(meter_id: u32, index: Option<i64>, config: Option<MeterConfig>) -> MeterEntry {
+        let mut entry = MeterEntry { meter_id, ..Default::default() };
+        if let Some(index) = index {
+            entry.mut_index().index = index;
+        }
+        entry.config = SingularPtrField::from_option(config);
+        entry
+    }
+
+    #[test]
+    fn validate() {
+        let p4info = p4info();
+        let meters = Meters::default();
+
+        let result = meters.validate(Update_Type::MODIFY, &entry(METER_ID, Some(0), Some(config(1, 2))), &p4info);
+        assert_eq!(result.unwrap(), Some(config(1, 2)));
+        let result = meters.validate(Update_Type::MODIFY, &entry(METER_ID, Some(METER_SIZE - 1), None), &p4info);
+        assert_eq!(result.unwrap(), None);
+
+        for &(op, meter_id, index, ref config, code) in &[
+            (Update_Type::INSERT, METER_ID, Some(0), Some(config(1, 2)), RpcStatusCode::INVALID_ARGUMENT),
+            (Update_Type::MODIFY, METER_ID + 1, Some(0), None, RpcStatusCode::NOT_FOUND),
+            (Update_Type::MODIFY, METER_ID, None, None, RpcStatusCode::INVALID_ARGUMENT),
+            (Update_Type::MODIFY, METER_ID, Some(-1), None, RpcStatusCode::OUT_OF_RANGE),
+            (Update_Type::MODIFY, METER_ID, Some(METER_SIZE), None, RpcStatusCode::OUT_OF_RANGE),
+            (Update_Type::MODIFY, METER_ID, Some(0), Some(config(2, 1)), RpcStatusCode::INVALID_ARGUMENT),
+            (Update_Type::MODIFY, METER_ID, Some(0), Some(config(-1, 1)), RpcStatusCode::INVALID_ARGUMENT),
+        ] {
+            let error = meters.validate(op, &entry(meter_id, index, config.clone()), &p4info).unwrap_err();
+            assert_eq!(error_code(&error), code, "{:?} {:?} {:?}", op, index, config);
+        }
+    }
+
+    #[test]
+    fn read() {
+        let p4info = p4info();
+        let mut meters = Meters::default();
+        meters.configure(METER_ID, 2, Some(config(1, 2)));
+
+        let indexes = |entries: Vec<MeterEntry>| -> Vec<i64> {
+            entries.iter().map(|me| me.get_index().index).collect()
+        };
+        let all = meters.read(&entry(METER_ID, None, None), &p4info).unwrap();
+        assert_eq!(indexes(all.clone()), vec![0, 1, 2, 3]);
+        assert_eq!(all.iter().filter(|me| me.has_config()).count(), 1);
+        assert_eq!(all[2].get_config(), &config(1, 2));
+
+        let wildcard = meters.read(&entry(0, None, None), &p4info).unwrap();
+        assert_eq!(wildcard, all);
+
+        // Reading a single cell clamps to the meter's size, so an out-of-range index reads
+        // nothing.
+        assert_eq!(indexes(meters.read(&entry(METER_ID, Some(1), None), &p4info).unwrap()), vec![1]);
+        assert_eq!(indexes(meters.read(&entry(METER_ID, Some(-1), None), &p4info).unwrap()), Vec::<i64>::new());
+        assert_eq!(indexes(meters.read(&entry(METER_ID, Some(METER_SIZE), None), &p4info).unwrap()), Vec::<i64>::new());
+
+        let error = meters.read(&entry(METER_ID + 1, None, None), &p4info).unwrap_err();
+        assert_eq!(error_code(&error), RpcStatusCode::NOT_FOUND);
+    }
+}
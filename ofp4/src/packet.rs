@@ -0,0 +1,168 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! P4Runtime packet I/O.
+//!
+//! A P4Runtime `PacketOut` becomes an OpenFlow packet-out that enters OpenFlow table 0 with
+//! `in_port=CONTROLLER` and the members of the `packet_out` controller header, in order, in
+//! 64-bit registers `xreg4`, `xreg5`, and so on.  Conversely, the OpenFlow translation of sending
+//! a packet to the CPU port loads the members of the `packet_in` controller header into the same
+//! registers and then executes `controller` without userdata.  ofp4 turns the resulting
+//! packet-in into a P4Runtime `PacketIn`.
+
+use anyhow::{Context, Result};
+
+use grpcio::RpcStatusCode;
+
+use ovs::ofp_packet::{self, N_XREGS};
+
+use p4ext::Error;
+
+use proto::p4info::{ControllerPacketMetadata_Metadata, P4Info};
+use proto::p4runtime::{PacketIn, PacketMetadata, PacketOut};
+
+use protobuf::RepeatedField;
+
+/// The 64-bit register that holds the first member of a controller header.
+pub const PACKET_XREG_BASE: usize = 4;
+
+/// Returns the members of the controller header named `name` in `p4info`, or an empty slice if
+/// the P4 program doesn't have one.
+fn controller_header<'a>(p4info: &'a P4Info, name: &str) -> Result<&'a [ControllerPacketMetadata_Metadata]> {
+    let members = match p4info.get_controller_packet_metadata().iter().find(|cpm| cpm.get_preamble().get_name() == name) {
+        Some(cpm) => cpm.get_metadata(),
+        None => return Ok(&[]),
+    };
+    if members.len() > N_XREGS - PACKET_XREG_BASE {
+        Err(Error(RpcStatusCode::UNIMPLEMENTED)).context(format!("{} header has too many members", name))?;
+    }
+    if let Some(m) = members.iter().find(|m| m.bitwidth <= 0 || m.bitwidth > 64) {
+        Err(Error(RpcStatusCode::UNIMPLEMENTED))
+            .context(format!("{} header member {} is not bit<1> through bit<64>", name, m.get_name()))?;
+    }
+    Ok(members)
+}
+
+/// Encodes the low `bitwidth` bits of `value` in big-endian order in as many bytes as `bitwidth`
+/// requires.
+pub fn encode_bitstring(value: u64, bitwidth: i32) -> Vec<u8> {
+    let value = value & (u64::MAX >> (64 - bitwidth));
+    let n_bytes = (bitwidth as usize + 7) / 8;
+    value.to_be_bytes()[8 - n_bytes..].to_vec()
+}
+
+/// Decodes `bytes` as a big-endian value that must fit in `bitwidth` bits.
+fn decode_bitstring(bytes: &[u8], bitwidth: i32) -> Option<u64> {
+    let mut value: u64 = 0;
+    for &b in bytes {
+        if value >> 56 != 0 {
+            return None;
+        }
+        value = (value << 8) | b as u64;
+    }
+    if bitwidth < 64 && value >> bitwidth != 0 {
+        return None;
+    }
+    Some(value)
+}
+
+/// Translates `packet` into an OpenFlow packet-out.
+pub fn packet_out_to_openflow(p4info: &P4Info, packet: &PacketOut) -> Result<ofp_packet::PacketOut> {
+    let members = controller_header(p4info, "packet_out")?;
+
+    let mut actions = Vec::with_capacity(packet.get_metadata().len() + 1);
+    for metadata in packet.get_metadata() {
+        let i = match members.iter().position(|m| m.id == metadata.metadata_id) {
+            Some(i) => i,
+            None => Err(Error(RpcStatusCode::NOT_FOUND))
+                .context(format!("unknown packet_out metadata {}", metadata.metadata_id))?,
+        };
+        let value = match decode_bitstring(metadata.get_value(), members[i].bitwidth) {
+            Some(value) => value,
+            None => Err(Error(RpcStatusCode::OUT_OF_RANGE))
+                .context(format!("value for packet_out metadata {} is too wide", members[i].get_name()))?,
+        };
+        actions.push(format!("load({:#x}->xreg{})", value, PACKET_XREG_BASE + i));
+    }
+    actions.push("resubmit(,0)".into());
+
+    let payload: String = packet.get_payload().iter().map(|b| format!("{:02x}", b)).collect();
+    let s = format!("in_port=CONTROLLER packet={} actions={}", payload, actions.join(", "));
+    match ofp_packet::PacketOut::parse(&s) {
+        Ok((packet_out, _)) => Ok(packet_out),
+        Err(e) => Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context(format!("{}: {}", s, e)),
+    }
+}
+
+/// Translates OpenFlow packet-in `pin` into a P4Runtime `PacketIn`.
+pub fn packet_in_to_p4runtime(p4info: &P4Info, pin: &ofp_packet::PacketIn) -> Result<PacketIn> {
+    let members = controller_header(p4info, "packet_in")?;
+
+    let metadata = members.iter().enumerate()
+        .map(|(i, m)| PacketMetadata {
+            metadata_id: m.id,
+            value: encode_bitstring(pin.xreg(PACKET_XREG_BASE + i), m.bitwidth),
+            ..Default::default()
+        })
+        .collect();
+
+    Ok(PacketIn {
+        payload: pin.packet.clone(),
+        metadata: RepeatedField::from_vec(metadata),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        assert_eq!(encode_bitstring(0x1ff, 9), vec![0x01, 0xff]);
+        assert_eq!(encode_bitstring(0xfff, 9), vec![0x01, 0xff]);
+        assert_eq!(encode_bitstring(1, 1), vec![1]);
+        assert_eq!(encode_bitstring(0, 48), vec![0; 6]);
+        assert_eq!(encode_bitstring(0x1234, 16), vec![0x12, 0x34]);
+        assert_eq!(encode_bitstring(u64::MAX, 64), vec![0xff; 8]);
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(decode_bitstring(&[], 9), Some(0));
+        assert_eq!(decode_bitstring(&[0x01, 0xff], 9), Some(0x1ff));
+        assert_eq!(decode_bitstring(&[0x02, 0x00], 9), None);
+        assert_eq!(decode_bitstring(&[0xff; 8], 64), Some(u64::MAX));
+        assert_eq!(decode_bitstring(&[0x01, 0, 0, 0, 0, 0, 0, 0, 0], 64), None);
+
+        // Leading zero bytes don't count against the width, however many there are.
+        assert_eq!(decode_bitstring(&[0, 0, 0x01], 9), Some(1));
+        let mut bytes = vec![0; 12];
+        bytes.extend_from_slice(&[0xff; 8]);
+        assert_eq!(decode_bitstring(&bytes, 64), Some(u64::MAX));
+    }
+
+    #[test]
+    fn round_trip() {
+        for &(value, bitwidth) in &[(0, 1), (1, 1), (0x155, 9), (0x0123_4567_89ab, 48), (u64::MAX, 64)] {
+            assert_eq!(decode_bitstring(&encode_bitstring(value, bitwidth), bitwidth), Some(value));
+        }
+    }
+}
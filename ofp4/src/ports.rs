@@ -0,0 +1,280 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! Port discovery through the Open vSwitch database.
+//!
+//! ofp4 monitors the `Bridge`, `Port`, and `Interface` tables in the `Open_vSwitch` database to
+//! learn the ports of the bridge behind each device.  An interface on the bridge is a P4 port if
+//! its `external_ids` has a `p4-port` key, whose value is its P4 port number, or otherwise if it
+//! has an `ofport_request`, which then serves as its P4 port number as well.  The interface's
+//! OpenFlow port number is whatever Open vSwitch assigned in its `ofport` column, which need not
+//! be the P4 port number, and its `link_state` says whether it is up.
+
+use ovs::ovsdb_cs::{Event, OvsdbCs};
+
+use serde_json::{Map, Value};
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+
+/// The columns that ofp4 monitors, as an OVSDB `<monitor-requests>` object.
+const MONITOR_REQUESTS: &str = r#"{
+    "Bridge": [{"columns": ["name", "ports"]}],
+    "Port": [{"columns": ["interfaces"]}],
+    "Interface": [{"columns": ["name", "ofport", "ofport_request", "external_ids", "link_state"]}]
+}"#;
+
+/// An Open vSwitch interface that is a P4 port.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub ofport: u16,
+    pub up: bool,
+}
+
+/// A monitor for the parts of the Open vSwitch database that ofp4 needs.
+pub struct PortMonitor {
+    cs: OvsdbCs,
+    db: Database,
+}
+
+/// A copy of the parts of the Open vSwitch database that ofp4 monitors.
+#[derive(Default)]
+struct Database {
+    // Rows in each table, indexed by table name and then by UUID.
+    tables: HashMap<String, HashMap<String, Map<String, Value>>>,
+}
+
+impl PortMonitor {
+    /// Creates a monitor for the `Open_vSwitch` database at `remote`, e.g.
+    /// `unix:/usr/local/var/run/openvswitch/db.sock`.
+    pub fn new(remote: &str) -> PortMonitor {
+        PortMonitor { cs: OvsdbCs::new("Open_vSwitch", remote, MONITOR_REQUESTS), db: Database::default() }
+    }
+
+    /// Processes updates from the database.  Returns true if anything changed.
+    pub fn run(&mut self) -> bool {
+        let mut changed = false;
+        for event in self.cs.run() {
+            match event {
+                Event::Reconnect => (),
+                Event::Update { clear, table_updates } => {
+                    if clear {
+                        self.db.tables.clear();
+                    }
+                    match serde_json::from_str(&table_updates) {
+                        Ok(table_updates) => self.db.apply(&table_updates),
+                        Err(e) => eprintln!("ovsdb update: {:#}", e),
+                    }
+                    changed = true;
+                },
+            }
+        }
+        changed
+    }
+
+    /// Arranges for `ovs::poll_loop::block()` to wake up when `run()` has work to do.
+    pub fn wait(&mut self) {
+        self.cs.wait();
+    }
+
+    /// Returns the P4 ports on the bridge named `bridge`, indexed by P4 port number.
+    pub fn ports(&self, bridge: &str) -> BTreeMap<u16, Interface> {
+        self.db.ports(bridge)
+    }
+}
+
+impl Database {
+    /// Applies `table_updates`, an OVSDB `<table-updates>` object, to our copy of the tables.
+    fn apply(&mut self, table_updates: &Map<String, Value>) {
+        for (table_name, table_update) in table_updates {
+            let table = self.tables.entry(table_name.clone()).or_default();
+            for (uuid, row_update) in table_update.as_object().into_iter().flatten() {
+                match row_update.get("new").and_then(Value::as_object) {
+                    // "new" has the values of the row's monitored columns.
+                    Some(new) => table.entry(uuid.clone()).or_default().extend(new.clone()),
+                    None => {
+                        table.remove(uuid);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the row with `uuid` in `table`, if there is one.
+    fn row(&self, table: &str, uuid: &str) -> Option<&Map<String, Value>> {
+        self.tables.get(table)?.get(uuid)
+    }
+
+    /// Returns the P4 ports on the bridge named `bridge`, indexed by P4 port number.
+    fn ports(&self, bridge: &str) -> BTreeMap<u16, Interface> {
+        let mut ports = BTreeMap::new();
+        let bridge = match self.tables.get("Bridge").into_iter().flatten()
+            .find(|(_, row)| row.get("name").and_then(Value::as_str) == Some(bridge)) {
+                Some((_, row)) => row,
+                None => return ports,
+            };
+        let interfaces = set_elements(bridge.get("ports"))
+            .filter_map(|port| self.row("Port", uuid(port)?))
+            .flat_map(|port| set_elements(port.get("interfaces")))
+            .filter_map(|interface| self.row("Interface", uuid(interface)?));
+        for interface in interfaces {
+            let p4_port = map_get(interface.get("external_ids"), "p4-port")
+                .and_then(|value| value.as_str()?.parse::<u16>().ok())
+                .or_else(|| integer(interface.get("ofport_request")));
+            let ofport = integer(interface.get("ofport")).filter(|&ofport| ofport > 0 && ofport < 0xff00);
+            if let (Some(p4_port), Some(ofport)) = (p4_port, ofport) {
+                let name = interface.get("name").and_then(Value::as_str).unwrap_or_default().into();
+                let up = set_elements(interface.get("link_state")).any(|state| state.as_str() == Some("up"));
+                ports.insert(p4_port, Interface { name, ofport, up });
+            }
+        }
+        ports
+    }
+}
+
+/// Returns the elements of `value`, an OVSDB `<set>` or a single `<atom>`.
+fn set_elements(value: Option<&Value>) -> impl Iterator<Item = &Value> {
+    let elements: Vec<&Value> = match value {
+        Some(Value::Array(a)) if a.len() == 2 && a[0] == "set" => a[1].as_array().into_iter().flatten().collect(),
+        Some(value) => vec![value],
+        None => Vec::new(),
+    };
+    elements.into_iter()
+}
+
+/// Returns the UUID in `value`, an OVSDB `<uuid>`.
+fn uuid(value: &Value) -> Option<&str> {
+    match value.as_array()?.as_slice() {
+        [kind, uuid] if kind == "uuid" => uuid.as_str(),
+        _ => None,
+    }
+}
+
+/// Returns the value for `key` in `value`, an OVSDB `<map>`.
+fn map_get<'a>(value: Option<&'a Value>, key: &str) -> Option<&'a Value> {
+    match value?.as_array()?.as_slice() {
+        [kind, pairs] if kind == "map" => pairs.as_array()?.iter()
+            .filter_map(|pair| pair.as_array())
+            .find(|pair| pair.len() == 2 && pair[0] == key)
+            .map(|pair| &pair[1]),
+        _ => None,
+    }
+}
+
+/// Returns the integer in `value`, an OVSDB optional integer, if it is present and fits in 16
+/// bits.
+fn integer(value: Option<&Value>) -> Option<u16> {
+    set_elements(value).next()?.as_u64()?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn elements() {
+        let set = json!(["set", [1, 2]]);
+        assert_eq!(set_elements(Some(&set)).collect::<Vec<_>>(), vec![&json!(1), &json!(2)]);
+        let atom = json!(["uuid", "abc"]);
+        assert_eq!(set_elements(Some(&atom)).collect::<Vec<_>>(), vec![&atom]);
+        assert_eq!(set_elements(Some(&json!(["set", []]))).count(), 0);
+        assert_eq!(set_elements(None).count(), 0);
+
+        assert_eq!(uuid(&atom), Some("abc"));
+        assert_eq!(uuid(&json!(["named-uuid", "abc"])), None);
+        assert_eq!(uuid(&json!("abc")), None);
+
+        assert_eq!(integer(Some(&json!(5))), Some(5));
+        assert_eq!(integer(Some(&json!(["set", [5]]))), Some(5));
+        assert_eq!(integer(Some(&json!(["set", []]))), None);
+        assert_eq!(integer(Some(&json!(-1))), None);
+        assert_eq!(integer(Some(&json!(0x10000))), None);
+    }
+
+    #[test]
+    fn map() {
+        let map = json!(["map", [["p4-port", "1"], ["other", "x"]]]);
+        assert_eq!(map_get(Some(&map), "p4-port"), Some(&json!("1")));
+        assert_eq!(map_get(Some(&map), "other"), Some(&json!("x")));
+        assert_eq!(map_get(Some(&map), "missing"), None);
+        assert_eq!(map_get(Some(&json!(["map", []])), "p4-port"), None);
+        assert_eq!(map_get(Some(&json!(["set", [["p4-port", "1"]]])), "p4-port"), None);
+        assert_eq!(map_get(None, "p4-port"), None);
+    }
+
+    fn update(db: &mut Database, table_updates: Value) {
+        db.apply(table_updates.as_object().unwrap());
+    }
+
+    #[test]
+    fn ports() {
+        let mut db = Database::default();
+        update(&mut db, json!({
+            "Bridge": {
+                "b0": {"new": {"name": "br0", "ports": ["set", [["uuid", "p1"], ["uuid", "p2"], ["uuid", "p3"], ["uuid", "p4"]]]}},
+                "b1": {"new": {"name": "br1", "ports": ["uuid", "p5"]}}
+            },
+            "Port": {
+                "p1": {"new": {"interfaces": ["uuid", "i1"]}},
+                "p2": {"new": {"interfaces": ["uuid", "i2"]}},
+                "p3": {"new": {"interfaces": ["uuid", "i3"]}},
+                "p4": {"new": {"interfaces": ["uuid", "i4"]}},
+                "p5": {"new": {"interfaces": ["uuid", "i5"]}}
+            },
+            "Interface": {
+                // P4 port number from external_ids, which overrides ofport_request.
+                "i1": {"new": {"name": "eth1", "ofport": 3, "ofport_request": 9,
+                               "external_ids": ["map", [["p4-port", "1"]]], "link_state": "up"}},
+                // P4 port number from ofport_request.
+                "i2": {"new": {"name": "eth2", "ofport": 4, "ofport_request": 2,
+                               "external_ids": ["map", []], "link_state": "down"}},
+                // Open vSwitch couldn't add the interface.
+                "i3": {"new": {"name": "eth3", "ofport": -1, "ofport_request": 5,
+                               "external_ids": ["map", []], "link_state": ["set", []]}},
+                // Not a P4 port.
+                "i4": {"new": {"name": "eth4", "ofport": 6, "ofport_request": ["set", []],
+                               "external_ids": ["map", []], "link_state": "up"}},
+                "i5": {"new": {"name": "eth5", "ofport": 7, "ofport_request": 7,
+                               "external_ids": ["map", []], "link_state": "up"}}
+            }
+        }));
+
+        let eth1 = Interface { name: "eth1".into(), ofport: 3, up: true };
+        let eth2 = Interface { name: "eth2".into(), ofport: 4, up: false };
+        let eth5 = Interface { name: "eth5".into(), ofport: 7, up: true };
+        assert_eq!(db.ports("br0"), vec![(1, eth1.clone()), (2, eth2)].into_iter().collect());
+        assert_eq!(db.ports("br1"), vec![(7, eth5)].into_iter().collect());
+        assert_eq!(db.ports("br2"), BTreeMap::new());
+
+        // Updates change only the columns they include, and a row update without "new" deletes
+        // the row.
+        update(&mut db, json!({
+            "Interface": {
+                "i1": {"new": {"link_state": "down"}},
+                "i2": {"old": {"name": "eth2"}}
+            }
+        }));
+        let eth1 = Interface { up: false, ..eth1 };
+        assert_eq!(db.ports("br0"), vec![(1, eth1)].into_iter().collect());
+    }
+}
@@ -74,8 +74,13 @@ pub mod ofpbuf;
 pub mod ofp_bundle;
 pub mod ofp_errors;
 pub mod ofp_flow;
+pub mod ofp_group;
+pub mod ofp_meter;
 pub mod ofp_msgs;
+pub mod ofp_packet;
 pub mod ofp_print;
 pub mod ofp_protocol;
+pub mod ovsdb_cs;
 pub mod poll_loop;
 pub mod rconn;
+pub mod unixctl;
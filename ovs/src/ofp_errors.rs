@@ -24,6 +24,7 @@ use std::ffi;
 use std::fmt;
 use std::io;
 use std::os::raw;
+use std::ptr::null_mut;
 
 use anyhow::Result;
 
@@ -82,3 +83,13 @@ pub fn parse(retval: sys::ofperr::Type) -> Result<()> {
         Err(UnknownError(retval))?
     }
 }
+
+/// Decodes the OpenFlow error message in `oh`.  Returns `None` if `oh` is not an error message.
+pub fn decode_msg(oh: &[u8]) -> Option<Error> {
+    let error = unsafe { sys::ofperr_decode_msg(oh.as_ptr() as *const _, null_mut()) };
+    if error != 0 {
+        Some(Error(error))
+    } else {
+        None
+    }
+}
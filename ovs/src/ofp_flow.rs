@@ -27,6 +27,7 @@ use super::ds::Ds;
 use std::error;
 use std::ffi;
 use std::fmt;
+use std::mem;
 use std::os::raw;
 use std::ptr::{null, null_mut};
 
@@ -106,6 +107,40 @@ impl FlowMod {
         }
     }
 
+    /// Returns the cookie for the flow that this flow mod adds.
+    pub fn cookie(&self) -> u64 {
+        u64::from_be(self.0.new_cookie)
+    }
+
+    /// Sets the cookie for the flow that this flow mod adds.
+    pub fn set_cookie(&mut self, cookie: u64) {
+        self.0.new_cookie = cookie.to_be();
+    }
+
+    /// Makes this flow mod, if it modifies or deletes flows, affect only flows whose cookie is
+    /// exactly `cookie`.
+    pub fn set_cookie_match(&mut self, cookie: u64) {
+        self.0.cookie = cookie.to_be();
+        self.0.cookie_mask = u64::MAX;
+    }
+
+    /// Changes this flow mod's command to `command`.
+    pub fn set_command(&mut self, command: FlowModCommand) {
+        self.0.command = command.to_openflow() as _;
+    }
+
+    /// Sets the number of seconds that the flow that this flow mod adds may go unused before the
+    /// switch removes it, or 0 for no limit.
+    pub fn set_idle_timeout(&mut self, idle_timeout: u16) {
+        self.0.idle_timeout = idle_timeout;
+    }
+
+    /// Makes the switch send a flow removed message when it removes the flow that this flow mod
+    /// adds.
+    pub fn set_send_flow_rem(&mut self) {
+        self.0.flags |= sys::ofputil_flow_mod_flags_OFPUTIL_FF_SEND_FLOW_REM;
+    }
+
     pub fn encode(&self, protocol: Protocol) -> Ofpbuf {
         unsafe {
             let b = sys::ofputil_encode_flow_mod(&self.0 as *const sys::ofputil_flow_mod,
@@ -123,6 +158,103 @@ impl FlowMod {
     }
 }
 
+/// Statistics for one flow, from a flow stats reply.
+pub struct FlowStats {
+    pub table_id: u8,
+    pub priority: u16,
+    pub cookie: u64,
+    pub packet_count: u64,
+    pub byte_count: u64,
+}
+
+impl FlowStats {
+    /// Encodes a request for statistics for every flow in every table.
+    pub fn encode_dump_request(protocol: Protocol) -> Ofpbuf {
+        FlowStats::encode_cookie_dump_request(protocol, 0, 0)
+    }
+
+    /// Encodes a request for statistics for every flow, in every table, whose cookie matches
+    /// `cookie` in the bits that are 1-bits in `cookie_mask`.
+    pub fn encode_cookie_dump_request(protocol: Protocol, cookie: u64, cookie_mask: u64) -> Ofpbuf {
+        unsafe {
+            let mut fsr: sys::ofputil_flow_stats_request = mem::zeroed();
+            sys::match_init_catchall(&mut fsr.match_ as *mut _);
+            fsr.cookie = cookie.to_be();
+            fsr.cookie_mask = cookie_mask.to_be();
+            fsr.out_port = 0xffff;      // OFPP_ANY
+            fsr.out_group = 0xffffffff; // OFPG_ANY
+            fsr.table_id = 0xff;        // All tables.
+            Ofpbuf::from_ptr(sys::ofputil_encode_flow_stats_request(&fsr as *const _, protocol.into()))
+        }
+    }
+
+    /// Decodes all of the flow statistics in flow stats reply `msg`.
+    pub fn decode_reply(msg: &[u8]) -> Result<Vec<FlowStats>> {
+        let mut stats = Vec::new();
+        unsafe {
+            let mut b: sys::ofpbuf = mem::zeroed();
+            sys::ofpbuf_use_const(&mut b as *mut _, msg.as_ptr() as *const _, msg.len() as sys::size_t);
+            let mut ofpacts: sys::ofpbuf = mem::zeroed();
+            sys::ofpbuf_init(&mut ofpacts as *mut _, 0);
+            let retval = loop {
+                let mut fs: sys::ofputil_flow_stats = mem::zeroed();
+                let retval = sys::ofputil_decode_flow_stats_reply(&mut fs as *mut _, &mut b as *mut _, false,
+                                                                  &mut ofpacts as *mut _);
+                if retval != 0 {
+                    break retval;
+                }
+                stats.push(FlowStats {
+                    table_id: fs.table_id,
+                    priority: fs.priority,
+                    cookie: u64::from_be(fs.cookie),
+                    packet_count: fs.packet_count,
+                    byte_count: fs.byte_count,
+                });
+            };
+            sys::ofpbuf_uninit(&mut ofpacts as *mut _);
+            if retval != libc::EOF {
+                ofp_errors::parse(retval as sys::ofperr::Type)?;
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// Why the switch removed a flow: its idle timeout expired.
+pub const OFPRR_IDLE_TIMEOUT: u8 = sys::ofp_flow_removed_reason_OFPRR_IDLE_TIMEOUT as u8;
+
+/// A flow removed message, which the switch sends when it removes a flow that was added with
+/// [`FlowMod::set_send_flow_rem`].
+pub struct FlowRemoved {
+    pub table_id: u8,
+    pub priority: u16,
+    pub cookie: u64,
+
+    /// Why the switch removed the flow, e.g. [`OFPRR_IDLE_TIMEOUT`].
+    pub reason: u8,
+
+    pub packet_count: u64,
+    pub byte_count: u64,
+}
+
+impl FlowRemoved {
+    /// Decodes flow removed message `msg`.
+    pub fn decode(msg: &[u8]) -> Result<FlowRemoved> {
+        unsafe {
+            let mut fr: sys::ofputil_flow_removed = mem::zeroed();
+            ofp_errors::parse(sys::ofputil_decode_flow_removed(&mut fr as *mut _, msg.as_ptr() as *const _))?;
+            Ok(FlowRemoved {
+                table_id: fr.table_id,
+                priority: fr.priority,
+                cookie: u64::from_be(fr.cookie),
+                reason: fr.reason,
+                packet_count: fr.packet_count,
+                byte_count: fr.byte_count,
+            })
+        }
+    }
+}
+
 mod tests {
     #[test]
     fn it_works() {
@@ -0,0 +1,120 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! OpenFlow groups.
+//!
+//! A group is a set of "buckets", each of which is a list of actions.  A flow sends a packet to
+//! a group with the `group:N` action.  What happens next depends on the group's type: an `all`
+//! group executes every bucket on its own copy of the packet, and a `select` group executes just
+//! one bucket, chosen by hashing the packet.
+
+use super::sys;
+
+use super::ofpbuf::Ofpbuf;
+use super::ofp_flow::FlowModParseError;
+use super::ofp_protocol::{Protocols, Version};
+
+use std::ffi;
+use std::mem;
+use std::os::raw;
+use std::ptr::{null, null_mut};
+
+use anyhow::Result;
+
+const OFPGC11_ADD: u16 = sys::ofp11_group_mod_command_OFPGC11_ADD as u16;
+const OFPGC11_MODIFY: u16 = sys::ofp11_group_mod_command_OFPGC11_MODIFY as u16;
+const OFPGC11_DELETE: u16 = sys::ofp11_group_mod_command_OFPGC11_DELETE as u16;
+const OFPGC11_ADD_OR_MOD: u16 = sys::ofp11_group_mod_command_OFPGC11_ADD_OR_MOD as u16;
+
+pub enum GroupModCommand {
+    Add,
+    Modify,
+    Delete,
+
+    /// Adds the group if it doesn't exist, otherwise modifies it.  This is an Open vSwitch
+    /// extension.
+    AddOrModify
+}
+
+impl GroupModCommand {
+    fn to_openflow(&self) -> u16 {
+        match self {
+            GroupModCommand::Add => OFPGC11_ADD,
+            GroupModCommand::Modify => OFPGC11_MODIFY,
+            GroupModCommand::Delete => OFPGC11_DELETE,
+            GroupModCommand::AddOrModify => OFPGC11_ADD_OR_MOD
+        }
+    }
+}
+
+/// A group mod, that is, a request to add, modify, or delete a group.
+///
+/// The underlying `ofputil_group_mod` contains a list head for its buckets, so it has to stay at a
+/// fixed address.  That's why it's boxed.
+pub struct GroupMod(Box<sys::ofputil_group_mod>);
+
+impl Drop for GroupMod {
+    fn drop(&mut self) {
+        unsafe {
+            sys::ofputil_uninit_group_mod(&mut *self.0 as *mut _);
+        }
+    }
+}
+
+unsafe impl Send for GroupMod {}
+unsafe impl Sync for GroupMod {}
+impl GroupMod {
+    /// Parses `s`, which should be in the syntax of `ovs-ofctl add-group`, as a group mod with the
+    /// given `command`.  `Delete` only needs a `group_id`, which may be `all`.
+    pub fn parse(s: &str, command: GroupModCommand) -> Result<(GroupMod, Protocols)> {
+        let s = match ffi::CString::new(s) {
+            Ok(cs) => cs,
+            Err(_) => Err(FlowModParseError("unexpected NUL in string".into()))?
+        };
+        let mut usable_protocols = Protocols::all().bits();
+        unsafe {
+            let mut gm: Box<sys::ofputil_group_mod> = Box::new(mem::zeroed());
+            let error = sys::parse_ofp_group_mod_str(&mut *gm as *mut _, command.to_openflow() as raw::c_int,
+                                                     s.as_ptr(), null(), null(),
+                                                     &mut usable_protocols as *mut sys::ofputil_protocol);
+            if error == null_mut() {
+                Ok((GroupMod(gm), Protocols::from_bits_unchecked(usable_protocols)))
+            } else {
+                // On error, `parse_ofp_group_mod_str` has already freed whatever it allocated.
+                let cs = ffi::CStr::from_ptr(error).to_string_lossy().into();
+                libc::free(error as *mut ffi::c_void);
+                Err(FlowModParseError(cs))?
+            }
+        }
+    }
+
+    /// Returns the ID of the group that this group mod affects.
+    pub fn group_id(&self) -> u32 {
+        self.0.group_id
+    }
+
+    pub fn encode(&self, version: Version) -> Ofpbuf {
+        unsafe {
+            let b = sys::ofputil_encode_group_mod(version as sys::ofp_version, &*self.0 as *const _,
+                                                  null(), -1);
+            Ofpbuf::from_ptr(b)
+        }
+    }
+}
@@ -0,0 +1,183 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! OpenFlow meters.
+//!
+//! A meter measures the rate of the packets that pass through it and applies the band for the
+//! highest rate that the packets exceed, e.g. dropping them.  A flow sends a packet through a
+//! meter with the `meter:N` action.  Unlike flow mods and group mods, meter mods can't be put in
+//! a bundle.
+
+use super::sys;
+
+use super::ofpbuf::Ofpbuf;
+use super::ofp_errors;
+use super::ofp_flow::FlowModParseError;
+use super::ofp_protocol::{Protocols, Version};
+
+use std::ffi;
+use std::mem;
+use std::os::raw;
+use std::ptr::null_mut;
+
+use anyhow::Result;
+
+const OFPMC13_ADD: u16 = sys::ofp13_meter_mod_command_OFPMC13_ADD as u16;
+const OFPMC13_MODIFY: u16 = sys::ofp13_meter_mod_command_OFPMC13_MODIFY as u16;
+const OFPMC13_DELETE: u16 = sys::ofp13_meter_mod_command_OFPMC13_DELETE as u16;
+
+pub enum MeterModCommand {
+    Add,
+    Modify,
+    Delete
+}
+
+impl MeterModCommand {
+    fn to_openflow(&self) -> u16 {
+        match self {
+            MeterModCommand::Add => OFPMC13_ADD,
+            MeterModCommand::Modify => OFPMC13_MODIFY,
+            MeterModCommand::Delete => OFPMC13_DELETE
+        }
+    }
+}
+
+/// A meter mod, that is, a request to add, modify, or delete a meter.
+pub struct MeterMod(sys::ofputil_meter_mod);
+
+impl Drop for MeterMod {
+    fn drop(&mut self) {
+        unsafe {
+            libc::free(self.0.meter.bands as *mut raw::c_void);
+        }
+    }
+}
+
+unsafe impl Send for MeterMod {}
+unsafe impl Sync for MeterMod {}
+impl MeterMod {
+    /// Parses `s`, which should be in the syntax of `ovs-ofctl add-meter`, as a meter mod with the
+    /// given `command`.  `Delete` only needs a `meter` ID, which may be `all`.
+    pub fn parse(s: &str, command: MeterModCommand) -> Result<(MeterMod, Protocols)> {
+        let s = match ffi::CString::new(s) {
+            Ok(cs) => cs,
+            Err(_) => Err(FlowModParseError("unexpected NUL in string".into()))?
+        };
+        let mut usable_protocols = Protocols::all().bits();
+        unsafe {
+            let mut mm: sys::ofputil_meter_mod = mem::zeroed();
+            let error = sys::parse_ofp_meter_mod_str(&mut mm as *mut _, s.as_ptr(),
+                                                     command.to_openflow() as raw::c_int,
+                                                     &mut usable_protocols as *mut sys::ofputil_protocol);
+            if error == null_mut() {
+                Ok((MeterMod(mm), Protocols::from_bits_unchecked(usable_protocols)))
+            } else {
+                let cs = ffi::CStr::from_ptr(error).to_string_lossy().into();
+                libc::free(error as *mut ffi::c_void);
+                Err(FlowModParseError(cs))?
+            }
+        }
+    }
+
+    /// Returns the ID of the meter that this meter mod affects.
+    pub fn meter_id(&self) -> u32 {
+        self.0.meter.meter_id
+    }
+
+    /// Returns the configuration of the meter that this meter mod adds or modifies.
+    pub fn config(&self) -> MeterConfig {
+        unsafe { MeterConfig::from_raw(&self.0.meter) }
+    }
+
+    pub fn encode(&self, version: Version) -> Ofpbuf {
+        unsafe {
+            let b = sys::ofputil_encode_meter_mod(version as sys::ofp_version, &self.0 as *const _);
+            Ofpbuf::from_ptr(b)
+        }
+    }
+}
+
+/// One band of a meter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MeterBand {
+    /// The band type, e.g. `OFPMBT13_DROP`.
+    pub type_: u16,
+    pub prec_level: u8,
+    pub rate: u32,
+    pub burst_size: u32,
+}
+
+/// A meter's configuration, as in a meter mod or a meter config reply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MeterConfig {
+    pub meter_id: u32,
+    pub flags: u16,
+    pub bands: Vec<MeterBand>,
+}
+
+impl MeterConfig {
+    unsafe fn from_raw(mc: &sys::ofputil_meter_config) -> MeterConfig {
+        let bands = if mc.n_bands == 0 {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(mc.bands, mc.n_bands as usize)
+        };
+        MeterConfig {
+            meter_id: mc.meter_id,
+            flags: mc.flags,
+            bands: bands.iter()
+                .map(|b| MeterBand { type_: b.type_, prec_level: b.prec_level, rate: b.rate, burst_size: b.burst_size })
+                .collect(),
+        }
+    }
+
+    /// Encodes a request for the configuration of every meter.
+    pub fn encode_dump_request(version: Version) -> Ofpbuf {
+        unsafe {
+            Ofpbuf::from_ptr(sys::ofputil_encode_meter_request(version as sys::ofp_version,
+                                                               sys::ofputil_meter_request_type_OFPUTIL_METER_CONFIG,
+                                                               0xffffffff)) // OFPM13_ALL
+        }
+    }
+
+    /// Decodes all of the meter configurations in meter config reply `msg`.
+    pub fn decode_reply(msg: &[u8]) -> Result<Vec<MeterConfig>> {
+        let mut configs = Vec::new();
+        unsafe {
+            let mut b: sys::ofpbuf = mem::zeroed();
+            sys::ofpbuf_use_const(&mut b as *mut _, msg.as_ptr() as *const _, msg.len() as sys::size_t);
+            let mut bands: sys::ofpbuf = mem::zeroed();
+            sys::ofpbuf_init(&mut bands as *mut _, 0);
+            let retval = loop {
+                let mut mc: sys::ofputil_meter_config = mem::zeroed();
+                let retval = sys::ofputil_decode_meter_config(&mut b as *mut _, &mut mc as *mut _, &mut bands as *mut _);
+                if retval != 0 {
+                    break retval;
+                }
+                configs.push(MeterConfig::from_raw(&mc));
+            };
+            sys::ofpbuf_uninit(&mut bands as *mut _);
+            if retval != libc::EOF {
+                ofp_errors::parse(retval as sys::ofperr::Type)?;
+            }
+        }
+        Ok(configs)
+    }
+}
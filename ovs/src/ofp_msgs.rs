@@ -20,9 +20,47 @@ SOFTWARE.
 use super::sys;
 
 use super::ofpbuf::Ofpbuf;
+use super::ofp_errors;
+
+use std::convert::TryInto;
+use std::mem;
+
+use anyhow::Result;
+
+/// The type of an OpenFlow message, one of `OFPTYPE_*`.
+pub type OfpType = sys::ofptype;
+
+pub const OFPTYPE_ERROR: OfpType = sys::ofptype_OFPTYPE_ERROR;
+pub const OFPTYPE_BUNDLE_CONTROL: OfpType = sys::ofptype_OFPTYPE_BUNDLE_CONTROL;
+pub const OFPTYPE_FLOW_REMOVED: OfpType = sys::ofptype_OFPTYPE_FLOW_REMOVED;
+pub const OFPTYPE_FLOW_STATS_REPLY: OfpType = sys::ofptype_OFPTYPE_FLOW_STATS_REPLY;
+pub const OFPTYPE_METER_MOD: OfpType = sys::ofptype_OFPTYPE_METER_MOD;
+pub const OFPTYPE_METER_CONFIG_STATS_REPLY: OfpType = sys::ofptype_OFPTYPE_METER_CONFIG_STATS_REPLY;
 
 pub fn update_length(buf: &mut Ofpbuf) {
     unsafe {
         sys::ofpmsg_update_length(buf.0 as *mut _);
     }
 }
+
+/// Returns the type of the OpenFlow message in `oh`.
+pub fn decode_type(oh: &[u8]) -> Result<OfpType> {
+    if oh.len() < mem::size_of::<sys::ofp_header>() {
+        Err(ofp_errors::Error(sys::ofperr::OFPERR_OFPBRC_BAD_LEN))?
+    }
+    unsafe {
+        let mut type_: OfpType = mem::zeroed();
+        ofp_errors::parse(sys::ofptype_decode(&mut type_ as *mut _, oh.as_ptr() as *const _))?;
+        Ok(type_)
+    }
+}
+
+/// Returns the transaction ID in the header of the OpenFlow message in `oh`.
+pub fn xid(oh: &[u8]) -> u32 {
+    u32::from_be_bytes(oh[4..8].try_into().unwrap())
+}
+
+/// Returns true if the OpenFlow multipart reply in `oh` is followed by more parts.
+pub fn more(oh: &[u8]) -> bool {
+    unsafe { sys::ofpmp_more(oh.as_ptr() as *const _) }
+}
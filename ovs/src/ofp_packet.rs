@@ -0,0 +1,188 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! OpenFlow packet-in and packet-out support.
+//!
+//! A switch sends a "packet-in" message to its controller when a flow executes a `controller`
+//! action.  The Open vSwitch `NXT_PACKET_IN2` format of packet-in also carries the action's
+//! `userdata` and the packet's metadata, including its registers, which is what a controller
+//! needs to make sense of packets that the switch sends it on purpose.
+//!
+//! A controller sends a "packet-out" message to make the switch execute actions on a packet.
+use super::sys;
+
+use super::ofpbuf::Ofpbuf;
+use super::ofp_errors;
+use super::ofp_protocol::{Protocol, Protocols, Version};
+
+use std::error;
+use std::ffi;
+use std::fmt;
+use std::mem;
+use std::os::raw;
+use std::ptr::{null, null_mut};
+use std::slice;
+
+use anyhow::Result;
+
+/// The number of 64-bit registers `xreg0`, `xreg1`, ... in a packet's metadata.
+pub const N_XREGS: usize = sys::FLOW_N_REGS as usize / 2;
+
+/// The format in which a switch sends packet-ins to a controller.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum PacketInFormat {
+    /// `OFPT_PACKET_IN` for the connection's OpenFlow version.
+    Standard = sys::ofputil_packet_in_format_OFPUTIL_PACKET_IN_STD as isize,
+    /// `NXT_PACKET_IN`.
+    Nxt = sys::ofputil_packet_in_format_OFPUTIL_PACKET_IN_NXT as isize,
+    /// `NXT_PACKET_IN2`, which includes userdata and full metadata.
+    Nxt2 = sys::ofputil_packet_in_format_OFPUTIL_PACKET_IN_NXT2 as isize,
+}
+
+/// Returns a message that asks the switch to send packet-ins in `format`.
+pub fn encode_set_packet_in_format(version: Version, format: PacketInFormat) -> Ofpbuf {
+    unsafe {
+        Ofpbuf::from_ptr(sys::ofputil_encode_set_packet_in_format(version as sys::ofp_version,
+                                                                  format as sys::ofputil_packet_in_format))
+    }
+}
+
+/// A decoded packet-in message.
+pub struct PacketIn {
+    /// The packet, starting from its Ethernet header.
+    pub packet: Vec<u8>,
+
+    /// The OpenFlow table of the flow that sent the packet.
+    pub table_id: u8,
+
+    /// The cookie of the flow that sent the packet.
+    pub cookie: u64,
+
+    /// The `userdata` argument to the `controller` action, if any.
+    pub userdata: Vec<u8>,
+
+    /// The packet's OpenFlow ingress port.
+    pub in_port: u32,
+
+    /// The packet's registers `reg0`, `reg1`, ... at the time it was sent.
+    pub regs: Vec<u32>,
+}
+
+impl PacketIn {
+    /// Decodes `msg` as a packet-in.  Returns `Ok(None)` if `msg` is some other kind of OpenFlow
+    /// message.
+    pub fn decode(msg: &Ofpbuf) -> Result<Option<PacketIn>> {
+        let oh = msg.as_ptr() as *const sys::ofp_header;
+        unsafe {
+            let mut type_: sys::ofptype = mem::zeroed();
+            ofp_errors::parse(sys::ofptype_decode(&mut type_ as *mut _, oh))?;
+            if type_ != sys::ofptype_OFPTYPE_PACKET_IN {
+                return Ok(None);
+            }
+
+            let mut pin: sys::ofputil_packet_in = mem::zeroed();
+            let mut total_len: sys::size_t = 0;
+            let mut buffer_id: u32 = 0;
+            ofp_errors::parse(sys::ofputil_decode_packet_in(oh, true, null(), null(), &mut pin as *mut _,
+                                                            &mut total_len as *mut _, &mut buffer_id as *mut _,
+                                                            null_mut()))?;
+
+            // The packet and the userdata point into `msg`, so copy them out.
+            let packet = slice::from_raw_parts(pin.packet as *const u8, pin.packet_len as usize).into();
+            let userdata = if pin.userdata.is_null() {
+                Vec::new()
+            } else {
+                slice::from_raw_parts(pin.userdata as *const u8, pin.userdata_len as usize).into()
+            };
+            let flow = &pin.flow_metadata.flow;
+            Ok(Some(PacketIn {
+                packet,
+                table_id: pin.table_id,
+                cookie: u64::from_be(pin.cookie),
+                userdata,
+                in_port: flow.in_port.ofp_port,
+                regs: flow.regs.to_vec(),
+            }))
+        }
+    }
+
+    /// Returns 64-bit register `xreg<n>`, which consists of `reg<2n>` as its most-significant
+    /// bits and `reg<2n+1>` as its least-significant.
+    pub fn xreg(&self, n: usize) -> u64 {
+        ((self.regs[2 * n] as u64) << 32) | self.regs[2 * n + 1] as u64
+    }
+}
+
+/// An OpenFlow packet-out message.
+pub struct PacketOut(sys::ofputil_packet_out);
+
+#[derive(Debug)]
+pub struct PacketOutParseError(pub String);
+
+impl fmt::Display for PacketOutParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for PacketOutParseError {}
+
+impl Drop for PacketOut {
+    fn drop(&mut self) {
+        unsafe {
+            libc::free(self.0.packet as *mut raw::c_void);
+            libc::free(self.0.ofpacts as *mut raw::c_void);
+        }
+    }
+}
+
+unsafe impl Send for PacketOut {}
+unsafe impl Sync for PacketOut {}
+impl PacketOut {
+    /// Parses `s` in the syntax of `ovs-ofctl packet-out`, e.g.
+    /// `in_port=CONTROLLER packet=<hex> actions=resubmit(,0)`.
+    pub fn parse(s: &str) -> Result<(PacketOut, Protocols)> {
+        let s = match ffi::CString::new(s) {
+            Ok(cs) => cs,
+            Err(_) => Err(PacketOutParseError("unexpected NUL in string".into()))?
+        };
+        let mut usable_protocols = Protocols::all().bits();
+        unsafe {
+            let mut po: sys::ofputil_packet_out = mem::zeroed();
+            let s = sys::parse_ofp_packet_out_str(&mut po as *mut sys::ofputil_packet_out, s.as_ptr(),
+                                                  null(), null(),
+                                                  &mut usable_protocols as *mut sys::ofputil_protocol);
+            if s.is_null() {
+                Ok((PacketOut(po), Protocols::from_bits_unchecked(usable_protocols)))
+            } else {
+                let cs = ffi::CStr::from_ptr(s).to_string_lossy().into();
+                libc::free(s as *mut ffi::c_void);
+                Err(PacketOutParseError(cs))?
+            }
+        }
+    }
+
+    pub fn encode(&self, protocol: Protocol) -> Ofpbuf {
+        unsafe {
+            Ofpbuf::from_ptr(sys::ofputil_encode_packet_out(&self.0 as *const sys::ofputil_packet_out,
+                                                            protocol.into()))
+        }
+    }
+}
@@ -0,0 +1,123 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+use super::sys;
+
+use std::ffi;
+use std::os::raw;
+use std::ptr::null_mut;
+
+/// An event reported by [`OvsdbCs::run`].
+pub enum Event {
+    /// The client connected or reconnected to the database.  An update with `clear` set follows.
+    Reconnect,
+
+    /// Changes to the monitored tables, as the JSON text of an OVSDB `<table-updates>` object in
+    /// the format of the RFC 7047 "monitor" method.  If `clear` is true, the client should forget
+    /// its copy of the tables before applying the changes.
+    Update { clear: bool, table_updates: String },
+}
+
+/// A client for an OVSDB database that keeps the client up to date with changes to the tables
+/// that it monitors.  This wraps the Open vSwitch "client sync" library, which takes care of
+/// connecting and reconnecting to the database server and of resending the monitor request after
+/// a reconnection.
+pub struct OvsdbCs {
+    cs: *mut sys::ovsdb_cs,
+
+    // The client sync library passes this to `compose_monitor_requests` by pointer, so it has to
+    // stay in place for as long as `cs` exists.
+    _monitor_requests: Box<ffi::CString>,
+}
+
+unsafe extern "C" fn compose_monitor_requests(_schema: *const sys::json, aux: *mut raw::c_void) -> *mut sys::json {
+    let monitor_requests = &*(aux as *const ffi::CString);
+    sys::json_from_string(monitor_requests.as_ptr())
+}
+
+static OVSDB_CS_OPS: sys::ovsdb_cs_ops = sys::ovsdb_cs_ops {
+    compose_monitor_requests: Some(compose_monitor_requests),
+};
+
+impl OvsdbCs {
+    /// Creates a client that connects to `database` at `remote`, e.g. `unix:/path/to/db.sock`,
+    /// and monitors it with `monitor_requests`, the JSON text of the `<monitor-requests>` object
+    /// for an RFC 7047 "monitor" request, e.g. `{"Bridge": [{"columns": ["name"]}]}`.
+    pub fn new(database: &str, remote: &str, monitor_requests: &str) -> OvsdbCs {
+        let database = ffi::CString::new(database).unwrap();
+        let remote = ffi::CString::new(remote).unwrap();
+        let monitor_requests = Box::new(ffi::CString::new(monitor_requests).unwrap());
+        unsafe {
+            // Version 1 is the "monitor" method, whose updates have the format that [`Event`]
+            // documents.
+            let cs = sys::ovsdb_cs_create(database.as_ptr(), 1, &OVSDB_CS_OPS,
+                                          &*monitor_requests as *const ffi::CString as *mut raw::c_void);
+            sys::ovsdb_cs_set_remote(cs, remote.as_ptr(), true);
+            OvsdbCs { cs, _monitor_requests: monitor_requests }
+        }
+    }
+
+    /// Processes the connection to the database and returns whatever happened since the last
+    /// call.
+    pub fn run(&mut self) -> Vec<Event> {
+        let mut result = Vec::new();
+        unsafe {
+            let mut events = sys::ovs_list { prev: null_mut(), next: null_mut() };
+            let head: *mut sys::ovs_list = &mut events;
+            (*head).prev = head;
+            (*head).next = head;
+            sys::ovsdb_cs_run(self.cs, head);
+
+            let mut node = (*head).next;
+            while node != head {
+                let next = (*node).next;
+
+                // `list_node` is the first member of `ovsdb_cs_event`.
+                let event = node as *mut sys::ovsdb_cs_event;
+                match (*event).type_ {
+                    sys::ovsdb_cs_event_ovsdb_cs_event_type_OVSDB_CS_EVENT_TYPE_RECONNECT => {
+                        result.push(Event::Reconnect)
+                    },
+                    sys::ovsdb_cs_event_ovsdb_cs_event_type_OVSDB_CS_EVENT_TYPE_UPDATE => {
+                        let update = &(*event).__bindgen_anon_1.update;
+                        let s = sys::json_to_string(update.table_updates, 0);
+                        let table_updates = ffi::CStr::from_ptr(s).to_string_lossy().into_owned();
+                        libc::free(s as *mut raw::c_void);
+                        result.push(Event::Update { clear: update.clear, table_updates });
+                    },
+                    _ => (),
+                }
+                sys::ovsdb_cs_event_destroy(event);
+                node = next;
+            }
+        }
+        result
+    }
+
+    /// Arranges for `poll_loop::block()` to wake up when [`Self::run`] has something to do.
+    pub fn wait(&mut self) {
+        unsafe { sys::ovsdb_cs_wait(self.cs) }
+    }
+}
+
+impl Drop for OvsdbCs {
+    fn drop(&mut self) {
+        unsafe { sys::ovsdb_cs_destroy(self.cs) }
+    }
+}
@@ -0,0 +1,96 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+use super::sys;
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr::null_mut;
+
+/// A server for the commands that `ovs-appctl` sends over a Unix domain socket.
+pub struct UnixctlServer(*mut sys::unixctl_server);
+
+impl UnixctlServer {
+    /// Creates a server that listens on the Unix domain socket at `path`.
+    pub fn new(path: &str) -> io::Result<UnixctlServer> {
+        let path = CString::new(path).unwrap();
+        let mut server = null_mut();
+        unsafe {
+            match sys::unixctl_server_create(path.as_ptr(), &mut server) {
+                0 => Ok(UnixctlServer(server)),
+                errno => Err(io::Error::from_raw_os_error(errno))
+            }
+        }
+    }
+
+    /// Accepts connections and executes the commands that they send.
+    pub fn run(&mut self) {
+        unsafe { sys::unixctl_server_run(self.0) }
+    }
+
+    pub fn wait(&mut self) {
+        unsafe { sys::unixctl_server_wait(self.0) }
+    }
+}
+
+impl Drop for UnixctlServer {
+    fn drop(&mut self) {
+        unsafe { sys::unixctl_server_destroy(self.0) }
+    }
+}
+
+type Callback = Box<dyn Fn(&[&str]) -> Result<String, String>>;
+
+/// Registers `name` as a command that takes between `min_args` and `max_args` arguments,
+/// described by `usage`.  When a [`UnixctlServer`] receives the command, it calls `callback`
+/// with the arguments and replies with the string it returns, as an error if it returns `Err`.
+/// The registration lasts as long as the process.
+pub fn register_command<F>(name: &str, usage: &str, min_args: i32, max_args: i32, callback: F)
+    where F: Fn(&[&str]) -> Result<String, String> + 'static
+{
+    let name = CString::new(name).unwrap();
+    let usage = CString::new(usage).unwrap();
+    let aux: *mut Callback = Box::into_raw(Box::new(Box::new(callback)));
+    unsafe {
+        sys::unixctl_command_register(name.as_ptr(), usage.as_ptr(), min_args, max_args,
+                                      Some(run_command), aux as *mut c_void)
+    }
+}
+
+unsafe extern "C" fn run_command(conn: *mut sys::unixctl_conn, argc: c_int, argv: *mut *const c_char,
+                                 aux: *mut c_void) {
+    let callback = &*(aux as *const Callback);
+
+    // `argv[0]` is the command name.
+    let args: Vec<String> = (1..argc as usize)
+        .map(|i| CStr::from_ptr(*argv.add(i)).to_string_lossy().into_owned())
+        .collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match callback(&args) {
+        Ok(reply) => {
+            let reply = CString::new(reply).unwrap();
+            sys::unixctl_command_reply(conn, reply.as_ptr())
+        },
+        Err(error) => {
+            let error = CString::new(error).unwrap();
+            sys::unixctl_command_reply_error(conn, error.as_ptr())
+        }
+    }
+}
@@ -3,7 +3,10 @@
 #include "ovs/include/openvswitch/ofp-bundle.h"
 #include "ovs/include/openvswitch/ofp-errors.h"
 #include "ovs/include/openvswitch/ofp-flow.h"
+#include "ovs/include/openvswitch/ofp-group.h"
+#include "ovs/include/openvswitch/ofp-meter.h"
 #include "ovs/include/openvswitch/ofp-msgs.h"
+#include "ovs/include/openvswitch/ofp-packet.h"
 #include "ovs/include/openvswitch/ofp-print.h"
 #include "ovs/include/openvswitch/ofpbuf.h"
 #include "ovs/include/openvswitch/poll-loop.h"
@@ -17,6 +20,7 @@
 #include "ovs/lib/sset.h"
 #include "ovs/lib/stream.h"
 #include "ovs/lib/svec.h"
+#include "ovs/lib/unixctl.h"
 
 /**
  * <div rustbindgen replaces="minimatch"></div>
@@ -0,0 +1,450 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Translation of `simple_switch_CLI` command files into P4Runtime updates.
+//!
+//! Nerpa programs ship a `commands.txt` that bootstraps the switch with table entries and
+//! multicast groups.  BMv2 runs such files through its Thrift CLI, which isn't available for other
+//! targets.  This module parses the most common commands and converts them into P4Runtime updates
+//! that any target accepts:
+//!
+//! * `table_add <table> <action> <match>... => <arg>... [<priority>]`
+//! * `table_set_default <table> <action> <arg>...`
+//! * `table_delete <table> <handle>`
+//! * `mc_mgrp_create <group>`
+//! * `mc_node_create <rid> <port>...`
+//! * `mc_node_associate <group> <node>`
+//! * `mirroring_add <session> <port>`
+//!
+//! Match and argument values may be written in decimal, in hex with `0x`, in binary with `0b`, or
+//! as an IPv4, IPv6, or Ethernet address.  Ternary matches are written `<value>&&&<mask>`, LPM
+//! matches `<value>/<prefix-length>`, and range matches `<low>-><high>`.
+
+use anyhow::{anyhow, Context, Result};
+
+use grpcio::RpcStatusCode;
+
+use proto::p4runtime::{
+    CloneSessionEntry,
+    Entity,
+    PacketReplicationEngineEntry,
+    Update,
+    Update_Type,
+};
+
+use protobuf::RepeatedField;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use super::{
+    Error,
+    MatchType,
+    MulticastGroupEntry,
+    MulticastGroupId,
+    Replica,
+    Switch,
+    Table,
+    TableEntry,
+    TableEntryBuilder,
+    TableId,
+};
+
+/// Parses `input`, the contents of a `simple_switch_CLI` command file, and returns the P4Runtime
+/// updates that have the same effect on a switch whose pipeline is described by `switch`.
+///
+/// Table updates are returned in the order that they appear in `input`, followed by one insertion
+/// per multicast group and clone session, since those are built up by several commands.
+pub fn parse_commands(switch: &Switch, input: &str) -> Result<Vec<Update>> {
+    let mut parser = Parser::new(switch);
+    for (index, line) in input.lines().enumerate() {
+        parser.parse_line(line).with_context(|| format!("line {}: {}", index + 1, line.trim()))?;
+    }
+    Ok(parser.finish())
+}
+
+/// Reads the `simple_switch_CLI` command file at `path` and translates it with
+/// [`parse_commands`].
+pub fn parse_commands_file<P: AsRef<Path>>(switch: &Switch, path: P) -> Result<Vec<Update>> {
+    let path = path.as_ref();
+    let input = fs::read_to_string(path).with_context(|| format!("{}: read failed", path.display()))?;
+    parse_commands(switch, &input).with_context(|| format!("{}", path.display()))
+}
+
+/// Returns an error with `code` and `message`.
+fn fail<T>(code: RpcStatusCode, message: String) -> Result<T> {
+    Err(Error(code)).context(message)
+}
+
+/// Parses `s` as a numeric value, an IPv4 address, an IPv6 address, or an Ethernet address.
+fn parse_value(s: &str) -> Result<u128> {
+    let number = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u128::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix("0b").or_else(|| s.strip_prefix("0B")) {
+        u128::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse::<u128>().ok()
+    };
+    if let Some(value) = number {
+        Ok(value)
+    } else if let Ok(ip) = s.parse::<Ipv4Addr>() {
+        Ok(u32::from(ip) as u128)
+    } else if let Ok(ip) = s.parse::<Ipv6Addr>() {
+        Ok(u128::from(ip))
+    } else if let Some(mac) = parse_mac(s) {
+        Ok(mac)
+    } else {
+        fail(RpcStatusCode::INVALID_ARGUMENT, format!("{}: invalid value", s))
+    }
+}
+
+/// Parses `s` as an Ethernet address in the form `xx:xx:xx:xx:xx:xx`.
+fn parse_mac(s: &str) -> Option<u128> {
+    let octets: Vec<&str> = s.split(':').collect();
+    if octets.len() != 6 {
+        return None;
+    }
+    let mut mac = 0;
+    for octet in octets {
+        if octet.is_empty() || octet.len() > 2 {
+            return None;
+        }
+        mac = (mac << 8) | u8::from_str_radix(octet, 16).ok()? as u128;
+    }
+    Some(mac)
+}
+
+/// Returns a value with the low-order `bits` bits set to 1.
+fn all_ones(bits: i32) -> u128 {
+    if bits >= 128 {
+        u128::MAX
+    } else {
+        (1u128 << bits) - 1
+    }
+}
+
+struct Parser<'a> {
+    switch: &'a Switch,
+
+    /// Table updates, in order.
+    updates: Vec<Update>,
+
+    /// Entries added by `table_add`, indexed by table and then by the entry handle that BMv2
+    /// would have assigned.  BMv2 assigns the lowest handle not in use.
+    handles: HashMap<TableId, Vec<Option<TableEntry>>>,
+
+    /// Multicast nodes created by `mc_node_create`, indexed by node handle.
+    nodes: Vec<BTreeSet<Replica>>,
+
+    /// Multicast groups, with the replicas from the nodes associated with them.
+    groups: BTreeMap<MulticastGroupId, BTreeSet<Replica>>,
+
+    /// Clone sessions created by `mirroring_add`, mapped to their egress ports.
+    clone_sessions: BTreeMap<u32, u32>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(switch: &'a Switch) -> Self {
+        Parser {
+            switch,
+            updates: Vec::new(),
+            handles: HashMap::new(),
+            nodes: Vec::new(),
+            groups: BTreeMap::new(),
+            clone_sessions: BTreeMap::new(),
+        }
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = (words[0], &words[1..]);
+        match command {
+            "table_add" => self.table_add(args),
+            "table_set_default" => self.table_set_default(args),
+            "table_delete" => self.table_delete(args),
+            "mc_mgrp_create" => self.mc_mgrp_create(args),
+            "mc_node_create" => self.mc_node_create(args),
+            "mc_node_associate" => self.mc_node_associate(args),
+            "mirroring_add" => self.mirroring_add(args),
+            _ => fail(RpcStatusCode::UNIMPLEMENTED, format!("unsupported command {}", command)),
+        }
+    }
+
+    /// Adds arguments `args` for the parameters of the action in `builder`, which is named
+    /// `action_name`.
+    fn add_params<'b>(&self, mut builder: TableEntryBuilder<'b>, table_name: &str, action_name: &str,
+                      args: &[&str]) -> Result<TableEntryBuilder<'b>> {
+        let table = self.table(table_name)?;
+        let ar = match table.action_by_name(action_name) {
+            Some(ar) => ar,
+            None => return fail(RpcStatusCode::NOT_FOUND,
+                                format!("table {} has no action {}", table_name, action_name)),
+        };
+        if args.len() != ar.action.params.len() {
+            return fail(RpcStatusCode::INVALID_ARGUMENT,
+                        format!("action {} takes {} arguments but {} were supplied",
+                                action_name, ar.action.params.len(), args.len()));
+        }
+        for (param, arg) in ar.action.params.iter().zip(args) {
+            builder = builder.param(&param.preamble.name, parse_value(arg)?);
+        }
+        Ok(builder)
+    }
+
+    fn table(&self, name: &str) -> Result<&'a Table> {
+        match self.switch.table_by_name(name) {
+            Some(table) => Ok(table),
+            None => fail(RpcStatusCode::NOT_FOUND, format!("no table named {}", name)),
+        }
+    }
+
+    fn table_add(&mut self, args: &[&str]) -> Result<()> {
+        let arrow = args.iter().position(|&arg| arg == "=>");
+        let (table_name, action_name, matches, params) = match (args, arrow) {
+            ([table_name, action_name, ..], Some(arrow)) if arrow >= 2
+                => (*table_name, *action_name, &args[2..arrow], &args[arrow + 1..]),
+            _ => return fail(RpcStatusCode::INVALID_ARGUMENT,
+                             "usage: table_add <table> <action> <match>... => <arg>... [<priority>]".into()),
+        };
+        let table = self.table(table_name)?;
+        if matches.len() != table.match_fields.len() {
+            return fail(RpcStatusCode::INVALID_ARGUMENT,
+                        format!("table {} has {} match fields but {} were supplied",
+                                table_name, table.match_fields.len(), matches.len()));
+        }
+
+        let mut builder = self.switch.entry(table_name);
+        for (mf, &arg) in table.match_fields.iter().zip(matches) {
+            let name = &mf.preamble.name;
+            builder = match mf.match_type {
+                MatchType::Exact => builder.exact(name, parse_value(arg)?),
+                MatchType::LPM => match arg.split_once('/') {
+                    Some((value, plen)) => {
+                        let plen = plen.parse::<usize>()
+                            .map_err(|_| anyhow!(Error(RpcStatusCode::INVALID_ARGUMENT)))
+                            .with_context(|| format!("{}: invalid prefix length", arg))?;
+                        // A prefix length of 0 is a don't-care, which P4Runtime expresses by
+                        // omitting the match.
+                        if plen == 0 { builder } else { builder.lpm(name, parse_value(value)?, plen) }
+                    },
+                    None => return fail(RpcStatusCode::INVALID_ARGUMENT,
+                                        format!("{}: LPM match must have the form <value>/<prefix-length>", arg)),
+                },
+                MatchType::Ternary | MatchType::Optional => {
+                    let (value, mask) = match arg.split_once("&&&") {
+                        Some((value, mask)) => (parse_value(value)?, parse_value(mask)?),
+                        None => (parse_value(arg)?, all_ones(mf.bit_width)),
+                    };
+                    if mask == 0 {
+                        builder
+                    } else if mf.match_type == MatchType::Ternary {
+                        builder.ternary(name, value, mask)
+                    } else if mask == all_ones(mf.bit_width) {
+                        builder.optional(name, value)
+                    } else {
+                        return fail(RpcStatusCode::INVALID_ARGUMENT,
+                                    format!("{}: optional match mask must be all-zeros or all-ones", arg));
+                    }
+                },
+                MatchType::Range => match arg.split_once("->") {
+                    Some((low, high)) => {
+                        let (low, high) = (parse_value(low)?, parse_value(high)?);
+                        if low == 0 && high == all_ones(mf.bit_width) {
+                            builder
+                        } else {
+                            builder.range(name, low, high)
+                        }
+                    },
+                    None => return fail(RpcStatusCode::INVALID_ARGUMENT,
+                                        format!("{}: range match must have the form <low>-><high>", arg)),
+                },
+                MatchType::Unspecified | MatchType::Other(_)
+                    => return fail(RpcStatusCode::UNIMPLEMENTED,
+                                   format!("{}: unsupported match type {}", name, mf.match_type)),
+            };
+        }
+
+        // BMv2 gives priority to the numerically lowest priority value, P4Runtime to the
+        // highest, so we have to invert the priority.
+        let n_params = table.action_by_name(action_name).map_or(0, |ar| ar.action.params.len());
+        let params = if params.len() == n_params + 1 {
+            let priority = params[n_params].parse::<i32>()
+                .ok()
+                .filter(|&priority| priority >= 0)
+                .ok_or_else(|| anyhow!(Error(RpcStatusCode::INVALID_ARGUMENT)))
+                .with_context(|| format!("{}: invalid priority", params[n_params]))?;
+            builder = builder.priority(i32::MAX - priority);
+            &params[..n_params]
+        } else {
+            params
+        };
+        let builder = self.add_params(builder.action(action_name), table_name, action_name, params)?;
+        let entry = builder.build()?;
+
+        // Record the entry's handle so that 'table_delete' can refer to it.
+        let handles = self.handles.entry(table.preamble.id).or_default();
+        match handles.iter_mut().find(|handle| handle.is_none()) {
+            Some(handle) => *handle = Some(entry.clone()),
+            None => handles.push(Some(entry.clone())),
+        }
+
+        self.updates.push(entry.to_update(Update_Type::INSERT));
+        Ok(())
+    }
+
+    fn table_set_default(&mut self, args: &[&str]) -> Result<()> {
+        let (table_name, action_name, params) = match args {
+            [table_name, action_name, params @ ..] => (*table_name, *action_name, params),
+            _ => return fail(RpcStatusCode::INVALID_ARGUMENT,
+                             "usage: table_set_default <table> <action> <arg>...".into()),
+        };
+        let builder = self.switch.entry(table_name).default_action().action(action_name);
+        let entry = self.add_params(builder, table_name, action_name, params)?.build()?;
+        self.updates.push(entry.to_update(Update_Type::MODIFY));
+        Ok(())
+    }
+
+    fn table_delete(&mut self, args: &[&str]) -> Result<()> {
+        let (table_name, handle) = match args {
+            [table_name, handle] => (*table_name, *handle),
+            _ => return fail(RpcStatusCode::INVALID_ARGUMENT,
+                             "usage: table_delete <table> <handle>".into()),
+        };
+        let table_id = self.table(table_name)?.preamble.id;
+        let entry = handle.parse::<usize>().ok()
+            .and_then(|handle| self.handles.get_mut(&table_id)?.get_mut(handle)?.take());
+        match entry {
+            Some(entry) => {
+                self.updates.push(entry.to_update(Update_Type::DELETE));
+                Ok(())
+            },
+            None => fail(RpcStatusCode::NOT_FOUND,
+                         format!("table {} has no entry with handle {}", table_name, handle)),
+        }
+    }
+
+    fn mc_mgrp_create(&mut self, args: &[&str]) -> Result<()> {
+        let group_id = match args {
+            [group_id] => parse_value(group_id)?,
+            _ => return fail(RpcStatusCode::INVALID_ARGUMENT, "usage: mc_mgrp_create <group>".into()),
+        };
+        if group_id == 0 || group_id > u32::MAX as u128 {
+            return fail(RpcStatusCode::INVALID_ARGUMENT, format!("invalid multicast group {}", group_id));
+        }
+        if self.groups.insert(group_id as u32, BTreeSet::new()).is_some() {
+            return fail(RpcStatusCode::ALREADY_EXISTS, format!("multicast group {} already exists", group_id));
+        }
+        Ok(())
+    }
+
+    fn mc_node_create(&mut self, args: &[&str]) -> Result<()> {
+        let (rid, ports) = match args {
+            [rid, ports @ ..] => (parse_value(rid)?, ports),
+            _ => return fail(RpcStatusCode::INVALID_ARGUMENT,
+                             "usage: mc_node_create <rid> <port>...".into()),
+        };
+        let mut replicas = BTreeSet::new();
+        for &port in ports {
+            if port == "|" {
+                return fail(RpcStatusCode::UNIMPLEMENTED, "LAG replicas are not supported".into());
+            }
+            let egress_port = parse_value(port)?;
+            if egress_port > u32::MAX as u128 || rid > u32::MAX as u128 {
+                return fail(RpcStatusCode::OUT_OF_RANGE, format!("invalid replica {} {}", rid, port));
+            }
+            replicas.insert(Replica { egress_port: egress_port as u32, instance: rid as u32 });
+        }
+        self.nodes.push(replicas);
+        Ok(())
+    }
+
+    fn mc_node_associate(&mut self, args: &[&str]) -> Result<()> {
+        let (group_id, node) = match args {
+            [group_id, node] => (parse_value(group_id)?, parse_value(node)?),
+            _ => return fail(RpcStatusCode::INVALID_ARGUMENT,
+                             "usage: mc_node_associate <group> <node>".into()),
+        };
+        let replicas = match self.nodes.get(node as usize) {
+            Some(replicas) => replicas,
+            None => return fail(RpcStatusCode::NOT_FOUND, format!("no multicast node {}", node)),
+        };
+        match self.groups.get_mut(&(group_id as u32)) {
+            Some(group) if group_id <= u32::MAX as u128 => {
+                group.extend(replicas.iter().copied());
+                Ok(())
+            },
+            _ => fail(RpcStatusCode::NOT_FOUND, format!("no multicast group {}", group_id)),
+        }
+    }
+
+    fn mirroring_add(&mut self, args: &[&str]) -> Result<()> {
+        let (session_id, egress_port) = match args {
+            [session_id, egress_port] => (parse_value(session_id)?, parse_value(egress_port)?),
+            _ => return fail(RpcStatusCode::INVALID_ARGUMENT,
+                             "usage: mirroring_add <session> <port>".into()),
+        };
+        if session_id == 0 || session_id > u32::MAX as u128 || egress_port > u32::MAX as u128 {
+            return fail(RpcStatusCode::INVALID_ARGUMENT,
+                        format!("invalid mirroring session {} to port {}", session_id, egress_port));
+        }
+        if self.clone_sessions.insert(session_id as u32, egress_port as u32).is_some() {
+            return fail(RpcStatusCode::ALREADY_EXISTS,
+                        format!("mirroring session {} already exists", session_id));
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Vec<Update> {
+        let mut updates = self.updates;
+        for (multicast_group_id, replicas) in self.groups {
+            let mge = MulticastGroupEntry { multicast_group_id, replicas };
+            let mut pre_entry = PacketReplicationEngineEntry::new();
+            pre_entry.set_multicast_group_entry((&mge).into());
+            updates.push(pre_update(pre_entry));
+        }
+        for (session_id, egress_port) in self.clone_sessions {
+            let mut cse = CloneSessionEntry::new();
+            cse.set_session_id(session_id);
+            cse.set_replicas(RepeatedField::from_vec(vec![(&Replica { egress_port, instance: 0 }).into()]));
+            let mut pre_entry = PacketReplicationEngineEntry::new();
+            pre_entry.set_clone_session_entry(cse);
+            updates.push(pre_update(pre_entry));
+        }
+        updates
+    }
+}
+
+/// Returns an update that inserts `pre_entry`.
+fn pre_update(pre_entry: PacketReplicationEngineEntry) -> Update {
+    let mut entity = Entity::new();
+    entity.set_packet_replication_engine_entry(pre_entry);
+
+    let mut update = Update::new();
+    update.set_field_type(Update_Type::INSERT);
+    update.set_entity(entity);
+    update
+}
@@ -32,9 +32,9 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use futures::{SinkExt, StreamExt};
+use futures::{SinkExt, TryStreamExt};
 
-use grpcio::{ChannelBuilder, EnvBuilder, WriteFlags, RpcStatusCode};
+use grpcio::{ChannelBuilder, ClientDuplexReceiver, EnvBuilder, StreamingCallSink, WriteFlags, RpcStatusCode};
 
 use itertools::Itertools;
 
@@ -69,7 +69,7 @@ use proto::p4types;
 
 use protobuf::{Message, RepeatedField};
 
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::convert::{TryFrom, TryInto};
 use std::env;
 use std::ffi::OsStr;
@@ -81,6 +81,16 @@ use std::sync::Arc;
 
 use thiserror::Error;
 
+pub mod commands;
+pub mod mock;
+pub mod server;
+pub mod session;
+pub mod textproto;
+#[cfg(feature = "ofp4")]
+pub mod translation;
+
+pub use session::Session;
+
 /// An annotation's [location](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-sourcelocation-message>) within a `.p4` file.
 #[derive(Clone, Debug, Default)]
 pub struct SourceLocation {
@@ -510,10 +520,9 @@ pub enum FieldMatchType {
         mask: FieldValue
     },
 
-    /// The high-order `plen` bits of the field must match the `plen` least-significant bits of
-    /// `value`.
+    /// The high-order `plen` bits of the field must match the high-order `plen` bits of `value`.
     LPM {
-        /// Value to match, in the least-significant `plen` bits.
+        /// Value to match.  Bits beyond the first `plen` bits of the field must be 0.
         value: FieldValue,
 
         /// Number of bits that must match.
@@ -591,12 +600,11 @@ impl FieldMatch {
                 => {
                     let value: FieldValue = value.try_into()?;
                     let plen = *prefix_len;
+                    // Without the field's width, we can't check here that 'value' has no
+                    // 1-bits beyond the prefix.
                     if plen < 0 || plen > 128 {
                         Err(Error(RpcStatusCode::INVALID_ARGUMENT))
                             .context(format!("P4 prefix_len {} outside supported range [0,128]", plen))
-                    } else if plen < 128 && (value.0 >> plen) != 0 {
-                        Err(Error(RpcStatusCode::INVALID_ARGUMENT))
-                            .context(format!("P4 field value {} has 1-bits not in prefix_len {}", value, plen))
                     } else {
                         Ok(FieldMatch { field_id, match_type: FieldMatchType::LPM { value, plen: plen as usize }})
                     }
@@ -837,11 +845,7 @@ impl TryFrom<&proto::p4runtime::TableAction> for TableAction {
 
     fn try_from(ta: &proto::p4runtime::TableAction) -> Result<Self> {
         match &ta.field_type {
-            Some(TableAction_oneof_type::action(a)) =>
-                Ok(TableAction {
-                    action_id: a.action_id,
-                    params: a.params.iter().map(|x| x.try_into()).collect::<Result<Vec<_>>>()?,
-                }),
+            Some(TableAction_oneof_type::action(a)) => a.try_into(),
             Some(_) => Err(Error(RpcStatusCode::UNIMPLEMENTED))
                 .context(format!("unsupported TableAction type {:?}", ta)),
             None => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
@@ -849,22 +853,182 @@ impl TryFrom<&proto::p4runtime::TableAction> for TableAction {
         }
     }
 }
+impl TryFrom<&proto::p4runtime::Action> for TableAction {
+    type Error = anyhow::Error;
+
+    fn try_from(a: &proto::p4runtime::Action) -> Result<Self> {
+        Ok(TableAction {
+            action_id: a.action_id,
+            params: a.params.iter().map(|x| x.try_into()).collect::<Result<Vec<_>>>()?,
+        })
+    }
+}
+impl From<&TableAction> for proto::p4runtime::Action {
+    fn from(ta: &TableAction) -> proto::p4runtime::Action {
+        let (unknown_fields, cached_size) = Default::default();
+        proto::p4runtime::Action {
+            action_id: ta.action_id,
+            params: ta.params.iter().map(|param| param.into()).collect(),
+            unknown_fields, cached_size
+        }
+    }
+}
 impl From<&TableAction> for proto::p4runtime::TableAction {
     fn from(ta: &TableAction) -> proto::p4runtime::TableAction {
         let (unknown_fields, cached_size) = Default::default();
         proto::p4runtime::TableAction {
-            field_type: Some({
-                let (unknown_fields, cached_size) = Default::default();
-                TableAction_oneof_type::action(proto::p4runtime::Action {
-                    action_id: ta.action_id,
-                    params: ta.params.iter().map(|param| param.into()).collect(),
-                    unknown_fields, cached_size
-                })
+            field_type: Some(TableAction_oneof_type::action(ta.into())),
+            unknown_fields, cached_size
+        }
+    }
+}
+
+/// Identifier for a P4Runtime action profile.
+pub type ActionProfileId = u32;
+
+/// A reference from an entry in a table with an action profile to the member or group of the
+/// profile that supplies the entry's action.
+///
+/// Based on the `action_profile_member_id` and `action_profile_group_id` cases of [P4Runtime
+/// `TableAction`](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-action-profile-member-programming).
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ActionProfileRef {
+    /// The member with the given ID.
+    Member(u32),
+
+    /// The group with the given ID, which only an action profile with a selector can have.
+    Group(u32),
+}
+impl From<ActionProfileRef> for proto::p4runtime::TableAction {
+    fn from(apr: ActionProfileRef) -> proto::p4runtime::TableAction {
+        let (unknown_fields, cached_size) = Default::default();
+        proto::p4runtime::TableAction {
+            field_type: Some(match apr {
+                ActionProfileRef::Member(id) => TableAction_oneof_type::action_profile_member_id(id),
+                ActionProfileRef::Group(id) => TableAction_oneof_type::action_profile_group_id(id),
             }),
             unknown_fields, cached_size
         }
     }
 }
+impl Display for ActionProfileRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionProfileRef::Member(id) => write!(f, "member {}", id),
+            ActionProfileRef::Group(id) => write!(f, "group {}", id),
+        }
+    }
+}
+
+/// A member of an action profile, that is, an action with arguments that entries in the
+/// profile's tables can refer to by ID.
+///
+/// Based on the [P4Runtime
+/// specification](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-action-profile-member-programming).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ActionProfileMember {
+    /// Identifies the action profile by its [`Preamble::id`].  A value of zero acts as a wildcard
+    /// for read operations.
+    pub action_profile_id: ActionProfileId,
+
+    /// Member ID, which the controller chooses.  A value of zero acts as a wildcard for read
+    /// operations.
+    pub member_id: u32,
+
+    /// The member's action.  `None` is only allowed for specifying a member to be deleted or read.
+    pub action: Option<TableAction>,
+}
+impl TryFrom<&proto::p4runtime::ActionProfileMember> for ActionProfileMember {
+    type Error = anyhow::Error;
+
+    fn try_from(apm: &proto::p4runtime::ActionProfileMember) -> Result<Self> {
+        Ok(ActionProfileMember {
+            action_profile_id: apm.action_profile_id,
+            member_id: apm.member_id,
+            action: match apm.action.as_ref() {
+                Some(action) => Some(action.try_into()?),
+                None => None,
+            },
+        })
+    }
+}
+impl From<&ActionProfileMember> for proto::p4runtime::ActionProfileMember {
+    fn from(apm: &ActionProfileMember) -> proto::p4runtime::ActionProfileMember {
+        proto::p4runtime::ActionProfileMember {
+            action_profile_id: apm.action_profile_id,
+            member_id: apm.member_id,
+            action: apm.action.as_ref().map(|action| action.into()).into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A group of members of an action profile with a selector.  For each packet, the selector
+/// chooses one of the members, in proportion to their weights, to supply the action.
+///
+/// Based on the [P4Runtime
+/// specification](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-action-profile-group-programming).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ActionProfileGroup {
+    /// Identifies the action profile by its [`Preamble::id`].  A value of zero acts as a wildcard
+    /// for read operations.
+    pub action_profile_id: ActionProfileId,
+
+    /// Group ID, which the controller chooses.  A value of zero acts as a wildcard for read
+    /// operations.
+    pub group_id: u32,
+
+    /// The weight of each member in the group, indexed by member ID.  Each weight is positive.
+    pub members: BTreeMap<u32, u32>,
+
+    /// The maximum number of members that the group may ever have, or 0 for the action
+    /// profile's limit.
+    pub max_size: i32,
+}
+impl TryFrom<&proto::p4runtime::ActionProfileGroup> for ActionProfileGroup {
+    type Error = anyhow::Error;
+
+    fn try_from(apg: &proto::p4runtime::ActionProfileGroup) -> Result<Self> {
+        let mut members = BTreeMap::new();
+        for m in apg.members.iter() {
+            if m.weight <= 0 {
+                return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("member {} of group {} has nonpositive weight {}", m.member_id, apg.group_id, m.weight));
+            }
+            if members.insert(m.member_id, m.weight as u32).is_some() {
+                return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("member {} appears more than once in group {}", m.member_id, apg.group_id));
+            }
+        }
+        if apg.max_size < 0 {
+            return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("group {} has negative max_size {}", apg.group_id, apg.max_size));
+        }
+        Ok(ActionProfileGroup {
+            action_profile_id: apg.action_profile_id,
+            group_id: apg.group_id,
+            members,
+            max_size: apg.max_size,
+        })
+    }
+}
+impl From<&ActionProfileGroup> for proto::p4runtime::ActionProfileGroup {
+    fn from(apg: &ActionProfileGroup) -> proto::p4runtime::ActionProfileGroup {
+        proto::p4runtime::ActionProfileGroup {
+            action_profile_id: apg.action_profile_id,
+            group_id: apg.group_id,
+            members: apg.members.iter()
+                .map(|(&member_id, &weight)| proto::p4runtime::ActionProfileGroup_Member {
+                    member_id,
+                    weight: weight as i32,
+                    ..Default::default()
+                })
+                .collect(),
+            max_size: apg.max_size,
+            ..Default::default()
+        }
+    }
+}
 
 /// A [`grpcio::RpcStatusCode`] wrapper that implements [`std::error::Error`], to allow it
 /// to be used with [`anyhow::error`].
@@ -899,14 +1063,23 @@ pub struct TableKey {
 /// Value data within a [`TableEntry`].
 pub struct TableValue {
     /// The action to be taken when this entry is matched.  `None` is not allowed within a real
-    /// [`TableEntry`], only for specifying an entry to be deleted.
+    /// [`TableEntry`], only for specifying an entry to be deleted, except in a table with an
+    /// action profile, whose entries take their actions from `action_profile_ref` instead.
     pub action: Option<TableAction>,
 
+    /// In a table with an action profile, the member or group that supplies the entry's action.
+    /// `None` in tables without an action profile and for default entries.
+    pub action_profile_ref: Option<ActionProfileRef>,
+
     /// Arbitrary controller-specified metadata.  Deprecated by P4Runtime in favor of `metadata`.
     pub controller_metadata: u64,
 
     /// Arbitrary controller-specified metadata.
-    pub metadata: Vec<u8>
+    pub metadata: Vec<u8>,
+
+    /// How long the entry may go unused, in nanoseconds, before the server notifies the
+    /// controller, or 0 for no limit.  Only tables with idle notification support this.
+    pub idle_timeout_ns: i64
 }
 
 /// An entry within a [`Table`].
@@ -939,6 +1112,17 @@ impl TableEntry {
             }
         }
 
+        // An entry in a table with an action profile refers to a member or group of the profile
+        // instead of specifying an action.
+        let (action, action_profile_ref) = match te.action.as_ref() {
+            Some(ta) => match ta.field_type {
+                Some(TableAction_oneof_type::action_profile_member_id(id)) => (None, Some(ActionProfileRef::Member(id))),
+                Some(TableAction_oneof_type::action_profile_group_id(id)) => (None, Some(ActionProfileRef::Group(id))),
+                _ => (Some(ta.try_into()?), None),
+            },
+            None => (None, None),
+        };
+
         Ok(TableEntry {
             key: TableKey {
                 table_id: te.table_id,
@@ -947,12 +1131,11 @@ impl TableEntry {
                 is_default_action: te.is_default_action
             },
             value: TableValue {
-                action: match te.action.clone().into_option() {
-                    Some(table_action) => Some((&table_action).try_into()?),
-                    None => None
-                },
+                action,
+                action_profile_ref,
                 controller_metadata: te.controller_metadata,
                 metadata: te.metadata.clone(),
+                idle_timeout_ns: te.idle_timeout_ns,
             }
         })
     }
@@ -966,19 +1149,22 @@ impl TryFrom<&proto::p4runtime::TableEntry> for TableEntry {
 }
 impl From<&TableEntry> for proto::p4runtime::TableEntry {
     fn from(te: &TableEntry) -> proto::p4runtime::TableEntry {
-        let (meter_config, counter_data, meter_counter_data, idle_timeout_ns, time_since_last_hit, unknown_fields, cached_size)
+        let (meter_config, counter_data, meter_counter_data, time_since_last_hit, unknown_fields, cached_size)
             = Default::default();
         proto::p4runtime::TableEntry {
             table_id: te.key.table_id,
             field_match: te.key.matches.iter().map(|fm| fm.into()).collect(),
-            action: (&te.value.action).as_ref().map(|ta| ta.into()).into(),
+            action: match te.value.action_profile_ref {
+                Some(apr) => Some(apr.into()),
+                None => (&te.value.action).as_ref().map(|ta| ta.into()),
+            }.into(),
             priority: te.key.priority,
             controller_metadata: te.value.controller_metadata,
             meter_config,
             counter_data,
             meter_counter_data,
             is_default_action: te.key.is_default_action,
-            idle_timeout_ns,
+            idle_timeout_ns: te.value.idle_timeout_ns,
             time_since_last_hit,
             metadata: te.value.metadata.clone(),
             unknown_fields,
@@ -986,6 +1172,180 @@ impl From<&TableEntry> for proto::p4runtime::TableEntry {
         }
     }
 }
+impl TableEntry {
+    /// Returns an update that applies `update_type` to this entry.  The update can be directly
+    /// passed to [`write`].
+    pub fn to_update(&self, update_type: proto::p4runtime::Update_Type) -> proto::p4runtime::Update {
+        let mut entity = proto::p4runtime::Entity::new();
+        entity.set_table_entry(self.into());
+
+        let mut update = proto::p4runtime::Update::new();
+        update.set_field_type(update_type);
+        update.set_entity(entity);
+        update
+    }
+}
+
+impl TableEntry {
+    /// Checks this entry's key against the schema for `table`, the table that it is in: every
+    /// match must refer to a distinct match field in the table and agree with its match kind and
+    /// width, every exact-match field must be matched, and the priority must suit the table.  A
+    /// default entry must have no matches and no priority.
+    pub fn validate_key(&self, table: &Table) -> Result<()> {
+        if self.key.table_id != table.preamble.id {
+            return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("entry for table {} checked against table {}", self.key.table_id, table.preamble.name));
+        }
+        for (i, fm) in self.key.matches.iter().enumerate() {
+            let mf = match table.match_fields.iter().find(|mf| mf.preamble.id == fm.field_id) {
+                Some(mf) => mf,
+                None => return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("table {} has no match field {}", table.preamble.name, fm.field_id)),
+            };
+            if self.key.matches[..i].iter().any(|other| other.field_id == fm.field_id) {
+                return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("match field {} specified more than once", mf.preamble.name));
+            }
+            let name = &mf.preamble.name;
+            let values = match (&mf.match_type, &fm.match_type) {
+                (MatchType::Exact, FieldMatchType::Exact(value))
+                    | (MatchType::Optional, FieldMatchType::Optional(value)) => vec![value.0],
+                (MatchType::Ternary, FieldMatchType::Ternary { value, mask }) => {
+                    if value.0 & !mask.0 != 0 {
+                        return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                            .context(format!("{} value {} has 1-bits not in mask {}", name, value, mask));
+                    }
+                    vec![value.0, mask.0]
+                },
+                (MatchType::Range, FieldMatchType::Range(low, high)) => {
+                    if high.0 < low.0 {
+                        return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                            .context(format!("{} range {}...{} has high less than low", name, low, high));
+                    }
+                    vec![low.0, high.0]
+                },
+                (MatchType::LPM, FieldMatchType::LPM { value, plen }) => {
+                    let bit_width = mf.bit_width.max(0) as usize;
+                    if *plen > bit_width {
+                        return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                            .context(format!("{} prefix length {} exceeds field width {}", name, plen, bit_width));
+                    }
+                    let host_bits = bit_width - plen;
+                    if host_bits > 0 && (host_bits >= 128 || value.0 & ((1u128 << host_bits) - 1) != 0) {
+                        return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                            .context(format!("{} value {} has 1-bits beyond prefix length {}", name, value, plen));
+                    }
+                    vec![value.0]
+                },
+                _ => return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("match {} is the wrong kind for {}-match field {}",
+                                     fm.match_type, mf.match_type, name)),
+            };
+            for value in values {
+                check_bit_width(value, mf.bit_width, name)?;
+            }
+        }
+
+        if self.key.is_default_action {
+            if !self.key.matches.is_empty() {
+                return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("default action for {} must not have matches", table.preamble.name));
+            }
+            if self.key.priority != 0 {
+                return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("default action for {} must not have a priority", table.preamble.name));
+            }
+        } else {
+            for mf in &table.match_fields {
+                if mf.match_type == MatchType::Exact
+                    && !self.key.matches.iter().any(|fm| fm.field_id == mf.preamble.id) {
+                    return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                        .context(format!("missing value for exact-match field {}", mf.preamble.name));
+                }
+            }
+            let needs_priority = table.match_fields.iter().any(|mf| matches!(
+                mf.match_type, MatchType::Ternary | MatchType::Range | MatchType::Optional));
+            if needs_priority && self.key.priority <= 0 {
+                return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("entries in table {} require a positive priority", table.preamble.name));
+            } else if !needs_priority && self.key.priority != 0 {
+                return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("entries in table {} must not have a priority", table.preamble.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks this entry against the schema for `table`, the table that it is in, as an entry
+    /// to insert or modify: in addition to the checks in [`Self::validate_key`], the entry must
+    /// have an action that passes [`TableAction::validate`].  An entry in a table with an action
+    /// profile, other than the default entry, must instead refer to a member or group of the
+    /// profile, whose existence only the server can check.
+    pub fn validate(&self, table: &Table) -> Result<()> {
+        self.validate_key(table)?;
+
+        if table.action_profile_id().is_some() && !self.key.is_default_action {
+            return match (&self.value.action, self.value.action_profile_ref) {
+                (None, Some(_)) => Ok(()),
+                _ => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("entries in table {} must refer to an action profile member or group",
+                                     table.preamble.name)),
+            };
+        } else if let Some(apr) = self.value.action_profile_ref {
+            return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("{} in table {} may not refer to action profile {}",
+                                 if self.key.is_default_action { "default action" } else { "entry" },
+                                 table.preamble.name, apr));
+        }
+
+        match &self.value.action {
+            Some(action) => action.validate(table, self.key.is_default_action),
+            None => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("entry for table {} lacks an action", table.preamble.name)),
+        }
+    }
+}
+
+impl TableAction {
+    /// Checks this action against the schema for `table`: the table must allow the action, as
+    /// its default action if `is_default_action` is true and otherwise in its entries, and there
+    /// must be an argument of the proper width for each of the action's parameters.  Actions of
+    /// members of an action profile are checked as entry actions of one of the profile's tables.
+    pub fn validate(&self, table: &Table, is_default_action: bool) -> Result<()> {
+        let TableAction { action_id, params } = self;
+        let ar = match table.actions.iter().find(|ar| ar.action.preamble.id == *action_id) {
+            Some(ar) => ar,
+            None => return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("table {} has no action {}", table.preamble.name, action_id)),
+        };
+        if is_default_action && !ar.scope.may_be_default() {
+            return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("action {} may not be a default action", ar.action.preamble.name));
+        } else if !is_default_action && !ar.scope.may_be_entry() {
+            return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("action {} may only be a default action", ar.action.preamble.name));
+        }
+
+        for (i, ap) in params.iter().enumerate() {
+            let p = match ar.action.params.iter().find(|p| p.preamble.id == ap.param_id) {
+                Some(p) => p,
+                None => return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("action {} has no parameter {}", ar.action.preamble.name, ap.param_id)),
+            };
+            if params[..i].iter().any(|other| other.param_id == ap.param_id) {
+                return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("parameter {} specified more than once", p.preamble.name));
+            }
+            check_bit_width(ap.value.0, p.bit_width, &p.preamble.name)?;
+        }
+        if let Some(p) = ar.action.params.iter().find(|p| !params.iter().any(|ap| ap.param_id == p.preamble.id)) {
+            return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("missing argument for parameter {} of action {}",
+                                 p.preamble.name, ar.action.preamble.name));
+        }
+        Ok(())
+    }
+}
 
 #[cfg(feature = "ofp4")]
 use differential_datalog::record::{IntoRecord, Name, Record};
@@ -1044,6 +1404,17 @@ impl TableEntry {
     /// Converts this `TableEntry` into a DDlog Record.  The caller must specify the [`Table`] that
     /// the entry is inside.
     pub fn to_record(&self, table: &Table) -> Result<Record> {
+        let mut values = self.to_fields(table)?;
+        if values.len() == 1 && table.is_nerpa_singleton() {
+            Ok(values.pop().unwrap().1)
+        } else {
+            Ok(Record::NamedStruct(Name::Owned(table.base_name().into()), values))
+        }
+    }
+
+    /// Converts this `TableEntry` into the named fields of a DDlog record for [`Table`] `table`,
+    /// in the order that `p4info2ddlog` declares them.
+    pub fn to_fields(&self, table: &Table) -> Result<Vec<(Name, Record)>> {
         let mut values: Vec<(Name, Record)> = Vec::new();
         for mf in &table.match_fields {
             let fm = self.key.matches.iter().find(|fm| fm.field_id == mf.preamble.id);
@@ -1052,44 +1423,66 @@ impl TableEntry {
         if table.has_priority() {
             values.push((Name::from("priority"), self.key.priority.into_record()));
         }
-        match &self.value.action {
-            Some(TableAction { action_id, params }) => {
-                // Find the ActionRef corresponding to 'action_id'.
-                let ar = match table.actions.iter().find(|ar| ar.action.preamble.id == *action_id) {
-                    Some(ar) => ar,
-                    None => return Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("TableEntry {:?} references action not in table", self))
-                };
-
-                if ar.action.params.len() == 0 && table.entry_actions().count() == 1 {
-                    // This action doesn't have any parameters, and it's the only action.  Don't
-                    // include it in the output.
-                } else {
-                    let action_name = format!("{}Action{}", table.base_name(), ar.action.preamble.alias);
-                    let mut param_values: Vec<(Name, Record)> = Vec::new();
-                    for p in &ar.action.params {
-                        let arg = match params.iter().find(|arg| arg.param_id == p.preamble.id) {
-                            Some(arg) => arg,
-                            None => return Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context(format!("table entry lacks argument for parameter {:?}", p))?
-                        };
-                        let record = if p.is_nerpa_bool() {
-                            Record::Bool(arg.value.0 != 0)
-                        } else {
-                            Record::Int(arg.value.0.into())
-                        };
-                        param_values.push((Name::Owned(p.preamble.name.clone()), record));
-                    }
-                    values.push((Name::from("action"),
-                                 Record::NamedStruct(Name::Owned(action_name), param_values)));
-                };
-            },
-            None => ()
+        if let Some(action) = &self.value.action {
+            if let Some(record) = action.to_record(table)? {
+                values.push((Name::from("action"), record));
+            }
+        }
+        if let Some(apr) = self.value.action_profile_ref {
+            values.push((Name::from("action"), apr.to_record(table)));
         }
+        Ok(values)
+    }
+}
 
-        if values.len() == 1 && table.is_nerpa_singleton() {
-            Ok(values.pop().unwrap().1)
-        } else {
-            Ok(Record::NamedStruct(Name::Owned(table.base_name().into()), values))
+#[cfg(feature = "ofp4")]
+impl ActionProfileRef {
+    /// Converts this `ActionProfileRef` into the DDlog record for the `action` field of a record
+    /// for [`Table`] `table`, which has an action profile: `<Table>MemberRef{member_id}` or
+    /// `<Table>GroupRef{group_id}`.
+    pub fn to_record(&self, table: &Table) -> Record {
+        let (kind, field, id) = match *self {
+            ActionProfileRef::Member(id) => ("Member", "member_id", id),
+            ActionProfileRef::Group(id) => ("Group", "group_id", id),
+        };
+        Record::NamedStruct(Name::Owned(format!("{}{}Ref", table.base_name(), kind)),
+                            vec![(Name::from(field), id.into_record())])
+    }
+}
+
+#[cfg(feature = "ofp4")]
+impl TableAction {
+    /// Converts this `TableAction` into the DDlog record for the `action` field of a record for
+    /// [`Table`] `table`.  Returns `None` if the table has only one action, without any parameters,
+    /// in which case its records don't have an `action` field.
+    pub fn to_record(&self, table: &Table) -> Result<Option<Record>> {
+        // Find the ActionRef corresponding to 'action_id'.
+        let ar = match table.actions.iter().find(|ar| ar.action.preamble.id == self.action_id) {
+            Some(ar) => ar,
+            None => return Err(Error(RpcStatusCode::NOT_FOUND)).context(format!("action {:?} not in table", self))
+        };
+
+        if ar.action.params.len() == 0 && table.entry_actions().count() == 1 {
+            // This action doesn't have any parameters, and it's the only action.  Don't include it
+            // in the output.
+            return Ok(None);
+        }
+
+        let action_name = format!("{}Action{}", table.base_name(), ar.action.preamble.alias);
+        let mut param_values: Vec<(Name, Record)> = Vec::new();
+        for p in &ar.action.params {
+            let arg = match self.params.iter().find(|arg| arg.param_id == p.preamble.id) {
+                Some(arg) => arg,
+                None => return Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context(format!("table entry lacks argument for parameter {:?}", p))?
+            };
+            let record = if p.is_nerpa_bool() {
+                Record::Bool(arg.value.0 != 0)
+            } else {
+                Record::Int(arg.value.0.into())
+            };
+            param_values.push((Name::Owned(p.preamble.name.clone()), record));
         }
+        Ok(Some(Record::NamedStruct(Name::Owned(action_name), param_values)))
     }
 }
 
@@ -1284,7 +1677,7 @@ pub struct Table {
     /// Set of possible actions for the table.
     pub actions: Vec<ActionRef>,
     pub const_default_action: Option<Action>,
-    //action_profile: Option<ActionProfile>,
+    action_profile_id: Option<ActionProfileId>,
     //direct_counter: Option<DirectCounter>,
     //direct_meter: Option<DirectMeter>,
     max_entries: Option<u64>,
@@ -1312,6 +1705,11 @@ impl Table {
                     None
                 }
             },
+            action_profile_id: if t.implementation_id != 0 {
+                Some(t.implementation_id)
+            } else {
+                None
+            },
             max_entries: if t.size > 0 {
                 Some(t.size as u64)
             } else {
@@ -1348,6 +1746,48 @@ impl Table {
         self.match_fields.iter().any(|mf| mf.match_type != MatchType::Exact)
     }
 
+    /// Returns true if this table supports idle timeouts for its entries, otherwise false.
+    pub fn idle_notify(&self) -> bool {
+        self.idle_notify
+    }
+
+    /// Returns true if records for this table's entries in DDlog need an `action` field, that is,
+    /// if the table has more than one action that may be part of table entries or its only one has
+    /// parameters.
+    pub fn needs_actions(&self) -> bool {
+        let mut actions = self.entry_actions();
+        match (actions.next(), actions.next()) {
+            (Some(ar), None) => !ar.action.params.is_empty(),
+            (Some(_), Some(_)) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Returns true if DDlog has a `<Table>DefaultAction` relation for this table, through which
+    /// the controller can change its default action.  That is true if the table's records need an
+    /// `action` field, the P4 program doesn't fix the default action, and the table doesn't have
+    /// an action profile.
+    pub fn has_default_action_relation(&self) -> bool {
+        self.needs_actions() && self.const_default_action.is_none() && self.action_profile_id.is_none()
+    }
+
+    /// Returns the ID of this table's action profile, if it has one.  Entries in a table with an
+    /// action profile refer to members or groups of the profile instead of specifying actions.
+    pub fn action_profile_id(&self) -> Option<ActionProfileId> {
+        self.action_profile_id
+    }
+
+    /// Returns true if the P4 program fixes this table's entries, so that the controller may not
+    /// change them, otherwise false.
+    pub fn is_const_table(&self) -> bool {
+        self.is_const_table
+    }
+
+    /// Returns the maximum number of entries this table can hold, if the P4 program specifies it.
+    pub fn max_entries(&self) -> Option<u64> {
+        self.max_entries
+    }
+
     /// Returns only the actions that may be part of table entries, that is, actions with [`Scope`]
     /// of [`Scope::TableAndDefault`] or [`Scope::TableOnly`].
     pub fn entry_actions(&self) -> impl Iterator<Item=&ActionRef> {
@@ -1384,6 +1824,9 @@ impl Display for Table {
         if self.idle_notify {
             write!(f, "\tidle notify")?;
         }
+        if let Some(action_profile_id) = self.action_profile_id {
+            write!(f, "\taction profile {}", action_profile_id)?;
+        }
         Ok(())
     }
 }
@@ -1410,17 +1853,341 @@ impl From<&p4info::P4Info> for Switch {
     }
 }
 
-/// An error received from the dataplane.
-#[derive(Debug)]
-pub struct P4Error {
-    /// Error message.
-    pub message: String
+impl Switch {
+    /// Returns the table named `name`, which may be either the table's full name, e.g.
+    /// `MyIngress.vlan_incoming_exact`, or its alias, e.g. `vlan_incoming_exact`.
+    pub fn table_by_name(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|t| t.preamble.name == name)
+            .or_else(|| self.tables.iter().find(|t| t.preamble.alias == name))
+    }
+
+    /// Starts building an entry for the table named `table_name` (see [`Self::table_by_name`]).
+    /// Errors, such as an unknown table name, are reported by [`TableEntryBuilder::build`].
+    ///
+    /// ```ignore
+    /// let entry = switch.entry("MyIngress.vlan_incoming_exact")
+    ///     .exact("standard_metadata.ingress_port", 11)
+    ///     .exact("hdr.vlan.vid", 1)
+    ///     .action("vlan_incoming_forward")
+    ///     .param("port", 11)
+    ///     .build()?;
+    /// ```
+    pub fn entry(&self, table_name: &str) -> TableEntryBuilder<'_> {
+        let mut builder = TableEntryBuilder {
+            table: self.table_by_name(table_name),
+            action: None,
+            action_profile_ref: None,
+            matches: Vec::new(),
+            params: Vec::new(),
+            priority: 0,
+            is_default_action: false,
+            controller_metadata: 0,
+            metadata: Vec::new(),
+            error: None,
+        };
+        if builder.table.is_none() {
+            builder.fail(RpcStatusCode::NOT_FOUND, format!("no table named {}", table_name));
+        }
+        builder
+    }
+}
+
+impl Table {
+    /// Returns the match field in this table named `name`, e.g. `hdr.vlan.vid`.
+    pub fn match_field_by_name(&self, name: &str) -> Option<&MatchField> {
+        self.match_fields.iter().find(|mf| mf.preamble.name == name)
+    }
+
+    /// Returns the action in this table named `name`, which may be either the action's full name,
+    /// e.g. `MyIngress.drop`, or its alias, e.g. `drop`.
+    pub fn action_by_name(&self, name: &str) -> Option<&ActionRef> {
+        self.actions.iter().find(|ar| ar.action.preamble.name == name)
+            .or_else(|| self.actions.iter().find(|ar| ar.action.preamble.alias == name))
+    }
+}
+
+impl Action {
+    /// Returns this action's parameter named `name`.
+    pub fn param_by_name(&self, name: &str) -> Option<&Param> {
+        self.params.iter().find(|p| p.preamble.name == name)
+    }
+}
+
+/// Returns an error if `value` doesn't fit in `bit_width` bits.
+fn check_bit_width(value: u128, bit_width: i32, what: &str) -> Result<()> {
+    if bit_width < 128 && (bit_width < 0 || value >> bit_width != 0) {
+        Err(Error(RpcStatusCode::OUT_OF_RANGE))
+            .context(format!("{} value {} does not fit in {} bits", what, FieldValue(value), bit_width))
+    } else {
+        Ok(())
+    }
+}
+
+/// Builds a [`TableEntry`] by P4 names instead of numeric IDs.  Obtain one from
+/// [`Switch::entry`].
+///
+/// Each method resolves names through the P4Info model, and [`Self::build`] checks the result
+/// against the table's schema with [`TableEntry::validate`].  The first error is kept and
+/// reported by [`Self::build`], so that calls can be chained without checking each one.
+pub struct TableEntryBuilder<'a> {
+    table: Option<&'a Table>,
+    action: Option<&'a ActionRef>,
+    action_profile_ref: Option<ActionProfileRef>,
+    matches: Vec<FieldMatch>,
+    params: Vec<ActionParam>,
+    priority: i32,
+    is_default_action: bool,
+    controller_metadata: u64,
+    metadata: Vec<u8>,
+    error: Option<anyhow::Error>,
+}
+
+impl<'a> TableEntryBuilder<'a> {
+    fn fail(&mut self, code: RpcStatusCode, message: String) {
+        if self.error.is_none() {
+            self.error = Some(anyhow::Error::new(Error(code)).context(message));
+        }
+    }
+
+    /// Adds `match_type` as the match for the match field named `name`.  [`Self::build`] checks
+    /// that the match suits the field.
+    fn add_match(&mut self, name: &str, match_type: FieldMatchType) {
+        if self.error.is_some() {
+            return;
+        }
+        let table = match self.table {
+            Some(table) => table,
+            None => return,
+        };
+        match table.match_field_by_name(name) {
+            Some(mf) => self.matches.push(FieldMatch { field_id: mf.preamble.id, match_type }),
+            None => self.fail(RpcStatusCode::NOT_FOUND,
+                              format!("table {} has no match field {}", table.preamble.name, name)),
+        }
+    }
+
+    /// Matches exact-match field `name` against `value`.
+    pub fn exact(mut self, name: &str, value: u128) -> Self {
+        self.add_match(name, FieldMatchType::Exact(FieldValue(value)));
+        self
+    }
+
+    /// Matches ternary-match field `name` against the bits in `value` that are 1-bits in `mask`.
+    pub fn ternary(mut self, name: &str, value: u128, mask: u128) -> Self {
+        self.add_match(name, FieldMatchType::Ternary { value: FieldValue(value), mask: FieldValue(mask) });
+        self
+    }
+
+    /// Matches the high-order `plen` bits of LPM-match field `name` against those of `value`.
+    pub fn lpm(mut self, name: &str, value: u128, plen: usize) -> Self {
+        self.add_match(name, FieldMatchType::LPM { value: FieldValue(value), plen });
+        self
+    }
+
+    /// Matches range-match field `name` against `low...high`, inclusive.
+    pub fn range(mut self, name: &str, low: u128, high: u128) -> Self {
+        self.add_match(name, FieldMatchType::Range(FieldValue(low), FieldValue(high)));
+        self
+    }
+
+    /// Matches optional-match field `name` against `value`.
+    pub fn optional(mut self, name: &str, value: u128) -> Self {
+        self.add_match(name, FieldMatchType::Optional(FieldValue(value)));
+        self
+    }
+
+    /// Sets the entry's priority.  Tables that have ternary, range, or optional match fields
+    /// require a nonzero priority.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Makes this entry the table's default action, which has no matches.
+    pub fn default_action(mut self) -> Self {
+        self.is_default_action = true;
+        self
+    }
+
+    /// Sets the entry's action to the one named `name`, which may be either the action's full
+    /// name or its alias (see [`Table::action_by_name`]).
+    pub fn action(mut self, name: &str) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        let table = match self.table {
+            Some(table) => table,
+            None => return self,
+        };
+        if self.action.is_some() || self.action_profile_ref.is_some() {
+            self.fail(RpcStatusCode::INVALID_ARGUMENT, format!("action specified more than once"));
+            return self;
+        }
+        match table.action_by_name(name) {
+            Some(ar) => self.action = Some(ar),
+            None => self.fail(RpcStatusCode::NOT_FOUND,
+                              format!("table {} has no action {}", table.preamble.name, name)),
+        }
+        self
+    }
+
+    /// Makes the entry, in a table with an action profile, take its action from the profile's
+    /// member with ID `member_id`.
+    pub fn member(self, member_id: u32) -> Self {
+        self.set_action_profile_ref(ActionProfileRef::Member(member_id))
+    }
+
+    /// Makes the entry, in a table with an action profile that has a selector, take its action
+    /// from a member of the profile's group with ID `group_id`.
+    pub fn group(self, group_id: u32) -> Self {
+        self.set_action_profile_ref(ActionProfileRef::Group(group_id))
+    }
+
+    fn set_action_profile_ref(mut self, apr: ActionProfileRef) -> Self {
+        if self.action.is_some() || self.action_profile_ref.is_some() {
+            self.fail(RpcStatusCode::INVALID_ARGUMENT, format!("action specified more than once"));
+        } else {
+            self.action_profile_ref = Some(apr);
+        }
+        self
+    }
+
+    /// Supplies `value` as the argument for the action's parameter named `name`.  Must follow
+    /// [`Self::action`].
+    pub fn param(mut self, name: &str, value: u128) -> Self {
+        if self.error.is_some() || self.table.is_none() {
+            return self;
+        }
+        let ar = match self.action {
+            Some(ar) => ar,
+            None => {
+                self.fail(RpcStatusCode::INVALID_ARGUMENT,
+                          format!("parameter {} specified before action", name));
+                return self;
+            }
+        };
+        match ar.action.param_by_name(name) {
+            None => self.fail(RpcStatusCode::NOT_FOUND,
+                              format!("action {} has no parameter {}", ar.action.preamble.name, name)),
+            Some(p) => self.params.push(ActionParam { param_id: p.preamble.id, value: FieldValue(value) }),
+        }
+        self
+    }
+
+    /// Sets the entry's arbitrary controller-specified metadata.
+    pub fn metadata(mut self, metadata: Vec<u8>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Sets the entry's deprecated controller-specified metadata.
+    pub fn controller_metadata(mut self, controller_metadata: u64) -> Self {
+        self.controller_metadata = controller_metadata;
+        self
+    }
+
+    /// Returns the entry that has been built, or the first error encountered in building it.
+    ///
+    /// Without an action, member, or group, the entry is only suitable for deletion, and it must
+    /// pass [`TableEntry::validate_key`].  Otherwise, it must pass [`TableEntry::validate`], so
+    /// every one of the action's parameters must have been supplied.
+    pub fn build(self) -> Result<TableEntry> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let table = self.table.unwrap();
+        let action = self.action.map(|ar| TableAction { action_id: ar.action.preamble.id, params: self.params });
+        let entry = TableEntry {
+            key: TableKey {
+                table_id: table.preamble.id,
+                matches: self.matches,
+                priority: self.priority,
+                is_default_action: self.is_default_action,
+            },
+            value: TableValue {
+                action,
+                action_profile_ref: self.action_profile_ref,
+                controller_metadata: self.controller_metadata,
+                metadata: self.metadata,
+                idle_timeout_ns: 0,
+            }
+        };
+        if entry.value.action.is_some() || entry.value.action_profile_ref.is_some() {
+            entry.validate(table)?;
+        } else {
+            entry.validate_key(table)?;
+        }
+        Ok(entry)
+    }
+}
+
+/// An error from one of the functions that talk to a P4Runtime device.
+#[derive(Debug, Error)]
+pub enum P4Error {
+    /// Reading a local file, such as a P4Info or device configuration, failed.
+    #[error("{path}: {source}")]
+    Io {
+        /// Name of the file.
+        path: String,
+        /// The underlying error.
+        source: std::io::Error,
+    },
+
+    /// A protobuf message, such as a P4Info file, could not be parsed.
+    #[error("{what}: {source}")]
+    Parse {
+        /// What was being parsed.
+        what: String,
+        /// The underlying error.
+        source: protobuf::ProtobufError,
+    },
+
+    /// An RPC failed, either with a status code from the device or because of a transport error.
+    #[error("{target}: {rpc} failed: {source}")]
+    Rpc {
+        /// Device that the RPC was sent to.
+        target: String,
+        /// Name of the RPC.
+        rpc: &'static str,
+        /// The underlying error.
+        source: grpcio::Error,
+    },
+
+    /// The device replied in a way that the P4Runtime specification does not allow.
+    #[error("{target}: {message}")]
+    Spec {
+        /// Device that replied.
+        target: String,
+        /// Description of the problem.
+        message: String,
+    },
+
+    /// An argument passed to a `p4ext` function was invalid.
+    #[error("{0}")]
+    InvalidArgument(String),
 }
 
-impl fmt::Display for P4Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.message)
+impl P4Error {
+    /// Returns the status code that the device reported, if this is an RPC failure with a status.
+    pub fn status_code(&self) -> Option<RpcStatusCode> {
+        match self {
+            P4Error::Rpc { source: grpcio::Error::RpcFailure(status), .. } => Some(status.code()),
+            _ => None,
+        }
     }
+
+    fn rpc(target: &str, rpc: &'static str) -> impl FnOnce(grpcio::Error) -> Self {
+        let target = target.to_string();
+        move |source| P4Error::Rpc { target, rpc, source }
+    }
+}
+
+/// Reads and parses the binary P4Info file at `path`.
+pub fn load_p4info(path: &str) -> Result<p4info::P4Info, P4Error> {
+    let mut file = fs::File::open(OsStr::new(path))
+        .map_err(|source| P4Error::Io { path: path.to_string(), source })?;
+    Message::parse_from_reader(&mut file)
+        .map_err(|source| P4Error::Parse { what: format!("{}: P4Info", path), source })
 }
 
 /// Necessary data to test library function.
@@ -1445,17 +2212,26 @@ pub struct TestSetup {
     pub table_name: String,
     /// Name of the action.
     pub action_name: String,
-    /// Action parameters mapped to values.
-    // TODO: Change this data type.
-    pub params_values: HashMap<String, u16>,
-    /// Match fields mapped to values.
-    // TODO: Change this data type.
-    pub match_fields_map: HashMap<String, u16>,
+    /// The in-process switch, if the setup isn't using `simple_switch_grpc`.  It keeps running
+    /// for as long as the setup exists.
+    pub mock: Option<mock::MockServer>,
+    /// The stream over which `client` became primary, if it has.
+    stream: Option<(StreamingCallSink<StreamMessageRequest>, ClientDuplexReceiver<StreamMessageResponse>)>,
 }
 
 impl TestSetup {
-    /// Set up a switch for testing.
+    /// Set up an in-process [`mock::MockServer`] for testing.  Each setup listens on its own port,
+    /// so tests can run in parallel.
     pub fn new() -> Self {
+        let server = mock::MockServer::start(0)
+            .unwrap_or_else(|err| panic!("mock server didn't start: {:#}", err));
+        let client = server.client();
+        Self::with_target(server.target(), client, Some(server))
+    }
+
+    /// Set up a `simple_switch_grpc` switch for testing.  The switch must have been built in
+    /// `$NERPA_DEPS`, and it listens on port 50051, so only one such setup can exist at a time.
+    pub fn new_bmv2() -> Self {
         let deps_var = "NERPA_DEPS";
         let switch_path = "behavioral-model/targets/simple_switch_grpc/simple_switch_grpc";
 
@@ -1485,13 +2261,10 @@ impl TestSetup {
         let ch = ChannelBuilder::new(env).connect(target);
         let client = P4RuntimeClient::new(ch);
 
-        let mut params_values : HashMap<String, u16> = HashMap::new();
-        params_values.insert("port".to_string(), 11);
-        let mut match_fields_map : HashMap<String, u16> = HashMap::new();
-        match_fields_map.insert("standard_metadata.ingress_port".to_string(), 11);
-        match_fields_map.insert("hdr.vlan.vid".to_string(), 1);
-
+        Self::with_target(target.to_string(), client, None)
+    }
 
+    fn with_target(target: String, client: P4RuntimeClient, mock: Option<mock::MockServer>) -> Self {
         Self {
             p4info: "examples/vlan/vlan.p4info.bin".to_string(),
             json: "examples/vlan/vlan.json".to_string(),
@@ -1499,13 +2272,35 @@ impl TestSetup {
             action: "verify-and-commit".to_string(),
             device_id: 0,
             role_id: 0,
-            target: target.to_string(),
-            client: client,
+            target,
+            client,
             table_name: "MyIngress.vlan_incoming_exact".to_string(),
             action_name: "MyIngress.vlan_incoming_forward".to_string(),
-            params_values: params_values,
-            match_fields_map: match_fields_map,
+            mock,
+            stream: None,
+        }
+    }
+
+    /// Makes the setup's client the primary controller for `role_id`, using election ID 0, which
+    /// is the election ID that [`write`] and [`set_pipeline_config`] send.  The client stays
+    /// primary for as long as the setup exists.
+    pub async fn become_primary(&mut self) {
+        let mut update = MasterArbitrationUpdate::new();
+        update.set_device_id(self.device_id);
+        update.mut_role().set_id(self.role_id);
+        let mut request = StreamMessageRequest::new();
+        request.set_arbitration(update);
+
+        let (mut sink, mut receiver) = self.client.stream_channel().unwrap();
+        sink.send((request, WriteFlags::default())).await.unwrap();
+        loop {
+            let response = receiver.try_next().await.unwrap().expect("stream closed during arbitration");
+            if response.has_arbitration() {
+                assert_eq!(response.get_arbitration().get_status().code, i32::from(RpcStatusCode::OK));
+                break;
+            }
         }
+        self.stream = Some((sink, receiver));
     }
 }
 
@@ -1516,13 +2311,13 @@ impl TestSetup {
 /// # Arguments
 /// * `p4info_str` - filepath for the p4info binary file.
 /// * `json_str` - filepath for the compiled P4 program's JSON representation.
-/// * `cookie_str` - cookie for the forwarding config.
+/// * `cookie_str` - cookie for the forwarding config, as a decimal integer, or empty for none.
 /// * `action_str` - action for the forwarding pipeline.
 /// * `device_id` - ID of the P4-enabled device.
 /// * `role_id` - the controller's desired role.
 /// * `target` - entity hosting P4 Runtime.
 /// * `client` - P4 Runtime client.
-pub fn set_pipeline_config(
+pub async fn set_pipeline_config(
     p4info_str: &str,
     json_str: &str,
     cookie_str: &str,
@@ -1531,29 +2326,34 @@ pub fn set_pipeline_config(
     role_id: u64,
     target: &str,
     client: &P4RuntimeClient,
-) {
-    let p4info_os: &OsStr = OsStr::new(p4info_str);
-    let mut p4info_file = fs::File::open(p4info_os)
-        .unwrap_or_else(|err| panic!("{}: could not open P4Info ({})", p4info_str, err));
-    let p4info = Message::parse_from_reader(&mut p4info_file)
-        .unwrap_or_else(|err| panic!("{}: could not read P4Info ({})", p4info_str, err));
-
-    let json_filename = OsStr::new(json_str);
-    let json = fs::read(json_filename).unwrap_or_else(|err| {
-        panic!(
-            "{}: could not read json data ({})",
-            json_filename.to_string_lossy(),
-            err
-        )
-    });
+) -> Result<(), P4Error> {
+    let request = pipeline_config_request(p4info_str, json_str, cookie_str, action_str, device_id, role_id)?;
+    send_pipeline_config(request, target, client).await
+}
+
+/// Returns a `SetForwardingPipelineConfigRequest` with the arguments described for
+/// [`set_pipeline_config`].
+pub(crate) fn pipeline_config_request(
+    p4info_str: &str,
+    json_str: &str,
+    cookie_str: &str,
+    action_str: &str,
+    device_id: u64,
+    role_id: u64,
+) -> Result<SetForwardingPipelineConfigRequest, P4Error> {
+    let p4info = load_p4info(p4info_str)?;
+    let json = fs::read(OsStr::new(json_str))
+        .map_err(|source| P4Error::Io { path: json_str.to_string(), source })?;
 
     let mut config = ForwardingPipelineConfig::new();
     config.set_p4_device_config(json);
     config.set_p4info(p4info);
 
-    if cookie_str != "" {
+    if !cookie_str.is_empty() {
+        let cookie = cookie_str.parse::<u64>()
+            .map_err(|_| P4Error::InvalidArgument(format!("{}: invalid pipeline cookie", cookie_str)))?;
         let mut cookie_jar = ForwardingPipelineConfig_Cookie::new();
-        cookie_jar.set_cookie(str::parse::<u64>(&cookie_str).unwrap());
+        cookie_jar.set_cookie(cookie);
         config.set_cookie(cookie_jar);
     }
 
@@ -1570,45 +2370,52 @@ pub fn set_pipeline_config(
     set_pipeline_request.set_device_id(device_id);
     set_pipeline_request.set_role_id(role_id);
     set_pipeline_request.set_config(config);
-    client
-        .set_forwarding_pipeline_config(&set_pipeline_request)
-        .unwrap_or_else(|err| panic!("{}: failed to set forwarding pipeline ({})", target, err));
+    Ok(set_pipeline_request)
+}
+
+/// Sends `request` to `client` and waits for the reply.
+pub(crate) async fn send_pipeline_config(
+    request: SetForwardingPipelineConfigRequest,
+    target: &str,
+    client: &P4RuntimeClient,
+) -> Result<(), P4Error> {
+    let on_error = P4Error::rpc(target, "SetForwardingPipelineConfig");
+    match client.set_forwarding_pipeline_config_async(&request) {
+        Ok(receiver) => receiver.await.map(|_| ()).map_err(on_error),
+        Err(e) => Err(on_error(e)),
+    }
 }
 
 /// Retrieve configuration for the forwarding pipeline.
 ///
 /// Calls the [`GetForwardingPipelineConfig` RPC](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-getforwardingpipelineconfig-rpc).
 ///
-/// Panics if unable to get configuration from the provided device.
+/// Fails if the device's reply does not include a P4Info.
 ///
 /// # Arguments
 /// * `device_id` - ID of the P4 device to get config for.
 /// * `target` - hardware/software entity hosting P4 Runtime.
 /// * `client` - P4 Runtime client.
-pub fn get_pipeline_config(
+pub async fn get_pipeline_config(
     device_id: u64,
     target: &str,
     client: &P4RuntimeClient
-) -> ForwardingPipelineConfig {
+) -> Result<ForwardingPipelineConfig, P4Error> {
     let mut get_pipeline_request = GetForwardingPipelineConfigRequest::new();
     get_pipeline_request.set_device_id(device_id);
     get_pipeline_request.set_response_type(
         proto::p4runtime::GetForwardingPipelineConfigRequest_ResponseType::P4INFO_AND_COOKIE,
     );
 
-    let pipeline_response = client
-        .get_forwarding_pipeline_config(&get_pipeline_request)
-        .unwrap_or_else(|err| {
-            panic!(
-                "{}: failed to retrieve forwarding pipeline ({})",
-                target, err
-            )
-        });
-    let pipeline = pipeline_response.get_config();
-    if !pipeline.has_p4info() {
-        panic!("{}: device did not return P4Info", target);
+    let on_error = P4Error::rpc(target, "GetForwardingPipelineConfig");
+    let mut pipeline_response = match client.get_forwarding_pipeline_config_async(&get_pipeline_request) {
+        Ok(receiver) => receiver.await.map_err(on_error)?,
+        Err(e) => return Err(on_error(e)),
+    };
+    if !pipeline_response.get_config().has_p4info() {
+        return Err(P4Error::Spec { target: target.to_string(), message: "device did not return P4Info".into() });
     }
-    pipeline.clone()
+    Ok(pipeline_response.take_config())
 }
 
 /// Build an update for a [table entry](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-table-entry).
@@ -1655,7 +2462,7 @@ pub fn build_table_entry_update(
 /// * `role_id` - role of the controller.
 /// * `target` - entity hosting P4 runtime, used for debugging.
 /// * `client` - P4 Runtime client.
-pub fn write(
+pub async fn write(
     updates: Vec<proto::p4runtime::Update>,
     device_id: u64,
     role_id: u64,
@@ -1666,16 +2473,22 @@ pub fn write(
     write_request.set_device_id(device_id);
     write_request.set_role_id(role_id);
     write_request.set_updates(RepeatedField::from_vec(updates));
+    send_write(write_request, target, client).await
+}
 
-    match client.write(&write_request) {
-        Ok(_w) => Ok(()),
-        Err(e) => Err(P4Error{message: format!("{}, {}, {}: failed to write request ({})", target, device_id, role_id, e)}), 
+/// Sends `request` to `client` and waits for the reply.
+pub(crate) async fn send_write(request: WriteRequest, target: &str, client: &P4RuntimeClient) -> Result<(), P4Error> {
+    let on_error = P4Error::rpc(target, "Write");
+    match client.write_async(&request) {
+        Ok(receiver) => receiver.await.map(|_| ()).map_err(on_error),
+        Err(e) => Err(on_error(e)),
     }
 }
 
 /// Retrieve one or more P4 entities.
 ///
-/// Calls the [`Read RPC`](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-read-rpc).
+/// Calls the [`Read RPC`](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-read-rpc)
+/// and collects the entities from every response in the stream.
 ///
 /// # Arguments
 /// * `entities` - a list of P4 entities, each acting as a query filter.
@@ -1690,16 +2503,14 @@ pub async fn read(
     read_request.set_device_id(device_id);
     read_request.set_entities(RepeatedField::from_vec(entities));
 
-    let mut stream = match client.read(&read_request) {
-        Ok(r) => r.enumerate(),
-        Err(e) => return Err(P4Error {message: format!("{}: failed to read request({})", device_id, e)}),
-    };
+    let target = format!("device {}", device_id);
+    let mut stream = client.read(&read_request).map_err(P4Error::rpc(&target, "Read"))?;
 
-    let (_, response) = stream.next().await.unwrap();
-    match response {
-        Ok(r) => Ok(r.get_entities().to_vec()),
-        Err(e) => Err(P4Error{ message: format!("{}: received invalid response({})", device_id, e)}),
+    let mut entities = Vec::new();
+    while let Some(mut response) = stream.try_next().await.map_err(P4Error::rpc(&target, "Read"))? {
+        entities.extend(response.take_entities().into_iter());
     }
+    Ok(entities)
 }
 
 /// Return the response for a request over the streaming channel.
@@ -1708,22 +2519,29 @@ pub async fn read(
 /// used for session management and packet I/O,
 /// among other [stream messages](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-p4runtime-stream-messages).
 ///
+/// If `request` is an arbitration update, the response is the arbitration reply, skipping any
+/// other messages that the switch sends first.  Otherwise, it is the first message that the
+/// switch sends.
+///
 /// # Arguments
 /// * `request` - request to send over the channel.
 /// * `client` - P4 Runtime client.
 pub async fn stream_channel_request(
     request: StreamMessageRequest,
     client: &P4RuntimeClient,
-) -> Result<StreamMessageResponse, grpcio::Error> {
-    let (mut sink, mut receiver) = client.stream_channel().unwrap();
-
-    let send_result = sink.send((request, WriteFlags::default())).await;
-    match send_result {
-        Ok(_) => {},
-        Err(e) => return Err(e),
-    };
-
-    receiver.next().await.unwrap()
+) -> Result<StreamMessageResponse, P4Error> {
+    let target = "stream channel";
+    let (mut sink, mut receiver) = client.stream_channel().map_err(P4Error::rpc(target, "StreamChannel"))?;
+
+    let is_arbitration = request.has_arbitration();
+    sink.send((request, WriteFlags::default())).await.map_err(P4Error::rpc(target, "StreamChannel"))?;
+    loop {
+        match receiver.try_next().await.map_err(P4Error::rpc(target, "StreamChannel"))? {
+            Some(response) if is_arbitration && !response.has_arbitration() => continue,
+            Some(response) => return Ok(response),
+            None => return Err(P4Error::Spec { target: target.into(), message: "stream closed without a response".into() }),
+        }
+    }
 }
 
 /// Send a master arbitration update to the switch.
@@ -1736,7 +2554,7 @@ pub async fn stream_channel_request(
 pub async fn master_arbitration_update(
     device_id: u64,
     client: &P4RuntimeClient
-) -> Result<StreamMessageResponse, grpcio::Error> {
+) -> Result<StreamMessageResponse, P4Error> {
     let mut update = MasterArbitrationUpdate::new();
     update.set_device_id(device_id);
 
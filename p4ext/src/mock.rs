@@ -0,0 +1,624 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! An in-process P4Runtime server for tests.
+//!
+//! [`MockServer`] implements the P4Runtime service without a data plane.  It keeps the forwarding
+//! pipeline, table entries, multicast groups, digest configuration, and controller arbitration in
+//! memory and reports errors with the status codes that the P4Runtime specification requires.  It
+//! listens on an ephemeral port on the loopback interface, so tests that use it don't need
+//! `simple_switch_grpc` and can run in parallel.
+
+use anyhow::{Context, Result};
+
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::{FutureExt, SinkExt, StreamExt, TryFutureExt, TryStreamExt};
+
+use grpcio::{
+    ChannelBuilder,
+    DuplexSink,
+    EnvBuilder,
+    Environment,
+    RequestStream,
+    RpcContext,
+    RpcStatus,
+    RpcStatusCode,
+    Server,
+    ServerBuilder,
+    ServerStreamingSink,
+    UnarySink,
+    WriteFlags,
+};
+
+use proto::p4runtime::{
+    CapabilitiesRequest,
+    CapabilitiesResponse,
+    DigestEntry,
+    DigestEntry_Config,
+    Entity,
+    Entity_oneof_entity,
+    ForwardingPipelineConfig,
+    GetForwardingPipelineConfigRequest,
+    GetForwardingPipelineConfigRequest_ResponseType,
+    GetForwardingPipelineConfigResponse,
+    MasterArbitrationUpdate,
+    PacketReplicationEngineEntry,
+    PacketReplicationEngineEntry_oneof_type,
+    ReadRequest,
+    ReadResponse,
+    SetForwardingPipelineConfigRequest,
+    SetForwardingPipelineConfigRequest_Action,
+    SetForwardingPipelineConfigResponse,
+    StreamMessageRequest,
+    StreamMessageRequest_oneof_update,
+    StreamMessageResponse,
+    Uint128,
+    Update_Type,
+    WriteRequest,
+    WriteRequest_Atomicity,
+    WriteResponse,
+};
+use proto::p4runtime_grpc::{P4Runtime, P4RuntimeClient, create_p4_runtime};
+
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+
+use super::{Action, Error, MulticastGroupEntry, Table, TableEntry};
+use super::server::{
+    Capabilities,
+    Entities,
+    server_streaming_fail,
+    server_streaming_success,
+    unary_fail,
+    unary_success,
+    validate_table_write,
+    validate_write,
+    write_status,
+};
+use super::session::{election_id_to_u128, u128_to_election_id};
+
+/// A running in-process P4Runtime server.  The server stops when this is dropped.
+pub struct MockServer {
+    _server: Server,
+    port: u16,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockServer {
+    /// Starts a server for a single device with ID `device_id`, listening on an ephemeral port
+    /// on 127.0.0.1.
+    pub fn start(device_id: u64) -> Result<Self> {
+        let env = Arc::new(Environment::new(1));
+        let state = Arc::new(Mutex::new(MockState::new(device_id)));
+        let service = create_p4_runtime(MockService { state: state.clone() });
+        let mut server = ServerBuilder::new(env)
+            .register_service(service)
+            .bind("127.0.0.1", 0)
+            .build()
+            .context("failed to create mock P4Runtime server")?;
+        server.start();
+
+        let port = match server.bind_addrs().next() {
+            Some((_, port)) => port,
+            None => anyhow::bail!("mock P4Runtime server did not bind to a port"),
+        };
+        Ok(MockServer { _server: server, port, state })
+    }
+
+    /// Returns the port that the server is listening on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the server's address, in the form `127.0.0.1:<port>`.
+    pub fn target(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+
+    /// Returns a new client connected to the server.
+    pub fn client(&self) -> P4RuntimeClient {
+        let env = Arc::new(EnvBuilder::new().build());
+        P4RuntimeClient::new(ChannelBuilder::new(env).connect(&self.target()))
+    }
+
+    /// Returns a copy of the table entries and multicast groups that the server holds.
+    pub fn entities(&self) -> Entities {
+        self.state.lock().unwrap().entities.clone()
+    }
+
+    /// Sends `message`, e.g. a digest list or a packet-in, over the stream channel to the primary
+    /// controller for each role, as if the data plane had produced it.
+    pub fn send_stream_message(&self, message: StreamMessageResponse) {
+        let state = self.state.lock().unwrap();
+        let mut roles: Vec<u64> = state.controllers.values().map(|c| c.role_id).collect();
+        roles.sort_unstable();
+        roles.dedup();
+        for role_id in roles {
+            if let Some(primary) = state.primary(role_id) {
+                let _ = primary.sender.unbounded_send(message.clone());
+            }
+        }
+    }
+}
+
+/// A controller connected over the stream channel.
+struct Controller {
+    role_id: u64,
+    election_id: u128,
+    sender: mpsc::UnboundedSender<StreamMessageResponse>,
+}
+
+struct MockState {
+    device_id: u64,
+
+    // Configuration state.
+    config: Option<ForwardingPipelineConfig>,
+    saved_config: Option<ForwardingPipelineConfig>,
+    tables: HashMap<u32, Table>,
+    digest_ids: BTreeSet<u32>,
+
+    // Entity state.
+    entities: Entities,
+    digests: HashMap<u32, DigestEntry_Config>,
+
+    // The number of entries in each table that has any, indexed by table ID.
+    n_entries: HashMap<u32, u64>,
+
+    // Arbitration state, indexed by stream.
+    controllers: HashMap<u64, Controller>,
+    next_stream_id: u64,
+
+    // The highest election ID that any controller has sent for each role, indexed by role ID.
+    election_ids: HashMap<u64, u128>,
+}
+
+fn fail<T>(code: RpcStatusCode, message: String) -> Result<T> {
+    Err(Error(code)).context(message)
+}
+
+/// What the mock supports.
+fn capabilities() -> Capabilities {
+    Capabilities::new(&["table-entry", "multicast-group-entry", "digest-entry"],
+                      &[WriteRequest_Atomicity::CONTINUE_ON_ERROR])
+}
+
+impl MockState {
+    fn new(device_id: u64) -> Self {
+        MockState {
+            device_id,
+            config: None,
+            saved_config: None,
+            tables: HashMap::new(),
+            digest_ids: BTreeSet::new(),
+            entities: Entities::default(),
+            digests: HashMap::new(),
+            n_entries: HashMap::new(),
+            controllers: HashMap::new(),
+            next_stream_id: 0,
+            election_ids: HashMap::new(),
+        }
+    }
+
+    /// Returns the primary controller for `role_id`, which is the one whose election ID is the
+    /// highest that any controller has sent for the role.  Returns `None` if that controller has
+    /// disconnected or moved to a lower election ID, in which case the role has no primary until
+    /// some controller sends an election ID at least that high.
+    fn primary(&self, role_id: u64) -> Option<&Controller> {
+        let election_id = *self.election_ids.get(&role_id)?;
+        self.controllers.values().find(|c| c.role_id == role_id && c.election_id == election_id)
+    }
+
+    /// Checks that `device_id` is this server's device.
+    fn check_device(&self, device_id: u64) -> Result<()> {
+        if device_id != self.device_id {
+            return fail(RpcStatusCode::NOT_FOUND, format!("unknown device {}", device_id));
+        }
+        Ok(())
+    }
+
+    /// Checks that a request for `role_id` with `election_id` comes from the primary controller.
+    fn check_primary(&self, role_id: u64, election_id: Option<&Uint128>) -> Result<()> {
+        let election_id = election_id.map_or(0, election_id_to_u128);
+        match self.primary(role_id) {
+            None => fail(RpcStatusCode::PERMISSION_DENIED, format!("role {} has no primary controller", role_id)),
+            Some(primary) if election_id != primary.election_id => {
+                fail(RpcStatusCode::PERMISSION_DENIED,
+                     format!("election ID {} is not primary for role {}", election_id, role_id))
+            },
+            Some(_) => Ok(()),
+        }
+    }
+
+    fn check_pipeline(&self) -> Result<()> {
+        if self.config.is_none() {
+            return fail(RpcStatusCode::FAILED_PRECONDITION, "no forwarding pipeline config".into());
+        }
+        Ok(())
+    }
+
+    fn commit_config(&mut self, config: ForwardingPipelineConfig) {
+        let p4info = config.get_p4info();
+        let actions: HashMap<u32, Action> = p4info.get_actions().iter()
+            .map(|a| (a.get_preamble().id, a.into()))
+            .collect();
+        self.tables = p4info.get_tables().iter()
+            .map(|t| Table::new_from_proto(t, &actions))
+            .map(|t| (t.preamble.id, t))
+            .collect();
+        self.digest_ids = p4info.get_digests().iter().map(|d| d.get_preamble().id).collect();
+        self.entities = Entities::default();
+        self.digests.clear();
+        self.n_entries.clear();
+        self.config = Some(config);
+    }
+
+    fn set_config(&mut self, req: &SetForwardingPipelineConfigRequest) -> Result<()> {
+        self.check_device(req.device_id)?;
+        self.check_primary(req.role_id, req.election_id.as_ref())?;
+
+        use SetForwardingPipelineConfigRequest_Action::*;
+        if req.action == UNSPECIFIED {
+            return fail(RpcStatusCode::INVALID_ARGUMENT, "action must be specified".into());
+        }
+        if req.action == COMMIT {
+            return match self.saved_config.take() {
+                Some(config) => {
+                    self.commit_config(config);
+                    Ok(())
+                },
+                None => fail(RpcStatusCode::FAILED_PRECONDITION, "no saved forwarding pipeline config".into()),
+            };
+        }
+        if !req.has_config() || !req.get_config().has_p4info() {
+            return fail(RpcStatusCode::INVALID_ARGUMENT, "config must include P4Info".into());
+        }
+        let config = req.get_config().clone();
+        match req.action {
+            VERIFY => (),
+            VERIFY_AND_SAVE => self.saved_config = Some(config),
+            VERIFY_AND_COMMIT => self.commit_config(config),
+            _ => return fail(RpcStatusCode::UNIMPLEMENTED, format!("unsupported action {:?}", req.action)),
+        }
+        Ok(())
+    }
+
+    fn get_config(&self, req: &GetForwardingPipelineConfigRequest) -> Result<GetForwardingPipelineConfigResponse> {
+        self.check_device(req.device_id)?;
+        self.check_pipeline()?;
+
+        use GetForwardingPipelineConfigRequest_ResponseType::*;
+        let full = self.config.as_ref().unwrap();
+        let mut config = ForwardingPipelineConfig::new();
+        if full.has_cookie() {
+            config.set_cookie(full.get_cookie().clone());
+        }
+        if req.response_type == ALL || req.response_type == P4INFO_AND_COOKIE {
+            config.set_p4info(full.get_p4info().clone());
+        }
+        if req.response_type == ALL || req.response_type == DEVICE_CONFIG_AND_COOKIE {
+            config.set_p4_device_config(full.get_p4_device_config().to_vec());
+        }
+
+        let mut response = GetForwardingPipelineConfigResponse::new();
+        response.set_config(config);
+        Ok(response)
+    }
+
+    fn check_write(&self, req: &WriteRequest) -> Result<()> {
+        self.check_device(req.device_id)?;
+        self.check_primary(req.role_id, req.election_id.as_ref())?;
+        self.check_pipeline()?;
+        if req.atomicity != WriteRequest_Atomicity::CONTINUE_ON_ERROR {
+            return fail(RpcStatusCode::UNIMPLEMENTED, format!("unsupported atomicity {:?}", req.atomicity));
+        }
+        Ok(())
+    }
+
+    fn write_entity(&mut self, op: Update_Type, entity: Option<&Entity>) -> Result<()> {
+        match entity.and_then(|e| e.entity.as_ref()) {
+            Some(Entity_oneof_entity::table_entry(te)) => {
+                let te: TableEntry = te.try_into()?;
+                let table = match self.tables.get(&te.key.table_id) {
+                    Some(table) => table,
+                    None => return fail(RpcStatusCode::NOT_FOUND, format!("unknown table {}", te.key.table_id)),
+                };
+
+                // A table's default entry always exists and can only be modified.  A default entry
+                // without an action restores the initial default action.
+                if te.key.is_default_action {
+                    if op != Update_Type::MODIFY {
+                        return fail(RpcStatusCode::INVALID_ARGUMENT,
+                                    format!("default action may only be modified, not {:?}", op));
+                    }
+                    if table.const_default_action.is_some() {
+                        return fail(RpcStatusCode::PERMISSION_DENIED,
+                                    format!("table {} has a const default action", table.base_name()));
+                    }
+                    if te.value.action.is_some() {
+                        te.validate(table)?;
+                        self.entities.table_entries.insert(te.key, te.value);
+                    } else {
+                        self.entities.table_entries.remove(&te.key);
+                    }
+                    return Ok(());
+                }
+
+                let n_entries = self.n_entries.entry(te.key.table_id).or_default();
+                validate_table_write(op, &te, table, self.entities.table_entries.get(&te.key), *n_entries)?;
+                if op == Update_Type::DELETE {
+                    self.entities.table_entries.remove(&te.key);
+                    *n_entries -= 1;
+                } else if self.entities.table_entries.insert(te.key, te.value).is_none() {
+                    *n_entries += 1;
+                }
+                Ok(())
+            },
+            Some(Entity_oneof_entity::packet_replication_engine_entry(PacketReplicationEngineEntry {
+                field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(mge)), ..
+            })) => {
+                let mge: MulticastGroupEntry = mge.into();
+                if mge.multicast_group_id == 0 {
+                    return fail(RpcStatusCode::INVALID_ARGUMENT, "multicast_group_id must not be zero".into());
+                }
+                let groups = &mut self.entities.multicast_groups;
+                validate_write(op, groups.contains_key(&mge.multicast_group_id))?;
+                if op == Update_Type::DELETE {
+                    groups.remove(&mge.multicast_group_id);
+                } else {
+                    groups.insert(mge.multicast_group_id, mge.replicas);
+                }
+                Ok(())
+            },
+            Some(Entity_oneof_entity::digest_entry(de)) => {
+                if !self.digest_ids.contains(&de.digest_id) {
+                    return fail(RpcStatusCode::NOT_FOUND, format!("unknown digest {}", de.digest_id));
+                }
+                validate_write(op, self.digests.contains_key(&de.digest_id))?;
+                if op == Update_Type::DELETE {
+                    self.digests.remove(&de.digest_id);
+                } else {
+                    self.digests.insert(de.digest_id, de.get_config().clone());
+                }
+                Ok(())
+            },
+            Some(_) => fail(RpcStatusCode::UNIMPLEMENTED, "unsupported entity type for write".into()),
+            None => fail(RpcStatusCode::INVALID_ARGUMENT, "update must include an entity".into()),
+        }
+    }
+
+    fn read(&self, req: &ReadRequest) -> Result<Vec<ReadResponse>> {
+        self.check_device(req.device_id)?;
+        self.check_pipeline()?;
+
+        let mut responses = Vec::new();
+        for target in req.get_entities() {
+            let entities = match &target.entity {
+                Some(Entity_oneof_entity::digest_entry(de)) => self.read_digests(de.digest_id),
+                _ => self.entities.read(target)?,
+            };
+            let mut response = ReadResponse::new();
+            response.set_entities(entities.into());
+            responses.push(response);
+        }
+        Ok(responses)
+    }
+
+    /// Implements the P4Runtime `Read` operation for digest entries, in which a zero
+    /// `digest_id` acts as a wildcard.
+    fn read_digests(&self, digest_id: u32) -> Vec<Entity> {
+        let mut entities = Vec::new();
+        for (&id, config) in &self.digests {
+            if digest_id == 0 || digest_id == id {
+                let mut de = DigestEntry::new();
+                de.set_digest_id(id);
+                de.set_config(config.clone());
+                let mut entity = Entity::new();
+                entity.set_digest_entry(de);
+                entities.push(entity);
+            }
+        }
+        entities
+    }
+
+    /// Sends an arbitration update to the controller on `stream_id`, reporting whether it is the
+    /// primary for its role, or whether the role has no primary, along with the highest election
+    /// ID seen for the role.
+    fn send_arbitration(&self, stream_id: u64) {
+        let controller = &self.controllers[&stream_id];
+        let highest_election_id = self.election_ids.get(&controller.role_id).copied().unwrap_or(0);
+
+        let mut status = proto::status::Status::new();
+        status.set_code(match self.primary(controller.role_id) {
+            Some(primary) if primary.election_id == controller.election_id => RpcStatusCode::OK,
+            Some(_) => RpcStatusCode::ALREADY_EXISTS,
+            None => RpcStatusCode::NOT_FOUND,
+        }.into());
+
+        let mut update = MasterArbitrationUpdate::new();
+        update.set_device_id(self.device_id);
+        update.mut_role().set_id(controller.role_id);
+        update.set_election_id(u128_to_election_id(highest_election_id));
+        update.set_status(status);
+
+        let mut response = StreamMessageResponse::new();
+        response.set_arbitration(update);
+        let _ = controller.sender.unbounded_send(response);
+    }
+
+    /// Sends an arbitration update to every controller for `role_id`.
+    fn notify_role(&self, role_id: u64) {
+        for (&stream_id, controller) in &self.controllers {
+            if controller.role_id == role_id {
+                self.send_arbitration(stream_id);
+            }
+        }
+    }
+
+    fn stream_request(&mut self, stream_id: u64, sender: &mpsc::UnboundedSender<StreamMessageResponse>,
+                      request: StreamMessageRequest) -> Result<()> {
+        match request.update {
+            Some(StreamMessageRequest_oneof_update::arbitration(mau)) => {
+                self.check_device(mau.device_id)?;
+                let role_id = mau.get_role().id;
+                let election_id = election_id_to_u128(mau.get_election_id());
+                if self.controllers.iter().any(|(&id, c)| id != stream_id && c.role_id == role_id
+                                               && c.election_id == election_id) {
+                    return fail(RpcStatusCode::INVALID_ARGUMENT,
+                                format!("election ID {} is already in use for role {}", election_id, role_id));
+                }
+
+                let old_primary = self.primary(role_id).map(|c| c.election_id);
+                let old_role = self.controllers.insert(stream_id, Controller {
+                    role_id, election_id, sender: sender.clone()
+                }).map(|c| c.role_id);
+                let highest = self.election_ids.entry(role_id).or_insert(election_id);
+                *highest = (*highest).max(election_id);
+                if let Some(old_role) = old_role.filter(|&r| r != role_id) {
+                    self.notify_role(old_role);
+                }
+                if self.primary(role_id).map(|c| c.election_id) != old_primary {
+                    self.notify_role(role_id);
+                } else {
+                    self.send_arbitration(stream_id);
+                }
+                if old_role.is_none() {
+                    // A new stream hears about our capabilities after its arbitration reply.
+                    let _ = sender.unbounded_send(capabilities().to_stream_response());
+                }
+                Ok(())
+            },
+
+            // There is no data plane, so packets and digest acknowledgments go nowhere.
+            _ => Ok(()),
+        }
+    }
+
+    /// Forgets the controller on stream `stream_id`, which closed.  If it was the primary for its
+    /// role, the role has no primary until another controller sends an election ID at least as
+    /// high, so this tells the remaining controllers for the role.
+    fn stream_closed(&mut self, stream_id: u64) {
+        if let Some(controller) = self.controllers.remove(&stream_id) {
+            if self.election_ids.get(&controller.role_id) == Some(&controller.election_id) {
+                self.notify_role(controller.role_id);
+            }
+        }
+    }
+}
+
+/// Converts `result` into the gRPC status for a failed RPC.
+fn rpc_status<T>(result: &Result<T>) -> Option<RpcStatus> {
+    match result {
+        Ok(_) => None,
+        Err(e) => Some(RpcStatus::with_message(super::server::error_code(e), format!("{:#}", e))),
+    }
+}
+
+#[derive(Clone)]
+struct MockService {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl P4Runtime for MockService {
+    fn write(&mut self, ctx: RpcContext, req: WriteRequest, sink: UnarySink<WriteResponse>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(status) = rpc_status(&state.check_write(&req)) {
+            unary_fail(&ctx, sink, status);
+            return;
+        }
+
+        let results: Vec<Result<()>> = req.get_updates().iter()
+            .map(|update| state.write_entity(update.field_type, update.entity.as_ref()))
+            .collect();
+        match write_status(&results) {
+            Some(status) => unary_fail(&ctx, sink, status),
+            None => unary_success(&ctx, sink, WriteResponse::new()),
+        }
+    }
+
+    fn read(&mut self, ctx: RpcContext, req: ReadRequest, sink: ServerStreamingSink<ReadResponse>) {
+        let state = self.state.lock().unwrap();
+        match state.read(&req) {
+            Ok(responses) => server_streaming_success(&ctx, sink, responses),
+            Err(e) => server_streaming_fail(&ctx, sink, super::server::error_code(&e)),
+        }
+    }
+
+    fn set_forwarding_pipeline_config(&mut self, ctx: RpcContext, req: SetForwardingPipelineConfigRequest,
+                                      sink: UnarySink<SetForwardingPipelineConfigResponse>) {
+        let result = self.state.lock().unwrap().set_config(&req);
+        match rpc_status(&result) {
+            Some(status) => unary_fail(&ctx, sink, status),
+            None => unary_success(&ctx, sink, SetForwardingPipelineConfigResponse::new()),
+        }
+    }
+
+    fn get_forwarding_pipeline_config(&mut self, ctx: RpcContext, req: GetForwardingPipelineConfigRequest,
+                                      sink: UnarySink<GetForwardingPipelineConfigResponse>) {
+        let result = self.state.lock().unwrap().get_config(&req);
+        match result {
+            Ok(response) => unary_success(&ctx, sink, response),
+            Err(e) => unary_fail(&ctx, sink, RpcStatus::with_message(super::server::error_code(&e),
+                                                                     format!("{:#}", e))),
+        }
+    }
+
+    fn stream_channel(&mut self, ctx: RpcContext, mut stream: RequestStream<StreamMessageRequest>,
+                      mut sink: DuplexSink<StreamMessageResponse>) {
+        let (sender, mut receiver) = mpsc::unbounded();
+        let stream_id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_stream_id += 1;
+            state.next_stream_id
+        };
+
+        let state = self.state.clone();
+        let f = async move {
+            let result = async {
+                loop {
+                    match future::select(stream.try_next(), receiver.next()).await {
+                        Either::Left((request, _)) => match request? {
+                            Some(request) => {
+                                let result = state.lock().unwrap().stream_request(stream_id, &sender, request);
+                                if let Some(status) = rpc_status(&result) {
+                                    return sink.fail(status).await;
+                                }
+                            },
+                            None => break,
+                        },
+                        Either::Right((Some(response), _)) => sink.send((response, WriteFlags::default())).await?,
+                        Either::Right((None, _)) => break,
+                    }
+                }
+                sink.close().await
+            }.await;
+            state.lock().unwrap().stream_closed(stream_id);
+            result
+        }
+        .map_err(|e: grpcio::Error| eprintln!("stream_channel failed: {:?}", e))
+        .map(|_| ());
+        ctx.spawn(f)
+    }
+
+    fn capabilities(&mut self, ctx: RpcContext, _req: CapabilitiesRequest, sink: UnarySink<CapabilitiesResponse>) {
+        unary_success(&ctx, sink, capabilities().to_response());
+    }
+}
@@ -0,0 +1,359 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Building blocks for implementations of the P4Runtime service.
+//!
+//! `ofp4` and the in-process [`mock`](crate::mock) server both implement `P4Runtime` on top of the
+//! same in-memory model of the entities that a controller has written, which lives here along
+//! with helpers for replying to gRPC requests.
+
+use anyhow::{Context, Result};
+
+use futures::{FutureExt, SinkExt, TryFutureExt};
+
+use grpcio::{RpcContext, RpcStatus, RpcStatusCode, ServerStreamingSink, UnarySink, WriteFlags};
+
+use proto::p4runtime::{
+    CapabilitiesResponse,
+    Entity,
+    Entity_oneof_entity,
+    PacketReplicationEngineEntry,
+    PacketReplicationEngineEntry_oneof_type,
+    StreamMessageResponse,
+    Update_Type,
+    WriteRequest_Atomicity,
+};
+
+use protobuf::{Message, well_known_types::Any};
+
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryInto;
+
+use super::{
+    Error,
+    MulticastGroupEntry,
+    MulticastGroupId,
+    Replica,
+    Table,
+    TableEntry,
+    TableKey,
+    TableValue,
+};
+
+/// Checks whether P4Runtime permits update `op` on an entity, given whether the entity already
+/// exists, and returns the error that the specification requires if not.
+pub fn validate_write(op: Update_Type, entity_exists: bool) -> Result<()> {
+    match (op, entity_exists) {
+        (Update_Type::UNSPECIFIED, _) => Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context("update type must be specified"),
+        (Update_Type::INSERT, true) => Err(Error(RpcStatusCode::ALREADY_EXISTS)).context("entity already exists"),
+        (Update_Type::MODIFY, false) | (Update_Type::DELETE, false)
+            => Err(Error(RpcStatusCode::NOT_FOUND)).context("entity does not exist"),
+        _ => Ok(()),
+    }
+}
+
+/// Checks whether P4Runtime permits update `op` on `entry`, which must not be a default entry, in
+/// `table`.  `old_value` is the entry's current value, if it exists, and `n_entries` is the number
+/// of entries in the table.  Returns the error that the specification requires if not: the table
+/// must not be const, the entry must agree with the table's schema, the update must suit whether
+/// the entry exists, a new entry must not exceed the table's `max_entries`, and an idle timeout
+/// requires a table with idle notification.
+pub fn validate_table_write(op: Update_Type, entry: &TableEntry, table: &Table, old_value: Option<&TableValue>,
+                            n_entries: u64) -> Result<()> {
+    if table.is_const_table() {
+        Err(Error(RpcStatusCode::PERMISSION_DENIED)).context(format!("table {} is const", table.base_name()))?;
+    }
+    if op == Update_Type::DELETE {
+        entry.validate_key(table)?;
+    } else {
+        entry.validate(table)?;
+    }
+
+    validate_write(op, old_value.is_some())?;
+    if let (None, Some(max_entries)) = (old_value, table.max_entries()) {
+        if n_entries >= max_entries {
+            Err(Error(RpcStatusCode::RESOURCE_EXHAUSTED))
+                .context(format!("table {} is full ({} entries)", table.base_name(), max_entries))?;
+        }
+    }
+
+    if entry.value.idle_timeout_ns < 0 {
+        Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context("idle_timeout_ns must not be negative")?;
+    }
+    if entry.value.idle_timeout_ns > 0 && !table.idle_notify() {
+        Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+            .context(format!("table {} does not support idle timeouts", table.base_name()))?;
+    }
+    Ok(())
+}
+
+/// Returns the status code for `error`, which is `UNKNOWN` unless `error` wraps an [`Error`].
+pub fn error_code(error: &anyhow::Error) -> RpcStatusCode {
+    match error.downcast_ref::<Error>() {
+        Some(Error(code)) => *code,
+        None => RpcStatusCode::UNKNOWN,
+    }
+}
+
+/// Returns the status for a `Write` RPC whose updates had the given `results`, in order, or
+/// `None` if all of them succeeded.  As P4Runtime specifies, a failed batch reports `UNKNOWN`
+/// overall, with one `p4.v1.Error` per update in the details.
+pub fn write_status(results: &[Result<()>]) -> Option<RpcStatus> {
+    if results.iter().all(|result| result.is_ok()) {
+        return None;
+    }
+
+    let mut status = proto::status::Status::new();
+    status.set_code(RpcStatusCode::UNKNOWN.into());
+    for result in results {
+        let mut error = proto::p4runtime::Error::new();
+        match result {
+            Ok(()) => error.set_canonical_code(RpcStatusCode::OK.into()),
+            Err(e) => {
+                error.set_canonical_code(error_code(e).into());
+                error.set_message(format!("{:#}", e));
+            },
+        }
+        status.mut_details().push(Any::pack(&error).unwrap());
+    }
+    Some(RpcStatus::with_details(RpcStatusCode::UNKNOWN, String::new(), status.write_to_bytes().unwrap()))
+}
+
+/// The version of the P4Runtime specification that servers built on this module implement.
+pub const P4RUNTIME_API_VERSION: &str = "1.3.0";
+
+/// The type URL of an `ofp4.Capabilities` message packed in an `Any`.
+pub const CAPABILITIES_TYPE_URL: &str = "type.googleapis.com/ofp4.Capabilities";
+
+/// What a P4Runtime server supports.
+///
+/// A P4Runtime 1.3 `CapabilitiesResponse` carries only the API version, so a server built on this
+/// module reports the rest in an `ofp4.Capabilities` message, defined in `proto/nerpa/ofp4.proto`,
+/// that it sends on each stream channel just after it answers the stream's first arbitration
+/// update, so that a controller always hears about arbitration first.  [`Self::to_response`] and [`Self::to_stream_response`] produce the two messages, and
+/// [`Self::from_response`] and [`Self::merge_stream_message`] read them back.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// P4Runtime API version, e.g. `"1.3.0"`.
+    pub api_version: String,
+
+    /// The kinds of entity that may be written and read, named like the fields of `p4.v1.Entity`
+    /// but with hyphens, e.g. `"table-entry"`.  Multicast groups are `"multicast-group-entry"`.
+    pub entities: Vec<String>,
+
+    /// The write atomicity modes accepted.
+    pub atomicities: Vec<WriteRequest_Atomicity>,
+}
+
+impl Capabilities {
+    /// Returns capabilities for [`P4RUNTIME_API_VERSION`] with the given entity kinds and
+    /// atomicity modes.
+    pub fn new(entities: &[&str], atomicities: &[WriteRequest_Atomicity]) -> Capabilities {
+        Capabilities {
+            api_version: P4RUNTIME_API_VERSION.into(),
+            entities: entities.iter().map(|e| e.to_string()).collect(),
+            atomicities: atomicities.to_vec(),
+        }
+    }
+
+    /// Returns true if `entity` (e.g. `"table-entry"`) is supported.
+    pub fn supports_entity(&self, entity: &str) -> bool {
+        self.entities.iter().any(|e| e == entity)
+    }
+
+    /// Returns true if write atomicity mode `atomicity` is supported.
+    pub fn supports_atomicity(&self, atomicity: WriteRequest_Atomicity) -> bool {
+        self.atomicities.contains(&atomicity)
+    }
+
+    /// Encodes the API version as a response to the `Capabilities` RPC.
+    pub fn to_response(&self) -> CapabilitiesResponse {
+        let mut response = CapabilitiesResponse::new();
+        response.set_p4runtime_api_version(self.api_version.clone());
+        response
+    }
+
+    /// Decodes the API version from a response to the `Capabilities` RPC.  The other capabilities
+    /// are empty until [`Self::merge_stream_message`] fills them in.
+    pub fn from_response(response: &CapabilitiesResponse) -> Capabilities {
+        Capabilities { api_version: response.get_p4runtime_api_version().into(), ..Default::default() }
+    }
+
+    /// Encodes the entity kinds and atomicity modes as an `ofp4.Capabilities` message, packed in
+    /// the `other` member of a stream channel response.
+    pub fn to_stream_response(&self) -> StreamMessageResponse {
+        let capabilities = proto::ofp4::Capabilities {
+            entities: self.entities.clone().into(),
+            atomicities: self.atomicities.clone(),
+            ..Default::default()
+        };
+        let mut other = Any::new();
+        other.set_type_url(CAPABILITIES_TYPE_URL.into());
+        other.set_value(capabilities.write_to_bytes().unwrap());
+
+        let mut response = StreamMessageResponse::new();
+        response.set_other(other);
+        response
+    }
+
+    /// If `other`, from a stream channel response, is an `ofp4.Capabilities` message, replaces
+    /// the entity kinds and atomicity modes by the ones in it and returns true.  Otherwise,
+    /// returns false.
+    pub fn merge_stream_message(&mut self, other: &Any) -> bool {
+        if other.get_type_url() != CAPABILITIES_TYPE_URL {
+            return false;
+        }
+        match proto::ofp4::Capabilities::parse_from_bytes(other.get_value()) {
+            Ok(capabilities) => {
+                self.entities = capabilities.entities.into_vec();
+                self.atomicities = capabilities.atomicities;
+                true
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+/// The table entries and multicast groups that a P4Runtime server has accepted.
+#[derive(Clone, Debug, Default)]
+pub struct Entities {
+    /// Multicast groups.
+    pub multicast_groups: HashMap<MulticastGroupId, BTreeSet<Replica>>,
+
+    /// Table entries, including modified default actions.
+    pub table_entries: HashMap<TableKey, TableValue>,
+}
+
+impl Entities {
+    /// Implements the P4Runtime `Read` operation for the entity filter `target`.  Returns the
+    /// entities to send back to the P4Runtime client.
+    pub fn read(&self, target: &Entity) -> Result<Vec<Entity>> {
+        match &target.entity {
+            Some(Entity_oneof_entity::packet_replication_engine_entry(PacketReplicationEngineEntry {
+                field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(mge)), ..}))
+                => Ok(self.read_multicast_groups(mge.multicast_group_id)),
+            Some(Entity_oneof_entity::table_entry(te)) => self.read_table_entries(te),
+            _ => Err(Error(RpcStatusCode::UNIMPLEMENTED)).context("unsupported entity type for read"),
+        }
+    }
+
+    /// Implements the P4Runtime `Read` operation for the specified `multicast_group_id`,
+    /// including the P4Runtime behavior that a zero or missing multicast group acts as a wildcard.
+    /// Returns the entities to send back to the P4Runtime client.
+    pub fn read_multicast_groups(&self, multicast_group_id: u32) -> Vec<Entity> {
+        let group_ids: Vec<MulticastGroupId> = if multicast_group_id == 0 {
+            self.multicast_groups.keys().cloned().collect()
+        } else {
+            vec![multicast_group_id]
+        };
+
+        let mut entities = Vec::new();
+        for multicast_group_id in group_ids {
+            if let Some(mg_replicas) = self.multicast_groups.get(&multicast_group_id) {
+                let p_mge: proto::p4runtime::MulticastGroupEntry = (&MulticastGroupEntry {
+                    multicast_group_id, replicas: mg_replicas.clone()
+                }).into();
+                let entity = Entity {
+                    entity: Some(Entity_oneof_entity::packet_replication_engine_entry(PacketReplicationEngineEntry {
+                        field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(p_mge)), ..Default::default()})), ..Default::default()};
+                entities.push(entity);
+            }
+        }
+        entities
+    }
+
+    /// Implements the P4Runtime `Read` operation for table entries that match all of the fields
+    /// in `target`.  As P4Runtime specifies, any field in `target` that is zero or missing acts as
+    /// a wildcard.  Returns the entities to send back to the P4Runtime client.
+    pub fn read_table_entries(&self, target: &proto::p4runtime::TableEntry) -> Result<Vec<Entity>> {
+        let target: TableEntry = target.try_into()?;
+
+        let mut entities = Vec::new();
+        for (key, value) in &self.table_entries {
+            if target.key.table_id != 0 && target.key.table_id != key.table_id {
+                continue;
+            }
+            if !target.key.matches.is_empty() && target.key.matches != key.matches {
+                continue;
+            }
+            if target.key.priority != 0 && target.key.priority != key.priority {
+                continue;
+            }
+            if target.key.is_default_action && !key.is_default_action {
+                continue;
+            }
+            if target.value.controller_metadata != 0 && target.value.controller_metadata != value.controller_metadata {
+                continue;
+            }
+            if !target.value.metadata.is_empty() && target.value.metadata != value.metadata {
+                continue;
+            }
+            // XXX meter_config
+            // XXX counter_data
+            // XXX idle_timeout_ns?
+            // XXX time_since_last_hit?
+            let te = TableEntry { key: key.clone(), value: value.clone() };
+            let mut entity = Entity::new();
+            entity.set_table_entry((&te).into());
+            entities.push(entity);
+        }
+        Ok(entities)
+    }
+}
+
+/// Fails a unary RPC with `status`.
+pub fn unary_fail<T>(ctx: &RpcContext, sink: UnarySink<T>, status: RpcStatus) {
+    let f = sink.fail(status)
+        .map_err(|e| eprintln!("failed to send error: {:?}", e))
+        .map(|_| ());
+    ctx.spawn(f);
+}
+
+/// Completes a unary RPC with `reply`.
+pub fn unary_success<T>(ctx: &RpcContext, sink: UnarySink<T>, reply: T) {
+    let f = sink
+        .success(reply)
+        .map_err(|e: grpcio::Error| eprintln!("write failed: {:?}", e))
+        .map(|_| ());
+    ctx.spawn(f);
+}
+
+/// Fails a server-streaming RPC with `code`.
+pub fn server_streaming_fail<T>(ctx: &RpcContext, sink: ServerStreamingSink<T>, code: RpcStatusCode) {
+    let f = sink.fail(RpcStatus::new(code))
+        .map_err(|e| eprintln!("failed to send error: {:?}", e))
+        .map(|_| ());
+    ctx.spawn(f);
+}
+
+/// Completes a server-streaming RPC by sending each message in `reply`.
+pub fn server_streaming_success<T: Send + 'static>(ctx: &RpcContext, mut sink: ServerStreamingSink<T>,
+                                                   reply: Vec<T>) {
+    let f = async move {
+        for msg in reply {
+            sink.send((msg, WriteFlags::default())).await?;
+        }
+        sink.close().await?;
+        Ok(())
+    }
+    .map_err(|e: grpcio::Error| eprintln!("failed to stream response: {:?}", e))
+        .map(|_| ());
+    ctx.spawn(f);
+}
@@ -0,0 +1,417 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A long-lived connection to a P4Runtime device.
+//!
+//! A [`Session`] bundles what every P4Runtime request needs: the gRPC client, the device and
+//! role IDs, and the controller's election ID.  It also owns the single `StreamChannel` that
+//! P4Runtime uses for arbitration and for packet and notification I/O.  A background task keeps
+//! the stream open, reconnecting and re-sending arbitration with exponential backoff if it fails,
+//! and forwards digests, packet-ins, and idle-timeout notifications to subscribers.
+
+use futures::{SinkExt, TryStreamExt};
+
+use grpcio::{ChannelBuilder, ClientDuplexReceiver, EnvBuilder, RpcStatusCode, StreamingCallSink, WriteFlags};
+
+use log::{error, warn};
+
+use proto::p4runtime::{
+    CapabilitiesRequest,
+    DigestList,
+    DigestListAck,
+    Entity,
+    ForwardingPipelineConfig,
+    IdleTimeoutNotification,
+    MasterArbitrationUpdate,
+    PacketIn,
+    PacketOut,
+    StreamMessageRequest,
+    StreamMessageResponse,
+    StreamMessageResponse_oneof_update,
+    Uint128,
+    Update,
+    WriteRequest,
+};
+use proto::p4runtime_grpc::P4RuntimeClient;
+
+use protobuf::RepeatedField;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, sleep};
+
+use super::P4Error;
+use super::server::Capabilities;
+
+/// Delay before the first attempt to reconnect a failed stream channel.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Maximum delay between attempts to reconnect a failed stream channel.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Converts `id` to its P4Runtime representation.
+pub fn u128_to_election_id(id: u128) -> Uint128 {
+    let mut election_id = Uint128::new();
+    election_id.set_high((id >> 64) as u64);
+    election_id.set_low(id as u64);
+    election_id
+}
+
+/// Converts `id` from its P4Runtime representation.
+pub fn election_id_to_u128(id: &Uint128) -> u128 {
+    ((id.high as u128) << 64) | id.low as u128
+}
+
+/// A connection to a P4Runtime device as a particular controller.
+///
+/// Dropping the session closes its stream channel.
+pub struct Session {
+    client: P4RuntimeClient,
+    params: Arc<SessionParams>,
+    requests: mpsc::UnboundedSender<StreamMessageRequest>,
+    subscribers: Arc<Mutex<Subscribers>>,
+    primary: watch::Receiver<Option<bool>>,
+    capabilities: Arc<Mutex<Capabilities>>,
+    task: JoinHandle<()>,
+}
+
+/// What identifies a session to the device.
+struct SessionParams {
+    target: String,
+    device_id: u64,
+    role_id: u64,
+    election_id: u128,
+}
+
+impl SessionParams {
+    fn arbitration_request(&self) -> StreamMessageRequest {
+        let mut update = MasterArbitrationUpdate::new();
+        update.set_device_id(self.device_id);
+        if self.role_id != 0 {
+            update.mut_role().set_id(self.role_id);
+        }
+        update.set_election_id(u128_to_election_id(self.election_id));
+
+        let mut request = StreamMessageRequest::new();
+        request.set_arbitration(update);
+        request
+    }
+
+    fn rpc_error(&self, source: grpcio::Error) -> P4Error {
+        P4Error::Rpc { target: self.target.clone(), rpc: "StreamChannel", source }
+    }
+}
+
+/// Senders for the subscribers to each kind of stream message.  A subscriber whose receiver has
+/// been dropped is removed the next time a message arrives for it.
+#[derive(Default)]
+struct Subscribers {
+    digests: Vec<mpsc::UnboundedSender<DigestList>>,
+    packets: Vec<mpsc::UnboundedSender<PacketIn>>,
+    idle_timeouts: Vec<mpsc::UnboundedSender<IdleTimeoutNotification>>,
+}
+
+/// Sends `message` to each of `subscribers`, dropping any that have gone away.
+fn publish<T: Clone>(subscribers: &mut Vec<mpsc::UnboundedSender<T>>, message: &T) {
+    subscribers.retain(|s| s.send(message.clone()).is_ok());
+}
+
+impl Session {
+    /// Connects to the P4Runtime server at `target` (e.g. `"localhost:50051"`) for the device with
+    /// ID `device_id`, as a controller with role `role_id` (0 for the default role) and election
+    /// ID `election_id`.
+    ///
+    /// Returns once the device has answered the initial arbitration request, whether or not this
+    /// controller became primary; use [`Self::is_primary`] to find out.
+    pub async fn connect(target: &str, device_id: u64, role_id: u64, election_id: u128) -> Result<Session, P4Error> {
+        let env = Arc::new(EnvBuilder::new().build());
+        let client = P4RuntimeClient::new(ChannelBuilder::new(env).connect(target));
+        let params = Arc::new(SessionParams { target: target.to_string(), device_id, role_id, election_id });
+
+        let capabilities = Arc::new(Mutex::new(Capabilities::default()));
+        let (sink, receiver, primary) = arbitrate(&client, &params).await?;
+        let (primary_tx, primary_rx) = watch::channel(Some(primary));
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        let subscribers = Arc::new(Mutex::new(Subscribers::default()));
+
+        let task = tokio::spawn(run_stream(client.clone(), params.clone(), sink, receiver, requests_rx,
+                                           subscribers.clone(), primary_tx, capabilities.clone()));
+        Ok(Session {
+            client,
+            params,
+            requests: requests_tx,
+            subscribers,
+            primary: primary_rx,
+            capabilities,
+            task,
+        })
+    }
+
+    /// Returns the P4Runtime client, for requests that the session does not wrap.
+    pub fn client(&self) -> &P4RuntimeClient {
+        &self.client
+    }
+
+    /// Returns the server address that the session connected to.
+    pub fn target(&self) -> &str {
+        &self.params.target
+    }
+
+    /// Returns the ID of the device.
+    pub fn device_id(&self) -> u64 {
+        self.params.device_id
+    }
+
+    /// Returns the controller's role ID.
+    pub fn role_id(&self) -> u64 {
+        self.params.role_id
+    }
+
+    /// Returns the controller's election ID.
+    pub fn election_id(&self) -> u128 {
+        self.params.election_id
+    }
+
+    /// Returns true if the device most recently reported that this controller is the primary for
+    /// its role.  Returns false while the stream channel is reconnecting.
+    pub fn is_primary(&self) -> bool {
+        *self.primary.borrow() == Some(true)
+    }
+
+    /// Sets the forwarding pipeline from the files named `p4info` and `json`.  See
+    /// [`crate::set_pipeline_config`] for the meaning of the arguments.
+    pub async fn set_pipeline(&self, p4info: &str, json: &str, cookie: &str, action: &str) -> Result<(), P4Error> {
+        let mut request = super::pipeline_config_request(p4info, json, cookie, action,
+                                                         self.params.device_id, self.params.role_id)?;
+        request.set_election_id(u128_to_election_id(self.params.election_id));
+        super::send_pipeline_config(request, &self.params.target, &self.client).await
+    }
+
+    /// Retrieves the device's forwarding pipeline, including its P4Info and cookie.
+    pub async fn get_pipeline(&self) -> Result<ForwardingPipelineConfig, P4Error> {
+        super::get_pipeline_config(self.params.device_id, &self.params.target, &self.client).await
+    }
+
+    /// Writes `updates` to the device.
+    pub async fn write(&self, updates: Vec<Update>) -> Result<(), P4Error> {
+        let mut request = WriteRequest::new();
+        request.set_device_id(self.params.device_id);
+        request.set_role_id(self.params.role_id);
+        request.set_election_id(u128_to_election_id(self.params.election_id));
+        request.set_updates(RepeatedField::from_vec(updates));
+        super::send_write(request, &self.params.target, &self.client).await
+    }
+
+    /// Reads the entities that match the filters in `entities`.
+    pub async fn read(&self, entities: Vec<Entity>) -> Result<Vec<Entity>, P4Error> {
+        super::read(entities, self.params.device_id, &self.client).await
+    }
+
+    /// Asks the device which P4Runtime features it supports.  The entity kinds and atomicity
+    /// modes are the ones that the device most recently reported on the stream channel, which
+    /// are empty if the device has not reported them (yet).  A device built on
+    /// [`crate::server`] reports them just after its first arbitration reply on each stream.
+    pub async fn capabilities(&self) -> Result<Capabilities, P4Error> {
+        let on_error = P4Error::rpc(&self.params.target, "Capabilities");
+        let response = match self.client.capabilities_async(&CapabilitiesRequest::new()) {
+            Ok(receiver) => receiver.await.map_err(on_error)?,
+            Err(e) => return Err(on_error(e)),
+        };
+        let reported = self.capabilities.lock().unwrap().clone();
+        Ok(Capabilities { api_version: response.get_p4runtime_api_version().into(), ..reported })
+    }
+
+    /// Queues `packet` to be sent to the device over the stream channel.
+    pub fn send_packet(&self, packet: PacketOut) -> Result<(), P4Error> {
+        let mut request = StreamMessageRequest::new();
+        request.set_packet(packet);
+        self.requests.send(request).map_err(|_| P4Error::Spec {
+            target: self.params.target.clone(),
+            message: "stream channel has shut down".into(),
+        })
+    }
+
+    /// Returns a receiver for the digest lists that the device sends from now on.  The session
+    /// acknowledges each digest list as it arrives.
+    pub fn subscribe_digests(&self) -> mpsc::UnboundedReceiver<DigestList> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().digests.push(tx);
+        rx
+    }
+
+    /// Returns a receiver for the packets that the device sends to the controller from now on.
+    pub fn subscribe_packets(&self) -> mpsc::UnboundedReceiver<PacketIn> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().packets.push(tx);
+        rx
+    }
+
+    /// Returns a receiver for the idle-timeout notifications that the device sends from now on.
+    pub fn subscribe_idle_timeouts(&self) -> mpsc::UnboundedReceiver<IdleTimeoutNotification> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.subscribers.lock().unwrap().idle_timeouts.push(tx);
+        rx
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+         .field("target", &self.params.target)
+         .field("device_id", &self.params.device_id)
+         .field("role_id", &self.params.role_id)
+         .field("election_id", &self.params.election_id)
+         .finish()
+    }
+}
+
+/// Opens a stream channel and sends an arbitration request on it.  Returns the stream and whether
+/// the device made this controller primary.
+async fn arbitrate(client: &P4RuntimeClient, params: &SessionParams)
+                   -> Result<(StreamingCallSink<StreamMessageRequest>, ClientDuplexReceiver<StreamMessageResponse>, bool), P4Error> {
+    let (mut sink, mut receiver) = client.stream_channel().map_err(|e| params.rpc_error(e))?;
+    sink.send((params.arbitration_request(), WriteFlags::default())).await.map_err(|e| params.rpc_error(e))?;
+    loop {
+        match receiver.try_next().await.map_err(|e| params.rpc_error(e))? {
+            Some(StreamMessageResponse { update: Some(StreamMessageResponse_oneof_update::arbitration(mau)), .. }) => {
+                let primary = mau.get_status().code == i32::from(RpcStatusCode::OK);
+                return Ok((sink, receiver, primary));
+            },
+            // Anything that arrives before the arbitration reply predates this controller.
+            Some(_) => continue,
+            None => return Err(P4Error::Spec {
+                target: params.target.clone(),
+                message: "stream channel closed before arbitration".into(),
+            }),
+        }
+    }
+}
+
+/// Services the stream channel until the session is dropped, reconnecting as needed.  The session
+/// holds the only sender for `requests` and the only receiver for `primary`, so either one closing
+/// means that the session is gone.
+#[allow(clippy::too_many_arguments)]
+async fn run_stream(client: P4RuntimeClient,
+                    params: Arc<SessionParams>,
+                    mut sink: StreamingCallSink<StreamMessageRequest>,
+                    mut receiver: ClientDuplexReceiver<StreamMessageResponse>,
+                    mut requests: mpsc::UnboundedReceiver<StreamMessageRequest>,
+                    subscribers: Arc<Mutex<Subscribers>>,
+                    primary: watch::Sender<Option<bool>>,
+                    capabilities: Arc<Mutex<Capabilities>>) {
+    // Digest acknowledgments, which this task sends on its own behalf.
+    let (acks_tx, mut acks) = mpsc::unbounded_channel();
+    loop {
+        // Forward requests and responses until the stream fails.
+        let error = loop {
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(request) => if let Err(e) = sink.send((request, WriteFlags::default())).await {
+                        break params.rpc_error(e);
+                    },
+                    // The session was dropped.
+                    None => return,
+                },
+                Some(ack) = acks.recv() => if let Err(e) = sink.send((ack, WriteFlags::default())).await {
+                    break params.rpc_error(e);
+                },
+                response = receiver.try_next() => match response {
+                    Ok(Some(response)) => dispatch(response, &acks_tx, &subscribers, &primary, &capabilities),
+                    Ok(None) => break P4Error::Spec { target: params.target.clone(),
+                                                      message: "stream channel closed".into() },
+                    Err(e) => break params.rpc_error(e),
+                },
+            }
+        };
+        warn!("{}; reconnecting", error);
+        let _ = primary.send(None);
+
+        // Reconnect and re-arbitrate, backing off exponentially, until that succeeds or the
+        // session is dropped.
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let attempt = async {
+                sleep(backoff).await;
+                arbitrate(&client, &params).await
+            };
+            let result = tokio::select! {
+                result = attempt => result,
+                _ = primary.closed() => return,
+            };
+            match result {
+                Ok((new_sink, new_receiver, is_primary)) => {
+                    sink = new_sink;
+                    receiver = new_receiver;
+                    let _ = primary.send(Some(is_primary));
+                    break;
+                },
+                Err(e) => {
+                    warn!("{}; retrying in {:?}", e, backoff);
+                    backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+/// Handles `response` from the stream channel.
+fn dispatch(response: StreamMessageResponse,
+            acks: &mpsc::UnboundedSender<StreamMessageRequest>,
+            subscribers: &Mutex<Subscribers>,
+            primary: &watch::Sender<Option<bool>>,
+            capabilities: &Mutex<Capabilities>) {
+    match response.update {
+        Some(StreamMessageResponse_oneof_update::arbitration(mau)) => {
+            let _ = primary.send(Some(mau.get_status().code == i32::from(RpcStatusCode::OK)));
+        },
+        Some(StreamMessageResponse_oneof_update::digest(digest)) => {
+            let mut ack = DigestListAck::new();
+            ack.set_digest_id(digest.digest_id);
+            ack.set_list_id(digest.list_id);
+            let mut request = StreamMessageRequest::new();
+            request.set_digest_ack(ack);
+            let _ = acks.send(request);
+
+            publish(&mut subscribers.lock().unwrap().digests, &digest);
+        },
+        Some(StreamMessageResponse_oneof_update::packet(packet)) => {
+            publish(&mut subscribers.lock().unwrap().packets, &packet);
+        },
+        Some(StreamMessageResponse_oneof_update::idle_timeout_notification(notification)) => {
+            publish(&mut subscribers.lock().unwrap().idle_timeouts, &notification);
+        },
+        Some(StreamMessageResponse_oneof_update::error(error)) => {
+            error!("stream error from device: {:?}", error);
+        },
+        Some(StreamMessageResponse_oneof_update::other(other)) => {
+            capabilities.lock().unwrap().merge_stream_message(&other);
+        },
+        _ => (),
+    }
+}
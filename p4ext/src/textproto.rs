@@ -0,0 +1,491 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Parsing of protobuf messages, such as P4Runtime `WriteRequest`s, in the protobuf text format
+//! and in the proto3 JSON mapping.
+//!
+//! rust-protobuf 2 can print a message as text, but it can parse only the binary format, and its
+//! reflection API can't set fields.  So this module parses its input into a tree of field names
+//! and values, encodes the tree in the binary format with the help of the message's descriptor,
+//! and then parses that.  It knows the descriptors for the messages in the [`proto`] crate and for
+//! `google.protobuf.Any`, without the text format's expanded `Any` syntax, extensions, or groups.
+//!
+//! Text format input looks like:
+//!
+//! ```text
+//! # Add an entry to table 33572104.
+//! updates {
+//!   type: INSERT
+//!   entity { table_entry { table_id: 33572104 match { field_id: 1 exact { value: "\x00\x01" } } } }
+//! }
+//! ```
+//!
+//! In JSON, field names may be written either as in the `.proto` file or in lowerCamelCase,
+//! 64-bit integers may be strings, and `bytes` fields are base64.
+
+use anyhow::{Context, Result};
+
+use grpcio::RpcStatusCode;
+
+use protobuf::descriptor::{DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Type};
+use protobuf::well_known_types::Any;
+use protobuf::{CodedOutputStream, Message};
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use super::Error;
+
+/// Parses `input` as a message of type `M` in the protobuf text format.
+pub fn parse_text<M: Message>(input: &str) -> Result<M> {
+    let tokens = tokenize(input)?;
+    let mut pos = 0;
+    let fields = parse_text_fields(&tokens, &mut pos, None)?;
+    Encoder::new(false).parse(&fields)
+}
+
+/// Parses `input` as a message of type `M` in the proto3 JSON mapping.
+pub fn parse_json<M: Message>(input: &str) -> Result<M> {
+    let json: serde_json::Value = serde_json::from_str(input).context("invalid JSON")?;
+    parse_json_message(&json)
+}
+
+/// Parses `input` as a JSON array of messages of type `M`, or as a single message.
+pub fn parse_json_list<M: Message>(input: &str) -> Result<Vec<M>> {
+    let json: serde_json::Value = serde_json::from_str(input).context("invalid JSON")?;
+    match &json {
+        serde_json::Value::Array(elements) => elements.iter()
+            .enumerate()
+            .map(|(i, element)| parse_json_message(element).with_context(|| format!("element {}", i)))
+            .collect(),
+        _ => Ok(vec![parse_json_message(&json)?]),
+    }
+}
+
+fn parse_json_message<M: Message>(json: &serde_json::Value) -> Result<M> {
+    match json_to_value(json) {
+        Value::Message(fields) => Encoder::new(true).parse(&fields),
+        _ => fail("expected a JSON object".into()),
+    }
+}
+
+/// Returns an `INVALID_ARGUMENT` error with `message`.
+fn fail<T>(message: String) -> Result<T> {
+    Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context(message)
+}
+
+/// A value in the parsed input, before it is matched up with a field.
+#[derive(Debug)]
+enum Value {
+    /// A number or identifier, as written.
+    Token(String),
+
+    /// A string.  In the text format, escapes have been processed.
+    Str(Vec<u8>),
+
+    /// A message, as its fields in the order written.  A field may appear more than once.
+    Message(Vec<(String, Value)>),
+
+    /// The elements of a repeated field.
+    List(Vec<Value>),
+}
+
+/// Converts `json` into a [`Value`].  JSON `null` becomes an empty list, so that fields set to
+/// `null` are omitted.
+fn json_to_value(json: &serde_json::Value) -> Value {
+    use serde_json::Value as Json;
+    match json {
+        Json::Null => Value::List(Vec::new()),
+        Json::Bool(b) => Value::Token(b.to_string()),
+        Json::Number(n) => Value::Token(n.to_string()),
+        Json::String(s) => Value::Str(s.clone().into_bytes()),
+        Json::Array(elements) => Value::List(elements.iter().map(json_to_value).collect()),
+        Json::Object(members) => Value::Message(members.iter()
+            .map(|(name, value)| (name.clone(), json_to_value(value)))
+            .collect()),
+    }
+}
+
+/// A token in the text format.
+#[derive(Debug, PartialEq)]
+enum Token {
+    /// An identifier or a number.
+    Word(String),
+
+    /// A quoted string, with escapes processed.
+    Str(Vec<u8>),
+
+    /// One of `{}<>[]:,;`.
+    Punct(char),
+}
+
+/// Breaks `input`, in the text format, into tokens.  Comments start with `#`.
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '{' | '}' | '<' | '>' | '[' | ']' | ':' | ',' | ';' => tokens.push(Token::Punct(c)),
+            '"' | '\'' => {
+                let mut s = Vec::new();
+                loop {
+                    match chars.next() {
+                        None | Some('\n') => return fail("unterminated string".into()),
+                        Some(q) if q == c => break,
+                        Some('\\') => s.push(unescape(&mut chars)?),
+                        Some(c) => s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            },
+            c if c.is_ascii_alphanumeric() || "_.+-".contains(c) => {
+                let mut word = String::from(c);
+                while let Some(c) = chars.next_if(|&c| c.is_ascii_alphanumeric() || "_.+-".contains(c)) {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            },
+            c => return fail(format!("unexpected character {:?}", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Processes the escape sequence that follows a backslash in `chars`, returning the byte that it
+/// represents.
+fn unescape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<u8> {
+    let digits = |chars: &mut std::iter::Peekable<std::str::Chars>, radix, max| {
+        let mut value = 0u32;
+        let mut n = 0;
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(radix)).filter(|_| n < max) {
+            chars.next();
+            value = value * radix + digit;
+            n += 1;
+        }
+        (value, n)
+    };
+    Ok(match chars.next() {
+        Some('n') => b'\n',
+        Some('r') => b'\r',
+        Some('t') => b'\t',
+        Some('a') => 0x07,
+        Some('b') => 0x08,
+        Some('f') => 0x0c,
+        Some('v') => 0x0b,
+        Some(c @ ('\\' | '\'' | '"' | '?')) => c as u8,
+        Some('x') | Some('X') => match digits(chars, 16, 2) {
+            (_, 0) => return fail("\\x must be followed by hex digits".into()),
+            (value, _) => value as u8,
+        },
+        Some(c) if c.is_digit(8) => {
+            let (value, n) = digits(chars, 8, 2);
+            (c.to_digit(8).unwrap() * 8u32.pow(n) + value) as u8
+        },
+        _ => return fail("invalid escape sequence".into()),
+    })
+}
+
+/// Parses the fields of a message from `tokens`, starting at `*pos`, up to the closing `end`
+/// punctuation or, if `end` is `None`, the end of the input.
+fn parse_text_fields(tokens: &[Token], pos: &mut usize, end: Option<char>) -> Result<Vec<(String, Value)>> {
+    let mut fields = Vec::new();
+    loop {
+        let name = match (tokens.get(*pos), end) {
+            (None, None) => break,
+            (None, Some(end)) => return fail(format!("missing {:?}", end)),
+            (Some(Token::Punct(c)), Some(end)) if *c == end => {
+                *pos += 1;
+                break;
+            },
+            (Some(Token::Word(name)), _) => name.clone(),
+            (Some(token), _) => return fail(format!("expected field name, found {:?}", token)),
+        };
+        *pos += 1;
+
+        // The colon is optional before a message.
+        if tokens.get(*pos) == Some(&Token::Punct(':')) {
+            *pos += 1;
+        }
+        let value = if tokens.get(*pos) == Some(&Token::Punct('[')) {
+            *pos += 1;
+            let mut elements = Vec::new();
+            while tokens.get(*pos) != Some(&Token::Punct(']')) {
+                if !elements.is_empty() {
+                    expect(tokens, pos, ',')?;
+                }
+                elements.push(parse_text_value(tokens, pos)?);
+            }
+            *pos += 1;
+            Value::List(elements)
+        } else {
+            parse_text_value(tokens, pos)?
+        };
+        fields.push((name, value));
+
+        if matches!(tokens.get(*pos), Some(Token::Punct(',')) | Some(Token::Punct(';'))) {
+            *pos += 1;
+        }
+    }
+    Ok(fields)
+}
+
+/// Parses a single value from `tokens` at `*pos`: a message in braces or angle brackets, one or
+/// more adjacent strings, or a number or identifier.
+fn parse_text_value(tokens: &[Token], pos: &mut usize) -> Result<Value> {
+    let token = match tokens.get(*pos) {
+        Some(token) => token,
+        None => return fail("missing value".into()),
+    };
+    *pos += 1;
+    match token {
+        Token::Punct('{') => Ok(Value::Message(parse_text_fields(tokens, pos, Some('}'))?)),
+        Token::Punct('<') => Ok(Value::Message(parse_text_fields(tokens, pos, Some('>'))?)),
+        Token::Str(s) => {
+            let mut s = s.clone();
+            while let Some(Token::Str(next)) = tokens.get(*pos) {
+                s.extend_from_slice(next);
+                *pos += 1;
+            }
+            Ok(Value::Str(s))
+        },
+        Token::Word(word) => Ok(Value::Token(word.clone())),
+        Token::Punct(c) => fail(format!("expected value, found {:?}", c)),
+    }
+}
+
+/// Consumes `c` from `tokens` at `*pos`, or fails if it isn't there.
+fn expect(tokens: &[Token], pos: &mut usize, c: char) -> Result<()> {
+    if tokens.get(*pos) != Some(&Token::Punct(c)) {
+        return fail(format!("expected {:?}", c));
+    }
+    *pos += 1;
+    Ok(())
+}
+
+/// Encodes [`Value`] trees in the binary format, according to message descriptors.
+struct Encoder {
+    // Descriptors for the messages and enums we know, indexed by fully qualified name with a
+    // leading `.`, as in `FieldDescriptorProto::type_name`.
+    messages: HashMap<String, &'static DescriptorProto>,
+    enums: HashMap<String, &'static EnumDescriptorProto>,
+
+    // Whether the input is JSON.
+    json: bool,
+}
+
+impl Encoder {
+    fn new(json: bool) -> Encoder {
+        let mut encoder = Encoder { messages: HashMap::new(), enums: HashMap::new(), json };
+        for &file in &[proto::p4runtime::file_descriptor_proto(),
+                      proto::p4data::file_descriptor_proto(),
+                      proto::p4info::file_descriptor_proto(),
+                      proto::p4types::file_descriptor_proto(),
+                      proto::status::file_descriptor_proto(),
+                      proto::code::file_descriptor_proto(),
+                      proto::ofp4::file_descriptor_proto()] {
+            let prefix = format!(".{}", file.get_package());
+            encoder.add_types(&prefix, file.get_message_type(), file.get_enum_type());
+        }
+        let any = Any::descriptor_static();
+        encoder.messages.insert(format!(".{}", any.full_name()), any.get_proto());
+        encoder
+    }
+
+    /// Adds `messages` and `enums`, and the types nested within them, whose names are qualified by
+    /// `prefix`.
+    fn add_types(&mut self, prefix: &str, messages: &'static [DescriptorProto], enums: &'static [EnumDescriptorProto]) {
+        for message in messages {
+            let name = format!("{}.{}", prefix, message.get_name());
+            self.add_types(&name, message.get_nested_type(), message.get_enum_type());
+            self.messages.insert(name, message);
+        }
+        for e in enums {
+            self.enums.insert(format!("{}.{}", prefix, e.get_name()), e);
+        }
+    }
+
+    /// Parses `fields` as a message of type `M`.
+    fn parse<M: Message>(&self, fields: &[(String, Value)]) -> Result<M> {
+        let descriptor = M::descriptor_static();
+        let message = self.messages.get(&format!(".{}", descriptor.full_name()))
+            .copied()
+            .unwrap_or_else(|| descriptor.get_proto());
+        let bytes = self.encode(message, fields)?;
+        Ok(M::parse_from_bytes(&bytes)?)
+    }
+
+    /// Encodes `fields` as a `message` in the binary format.
+    fn encode(&self, message: &DescriptorProto, fields: &[(String, Value)]) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut os = CodedOutputStream::vec(&mut bytes);
+        for (name, value) in fields {
+            let field = message.get_field().iter()
+                .find(|f| f.get_name() == name.as_str() || (self.json && json_name(f.get_name()) == *name));
+            let field = match field {
+                Some(field) => field,
+                None => return fail(format!("{} has no field {}", message.get_name(), name)),
+            };
+            let values = match value {
+                Value::List(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            for value in values {
+                self.encode_field(&mut os, field, value)
+                    .with_context(|| format!("{}.{}", message.get_name(), name))?;
+            }
+        }
+        os.flush()?;
+        drop(os);
+        Ok(bytes)
+    }
+
+    /// Encodes `value` for `field` into `os`.
+    fn encode_field(&self, os: &mut CodedOutputStream, field: &FieldDescriptorProto, value: &Value) -> Result<()> {
+        use FieldDescriptorProto_Type::*;
+        let number = field.get_number() as u32;
+        match (field.get_field_type(), value) {
+            (TYPE_MESSAGE, Value::Message(fields)) => {
+                let message = match self.messages.get(field.get_type_name()) {
+                    Some(message) => message,
+                    None => return fail(format!("unknown message type {}", field.get_type_name())),
+                };
+                os.write_bytes(number, &self.encode(message, fields)?)?;
+            },
+            (TYPE_MESSAGE, _) => return fail("expected a message".into()),
+            (TYPE_STRING, Value::Str(s)) => os.write_bytes(number, s)?,
+            (TYPE_BYTES, Value::Str(s)) if self.json => os.write_bytes(number, &base64_decode(s)?)?,
+            (TYPE_BYTES, Value::Str(s)) => os.write_bytes(number, s)?,
+            (TYPE_STRING, _) | (TYPE_BYTES, _) => return fail("expected a string".into()),
+            (TYPE_ENUM, value) => {
+                let s = scalar(value)?;
+                let known = self.enums.get(field.get_type_name())
+                    .and_then(|e| e.get_value().iter().find(|v| v.get_name() == s));
+                let n = match known {
+                    Some(v) => v.get_number(),
+                    None => parse_int(s)?.try_into()?,
+                };
+                os.write_enum(number, n)?;
+            },
+            (TYPE_BOOL, value) => match scalar(value)? {
+                "true" | "True" | "t" | "1" => os.write_bool(number, true)?,
+                "false" | "False" | "f" | "0" => os.write_bool(number, false)?,
+                s => return fail(format!("{}: expected true or false", s)),
+            },
+            (TYPE_DOUBLE, value) => os.write_double(number, parse_float(scalar(value)?)?)?,
+            (TYPE_FLOAT, value) => os.write_float(number, parse_float(scalar(value)?)? as f32)?,
+            (field_type, value) => {
+                let n = parse_int(scalar(value)?)?;
+                match field_type {
+                    TYPE_INT32 => os.write_int32(number, n.try_into()?)?,
+                    TYPE_INT64 => os.write_int64(number, n.try_into()?)?,
+                    TYPE_UINT32 => os.write_uint32(number, n.try_into()?)?,
+                    TYPE_UINT64 => os.write_uint64(number, n.try_into()?)?,
+                    TYPE_SINT32 => os.write_sint32(number, n.try_into()?)?,
+                    TYPE_SINT64 => os.write_sint64(number, n.try_into()?)?,
+                    TYPE_FIXED32 => os.write_fixed32(number, n.try_into()?)?,
+                    TYPE_FIXED64 => os.write_fixed64(number, n.try_into()?)?,
+                    TYPE_SFIXED32 => os.write_sfixed32(number, n.try_into()?)?,
+                    TYPE_SFIXED64 => os.write_sfixed64(number, n.try_into()?)?,
+                    _ => return fail(format!("unsupported field type {:?}", field_type)),
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Returns `value` as text, if it is a number, identifier, or string.
+fn scalar(value: &Value) -> Result<&str> {
+    match value {
+        Value::Token(s) => Ok(s.as_str()),
+        Value::Str(s) => Ok(std::str::from_utf8(s).context("invalid UTF-8")?),
+        _ => fail("expected a number, identifier, or string".into()),
+    }
+}
+
+/// Parses `s` as an integer in decimal, in hex with `0x`, or in octal with a leading `0`.
+fn parse_int(s: &str) -> Result<i128> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let magnitude = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i128::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i128::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse::<i128>()
+    };
+    match magnitude {
+        Ok(magnitude) => Ok(if negative { -magnitude } else { magnitude }),
+        Err(_) => fail(format!("{}: invalid integer", s)),
+    }
+}
+
+/// Parses `s` as a floating-point number.
+fn parse_float(s: &str) -> Result<f64> {
+    match s.trim_end_matches(&['f', 'F'][..]).parse::<f64>() {
+        Ok(f) => Ok(f),
+        Err(_) => fail(format!("{}: invalid number", s)),
+    }
+}
+
+/// Returns the JSON name for a field named `name`, which is `name` in lowerCamelCase.
+fn json_name(name: &str) -> String {
+    let mut json_name = String::with_capacity(name.len());
+    let mut capitalize = false;
+    for c in name.chars() {
+        if c == '_' {
+            capitalize = true;
+        } else if capitalize {
+            json_name.push(c.to_ascii_uppercase());
+            capitalize = false;
+        } else {
+            json_name.push(c);
+        }
+    }
+    json_name
+}
+
+/// Decodes `s` from base64, in the standard or the URL-safe alphabet, with or without padding.
+fn base64_decode(s: &[u8]) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut n_bits = 0;
+    for &c in s.iter().filter(|&&c| c != b'=') {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return fail("invalid base64".into()),
+        };
+        bits = ((bits << 6) | sextet as u32) & 0xffff;
+        n_bits += 6;
+        if n_bits >= 8 {
+            n_bits -= 8;
+            bytes.push((bits >> n_bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
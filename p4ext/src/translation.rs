@@ -0,0 +1,403 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! The interface between `ofp4` and a Nerpa program's translation from P4 to OpenFlow.
+//!
+//! A translation is a DDlog program with:
+//!
+//! * An input relation for each P4 table, named after the table's base name, whose records have
+//!   the fields that [`TableEntry::to_fields`](crate::TableEntry::to_fields) produces followed by
+//!   `entry_id: bit<32>`, a nonzero identifier that `ofp4` assigns to the table entry.  The
+//!   relation is never declared in the singleton form.
+//!
+//! * For each P4 table whose records have an `action` field and whose P4 program doesn't fix its
+//!   default action, an input relation named after the table's base name followed by
+//!   `DefaultAction`, e.g. `PortDefaultAction(action: PortAction)`.  It is empty while the table
+//!   has its initial default action and otherwise holds a record for the default action that the
+//!   controller set.  The translation should derive the table's miss flow from the record when
+//!   there is one, and from the P4 program's default action otherwise.
+//!
+//! * For each P4 table with an action profile, whose records' `action` field is a
+//!   `<Table>MemberRef{member_id: bit<32>}` or, if the profile has a selector,
+//!   `<Table>GroupRef{group_id: bit<32>}` (see [`ActionProfileRef::to_record`]), an input
+//!   relation `<Table>Member` with a record for each member of the profile, whose fields are
+//!   `member_id: bit<32>` followed by the member's `action` if the table's records would have an
+//!   `action` field without the profile, and an input relation `<Table>GroupMember(group_id:
+//!   bit<32>, member_id: bit<32>, weight: bit<32>)` with a record for each member of each group
+//!   in the profile.  Tables that share an action profile each get a copy of its members and
+//!   groups.
+//!
+//! * An input relation `MulticastGroup(mcast_id: bit<16>, port: bit<9>)` with a record for each
+//!   replica in each multicast group.
+//!
+//! * Optionally, an input relation `Port(p4_port: bit<9>, ofport: bit<16>)` with a record for
+//!   each P4 port whose OpenFlow port number `ofp4` learned from the Open vSwitch database.  The
+//!   translation should map `ofport` to `p4_port` when a packet enters the pipeline and back when
+//!   it leaves.  P4 ports without a record have the same number in OpenFlow.
+//!
+//! * An output relation `Flow(s: string)`, with an index `Flow` on it, whose records are OpenFlow
+//!   flows in the syntax of `ovs-ofctl add-flow`.  A flow derived from a table entry should
+//!   specify the entry's ID as its cookie, e.g. `cookie=${entry_id} table=...`, so that `ofp4` can
+//!   attribute the flow's statistics to the entry.  A flow derived from a multicast group should
+//!   specify the group's ID plus `0x80000000` as its cookie instead.  `ofp4` moves the cookie
+//!   into the upper 32 bits of the cookie that it installs and uses the lower 32 bits for its own
+//!   purposes.
+//!
+//! * Optionally, an output relation `Group(s: string)`, with an index `Group` on it, whose records
+//!   are OpenFlow groups in the syntax of `ovs-ofctl add-group`, e.g.
+//!   `group_id=5,type=all,bucket=actions=...`.  Flows refer to groups with the `group:N` action.
+//!   A translation might use an `all` group for each P4 multicast group, and a `select` group,
+//!   with a bucket per member weighted by the member's weight, for each table entry that refers
+//!   to a P4 action selector group.  Each group must have a distinct `group_id`.
+//!
+//! * Optionally, for P4 meters, an input relation `MeterConfig(meter_id: bit<32>, index: bit<64>,
+//!   cir: bit<64>, cburst: bit<64>, pir: bit<64>, pburst: bit<64>)` with a record for each
+//!   configured cell of an indirect meter, and an input relation `DirectMeterConfig(meter_id:
+//!   bit<32>, entry_id: bit<32>, cir: bit<64>, cburst: bit<64>, pir: bit<64>, pburst: bit<64>)`
+//!   with a record for each table entry whose direct meter is configured.  `meter_id` is the
+//!   meter's ID in the P4Info.  The rates and bursts are in the meter's units, bytes or packets.
+//!
+//! * Optionally, an output relation `Meter(s: string)`, with an index `Meter` on it, whose records
+//!   are OpenFlow meters in the syntax of `ovs-ofctl add-meter`, e.g.
+//!   `meter=5,kbps,burst,bands=type=drop,rate=1000,burst_size=100`.  Flows refer to meters with
+//!   the `meter:N` action.  Each meter must have a distinct ID; the ID of a table entry's direct
+//!   meter might be the entry's ID, for example.
+//!
+//! `ofp4` uses the translation through the [`Translation`] trait.  The implementation for a
+//! particular program lives in a small generated crate, so that the same `ofp4` source code works
+//! with every Nerpa program.
+
+use differential_datalog::api::HDDlog;
+use differential_datalog::ddval::DDValue;
+use differential_datalog::program::{IdxId, RelId};
+use differential_datalog::record::{IntoRecord, Name, Record, RelIdentifier, UpdCmd};
+use differential_datalog::DDlogInventory;
+
+use crate::{
+    Action,
+    ActionParam,
+    ActionProfileRef,
+    Error,
+    FieldMatch,
+    FieldMatchType,
+    FieldValue,
+    MatchType,
+    Table,
+    TableAction,
+    TableEntry,
+    TableKey,
+    TableValue,
+};
+
+use grpcio::RpcStatusCode;
+
+use proto::p4runtime::MeterConfig;
+
+use anyhow::{Context, Result};
+
+/// A DDlog program that translates P4Runtime entities into OpenFlow flows.
+///
+/// Only [`Self::run`] depends on the particular program.  The other methods have default
+/// implementations that find relations by the names listed in the [module
+/// documentation](self).
+pub trait Translation: Send {
+    /// Starts the DDlog program with `workers` worker threads.
+    fn run(&self, workers: usize) -> Result<HDDlog, String>;
+
+    /// Returns the ID of the input relation for the P4 table whose base name is `table`.
+    fn table_relation(&self, hddlog: &HDDlog, table: &str) -> Result<RelId, String> {
+        hddlog.inventory.get_table_id(table).map(|id| id as RelId)
+    }
+
+    /// Returns a record for the input relation for `table` that represents `entry`, whose ID is
+    /// `entry_id`.
+    fn table_record(&self, table: &Table, entry: &TableEntry, entry_id: u32) -> Result<Record> {
+        let mut fields = entry.to_fields(table)?;
+        fields.push((Name::from("entry_id"), entry_id.into_record()));
+        Ok(Record::NamedStruct(Name::Owned(table.base_name().into()), fields))
+    }
+
+    /// Checks that the DDlog program can hold entries for `table`, that is, that it has an input
+    /// relation for the table that accepts an entry with each of the table's actions or, if the
+    /// table has an action profile, with a reference to a member and to a group, along with
+    /// relations for the profile's members and groups; and, if
+    /// [`Table::has_default_action_relation`] is true, a default action relation that accepts
+    /// each of the table's default actions.  The entries have all-zero keys and arguments, so
+    /// this catches a P4Info that doesn't match the P4 program that the translation came from,
+    /// but not every possible mistake.
+    fn check_table(&self, hddlog: &HDDlog, table: &Table) -> Result<()> {
+        let check = |relid, record, what: &str| match hddlog.convert_update_command(
+            &UpdCmd::Insert(RelIdentifier::RelId(relid), record)) {
+            Ok(_) => Ok(()),
+            Err(s) => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("translation does not accept {} for table {} ({})", what, table.base_name(), s)),
+        };
+
+        let relid = match self.table_relation(hddlog, table.base_name()) {
+            Ok(relid) => relid,
+            Err(_) => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                .context(format!("translation has no input relation for table {}", table.base_name()))?,
+        };
+        let actions: Vec<Option<TableAction>> = if table.entry_actions().next().is_none() {
+            vec![None]
+        } else {
+            table.entry_actions().map(|ar| Some(sample_action(&ar.action))).collect()
+        };
+        if table.action_profile_id().is_some() {
+            for apr in &[ActionProfileRef::Member(0), ActionProfileRef::Group(0)] {
+                let mut entry = sample_entry(table, None);
+                entry.value.action_profile_ref = Some(*apr);
+                check(relid, self.table_record(table, &entry, 1)?, "entries")?;
+            }
+
+            let relid = match self.member_relation(hddlog, table) {
+                Ok(relid) => relid,
+                Err(_) => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("translation has no action profile member relation for table {}", table.base_name()))?,
+            };
+            for action in actions.iter().flatten() {
+                check(relid, self.member_record(table, 0, action)?, "action profile members")?;
+            }
+            let relid = match self.group_member_relation(hddlog, table) {
+                Ok(relid) => relid,
+                Err(_) => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("translation has no action profile group relation for table {}", table.base_name()))?,
+            };
+            check(relid, self.group_member_record(table, 0, 0, 1), "action profile groups")?;
+        } else {
+            for action in actions {
+                let entry = sample_entry(table, action);
+                check(relid, self.table_record(table, &entry, 1)?, "entries")?;
+            }
+        }
+
+        if table.has_default_action_relation() {
+            let relid = match self.default_action_relation(hddlog, table) {
+                Ok(relid) => relid,
+                Err(_) => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("translation has no default action relation for table {}", table.base_name()))?,
+            };
+            for ar in table.actions.iter().filter(|ar| ar.scope.may_be_default()) {
+                check(relid, self.default_action_record(table, &sample_action(&ar.action))?, "default actions")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the ID of the input relation for the default action of `table`.  Only tables for
+    /// which [`Table::has_default_action_relation`] is true have one.
+    fn default_action_relation(&self, hddlog: &HDDlog, table: &Table) -> Result<RelId, String> {
+        self.table_relation(hddlog, &format!("{}DefaultAction", table.base_name()))
+    }
+
+    /// Returns a record for the default action input relation for `table` that makes `action`
+    /// the table's default action.
+    fn default_action_record(&self, table: &Table, action: &TableAction) -> Result<Record> {
+        match action.to_record(table)? {
+            Some(record) => Ok(Record::NamedStruct(Name::Owned(format!("{}DefaultAction", table.base_name())),
+                                                   vec![(Name::from("action"), record)])),
+            None => Err(Error(RpcStatusCode::UNIMPLEMENTED))
+                .context(format!("table {} has no default action relation", table.base_name())),
+        }
+    }
+
+    /// Returns the ID of the input relation for the members of the action profile of `table`.
+    /// Only tables with an action profile have one.
+    fn member_relation(&self, hddlog: &HDDlog, table: &Table) -> Result<RelId, String> {
+        self.table_relation(hddlog, &format!("{}Member", table.base_name()))
+    }
+
+    /// Returns a record for the member input relation for `table` that represents the member of
+    /// the table's action profile with ID `member_id` and `action`.
+    fn member_record(&self, table: &Table, member_id: u32, action: &TableAction) -> Result<Record> {
+        let mut fields = vec![(Name::from("member_id"), member_id.into_record())];
+        if let Some(record) = action.to_record(table)? {
+            fields.push((Name::from("action"), record));
+        }
+        Ok(Record::NamedStruct(Name::Owned(format!("{}Member", table.base_name())), fields))
+    }
+
+    /// Returns the ID of the input relation for the groups of the action profile of `table`.
+    /// Only tables with an action profile have one.
+    fn group_member_relation(&self, hddlog: &HDDlog, table: &Table) -> Result<RelId, String> {
+        self.table_relation(hddlog, &format!("{}GroupMember", table.base_name()))
+    }
+
+    /// Returns a record for the group input relation for `table` that puts member `member_id`,
+    /// with `weight`, into group `group_id` of the table's action profile.
+    fn group_member_record(&self, table: &Table, group_id: u32, member_id: u32, weight: u32) -> Record {
+        Record::NamedStruct(Name::Owned(format!("{}GroupMember", table.base_name())),
+                            vec![(Name::from("group_id"), group_id.into_record()),
+                                 (Name::from("member_id"), member_id.into_record()),
+                                 (Name::from("weight"), weight.into_record())])
+    }
+
+    /// Returns the ID of the multicast group input relation.
+    fn multicast_relation(&self, hddlog: &HDDlog) -> Result<RelId, String> {
+        self.table_relation(hddlog, "MulticastGroup")
+    }
+
+    /// Returns a record for the multicast group input relation that adds `port` to multicast
+    /// group `mcast_id`.
+    fn multicast_record(&self, mcast_id: u16, port: u16) -> Record {
+        Record::NamedStruct(Name::from("MulticastGroup"),
+                            vec![(Name::from("mcast_id"), mcast_id.into_record()),
+                                 (Name::from("port"), port.into_record())])
+    }
+
+    /// Returns the ID of the port input relation.
+    fn port_relation(&self, hddlog: &HDDlog) -> Result<RelId, String> {
+        self.table_relation(hddlog, "Port")
+    }
+
+    /// Returns a record for the port input relation that maps P4 port `p4_port` to OpenFlow port
+    /// `ofport`.
+    fn port_record(&self, p4_port: u16, ofport: u16) -> Record {
+        Record::NamedStruct(Name::from("Port"),
+                            vec![(Name::from("p4_port"), p4_port.into_record()),
+                                 (Name::from("ofport"), ofport.into_record())])
+    }
+
+    /// Returns the ID of the indirect meter configuration input relation.
+    fn meter_config_relation(&self, hddlog: &HDDlog) -> Result<RelId, String> {
+        self.table_relation(hddlog, "MeterConfig")
+    }
+
+    /// Returns a record for the indirect meter configuration input relation that configures cell
+    /// `index` of meter `meter_id` with `config`.
+    fn meter_config_record(&self, meter_id: u32, index: u64, config: &MeterConfig) -> Record {
+        let mut fields = vec![(Name::from("meter_id"), meter_id.into_record()),
+                              (Name::from("index"), index.into_record())];
+        fields.extend(meter_config_fields(config));
+        Record::NamedStruct(Name::from("MeterConfig"), fields)
+    }
+
+    /// Returns the ID of the direct meter configuration input relation.
+    fn direct_meter_config_relation(&self, hddlog: &HDDlog) -> Result<RelId, String> {
+        self.table_relation(hddlog, "DirectMeterConfig")
+    }
+
+    /// Returns a record for the direct meter configuration input relation that configures direct
+    /// meter `meter_id` for the table entry with ID `entry_id` with `config`.
+    fn direct_meter_config_record(&self, meter_id: u32, entry_id: u32, config: &MeterConfig) -> Record {
+        let mut fields = vec![(Name::from("meter_id"), meter_id.into_record()),
+                              (Name::from("entry_id"), entry_id.into_record())];
+        fields.extend(meter_config_fields(config));
+        Record::NamedStruct(Name::from("DirectMeterConfig"), fields)
+    }
+
+    /// Returns the ID of the `Meter` output relation.
+    fn meter_relation(&self, hddlog: &HDDlog) -> Result<RelId, String> {
+        self.table_relation(hddlog, "Meter")
+    }
+
+    /// Returns the ID of the index on the `Meter` output relation.
+    fn meter_index(&self, hddlog: &HDDlog) -> Result<IdxId, String> {
+        hddlog.inventory.get_index_id("Meter").map(|id| id as IdxId)
+    }
+
+    /// Returns the OpenFlow meter that `value`, a record in the `Meter` output relation,
+    /// represents.
+    fn meter_string(&self, value: &DDValue) -> Option<String> {
+        self.flow_string(value)
+    }
+
+    /// Returns the ID of the `Flow` output relation.
+    fn flow_relation(&self, hddlog: &HDDlog) -> Result<RelId, String> {
+        self.table_relation(hddlog, "Flow")
+    }
+
+    /// Returns the ID of the index on the `Flow` output relation.
+    fn flow_index(&self, hddlog: &HDDlog) -> Result<IdxId, String> {
+        hddlog.inventory.get_index_id("Flow").map(|id| id as IdxId)
+    }
+
+    /// Returns the ID of the `Group` output relation.
+    fn group_relation(&self, hddlog: &HDDlog) -> Result<RelId, String> {
+        self.table_relation(hddlog, "Group")
+    }
+
+    /// Returns the ID of the index on the `Group` output relation.
+    fn group_index(&self, hddlog: &HDDlog) -> Result<IdxId, String> {
+        hddlog.inventory.get_index_id("Group").map(|id| id as IdxId)
+    }
+
+    /// Returns the OpenFlow group that `value`, a record in the `Group` output relation,
+    /// represents.
+    fn group_string(&self, value: &DDValue) -> Option<String> {
+        self.flow_string(value)
+    }
+
+    /// Returns the OpenFlow flow that `value`, a record in the `Flow` output relation, represents.
+    fn flow_string(&self, value: &DDValue) -> Option<String> {
+        match value.clone().into_record() {
+            Record::NamedStruct(_, fields) => match fields.into_iter().next() {
+                Some((_, Record::String(s))) => Some(s),
+                _ => None,
+            },
+            Record::PosStruct(_, fields) => match fields.into_iter().next() {
+                Some(Record::String(s)) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Returns `action` with all of its arguments 0, for [`Translation::check_table`].
+fn sample_action(action: &Action) -> TableAction {
+    TableAction {
+        action_id: action.preamble.id,
+        params: action.params.iter()
+            .map(|p| ActionParam { param_id: p.preamble.id, value: FieldValue(0) })
+            .collect(),
+    }
+}
+
+/// Returns an entry for `table` with `action`, whose exact-match fields are 0 and whose other
+/// fields are don't-cares, for [`Translation::check_table`].
+fn sample_entry(table: &Table, action: Option<TableAction>) -> TableEntry {
+    TableEntry {
+        key: TableKey {
+            table_id: table.preamble.id,
+            matches: table.match_fields.iter()
+                .filter(|mf| mf.match_type == MatchType::Exact)
+                .map(|mf| FieldMatch { field_id: mf.preamble.id, match_type: FieldMatchType::Exact(FieldValue(0)) })
+                .collect(),
+            priority: if table.has_priority() { 1 } else { 0 },
+            is_default_action: false,
+        },
+        value: TableValue {
+            action,
+            action_profile_ref: None,
+            controller_metadata: 0,
+            metadata: Vec::new(),
+            idle_timeout_ns: 0,
+        },
+    }
+}
+
+/// Returns the fields for the rates and bursts in `config`, for a meter configuration record.
+fn meter_config_fields(config: &MeterConfig) -> Vec<(Name, Record)> {
+    vec![(Name::from("cir"), (config.cir as u64).into_record()),
+         (Name::from("cburst"), (config.cburst as u64).into_record()),
+         (Name::from("pir"), (config.pir as u64).into_record()),
+         (Name::from("pburst"), (config.pburst as u64).into_record())]
+}
@@ -0,0 +1,117 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+extern crate p4ext;
+
+use grpcio::RpcStatusCode;
+use p4ext::{FieldMatchType, FieldValue, Switch, TableEntry};
+use p4ext::commands::parse_commands;
+use proto::p4runtime::Update_Type;
+use protobuf::Message;
+use std::convert::TryFrom;
+use std::fs::File;
+
+fn vlan_switch() -> Switch {
+    let mut file = File::open("examples/vlan/vlan.p4info.bin").unwrap();
+    let p4info: proto::p4info::P4Info = Message::parse_from_reader(&mut file).unwrap();
+    (&p4info).into()
+}
+
+fn error_code(result: anyhow::Result<Vec<proto::p4runtime::Update>>) -> RpcStatusCode {
+    match result.unwrap_err().downcast_ref::<p4ext::Error>() {
+        Some(p4ext::Error(code)) => *code,
+        None => RpcStatusCode::UNKNOWN,
+    }
+}
+
+#[test]
+fn parse_table_commands() {
+    let switch = vlan_switch();
+    let updates = parse_commands(&switch, "
+# Comments and blank lines are ignored.
+
+table_add vlan_incoming_exact vlan_incoming_forward 1 10 => 2
+table_add MyIngress.ipv4_lpm ipv4_forward 10.0.1.0/24 => 00:11:22:33:44:55 3
+table_add arp_exact arp_reply 0x0a000101 => 0xaabbccddeeff
+table_set_default ipv4_lpm drop
+table_delete arp_exact 0
+").unwrap();
+    assert_eq!(updates.len(), 5);
+    let types: Vec<_> = updates.iter().map(|u| u.get_field_type()).collect();
+    assert_eq!(types, vec![Update_Type::INSERT, Update_Type::INSERT, Update_Type::INSERT,
+                           Update_Type::MODIFY, Update_Type::DELETE]);
+
+    let entries: Vec<TableEntry> = updates.iter()
+        .map(|u| TableEntry::try_from(u.get_entity().get_table_entry()).unwrap())
+        .collect();
+    assert_eq!(entries[0].key.matches[1].match_type, FieldMatchType::Exact(FieldValue(10)));
+    assert_eq!(entries[1].key.matches[0].match_type,
+               FieldMatchType::LPM { value: FieldValue(0x0a000100), plen: 24 });
+    let action = entries[1].value.action.as_ref().unwrap();
+    assert_eq!(action.params[0].value, FieldValue(0x001122334455));
+    assert_eq!(action.params[1].value, FieldValue(3));
+    assert!(entries[3].key.is_default_action);
+    assert_eq!(entries[4], entries[2]);
+}
+
+#[test]
+fn parse_multicast_commands() {
+    let switch = vlan_switch();
+    let updates = parse_commands(&switch, "
+mc_mgrp_create 1
+mc_node_create 0 1 2
+mc_node_create 7 3
+mc_node_associate 1 0
+mc_node_associate 1 1
+mirroring_add 5 4
+").unwrap();
+    assert_eq!(updates.len(), 2);
+
+    let pre = updates[0].get_entity().get_packet_replication_engine_entry();
+    let mge: p4ext::MulticastGroupEntry = pre.get_multicast_group_entry().into();
+    assert_eq!(mge.multicast_group_id, 1);
+    let replicas: Vec<_> = mge.replicas.iter().map(|r| (r.egress_port, r.instance)).collect();
+    assert_eq!(replicas, vec![(1, 0), (2, 0), (3, 7)]);
+
+    let cse = updates[1].get_entity().get_packet_replication_engine_entry().get_clone_session_entry();
+    assert_eq!(cse.session_id, 5);
+    assert_eq!(cse.get_replicas()[0].egress_port, 4);
+}
+
+#[test]
+fn reject_bad_commands() {
+    let switch = vlan_switch();
+    assert_eq!(error_code(parse_commands(&switch, "no_such_command 1")), RpcStatusCode::UNIMPLEMENTED);
+    assert_eq!(error_code(parse_commands(&switch, "table_add no_such_table drop 1 =>")),
+               RpcStatusCode::NOT_FOUND);
+    assert_eq!(error_code(parse_commands(&switch, "table_add port_exact portfwd 1 2 => 3")),
+               RpcStatusCode::INVALID_ARGUMENT);
+    assert_eq!(error_code(parse_commands(&switch, "table_add port_exact portfwd 1 => 3 4 5")),
+               RpcStatusCode::INVALID_ARGUMENT);
+    assert_eq!(error_code(parse_commands(&switch, "table_add port_exact portfwd xyzzy => 3")),
+               RpcStatusCode::INVALID_ARGUMENT);
+    assert_eq!(error_code(parse_commands(&switch, "table_delete port_exact 0")), RpcStatusCode::NOT_FOUND);
+    assert_eq!(error_code(parse_commands(&switch, "mc_node_associate 1 0")), RpcStatusCode::NOT_FOUND);
+
+    // Errors report the line number.
+    let error = parse_commands(&switch, "mc_mgrp_create 1\nmc_mgrp_create 1").unwrap_err();
+    assert!(format!("{:#}", error).starts_with("line 2: mc_mgrp_create 1"));
+}
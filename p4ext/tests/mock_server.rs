@@ -0,0 +1,147 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+
+extern crate p4ext;
+
+use futures::{SinkExt, StreamExt};
+use grpcio::{ClientDuplexReceiver, RpcStatusCode, WriteFlags};
+use p4ext::mock::MockServer;
+use proto::p4runtime::{
+    MasterArbitrationUpdate,
+    StreamMessageRequest,
+    StreamMessageResponse,
+    Uint128,
+    Update_Type,
+    WriteRequest,
+};
+use protobuf::{Message, RepeatedField};
+
+fn rpc_code<T>(result: Result<T, grpcio::Error>) -> RpcStatusCode {
+    match result {
+        Ok(_) => RpcStatusCode::OK,
+        Err(grpcio::Error::RpcFailure(status)) => status.code(),
+        Err(_) => RpcStatusCode::UNKNOWN,
+    }
+}
+
+fn write_request(device_id: u64, election_id: u64, updates: Vec<proto::p4runtime::Update>) -> WriteRequest {
+    let mut request = WriteRequest::new();
+    request.set_device_id(device_id);
+    request.mut_election_id().set_low(election_id);
+    request.set_updates(RepeatedField::from_vec(updates));
+    request
+}
+
+fn arbitration_request(election_id: u64) -> StreamMessageRequest {
+    let mut election = Uint128::new();
+    election.set_low(election_id);
+    let mut update = MasterArbitrationUpdate::new();
+    update.set_device_id(0);
+    update.set_election_id(election);
+    let mut request = StreamMessageRequest::new();
+    request.set_arbitration(update);
+    request
+}
+
+/// Returns the next arbitration update from `receiver`, skipping other stream messages.
+async fn next_arbitration(receiver: &mut ClientDuplexReceiver<StreamMessageResponse>) -> MasterArbitrationUpdate {
+    loop {
+        let mut response = receiver.next().await.unwrap().unwrap();
+        if response.has_arbitration() {
+            return response.take_arbitration();
+        }
+    }
+}
+
+async fn set_pipeline(setup: &p4ext::TestSetup) {
+    p4ext::set_pipeline_config(&setup.p4info, &setup.json, &setup.cookie, &setup.action,
+                               setup.device_id, setup.role_id, &setup.target, &setup.client)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn write_errors() {
+    let mut setup = p4ext::TestSetup::new();
+    let multicast = p4ext::build_multicast_write(Update_Type::INSERT, 1, Vec::new());
+
+    // Writes require a primary controller, a pipeline, and the right device.
+    let request = write_request(0, 0, vec![multicast.clone()]);
+    assert_eq!(rpc_code(setup.client.write(&request)), RpcStatusCode::PERMISSION_DENIED);
+    setup.become_primary().await;
+    assert_eq!(rpc_code(setup.client.write(&request)), RpcStatusCode::FAILED_PRECONDITION);
+    set_pipeline(&setup).await;
+    let request = write_request(1, 0, vec![multicast.clone()]);
+    assert_eq!(rpc_code(setup.client.write(&request)), RpcStatusCode::NOT_FOUND);
+
+    // A batch with a failed update reports each update's status in the details.
+    let request = write_request(0, 0, vec![multicast.clone(), multicast.clone()]);
+    let status = match setup.client.write(&request) {
+        Err(grpcio::Error::RpcFailure(status)) => status,
+        other => panic!("unexpected write result {:?}", other),
+    };
+    assert_eq!(status.code(), RpcStatusCode::UNKNOWN);
+    let details: proto::status::Status = Message::parse_from_bytes(status.details()).unwrap();
+    let codes: Vec<i32> = details.get_details().iter()
+        .map(|any| any.unpack::<proto::p4runtime::Error>().unwrap().unwrap().canonical_code)
+        .collect();
+    assert_eq!(codes, vec![RpcStatusCode::OK.into(), RpcStatusCode::ALREADY_EXISTS.into()]);
+    assert_eq!(setup.mock.as_ref().unwrap().entities().multicast_groups.len(), 1);
+
+    // Modifying or deleting a nonexistent entity fails.
+    let modify = p4ext::build_multicast_write(Update_Type::MODIFY, 2, Vec::new());
+    let request = write_request(0, 0, vec![modify]);
+    assert_eq!(rpc_code(setup.client.write(&request)), RpcStatusCode::UNKNOWN);
+}
+
+#[tokio::test]
+async fn arbitration() {
+    let server = MockServer::start(0).unwrap();
+    let client = server.client();
+
+    // The first controller becomes primary.
+    let (mut sink1, mut receiver1) = client.stream_channel().unwrap();
+    sink1.send((arbitration_request(1), WriteFlags::default())).await.unwrap();
+    let response = next_arbitration(&mut receiver1).await;
+    assert_eq!(response.get_status().code, i32::from(RpcStatusCode::OK));
+
+    // A controller with a higher election ID takes over, and both are told.
+    let (mut sink2, mut receiver2) = client.stream_channel().unwrap();
+    sink2.send((arbitration_request(2), WriteFlags::default())).await.unwrap();
+    let response = next_arbitration(&mut receiver2).await;
+    assert_eq!(response.get_status().code, i32::from(RpcStatusCode::OK));
+    let response = next_arbitration(&mut receiver1).await;
+    assert_eq!(response.get_status().code, i32::from(RpcStatusCode::ALREADY_EXISTS));
+    assert_eq!(response.get_election_id().low, 2);
+
+    // Only the primary may write.
+    let multicast = p4ext::build_multicast_write(Update_Type::INSERT, 1, Vec::new());
+    let request = write_request(0, 1, vec![multicast]);
+    assert_eq!(rpc_code(client.write(&request)), RpcStatusCode::PERMISSION_DENIED);
+
+    // When the primary goes away, the backup isn't promoted, and there's no primary to write.
+    drop(sink2);
+    drop(receiver2);
+    let response = next_arbitration(&mut receiver1).await;
+    assert_eq!(response.get_status().code, i32::from(RpcStatusCode::NOT_FOUND));
+    assert_eq!(response.get_election_id().low, 2);
+    assert_eq!(rpc_code(client.write(&request)), RpcStatusCode::PERMISSION_DENIED);
+}
@@ -26,42 +26,54 @@ use std::collections::HashMap;
 rusty_fork_test! {
     #[test]
     fn set_get_pipeline() {
-        let setup = p4ext::TestSetup::new();
+        let mut setup = p4ext::TestSetup::new();
 
-        p4ext::set_pipeline_config(
-            &setup.p4info,
-            &setup.opaque,
-            &setup.cookie,
-            &setup.action,
-            setup.device_id,
-            setup.role_id,
-            &setup.target,
-            &setup.client,
-        );
+        futures::executor::block_on(async {
+            setup.become_primary().await;
+            p4ext::set_pipeline_config(
+                &setup.p4info,
+                &setup.json,
+                &setup.cookie,
+                &setup.action,
+                setup.device_id,
+                setup.role_id,
+                &setup.target,
+                &setup.client,
+            ).await.unwrap();
 
-        let cfg = p4ext::get_pipeline_config(setup.device_id, &setup.target, &setup.client);
-        let switch : p4ext::Switch = cfg.get_p4info().into();
-        assert_eq!(switch.tables.len(), 4);
+            let cfg = p4ext::get_pipeline_config(setup.device_id, &setup.target, &setup.client).await.unwrap();
+            let switch : p4ext::Switch = cfg.get_p4info().into();
+            assert_eq!(switch.tables.len(), 4);
+        });
     }
 }
 
 #[tokio::test]
 async fn write_read() {
-    let setup = p4ext::TestSetup::new();
+    let mut setup = p4ext::TestSetup::new();
+    setup.become_primary().await;
     p4ext::set_pipeline_config(
         &setup.p4info,
-        &setup.opaque,
+        &setup.json,
         &setup.cookie,
         &setup.action,
         setup.device_id,
         setup.role_id,
         &setup.target,
         &setup.client,
-    );
+    ).await.unwrap();
 
-    // Write an empty table entry.
-    // TODO: Rewrite the Setup to accommodate new table_entry_update function.
-    let update = proto::p4runtime::Update::new();
+    // Write a table entry.
+    let cfg = p4ext::get_pipeline_config(setup.device_id, &setup.target, &setup.client).await.unwrap();
+    let switch: p4ext::Switch = cfg.get_p4info().into();
+    let entry = switch.entry(&setup.table_name)
+        .exact("standard_metadata.ingress_port", 11)
+        .exact("hdr.vlan.vid", 1)
+        .action(&setup.action_name)
+        .param("port", 11)
+        .build()
+        .unwrap();
+    let update = entry.to_update(proto::p4runtime::Update_Type::INSERT);
 
     assert!(p4ext::write(
         [update.clone()].to_vec(),
@@ -69,7 +81,7 @@ async fn write_read() {
         setup.role_id,
         &setup.target,
         &setup.client
-    ).is_ok());
+    ).await.is_ok());
     let write_entities = [update.clone().take_entity()].to_vec();
 
     // Set the ReadRequest entity with an empty table entry.
@@ -88,21 +100,12 @@ async fn write_read() {
 
 #[tokio::test]
 async fn stream_channel() {
+    // The first controller to arbitrate becomes primary, so there's no need for a pipeline or
+    // for the setup to be primary itself.
     let setup = p4ext::TestSetup::new();
-    p4ext::set_pipeline_config(
-        &setup.p4info,
-        &setup.opaque,
-        &setup.cookie,
-        &setup.action,
-        setup.device_id,
-        setup.role_id,
-        &setup.target,
-        &setup.client,
-    );
-
     let master_result = p4ext::master_arbitration_update(
         setup.device_id,
         &setup.client,
-    );
-    assert!(master_result.await.is_ok());
+    ).await;
+    assert!(master_result.unwrap().has_arbitration());
 }
@@ -0,0 +1,101 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+extern crate p4ext;
+
+use grpcio::RpcStatusCode;
+use p4ext::Session;
+use proto::p4runtime::{DigestList, Replica, StreamMessageResponse, Update_Type, WriteRequest_Atomicity};
+use std::time::Duration;
+
+#[tokio::test]
+async fn session() {
+    let setup = p4ext::TestSetup::new();
+    let mock = setup.mock.as_ref().unwrap();
+
+    // The controller with the higher election ID is primary.
+    let primary = Session::connect(&setup.target, setup.device_id, setup.role_id, 2).await.unwrap();
+    let backup = Session::connect(&setup.target, setup.device_id, setup.role_id, 1).await.unwrap();
+    assert!(primary.is_primary());
+    assert!(!backup.is_primary());
+
+    // Only the primary may write.
+    primary.set_pipeline(&setup.p4info, &setup.json, &setup.cookie, &setup.action).await.unwrap();
+    let mut replica = Replica::new();
+    replica.set_egress_port(1);
+    let multicast = p4ext::build_multicast_write(Update_Type::INSERT, 1, vec![replica]);
+    let error = backup.write(vec![multicast.clone()]).await.unwrap_err();
+    assert_eq!(error.status_code(), Some(RpcStatusCode::PERMISSION_DENIED));
+    primary.write(vec![multicast.clone()]).await.unwrap();
+    let entities = primary.read(vec![multicast.get_entity().clone()]).await.unwrap();
+    assert_eq!(entities.len(), 1);
+
+    // Digests reach the primary's subscribers.
+    let mut digests = primary.subscribe_digests();
+    let mut digest = DigestList::new();
+    digest.set_digest_id(5);
+    let mut message = StreamMessageResponse::new();
+    message.set_digest(digest);
+    mock.send_stream_message(message);
+    assert_eq!(digests.recv().await.unwrap().digest_id, 5);
+
+    // The backup stays a backup when the primary goes away, so nobody may write...
+    drop(primary);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!backup.is_primary());
+    let error = backup.write(vec![multicast.clone()]).await.unwrap_err();
+    assert_eq!(error.status_code(), Some(RpcStatusCode::PERMISSION_DENIED));
+
+    // ...until it re-arbitrates with a higher election ID than the old primary's.
+    drop(backup);
+    let backup = Session::connect(&setup.target, setup.device_id, setup.role_id, 3).await.unwrap();
+    assert!(backup.is_primary());
+    let modify = p4ext::build_multicast_write(Update_Type::MODIFY, 1, Vec::new());
+    backup.write(vec![modify]).await.unwrap();
+}
+
+#[tokio::test]
+async fn capabilities() {
+    let setup = p4ext::TestSetup::new();
+    let session = Session::connect(&setup.target, setup.device_id, setup.role_id, 1).await.unwrap();
+
+    // The device reports its entity kinds just after the arbitration reply that ends `connect`.
+    let mut capabilities = session.capabilities().await.unwrap();
+    for _ in 0..50 {
+        if !capabilities.entities.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        capabilities = session.capabilities().await.unwrap();
+    }
+    assert_eq!(capabilities.api_version, p4ext::server::P4RUNTIME_API_VERSION);
+    assert!(capabilities.supports_entity("table-entry"));
+    assert!(!capabilities.supports_entity("counter-entry"));
+    assert!(capabilities.supports_atomicity(WriteRequest_Atomicity::CONTINUE_ON_ERROR));
+    assert!(!capabilities.supports_atomicity(WriteRequest_Atomicity::DATAPLANE_ATOMIC));
+
+    // The RPC response carries only the API version, and the stream message carries the rest.
+    let response = capabilities.to_response();
+    assert_eq!(response.get_p4runtime_api_version(), p4ext::server::P4RUNTIME_API_VERSION);
+    let mut decoded = p4ext::server::Capabilities::from_response(&response);
+    assert!(decoded.entities.is_empty());
+    assert!(decoded.merge_stream_message(capabilities.to_stream_response().get_other()));
+    assert_eq!(decoded, capabilities);
+}
@@ -0,0 +1,178 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+extern crate p4ext;
+
+use grpcio::RpcStatusCode;
+use p4ext::{FieldMatchType, FieldValue, Switch};
+use protobuf::Message;
+use std::fs::File;
+
+fn vlan_switch() -> Switch {
+    let mut file = File::open("examples/vlan/vlan.p4info.bin").unwrap();
+    let p4info: proto::p4info::P4Info = Message::parse_from_reader(&mut file).unwrap();
+    (&p4info).into()
+}
+
+fn error_code<T: std::fmt::Debug>(result: anyhow::Result<T>) -> RpcStatusCode {
+    match result.unwrap_err().downcast_ref::<p4ext::Error>() {
+        Some(p4ext::Error(code)) => *code,
+        None => RpcStatusCode::UNKNOWN,
+    }
+}
+
+#[test]
+fn build_exact_entry() {
+    let switch = vlan_switch();
+    let entry = switch.entry("MyIngress.vlan_incoming_exact")
+        .exact("standard_metadata.ingress_port", 11)
+        .exact("hdr.vlan.vid", 1)
+        .action("vlan_incoming_forward")
+        .param("port", 11)
+        .build()
+        .unwrap();
+
+    let table = switch.table_by_name("vlan_incoming_exact").unwrap();
+    assert_eq!(entry.key.table_id, table.preamble.id);
+    assert_eq!(entry.key.matches.len(), 2);
+    assert_eq!(entry.key.matches[1].match_type, FieldMatchType::Exact(FieldValue(1)));
+    let action = entry.value.action.unwrap();
+    assert_eq!(action.action_id, table.action_by_name("MyIngress.vlan_incoming_forward").unwrap().action.preamble.id);
+    assert_eq!(action.params[0].value, FieldValue(11));
+}
+
+#[test]
+fn build_lpm_entry() {
+    let switch = vlan_switch();
+    let entry = switch.entry("ipv4_lpm")
+        .lpm("hdr.ipv4.dstAddr", 0x0a000100, 24)
+        .action("drop")
+        .build()
+        .unwrap();
+    assert_eq!(entry.key.matches[0].match_type,
+               FieldMatchType::LPM { value: FieldValue(0x0a000100), plen: 24 });
+
+    let result = switch.entry("ipv4_lpm")
+        .lpm("hdr.ipv4.dstAddr", 0x0a000101, 24)
+        .action("drop")
+        .build();
+    assert_eq!(error_code(result), RpcStatusCode::INVALID_ARGUMENT);
+}
+
+#[test]
+fn build_default_action() {
+    let switch = vlan_switch();
+    let entry = switch.entry("port_exact")
+        .default_action()
+        .action("drop")
+        .build()
+        .unwrap();
+    assert!(entry.key.is_default_action);
+    assert!(entry.key.matches.is_empty());
+}
+
+#[test]
+fn reject_bad_entries() {
+    let switch = vlan_switch();
+
+    // Unknown names.
+    assert_eq!(error_code(switch.entry("no_such_table").build()), RpcStatusCode::NOT_FOUND);
+    assert_eq!(error_code(switch.entry("port_exact").exact("no.such.field", 1).build()),
+               RpcStatusCode::NOT_FOUND);
+    assert_eq!(error_code(switch.entry("port_exact")
+                          .exact("standard_metadata.ingress_port", 1)
+                          .action("vlan_incoming_forward")
+                          .build()),
+               RpcStatusCode::NOT_FOUND);
+
+    // Wrong match kind.
+    assert_eq!(error_code(switch.entry("ipv4_lpm").exact("hdr.ipv4.dstAddr", 1).build()),
+               RpcStatusCode::INVALID_ARGUMENT);
+
+    // Values too wide for their fields.
+    assert_eq!(error_code(switch.entry("port_exact")
+                          .exact("standard_metadata.ingress_port", 512)
+                          .build()),
+               RpcStatusCode::OUT_OF_RANGE);
+    assert_eq!(error_code(switch.entry("port_exact")
+                          .exact("standard_metadata.ingress_port", 1)
+                          .action("portfwd")
+                          .param("port", 1 << 9)
+                          .build()),
+               RpcStatusCode::OUT_OF_RANGE);
+
+    // Missing exact-match field and missing parameter.
+    assert_eq!(error_code(switch.entry("vlan_incoming_exact")
+                          .exact("hdr.vlan.vid", 1)
+                          .action("drop")
+                          .build()),
+               RpcStatusCode::INVALID_ARGUMENT);
+    assert_eq!(error_code(switch.entry("port_exact")
+                          .exact("standard_metadata.ingress_port", 1)
+                          .action("portfwd")
+                          .build()),
+               RpcStatusCode::INVALID_ARGUMENT);
+}
+
+#[test]
+fn validate_entries() {
+    let switch = vlan_switch();
+    let table = switch.table_by_name("port_exact").unwrap();
+    let entry = switch.entry("port_exact")
+        .exact("standard_metadata.ingress_port", 1)
+        .action("portfwd")
+        .param("port", 2)
+        .build()
+        .unwrap();
+    assert!(entry.validate(table).is_ok());
+
+    // Entries in the wrong table.
+    let lpm_table = switch.table_by_name("ipv4_lpm").unwrap();
+    assert_eq!(error_code(entry.validate(lpm_table)), RpcStatusCode::INVALID_ARGUMENT);
+
+    // Values too wide for their fields.
+    let mut bad = entry.clone();
+    bad.key.matches[0].match_type = FieldMatchType::Exact(FieldValue(512));
+    assert_eq!(error_code(bad.validate(table)), RpcStatusCode::OUT_OF_RANGE);
+    let mut bad = entry.clone();
+    bad.value.action.as_mut().unwrap().params[0].value = FieldValue(1 << 9);
+    assert_eq!(error_code(bad.validate(table)), RpcStatusCode::OUT_OF_RANGE);
+
+    // Wrong match kind, missing exact-match field, and unexpected priority.
+    let mut bad = entry.clone();
+    bad.key.matches[0].match_type = FieldMatchType::Optional(FieldValue(1));
+    assert_eq!(error_code(bad.validate(table)), RpcStatusCode::INVALID_ARGUMENT);
+    let mut bad = entry.clone();
+    bad.key.matches.clear();
+    assert_eq!(error_code(bad.validate(table)), RpcStatusCode::INVALID_ARGUMENT);
+    let mut bad = entry.clone();
+    bad.key.priority = 10;
+    assert_eq!(error_code(bad.validate(table)), RpcStatusCode::INVALID_ARGUMENT);
+
+    // Missing action and missing parameter.  An entry without an action is still fine for
+    // deletion.
+    let mut bad = entry.clone();
+    bad.value.action = None;
+    assert_eq!(error_code(bad.validate(table)), RpcStatusCode::INVALID_ARGUMENT);
+    assert!(bad.validate_key(table).is_ok());
+    let mut bad = entry.clone();
+    bad.value.action.as_mut().unwrap().params.clear();
+    assert_eq!(error_code(bad.validate(table)), RpcStatusCode::INVALID_ARGUMENT);
+}
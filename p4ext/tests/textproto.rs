@@ -0,0 +1,88 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+extern crate p4ext;
+
+use p4ext::textproto::{parse_json, parse_json_list, parse_text};
+use proto::p4runtime::{Update_Type, WriteRequest, WriteRequest_Atomicity};
+
+#[test]
+fn parse_text_write_request() {
+    let request: WriteRequest = parse_text(r#"
+# A comment.
+device_id: 1
+atomicity: ROLLBACK_ON_ERROR
+updates {
+  type: INSERT
+  entity {
+    table_entry {
+      table_id: 33572104
+      match { field_id: 1 exact { value: "\x00\001" } }
+      match < field_id: 2, lpm: { value: "\n\x00\x01\x00" prefix_len: 24 } >
+      action { action { action_id: 16799317 params [{ param_id: 1, value: "\x03" }] } }
+      priority: -1
+    }
+  }
+}
+updates { type: DELETE entity { packet_replication_engine_entry { multicast_group_entry { multicast_group_id: 0x10 } } } }
+"#).unwrap();
+    assert_eq!(request.device_id, 1);
+    assert_eq!(request.atomicity, WriteRequest_Atomicity::ROLLBACK_ON_ERROR);
+    assert_eq!(request.updates.len(), 2);
+    assert_eq!(request.updates[0].field_type, Update_Type::INSERT);
+
+    let te = request.updates[0].get_entity().get_table_entry();
+    assert_eq!(te.table_id, 33572104);
+    assert_eq!(te.get_field_match()[0].get_exact().value, vec![0, 1]);
+    assert_eq!(te.get_field_match()[1].get_lpm().value, vec![10, 0, 1, 0]);
+    assert_eq!(te.get_field_match()[1].get_lpm().prefix_len, 24);
+    assert_eq!(te.get_action().get_action().get_params()[0].value, vec![3]);
+    assert_eq!(te.priority, -1);
+
+    let mge = request.updates[1].get_entity().get_packet_replication_engine_entry().get_multicast_group_entry();
+    assert_eq!(mge.multicast_group_id, 16);
+}
+
+#[test]
+fn parse_json_write_requests() {
+    let text: WriteRequest = parse_text(r#"
+device_id: 18446744073709551615
+updates { type: MODIFY entity { table_entry { table_id: 5 match { field_id: 1 exact { value: "\x0a\x00" } } } } }
+"#).unwrap();
+    let json: WriteRequest = parse_json(r#"{
+        "deviceId": "18446744073709551615",
+        "updates": [{"type": "MODIFY",
+                     "entity": {"table_entry": {"tableId": 5,
+                                                "match": [{"fieldId": 1, "exact": {"value": "CgA="}}]}}}]
+    }"#).unwrap();
+    assert_eq!(text, json);
+
+    let list: Vec<WriteRequest> = parse_json_list(r#"[{"deviceId": 1}, {"deviceId": 2, "electionId": null}]"#).unwrap();
+    assert_eq!(list.iter().map(|r| r.device_id).collect::<Vec<_>>(), vec![1, 2]);
+}
+
+#[test]
+fn parse_errors() {
+    assert!(parse_text::<WriteRequest>("device_id: 1 no_such_field: 2").is_err());
+    assert!(parse_text::<WriteRequest>("updates { type: INSERT").is_err());
+    assert!(parse_text::<WriteRequest>("device_id: -1").is_err());
+    assert!(parse_text::<WriteRequest>("device_id: \"unterminated").is_err());
+    assert!(parse_json::<WriteRequest>("[1, 2]").is_err());
+}
@@ -29,6 +29,9 @@ SOFTWARE.
 
 mod dp2ddlog;
 mod controller;
+mod ofp4;
+
+pub use ofp4::p4_to_openflow;
 
 use anyhow::{anyhow, Context, Result};
 
@@ -176,6 +179,123 @@ fn p4data_to_ddlog_type(
     }
 }
 
+/// Writes to `output` the DDlog declaration of the relation that holds the entries in `table`,
+/// along with the types it needs.  `relation_type` is `"input"` or `"output"`.  If `entry_id` is
+/// true, the relation has an additional `entry_id` field, as `ofp4` translations require, and if
+/// the table has an action profile, its entries refer to the profile's members and groups, which
+/// are declared in relations of their own.
+fn write_table_relation(output: &mut String, table: &p4ext::Table, relation_type: &str,
+                        entry_id: bool) -> Result<()> {
+    let table_name = table.base_name();
+    let action_profile = entry_id && table.action_profile_id().is_some();
+
+    // Declarations for 'table', as (field_name, type) tuples.
+    let mut decls = Vec::new();
+
+    // Basic declaration for each match field.
+    for mf in table.match_fields.iter() {
+        decls.push((mf.preamble.name.clone(), mf.p4_full_type()));
+    }
+
+    // If the match fields are all exact-match, we don't need
+    // a priority, otherwise include one.
+    if table.has_priority() {
+        decls.push(("priority".to_string(), "bit<32>".to_string()));
+    }
+
+    // Grab the actions for 'table'.  We only care about
+    // actions that we can set through the control plane, so
+    // filter those.
+    let actions: Vec<_> = table.entry_actions().map(|ar| &ar.action).collect();
+
+    // If there is just one action and it doesn't have any
+    // parameters, then we don't need to include the actions
+    // in the relation.
+    if table.needs_actions() {
+        let action_type_name = format!("{}Action", table_name);
+
+        write!(output, "typedef {}", action_type_name)?;
+        for (i, a) in actions.iter().enumerate() {
+            write!(
+                output,
+                " {} {}{}",
+                if i == 0 { "=" } else { "|" },
+                action_type_name,
+                a.preamble.alias
+            )?;
+            if !a.params.is_empty() {
+                let params: String = a
+                    .params
+                    .iter()
+                    .map(|p| {
+                        format!("{}: {}", p.preamble.name, p.p4_basic_type())
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(output, "{{{}}}", params)?;
+            }
+        }
+        writeln!(output)?;
+
+        if !action_profile {
+            decls.push(("action".to_string(), action_type_name));
+        }
+    }
+    if action_profile {
+        writeln!(output, "typedef {name}ActionRef = {name}MemberRef{{member_id: bit<32>}} | {name}GroupRef{{group_id: bit<32>}}",
+                 name = table_name)?;
+        decls.push(("action".to_string(), format!("{}ActionRef", table_name)));
+    }
+
+    if entry_id {
+        decls.push(("entry_id".to_string(), "bit<32>".to_string()));
+    }
+
+    // Ordinarily, we declare the relation to contain structs,
+    // but if the relation only has a single member and it's
+    // annotated with @nerpa_singleton, declare it as the type
+    // of that single member.
+    if decls.len() == 1 && table.is_nerpa_singleton() {
+        let (_, full_type) = &decls[0];
+        writeln!(output, "{} relation {}[{}]", relation_type, table_name, full_type)?;
+    } else {
+        writeln!(output, "{} relation {}(", relation_type, table_name)?;
+        for (i, (name, full_type)) in decls.iter().enumerate() {
+            let delimiter = if i == decls.len() - 1 { "" } else { "," };
+            writeln!(output, "    {}: {}{}", name, full_type, delimiter)?;
+        }
+        writeln!(output, ")")?;
+    }
+
+    // If the table does not have a constant `default_action`, then we
+    // create a relation to configure the default action.
+    if table.has_default_action_relation() {
+        writeln!(output, "{} relation {}DefaultAction(", relation_type, table_name)?;
+        writeln!(output, "    action: {}Action", table_name)?;
+        writeln!(output, ")")?;
+    }
+
+    // The members of an action profile have the actions, and its groups
+    // list members with weights.
+    if action_profile {
+        writeln!(output, "{} relation {}Member(", relation_type, table_name)?;
+        if table.needs_actions() {
+            writeln!(output, "    member_id: bit<32>,")?;
+            writeln!(output, "    action: {}Action", table_name)?;
+        } else {
+            writeln!(output, "    member_id: bit<32>")?;
+        }
+        writeln!(output, ")")?;
+        writeln!(output, "{} relation {}GroupMember(", relation_type, table_name)?;
+        writeln!(output, "    group_id: bit<32>,")?;
+        writeln!(output, "    member_id: bit<32>,")?;
+        writeln!(output, "    weight: bit<32>")?;
+        writeln!(output, ")")?;
+    }
+
+    Ok(())
+}
+
 /// Convert P4 program information to DDlog relations. Generate external crates.
 ///
 /// # Arguments
@@ -202,85 +322,7 @@ pub fn p4info_to_ddlog(
 
     for (_, tables) in pipelines {
         for table in tables {
-            let table_name = table.base_name();
-
-            // Declarations for 'table', as (field_name, type) tuples.
-            let mut decls = Vec::new();
-
-            // Basic declaration for each match field.
-            for mf in table.match_fields.iter() {
-                decls.push((mf.preamble.name.clone(), mf.p4_full_type()));
-            }
-
-            // If the match fields are all exact-match, we don't need
-            // a priority, otherwise include one.
-            if table.has_priority() {
-                decls.push(("priority".to_string(), "bit<32>".to_string()));
-            }
-
-            // Grab the actions for 'table'.  We only care about
-            // actions that we can set through the control plane, so
-            // filter those.
-            let actions: Vec<_> = table.entry_actions().map(|ar| &ar.action).collect();
-
-            // If there is just one action and it doesn't have any
-            // parameters, then we don't need to include the actions
-            // in the relation.
-            let needs_actions =
-                actions.len() > 1 || (actions.len() == 1 && !actions[0].params.is_empty());
-            if needs_actions {
-                let action_type_name = format!("{}Action", table_name);
-
-                write!(output, "typedef {}", action_type_name)?;
-                for (i, a) in actions.iter().enumerate() {
-                    write!(
-                        output,
-                        " {} {}{}",
-                        if i == 0 { "=" } else { "|" },
-                        action_type_name,
-                        a.preamble.alias
-                    )?;
-                    if !a.params.is_empty() {
-                        let params: String = a
-                            .params
-                            .iter()
-                            .map(|p| {
-                                format!("{}: {}", p.preamble.name, p.p4_basic_type())
-                            })
-                            .collect::<Vec<_>>()
-                            .join(", ");
-                        write!(output, "{{{}}}", params)?;
-                    }
-                }
-                writeln!(output)?;
-
-                decls.push(("action".to_string(), action_type_name));
-            }
-
-            // Ordinarily, we declare the relation to contain structs,
-            // but if the relation only has a single member and it's
-            // annotated with @nerpa_singleton, declare it as the type
-            // of that single member.
-            if decls.len() == 1 && table.is_nerpa_singleton() {
-                let (_, full_type) = &decls[0];
-                writeln!(output, "output relation {}[{}]", table_name, full_type)?;
-            } else {
-                writeln!(output, "output relation {}(", table_name)?;
-                for (i, (name, full_type)) in decls.iter().enumerate() {
-                    let delimiter = if i == decls.len() - 1 { "" } else { "," };
-                    writeln!(output, "    {}: {}{}", name, full_type, delimiter)?;
-                }
-                writeln!(output, ")")?;
-            }
-
-            // If the table does not have a constant `default_action`, then we
-            // create a relation to configure the default action.
-            // TODO: Check the form of this relation.
-            if needs_actions && table.const_default_action.is_none() {
-                writeln!(output, "output relation {}DefaultAction(", table_name)?;
-                writeln!(output, "    action: {}Action", table_name)?;
-                writeln!(output, ")")?;
-            }
+            write_table_relation(&mut output, &table, "output", false)?;
         }
     }
 
@@ -0,0 +1,1025 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Generates a DDlog translation from a P4 program to OpenFlow flows for ofp4, like the
+//! handwritten `ofp4/snvs.dl`, from the program's bmv2 JSON and P4Info.
+//!
+//! Only a subset of P4 is supported:
+//!
+//! * Tables with exact, ternary, LPM, and optional match fields, without constant entries.
+//!   Tables must be applied unconditionally; `if` statements aren't supported.  A table may have
+//!   an action profile, with or without a selector.  An entry that refers to a group of an action
+//!   selector becomes an OpenFlow `select` group with a bucket for each member of the group,
+//!   weighted by the member's weight, so OpenFlow, not the P4 program's selector, picks a
+//!   member for each packet.  The default action of a table with an action profile can't change.
+//!   A table may have a direct meter, which drops packets that exceed its peak rate instead of
+//!   coloring them.  Indirect meters aren't supported.  Unless a table's default action is
+//!   `const`, the controller may replace it by any action that table entries may use, through the
+//!   `<Table>DefaultAction` input relation.
+//!
+//! * Actions built from assignments of fields, action parameters, and constants to fields,
+//!   `mark_to_drop`, `exit`, `resubmit`, `clone`, and assignment to
+//!   `standard_metadata.mcast_grp` for multicast.
+//!
+//! * Fields in standard Ethernet, VLAN, IPv4, IPv6, ARP, TCP, and UDP headers, which map to
+//!   OpenFlow fields, and metadata, which is allocated to registers.
+//!
+//! The ingress pipeline occupies OpenFlow tables 0 to 30 and the egress pipeline tables 32 to
+//! 63.  Table 31 implements multicast replication between them, by sending the packet to an
+//! OpenFlow `all` group with a bucket per replica, and table 64 outputs the packet.  Table 0 also
+//! changes `in_port` from the OpenFlow port number to the P4 port number, for the ports in the
+//! `Port` input relation, and table 64 maps the output port back.  Since the P4 program sees P4
+//! port numbers in `in_port`, table 64 drops packets whose output port is their input port, as
+//! OpenFlow would.
+
+use anyhow::{anyhow, Context, Result};
+
+use proto::p4info::{MeterSpec_Unit, P4Info};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fmt::{self, Display, Write};
+use std::fs::File;
+use std::io::Write as IoWrite;
+
+#[derive(Deserialize)]
+struct Bmv2Program {
+    header_types: Vec<Bmv2HeaderType>,
+    headers: Vec<Bmv2Header>,
+    actions: Vec<Bmv2Action>,
+    pipelines: Vec<Bmv2Pipeline>,
+}
+
+#[derive(Deserialize)]
+struct Bmv2HeaderType {
+    name: String,
+    // Each field is a (name, bit width, signed) triple.  Variable-width fields have width "*".
+    fields: Vec<(String, Value, Value)>,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Header {
+    name: String,
+    header_type: String,
+    metadata: bool,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Action {
+    name: String,
+    id: u32,
+    primitives: Vec<Bmv2Primitive>,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Primitive {
+    op: String,
+    parameters: Vec<Bmv2Parameter>,
+    source_info: Option<SourceInfo>,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Parameter {
+    #[serde(rename = "type")]
+    kind: String,
+    value: Value,
+}
+
+#[derive(Clone, Deserialize)]
+struct SourceInfo {
+    filename: String,
+    line: u32,
+    column: u32,
+    source_fragment: String,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Pipeline {
+    name: String,
+    init_table: Option<String>,
+    tables: Vec<Bmv2Table>,
+    conditionals: Vec<Bmv2Conditional>,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Conditional {
+    name: String,
+    source_info: Option<SourceInfo>,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Table {
+    name: String,
+    source_info: Option<SourceInfo>,
+    key: Vec<Bmv2Key>,
+    #[serde(rename = "type")]
+    kind: String,
+    action_ids: Vec<u32>,
+    next_tables: HashMap<String, Option<String>>,
+    default_entry: Option<Bmv2DefaultEntry>,
+    #[serde(default)]
+    entries: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct Bmv2Key {
+    match_type: String,
+    name: String,
+    target: Value,
+    #[serde(default)]
+    mask: Value,
+}
+
+#[derive(Deserialize)]
+struct Bmv2DefaultEntry {
+    action_id: u32,
+    action_data: Vec<String>,
+}
+
+/// A construct in the P4 program that the translation doesn't support.
+struct Unsupported {
+    source_info: Option<SourceInfo>,
+    what: String,
+}
+
+impl Display for Unsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source_info {
+            Some(si) => write!(f, "{}:{}:{}: {} (`{}`)", si.filename, si.line, si.column, self.what,
+                               si.source_fragment),
+            None => write!(f, "{}", self.what),
+        }
+    }
+}
+
+/// Bits within an OpenFlow register that hold a P4 field.
+#[derive(Clone)]
+struct Register {
+    /// Suffix of the name of the generated `r_<name>()` function.
+    name: String,
+    /// `reg<N>` or `xreg<N>`.
+    field: String,
+    ofs: u32,
+    width: u32,
+}
+
+impl Register {
+    fn new(name: &str, field: &str, ofs: u32, width: u32) -> Register {
+        Register { name: name.into(), field: field.into(), ofs, width }
+    }
+
+    fn register_width(&self) -> u32 {
+        if self.field.starts_with('x') { 64 } else { 32 }
+    }
+}
+
+/// How a P4 field is accessed in OpenFlow.
+#[derive(Clone)]
+enum OfField {
+    /// An OpenFlow field, such as `eth_dst`, along with the protocol that OVS requires a flow to
+    /// match to use it, if any.
+    Native { name: &'static str, width: u32, prereq: Option<&'static str>, maskable: bool, writable: bool },
+
+    /// Bits in a register.
+    Register(Register),
+}
+
+impl OfField {
+    fn width(&self) -> u32 {
+        match self {
+            OfField::Native { width, .. } => *width,
+            OfField::Register(r) => r.width,
+        }
+    }
+
+    fn prereq(&self) -> Option<&'static str> {
+        match self {
+            OfField::Native { prereq, .. } => *prereq,
+            OfField::Register(_) => None,
+        }
+    }
+
+    /// Returns the whole field as an OpenFlow subfield, e.g. `eth_dst[]`.
+    fn whole(&self) -> String {
+        match self {
+            OfField::Native { name, .. } => format!("{}[]", name),
+            OfField::Register(r) => format!("${{r_{}()}}", r.name),
+        }
+    }
+
+    /// Returns the low `width` bits of the field as an OpenFlow subfield.
+    fn low_bits(&self, width: u32) -> String {
+        if width == self.width() {
+            return self.whole();
+        }
+        match self {
+            OfField::Native { name, .. } => format!("{}[0..{}]", name, width - 1),
+            OfField::Register(r) => format!("{}[{}..{}]", r.field, r.ofs, r.ofs + width - 1),
+        }
+    }
+
+    /// Returns an OpenFlow match on the field for DDlog expression `value`.
+    fn match_exact(&self, value: &str) -> String {
+        match self {
+            OfField::Native { name, .. } => format!("{}=${{{}}}", name, value),
+            OfField::Register(r) => format!("${{r_{}_match({}, {})}}", r.name, value, all_ones(r.width)),
+        }
+    }
+
+    /// Returns an OpenFlow match on the field for DDlog expressions `value` and `mask`, or `None`
+    /// if OpenFlow can't mask the field.
+    fn match_masked(&self, value: &str, mask: &str) -> Option<String> {
+        match self {
+            OfField::Native { name, maskable: true, .. } => Some(format!("{}=${{{}}}/${{{}}}", name, value, mask)),
+            OfField::Native { .. } => None,
+            OfField::Register(r) => Some(format!("${{r_{}_match({}, {})}}", r.name, value, mask)),
+        }
+    }
+}
+
+fn all_ones(width: u32) -> u128 {
+    if width >= 128 { u128::MAX } else { (1 << width) - 1 }
+}
+
+/// Returns the OpenFlow field for `field` in a header named `header`, if it is a well-known
+/// protocol header.
+fn native_field(header: &str, field: &str) -> Option<OfField> {
+    let (name, width, prereq, maskable, writable) = match (header, field) {
+        ("standard_metadata", "ingress_port") => ("in_port", 16, None, false, true),
+        ("eth" | "ethernet", "dst" | "dstAddr" | "dst_addr") => ("eth_dst", 48, None, true, true),
+        ("eth" | "ethernet", "src" | "srcAddr" | "src_addr") => ("eth_src", 48, None, true, true),
+        ("eth" | "ethernet", "type" | "etherType" | "ether_type") => ("eth_type", 16, None, false, false),
+        ("vlan", "vid") => ("dl_vlan", 12, None, false, false),
+        ("vlan", "pcp") => ("dl_vlan_pcp", 3, None, false, false),
+        ("ipv4", "src" | "srcAddr" | "src_addr") => ("ip_src", 32, Some("ip"), true, true),
+        ("ipv4", "dst" | "dstAddr" | "dst_addr") => ("ip_dst", 32, Some("ip"), true, true),
+        ("ipv4", "protocol") => ("ip_proto", 8, Some("ip"), false, false),
+        ("ipv4", "ttl") => ("nw_ttl", 8, Some("ip"), false, true),
+        ("ipv6", "src" | "srcAddr" | "src_addr") => ("ipv6_src", 128, Some("ipv6"), true, true),
+        ("ipv6", "dst" | "dstAddr" | "dst_addr") => ("ipv6_dst", 128, Some("ipv6"), true, true),
+        ("arp", "op" | "oper" | "opcode" | "op_code") => ("arp_op", 16, Some("arp"), false, true),
+        ("tcp", "srcPort" | "src_port") => ("tcp_src", 16, Some("tcp"), true, true),
+        ("tcp", "dstPort" | "dst_port") => ("tcp_dst", 16, Some("tcp"), true, true),
+        ("udp", "srcPort" | "src_port") => ("udp_src", 16, Some("udp"), true, true),
+        ("udp", "dstPort" | "dst_port") => ("udp_dst", 16, Some("udp"), true, true),
+        _ => return None,
+    };
+    Some(OfField::Native { name, width, prereq, maskable, writable })
+}
+
+/// Returns the register for `field` in `standard_metadata`, for the fields that the translation
+/// keeps in fixed registers.
+fn standard_register(field: &str) -> Option<Register> {
+    match field {
+        "egress_spec" => Some(Register::new("egress_spec", "reg0", 0, 9)),
+        "mcast_grp" => Some(Register::new("mcast_grp", "reg1", 0, 16)),
+        "instance_type" => Some(Register::new("instance_type", "reg2", 0, 32)),
+        "egress_port" => Some(Register::new("egress_port", "reg3", 0, 9)),
+        _ => None,
+    }
+}
+
+/// bmv2's `standard_metadata.egress_spec` value for dropping a packet.
+const DROP_PORT: u32 = 511;
+
+/// Lowest and highest registers available for metadata.  Digests and controller headers use
+/// `xreg4` and up, that is, `reg8` and up.
+const FIRST_METADATA_REG: u32 = 4;
+const LAST_METADATA_REG: u32 = 7;
+
+/// Where a pipeline's tables go in OpenFlow.
+struct PipelineTables {
+    /// Table that the pipeline starts from.
+    first: u32,
+    /// Table to go to when the pipeline ends.
+    end: u32,
+    /// Maps from a bmv2 table name to its OpenFlow table.
+    tables: HashMap<String, u32>,
+}
+
+impl PipelineTables {
+    fn goto(&self, next: Option<&str>) -> String {
+        let table = next.and_then(|name| self.tables.get(name)).unwrap_or(&self.end);
+        format!("resubmit(,{})", table)
+    }
+}
+
+/// A source of a value for an assignment.
+enum Source {
+    Field(OfField),
+    /// A value, as text for a DDlog string, e.g. `5` or `${a0}`.
+    Value(String),
+}
+
+struct Generator<'a> {
+    header_types: HashMap<&'a str, &'a Bmv2HeaderType>,
+    headers: HashMap<&'a str, &'a Bmv2Header>,
+    actions: HashMap<u32, &'a Bmv2Action>,
+
+    // Register allocation.
+    registers: Vec<Register>,
+    metadata: HashMap<(String, String), Register>,
+    used_bits: [u32; (LAST_METADATA_REG - FIRST_METADATA_REG + 1) as usize],
+
+    // Direct meters, indexed by the P4Info ID of their tables, as the meter's P4Info ID and
+    // whether it counts packets (otherwise, bytes).
+    direct_meters: HashMap<u32, (u32, bool)>,
+
+    unsupported: Vec<Unsupported>,
+    uses_resubmit: bool,
+
+    // Action that starts the ingress pipeline, after port mapping, for resubmitted packets.
+    ingress_start: String,
+}
+
+impl<'a> Generator<'a> {
+    fn new(bmv2: &'a Bmv2Program, p4info: &P4Info) -> Generator<'a> {
+        let registers = ["egress_spec", "mcast_grp", "instance_type", "egress_port"].iter()
+            .map(|name| standard_register(name).unwrap())
+            .collect();
+        Generator {
+            header_types: bmv2.header_types.iter().map(|ht| (ht.name.as_str(), ht)).collect(),
+            headers: bmv2.headers.iter().map(|h| (h.name.as_str(), h)).collect(),
+            actions: bmv2.actions.iter().map(|a| (a.id, a)).collect(),
+            registers,
+            metadata: HashMap::new(),
+            used_bits: Default::default(),
+            direct_meters: p4info.get_direct_meters().iter()
+                .map(|dm| (dm.direct_table_id, (dm.get_preamble().id, dm.get_spec().get_unit() == MeterSpec_Unit::PACKETS)))
+                .collect(),
+            unsupported: Vec::new(),
+            uses_resubmit: false,
+            ingress_start: "resubmit(,0)".into(),
+        }
+    }
+
+    fn unsupported<S: Into<String>>(&mut self, source_info: &Option<SourceInfo>, what: S) {
+        self.unsupported.push(Unsupported { source_info: source_info.clone(), what: what.into() });
+    }
+
+    /// Allocates `width` bits of register space for `name`.
+    fn allocate(&mut self, name: String, width: u32) -> Option<Register> {
+        let n_regs = self.used_bits.len() as u32;
+        let register = if width <= 32 {
+            let mask = all_ones(width) as u32;
+            (0..n_regs).flat_map(|r| (0..=32 - width).map(move |ofs| (r, ofs)))
+                .find(|&(r, ofs)| self.used_bits[r as usize] & (mask << ofs) == 0)
+                .map(|(r, ofs)| {
+                    self.used_bits[r as usize] |= mask << ofs;
+                    Register { name, field: format!("reg{}", FIRST_METADATA_REG + r), ofs, width }
+                })
+        } else if width <= 64 {
+            // xreg<N> is reg<2N> and reg<2N+1>.
+            (0..n_regs).step_by(2)
+                .find(|&r| self.used_bits[r as usize] == 0 && self.used_bits[r as usize + 1] == 0)
+                .map(|r| {
+                    self.used_bits[r as usize] = u32::MAX;
+                    self.used_bits[r as usize + 1] = u32::MAX;
+                    Register { name, field: format!("xreg{}", (FIRST_METADATA_REG + r) / 2), ofs: 0, width }
+                })
+        } else {
+            None
+        };
+        if let Some(register) = &register {
+            self.registers.push(register.clone());
+        }
+        register
+    }
+
+    /// Returns the OpenFlow field for the bmv2 field reference `target`, which has the form
+    /// `[header, field]`.
+    fn field(&mut self, target: &Value, source_info: &Option<SourceInfo>) -> Option<OfField> {
+        let (header, field) = match target.as_array().map(|a| a.as_slice()) {
+            Some([Value::String(header), Value::String(field)]) => (header.as_str(), field.as_str()),
+            _ => {
+                self.unsupported(source_info, format!("field reference {}", target));
+                return None;
+            }
+        };
+
+        if let Some(of) = native_field(header, field) {
+            return Some(of);
+        }
+        if header == "standard_metadata" {
+            return match standard_register(field) {
+                Some(r) => Some(OfField::Register(r)),
+                None => {
+                    self.unsupported(source_info, format!("standard_metadata.{}", field));
+                    None
+                }
+            };
+        }
+
+        let width = self.headers.get(header)
+            .filter(|h| h.metadata)
+            .and_then(|h| self.header_types.get(h.header_type.as_str()))
+            .and_then(|ht| ht.fields.iter().find(|(name, _, _)| name == field))
+            .and_then(|(_, width, _)| width.as_u64());
+        let width = match width {
+            Some(width) => width as u32,
+            None => {
+                self.unsupported(source_info, format!("field {}.{}", header, field));
+                return None;
+            }
+        };
+
+        let key = (header.to_string(), field.to_string());
+        if let Some(r) = self.metadata.get(&key) {
+            return Some(OfField::Register(r.clone()));
+        }
+        let name = if header == "scalars" { field.to_string() } else { format!("{}_{}", header, field) };
+        let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        match self.allocate(name, width) {
+            Some(r) => {
+                self.metadata.insert(key, r.clone());
+                Some(OfField::Register(r))
+            },
+            None => {
+                self.unsupported(source_info, format!("no register space for {}-bit field {}.{}", width, header, field));
+                None
+            }
+        }
+    }
+
+    /// Returns the source for bmv2 action parameter `param`, given the values `args` of the
+    /// action's runtime data as text for a DDlog string.
+    fn source(&mut self, param: &Bmv2Parameter, args: &[String], source_info: &Option<SourceInfo>) -> Option<Source> {
+        match (param.kind.as_str(), &param.value) {
+            ("field", target) => self.field(target, source_info).map(Source::Field),
+            ("runtime_data", Value::Number(n)) => {
+                let arg = n.as_u64().and_then(|i| args.get(i as usize));
+                arg.map(|arg| Source::Value(arg.clone()))
+            },
+            ("hexstr", Value::String(s)) => match parse_hexstr(s) {
+                Some(value) => Some(Source::Value(value.to_string())),
+                None => {
+                    self.unsupported(source_info, format!("constant {}", s));
+                    None
+                }
+            },
+            ("bool", Value::Bool(b)) => Some(Source::Value(if *b { "1" } else { "0" }.into())),
+
+            // The P4 compiler masks values to the width of their destination with "&".  OpenFlow
+            // does that implicitly.
+            ("expression", Value::Object(e)) if e.get("op") == Some(&Value::String("&".into())) => {
+                let left: Option<Bmv2Parameter> = e.get("left").and_then(|v| serde_json::from_value(v.clone()).ok());
+                let right: Option<Bmv2Parameter> = e.get("right").and_then(|v| serde_json::from_value(v.clone()).ok());
+                match (left, right) {
+                    (Some(left), Some(right)) if right.kind == "hexstr" => self.source(&left, args, source_info),
+                    _ => {
+                        self.unsupported(source_info, "expression");
+                        None
+                    }
+                }
+            },
+            (kind, _) => {
+                self.unsupported(source_info, format!("{} operand", kind));
+                None
+            }
+        }
+    }
+
+    /// Returns the OpenFlow actions for `dst = src`.  Adds the protocols that the fields require
+    /// to `prereqs`.
+    fn assign(&mut self, dst: &Bmv2Parameter, src: &Bmv2Parameter, args: &[String],
+              source_info: &Option<SourceInfo>, prereqs: &mut BTreeSet<&'static str>) -> Option<String> {
+        if dst.kind != "field" {
+            self.unsupported(source_info, format!("assignment to {}", dst.kind));
+            return None;
+        }
+        let dst_field = self.field(&dst.value, source_info)?;
+        if let OfField::Native { writable: false, name, .. } = dst_field {
+            self.unsupported(source_info, format!("assignment to {}", name));
+            return None;
+        }
+        prereqs.extend(dst_field.prereq());
+        match self.source(src, args, source_info)? {
+            Source::Value(value) => Some(format!("load({}->{})", value, dst_field.whole())),
+            Source::Field(src_field) => {
+                prereqs.extend(src_field.prereq());
+                let width = src_field.width().min(dst_field.width());
+                let mut actions = String::new();
+                if width < dst_field.width() {
+                    write!(actions, "load(0->{}), ", dst_field.whole()).unwrap();
+                }
+                write!(actions, "move({}->{})", src_field.low_bits(width), dst_field.low_bits(width)).unwrap();
+                Some(actions)
+            }
+        }
+    }
+
+    /// Translates `action` into OpenFlow actions, given the values `args` of its runtime data as
+    /// text for a DDlog string, ending with `next`.  Adds the protocols that its fields require to `prereqs`.
+    fn action(&mut self, action: &Bmv2Action, args: &[String], pipeline: &PipelineTables, next: &str,
+              prereqs: &mut BTreeSet<&'static str>) -> String {
+        let mut actions = Vec::new();
+        let mut next = next.to_string();
+        for p in &action.primitives {
+            let si = &p.source_info;
+            let translation = match (p.op.as_str(), p.parameters.as_slice()) {
+                ("assign", [dst, src]) => self.assign(dst, src, args, si, prereqs),
+                ("mark_to_drop" | "drop" | "_drop", _) => Some(format!("load({}->${{r_egress_spec()}})", DROP_PORT)),
+                ("exit", _) => {
+                    next = format!("resubmit(,{})", pipeline.end);
+                    break;
+                },
+                ("resubmit" | "resubmit_preserving_field_list", _) => {
+                    self.uses_resubmit = true;
+                    Some("load(1->${r_resubmit()})".into())
+                },
+                (op @ ("clone_ingress_pkt_to_egress" | "clone_egress_pkt_to_egress"), [session, ..]) => {
+                    let instance_type = if op == "clone_ingress_pkt_to_egress" { 1 } else { 2 };
+                    let session = match self.source(session, args, si) {
+                        Some(Source::Value(value)) => Some(format!("load({}->${{r_mcast_grp()}})", value)),
+                        Some(Source::Field(field)) => Some(format!("move({}->${{r_mcast_grp()}})", field.low_bits(field.width().min(16)))),
+                        None => None,
+                    };
+                    session.map(|session| format!("clone(load({}->${{r_instance_type()}}), {}, resubmit(,31))",
+                                                  instance_type, session))
+                },
+                (op, _) => {
+                    self.unsupported(si, format!("primitive {}", op));
+                    None
+                }
+            };
+            actions.extend(translation);
+        }
+        actions.push(next);
+        actions.join(", ")
+    }
+
+    /// Translates bmv2 `table` into flows, which it appends to `output`.  `p4table` is the
+    /// table's P4Info, if it has any (tables that the compiler synthesizes don't).
+    fn table(&mut self, output: &mut String, table: &Bmv2Table, p4table: Option<&p4ext::Table>,
+             pipeline: &PipelineTables) -> Result<()> {
+        let si = &table.source_info;
+        let number = pipeline.tables[&table.name];
+        if !matches!(table.kind.as_str(), "simple" | "indirect" | "indirect_ws") {
+            self.unsupported(si, format!("{} table {}", table.kind, table.name));
+        }
+        if !table.entries.is_empty() {
+            self.unsupported(si, format!("constant entries in table {}", table.name));
+        }
+
+        let next_for = |action: &Bmv2Action, hit: bool| -> Option<String> {
+            let key = if table.next_tables.contains_key("__HIT__") {
+                if hit { "__HIT__" } else { "__MISS__" }
+            } else {
+                action.name.as_str()
+            };
+            table.next_tables.get(key).cloned().flatten()
+        };
+
+        writeln!(output, "// {}", table.name)?;
+
+        // Flow for the default action.
+        let mut default_prereqs = BTreeSet::new();
+        let default_actions = match &table.default_entry {
+            Some(de) => match self.actions.get(&de.action_id).copied() {
+                Some(action) => {
+                    let args: Vec<String> = de.action_data.iter()
+                        .map(|s| parse_hexstr(s).unwrap_or(0).to_string())
+                        .collect();
+                    let next = pipeline.goto(next_for(action, false).as_deref());
+                    self.action(action, &args, pipeline, &next, &mut default_prereqs)
+                },
+                None => {
+                    self.unsupported(si, format!("unknown default action {}", de.action_id));
+                    pipeline.goto(None)
+                }
+            },
+            None => pipeline.goto(table.next_tables.get("__MISS__").cloned().flatten().as_deref()),
+        };
+        // If the controller can change the default action, the flow for the P4 program's default
+        // action only applies until it does.
+        let default_relation = p4table.filter(|p4table| p4table.has_default_action_relation())
+            .map(|p4table| format!("{}DefaultAction", p4table.base_name()));
+        write!(output, "Flow(\"table={} priority=0 {}actions={}\")",
+               number, prereq_match(&default_prereqs), default_actions)?;
+        match &default_relation {
+            Some(relation) => writeln!(output, " :-\n    Always(_),\n    not {}(_).", relation)?,
+            None => writeln!(output, ".")?,
+        }
+
+        let p4table = match p4table {
+            Some(p4table) => p4table,
+            None => {
+                if !table.key.is_empty() {
+                    self.unsupported(si, format!("table {} is not in the P4Info", table.name));
+                }
+                writeln!(output)?;
+                return Ok(());
+            }
+        };
+
+        // Match fields.
+        let mut prereqs = BTreeSet::new();
+        let mut patterns = Vec::new();
+        let mut matches = String::new();
+        let mut clauses = Vec::new();
+        for (i, (key, mf)) in table.key.iter().zip(p4table.match_fields.iter()).enumerate() {
+            if !key.mask.is_null() {
+                self.unsupported(si, format!("masked key {} in table {}", key.name, table.name));
+                continue;
+            }
+            let field = match self.field(&key.target, si) {
+                Some(field) => field,
+                None => continue,
+            };
+            prereqs.extend(field.prereq());
+
+            let k = format!("k{}", i);
+            let value = if mf.is_nerpa_bool() { format!("(if ({}) 1 else 0)", k) } else { k.clone() };
+            let width = mf.bit_width;
+            let m = match key.match_type.as_str() {
+                "exact" => {
+                    patterns.push(k);
+                    Some(field.match_exact(&value))
+                },
+                "ternary" => {
+                    patterns.push(format!("({}, {}_mask)", k, k));
+                    field.match_masked(&k, &format!("{}_mask", k))
+                },
+                "lpm" => {
+                    patterns.push(format!("({}, {}_plen)", k, k));
+                    field.match_masked(&k, &format!("lpm_mask({}, {}_plen) as bit<{}>", width, k, width))
+                },
+                "optional" => {
+                    patterns.push(k.clone());
+                    clauses.push(format!("var m{} = match ({}) {{ Some{{v}} -> \"{} \", None -> \"\" }}",
+                                         i, k, field.match_exact("v")));
+                    Some(format!("${{m{}}}", i))
+                },
+                other => {
+                    self.unsupported(si, format!("{} match on key {} in table {}", other, key.name, table.name));
+                    patterns.push("_".into());
+                    continue;
+                }
+            };
+            match m {
+                Some(m) => {
+                    matches.push_str(&m);
+                    if key.match_type != "optional" {
+                        matches.push(' ');
+                    }
+                },
+                None => self.unsupported(si, format!("{} match on key {} in table {}", key.match_type, key.name, table.name)),
+            }
+        }
+        if p4table.has_priority() {
+            patterns.push("priority".into());
+        }
+
+        // Actions.
+        let entry_actions: Vec<_> = p4table.entry_actions().map(|ar| &ar.action).collect();
+        let needs_actions = p4table.needs_actions();
+        let mut arms = Vec::new();
+        let mut default_arms = Vec::new();
+        for a in &entry_actions {
+            let bmv2_action = table.action_ids.iter()
+                .filter_map(|id| self.actions.get(id).copied())
+                .find(|ba| ba.name == a.preamble.name);
+            let bmv2_action = match bmv2_action {
+                Some(ba) => ba,
+                None => {
+                    self.unsupported(si, format!("action {} in table {} is not in the bmv2 JSON", a.preamble.name, table.name));
+                    continue;
+                }
+            };
+            let vars: Vec<String> = (0..a.params.len()).map(|j| format!("a{}", j)).collect();
+            let args: Vec<String> = a.params.iter().zip(vars.iter())
+                .map(|(p, v)| if p.is_nerpa_bool() { format!("${{if ({}) 1 else 0}}", v) } else { format!("${{{}}}", v) })
+                .collect();
+            let next = pipeline.goto(next_for(bmv2_action, true).as_deref());
+            let actions = self.action(bmv2_action, &args, pipeline, &next, &mut prereqs);
+            let pattern = if vars.is_empty() {
+                format!("{}Action{}", p4table.base_name(), a.preamble.alias)
+            } else {
+                format!("{}Action{}{{{}}}", p4table.base_name(), a.preamble.alias, vars.join(", "))
+            };
+            if default_relation.is_some() {
+                let next = pipeline.goto(next_for(bmv2_action, false).as_deref());
+                let actions = self.action(bmv2_action, &args, pipeline, &next, &mut default_prereqs);
+                default_arms.push(format!("        {} -> \"{}\"", pattern, actions));
+            }
+            arms.push((pattern, actions));
+        }
+
+        // Flow for a default action that the controller set.
+        if let Some(relation) = &default_relation {
+            writeln!(output, "Flow(\"table={} priority=0 {}actions=${{actions}}\") :-\n    {}(action),\n    var actions = match (action) {{\n{}\n    }}.",
+                     number, prereq_match(&default_prereqs), relation, default_arms.join(",\n"))?;
+        }
+        let (actions, action_clause) = if needs_actions {
+            let arms: Vec<String> = arms.into_iter()
+                .map(|(pattern, actions)| format!("        {} -> \"{}\"", pattern, actions))
+                .collect();
+            ("${actions}".to_string(), Some(format!("var actions = match (action) {{\n{}\n    }}", arms.join(",\n"))))
+        } else {
+            (arms.pop().map(|(_, actions)| actions).unwrap_or_else(|| pipeline.goto(None)), None)
+        };
+
+        let name = p4table.base_name();
+        let priority = if p4table.has_priority() { "${priority}".to_string() } else { "100".to_string() };
+        let atom = |action: Option<&str>| {
+            let patterns: Vec<&str> = patterns.iter().map(|p| p.as_str()).chain(action).chain(Some("entry_id")).collect();
+            format!("{}({})", name, patterns.join(", "))
+        };
+
+        // Each kind of flow for the table's entries, as its actions and the atom and clauses that
+        // derive it.  An entry in a table with an action profile takes its actions from the
+        // profile member that it refers to.  With a selector, an entry that refers to a group
+        // sends packets to an OpenFlow `select` group of its own, with ID 65536 plus the entry's
+        // ID to stay clear of multicast groups, which has a bucket for each member of the group.
+        let mut entry_flows = Vec::new();
+        if table.kind == "simple" {
+            let action = if needs_actions { Some("action") } else { None };
+            let clauses: Vec<String> = clauses.into_iter().chain(action_clause).collect();
+            entry_flows.push((actions, atom(action), clauses));
+        } else {
+            let member = if needs_actions {
+                format!("{}Member(member_id, action)", name)
+            } else {
+                format!("{}Member(member_id)", name)
+            };
+            let member_clauses: Vec<String> = Some(member.clone()).into_iter()
+                .chain(clauses.iter().cloned())
+                .chain(action_clause.clone())
+                .collect();
+            entry_flows.push((actions.clone(), atom(Some(&format!("{}MemberRef{{member_id}}", name))), member_clauses));
+
+            if table.kind == "indirect_ws" {
+                let group_ref = format!("{}GroupRef{{group_id}}", name);
+                entry_flows.push(("group:${entry_id + 65536}".to_string(), atom(Some(&group_ref)), clauses));
+
+                let wildcards: Vec<&str> = patterns.iter().map(|_| "_").chain(vec![group_ref.as_str(), "entry_id"]).collect();
+                let group_atom = format!("{}({})", name, wildcards.join(", "));
+                writeln!(output, "Group(\"group_id=${{entry_id + 65536}},type=select,${{buckets}}\") :-")?;
+                writeln!(output, "    {},\n    {}GroupMember(group_id, member_id, weight),\n    {},", group_atom, name, member)?;
+                if let Some(action_clause) = &action_clause {
+                    writeln!(output, "    {},", action_clause)?;
+                }
+                writeln!(output, "    var bucket = \"bucket=weight:${{weight}},actions={}\",", actions)?;
+                writeln!(output, "    var buckets = bucket.group_by(entry_id).to_vec().join(\",\").")?;
+                writeln!(output, "Group(\"group_id=${{entry_id + 65536}},type=select\") :-")?;
+                writeln!(output, "    {},\n    not {}GroupMember(group_id, _, _).", group_atom, name)?;
+            }
+        }
+
+        // With a direct meter, an entry whose meter is configured sends packets through an
+        // OpenFlow meter with the entry's ID, which drops packets that exceed the peak rate.
+        let variants = match self.direct_meters.get(&p4table.preamble.id) {
+            Some(&(meter_id, packets)) => {
+                let config = |pir: &str, pburst: &str| format!("DirectMeterConfig({}, entry_id, _, _, {}, {})", meter_id, pir, pburst);
+                let (unit, pir, pburst) = if packets {
+                    ("pktps", "${pir}", "${pburst}")
+                } else {
+                    ("kbps", "${(pir * 8 + 999) / 1000}", "${(pburst * 8 + 999) / 1000}")
+                };
+                writeln!(output, "Meter(\"meter=${{entry_id}},{},burst,stats,bands=type=drop,rate={},burst_size={}\") :-\n    {}.",
+                         unit, pir, pburst, config("pir", "pburst"))?;
+                vec![("meter:${entry_id}, ", Some(config("_", "_"))),
+                     ("", Some(format!("not {}", config("_", "_"))))]
+            },
+            None => vec![("", None)],
+        };
+        for (actions, atom, clauses) in &entry_flows {
+            for (meter, meter_clause) in &variants {
+                writeln!(output, "Flow(\"cookie=${{entry_id}} table={} priority={} {}{}actions={}{}\") :-",
+                         number, priority, prereq_match(&prereqs), matches, meter, actions)?;
+                write!(output, "    {}", atom)?;
+                for clause in meter_clause.iter().chain(clauses.iter()) {
+                    write!(output, ",\n    {}", clause)?;
+                }
+                writeln!(output, ".")?;
+            }
+        }
+        writeln!(output)?;
+        Ok(())
+    }
+
+    /// Translates `pipeline`, whose tables are numbered starting from `base`, into flows, which
+    /// it appends to `output`.
+    fn pipeline(&mut self, output: &mut String, pipeline: &Bmv2Pipeline, base: u32, end: u32,
+                p4tables: &HashMap<String, p4ext::Table>) -> Result<()> {
+        writeln!(output, "/*\n * {}\n */\n", pipeline.name)?;
+        for c in &pipeline.conditionals {
+            self.unsupported(&c.source_info, format!("conditional {}", c.name));
+        }
+
+        // The pipeline's first table is 'base', which jumps to its initial table.  The rest are
+        // numbered in order.
+        let mut tables = HashMap::new();
+        for (i, table) in pipeline.tables.iter().enumerate() {
+            let number = base + 1 + i as u32;
+            if number >= end {
+                self.unsupported(&table.source_info, format!("more than {} tables in pipeline {}",
+                                                             end - base - 1, pipeline.name));
+                break;
+            }
+            tables.insert(table.name.clone(), number);
+        }
+        let pipeline_tables = PipelineTables { first: base, end, tables };
+        let start = pipeline_tables.goto(pipeline.init_table.as_deref());
+        if pipeline.name == "ingress" {
+            // Packets arrive with OpenFlow port numbers in 'in_port', which the P4 program needs
+            // to see as P4 port numbers.
+            writeln!(output, "Flow(\"table={} priority=100 in_port=${{ofport}} actions=load(${{p4_port}}->in_port[]), {}\") :-\n    Port(p4_port, ofport).",
+                     pipeline_tables.first, start)?;
+            self.ingress_start = start.clone();
+        }
+        writeln!(output, "Flow(\"table={} priority=0 actions={}\").\n", pipeline_tables.first, start)?;
+
+        for table in &pipeline.tables {
+            if pipeline_tables.tables.contains_key(&table.name) {
+                self.table(output, table, p4tables.get(&table.name), &pipeline_tables)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the OpenFlow match for the protocols in `prereqs`, e.g. `"tcp "`.
+fn prereq_match(prereqs: &BTreeSet<&'static str>) -> String {
+    let mut prereqs = prereqs.clone();
+    if prereqs.contains("tcp") || prereqs.contains("udp") {
+        prereqs.remove("ip");
+    }
+    prereqs.iter().map(|p| format!("{} ", p)).collect()
+}
+
+fn parse_hexstr(s: &str) -> Option<u128> {
+    u128::from_str_radix(s.strip_prefix("0x")?, 16).ok()
+}
+
+fn read_bmv2(filename: &str) -> Result<Bmv2Program> {
+    let file = File::open(filename).with_context(|| format!("{}: open failed", filename))?;
+    serde_json::from_reader(file).with_context(|| format!("{}: read failed", filename))
+}
+
+/// Generates `<file_dir>/<file_name>_of.dl`, a DDlog translation of P4 program `file_name` into
+/// OpenFlow flows for ofp4, from the program's bmv2 JSON and P4Info in `file_dir`.
+///
+/// Fails, listing every construct that it can't translate, if the program uses anything outside
+/// the supported subset.
+pub fn p4_to_openflow(file_dir: &str, file_name: &str) -> Result<()> {
+    let p4info_fn = format!("{}/{}.p4info.bin", file_dir, file_name);
+    let p4info = crate::read_p4info(OsStr::new(&p4info_fn))?;
+    let bmv2_fn = format!("{}/{}.json", file_dir, file_name);
+    let bmv2 = read_bmv2(&bmv2_fn)?;
+
+    let p4tables: HashMap<String, p4ext::Table> = crate::get_pipelines(p4info.clone(), None)?
+        .into_iter()
+        .flat_map(|(_, tables)| tables)
+        .map(|table| (table.preamble.name.clone(), table))
+        .collect();
+
+    let mut generator = Generator::new(&bmv2, &p4info);
+    let mut flows = String::new();
+    for pipeline in &bmv2.pipelines {
+        let (base, end) = match pipeline.name.as_str() {
+            "ingress" => (0, 31),
+            "egress" => (32, 64),
+            name => return Err(anyhow!("{}: unknown pipeline {}", bmv2_fn, name)),
+        };
+        generator.pipeline(&mut flows, pipeline, base, end, &p4tables)?;
+    }
+    let resubmit = if generator.uses_resubmit {
+        generator.allocate("resubmit".into(), 1)
+    } else {
+        None
+    };
+
+    if !generator.unsupported.is_empty() {
+        let list: Vec<String> = generator.unsupported.iter().map(|u| format!("    {}", u)).collect();
+        return Err(anyhow!("{}: cannot translate to OpenFlow because of unsupported constructs:\n{}",
+                           bmv2_fn, list.join("\n")));
+    }
+
+    let mut output = String::new();
+    writeln!(output, "/*
+ * DDlog program based on {name}.p4, generated by p4info2ddlog from
+ * {name}.json and {name}.p4info.bin.  Do not edit.
+ *
+ * This program accepts as input the contents of P4Runtime tables and uses
+ * DDlog rules to convert them into OpenFlow flows.
+ */
+
+// P4Runtime input relations.", name = file_name)?;
+    for table in p4info.get_tables() {
+        if let Some(table) = p4tables.get(table.get_preamble().get_name()) {
+            crate::write_table_relation(&mut output, table, "input", true)?;
+        }
+    }
+    writeln!(output, "input relation MulticastGroup(
+    mcast_id: bit<16>,
+    port: bit<9>)
+
+// OpenFlow port numbers of P4 ports, when they differ.
+input relation Port(
+    p4_port: bit<9>,
+    ofport: bit<16>)
+
+// A relation with one record, for rules that would otherwise begin with a
+// negation.
+relation Always(b: bool)
+Always(true).
+
+// OpenFlow flow output relation.
+output relation Flow(s: string)
+index Flow(s: string) on Flow(s)
+
+// OpenFlow group output relation.
+output relation Group(s: string)
+index Group(s: string) on Group(s)
+
+// P4Runtime meter configuration input relations and OpenFlow meter output
+// relation.
+input relation MeterConfig(
+    meter_id: bit<32>,
+    index: bit<64>,
+    cir: bit<64>,
+    cburst: bit<64>,
+    pir: bit<64>,
+    pburst: bit<64>)
+input relation DirectMeterConfig(
+    meter_id: bit<32>,
+    entry_id: bit<32>,
+    cir: bit<64>,
+    cburst: bit<64>,
+    pir: bit<64>,
+    pburst: bit<64>)
+output relation Meter(s: string)
+index Meter(s: string) on Meter(s)
+
+/*
+ * Register allocation
+ */")?;
+    for r in &generator.registers {
+        let last = r.ofs + r.width - 1;
+        writeln!(output, "function r_{}(): istring = i\"{}[{}..{}]\"", r.name, r.field, r.ofs, last)?;
+        writeln!(output, "function r_{name}_match(value: bit<{w}>, mask: bit<{w}>): string = \"{field}=${{(value as bit<{rw}>) << {ofs}}}/${{(mask as bit<{rw}>) << {ofs}}}\"",
+                 name = r.name, w = r.width, field = r.field, rw = r.register_width(), ofs = r.ofs)?;
+    }
+    writeln!(output, "
+function lpm_mask(width: bit<32>, plen: bit<32>): bit<128> =
+    if (plen == 0) 0 else ((1 << width) - 1) ^ ((1 << (width - plen)) - 1)
+")?;
+    output.push_str(&flows);
+
+    // Between the pipelines: multicast replication, dropping, and resubmission.
+    writeln!(output, "/*
+ * Ingress->Egress
+ */
+")?;
+    if resubmit.is_some() {
+        writeln!(output, "Flow(\"table=31 priority=200 ${{r_resubmit_match(1, 1)}} actions=load(0->${{r_resubmit()}}), {}\").",
+                 generator.ingress_start)?;
+    }
+    writeln!(output, "\
+Flow(\"table=31 priority=100 ${{r_mcast_grp_match(0, 65535)}} ${{r_egress_spec_match({drop}, {drop})}} actions=drop\").
+Flow(\"table=31 priority=50 ${{r_mcast_grp_match(0, 65535)}} actions=move(${{r_egress_spec()}}->${{r_egress_port()}}), resubmit(,32)\").
+Flow(\"cookie=${{32'h80000000 | (mcast_id as bit<32>)}} table=31 priority=50 ${{r_mcast_grp_match(mcast_id, 65535)}} actions=group:${{mcast_id}}\") :-
+    MulticastGroup(mcast_id, _).
+Group(\"group_id=${{mcast_id}},type=all,${{buckets}}\") :-
+    MulticastGroup(mcast_id, port),
+    var buckets = \"bucket=actions=load(0->${{r_egress_spec()}}),load(${{port}}->${{r_egress_port()}}),resubmit(,32)\".group_by(mcast_id).to_vec().join(\",\").
+
+/*
+ * Output
+ */
+
+Flow(\"table=64 priority=100 ${{r_egress_spec_match({drop}, {drop})}} actions=drop\").
+Flow(\"table=64 priority=60 ${{r_egress_port_match(p4_port, {drop})}} in_port=${{p4_port}} actions=drop\") :-
+    Port(p4_port, _).
+Flow(\"table=64 priority=50 ${{r_egress_port_match(p4_port, {drop})}} actions=load(0->in_port[]), output:${{ofport}}\") :-
+    Port(p4_port, ofport).
+Flow(\"table=64 priority=0 actions=output(${{r_egress_port()}})\").", drop = DROP_PORT)?;
+
+    let output_fn = format!("{}/{}_of.dl", file_dir, file_name);
+    File::create(&output_fn)
+        .with_context(|| format!("{}: create failed", output_fn))?
+        .write_all(output.as_bytes())
+        .with_context(|| format!("{}: write failed", output_fn))?;
+    Ok(())
+}
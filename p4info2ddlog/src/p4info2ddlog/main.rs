@@ -22,7 +22,7 @@ use anyhow::Result;
 
 use clap::{App, Arg};
 
-use p4info2ddlog::p4info_to_ddlog;
+use p4info2ddlog::{p4_to_openflow, p4info_to_ddlog};
 
 use std::env;
 
@@ -31,6 +31,7 @@ fn main() -> Result<()> {
     const FILE_NAME_ARG: &str = "FILE_NAME";
     const CRATE_ARG: &str = "OUTPUT_CRATE_DIR";
     const PIPELINE_ARG: &str = "pipeline";
+    const OFP4_ARG: &str = "ofp4";
 
     let matches = App::new("p4info2ddlog")
         .version(env!("CARGO_PKG_VERSION"))
@@ -60,6 +61,11 @@ fn main() -> Result<()> {
                 .takes_value(true)
                 .short("p"),
         )
+        .arg(
+            Arg::with_name(OFP4_ARG)
+                .help("also write {program}_of.dl, a translation to OpenFlow for ofp4, based on {program}.json")
+                .long(OFP4_ARG),
+        )
         .get_matches();
     
     // unwrap is safe, since these arguments are required.
@@ -71,5 +77,10 @@ fn main() -> Result<()> {
         file_name,
         matches.value_of(CRATE_ARG),
         matches.value_of(PIPELINE_ARG),
-    )
+    )?;
+
+    if matches.is_present(OFP4_ARG) {
+        p4_to_openflow(file_dir, file_name)?;
+    }
+    Ok(())
 }
@@ -3,6 +3,7 @@ extern crate grpcio;
 extern crate protobuf;
 
 pub mod code;
+pub mod ofp4;
 pub mod p4data;
 pub mod p4info;
 pub mod p4runtime_grpc;
@@ -0,0 +1,68 @@
+#!/bin/bash
+# Script that compiles a P4-to-OpenFlow DDlog translation and generates
+# the "ofp4_program" shim crate through which ofp4 uses it.
+
+# Exit when any command fails, since they are all sequential.
+set -e
+
+# Print usage if incorrectly invoked.
+if [ "$#" -gt 1 ]; then
+    cat >&2 <<EOF
+Usage: $0 [TRANSLATION]
+where TRANSLATION is a *.dl file that translates a P4 program into
+OpenFlow flows for ofp4 (default: ofp4/snvs.dl).
+EOF
+    exit 1
+fi
+
+NERPA_DIR=$(pwd)
+OFP4_DIR=$NERPA_DIR/ofp4
+TRANSLATION=$(realpath "${1:-$OFP4_DIR/snvs.dl}")
+NAME=$(basename "$TRANSLATION" .dl)
+
+if test ! -f "$TRANSLATION"; then
+    echo >&2 "$0: could not find DDlog translation $TRANSLATION"
+    exit 1
+fi
+if test ! -f "$DDLOG_HOME/lib/ddlog_std.dl"; then
+    echo >&2 "$0: \$DDLOG_HOME must point to the ddlog tree"
+    exit 1
+fi
+
+# Compile the translation into ofp4/${NAME}_ddlog, and make ofp4/ddlog
+# point to it, so that the Cargo manifests don't depend on its name.
+echo "Compiling DDlog translation $NAME..."
+ddlog -i "$TRANSLATION" -o "$OFP4_DIR"
+ln -sfn "${NAME}_ddlog" "$OFP4_DIR/ddlog"
+
+# Generate the shim crate.
+echo "Generating ofp4_program crate..."
+mkdir -p "$OFP4_DIR/program/src"
+cat > "$OFP4_DIR/program/Cargo.toml" <<EOF
+# Generated by scripts/ofp4-program.sh from $NAME.dl.  Do not edit.
+[package]
+name = "ofp4_program"
+version = "0.1.0"
+edition = "2018"
+license = "MIT"
+
+[dependencies]
+p4ext = { path = "../../p4ext", features = ["ofp4"] }
+differential_datalog = { path = "../ddlog/differential_datalog" }
+$NAME = { path = "../ddlog" }
+EOF
+cat > "$OFP4_DIR/program/src/lib.rs" <<EOF
+// Generated by scripts/ofp4-program.sh from $NAME.dl.  Do not edit.
+
+use differential_datalog::api::HDDlog;
+use p4ext::translation::Translation;
+
+/// The P4-to-OpenFlow translation in \`$NAME.dl\`.
+pub struct Program;
+
+impl Translation for Program {
+    fn run(&self, workers: usize) -> Result<HDDlog, String> {
+        ${NAME}_ddlog::run(workers, false).map(|(hddlog, _init_state)| hddlog)
+    }
+}
+EOF
@@ -135,19 +135,7 @@ case $TARGET in
 
         $SUDO $SWITCH_GRPC_EXEC $SWITCH_FLAGS -- $GRPC_FLAGS & sleep 2 
 
-        # Configure the switch.
-        COMMANDS_FILE=$FILE_DIR/commands.txt
-        if test ! -f $COMMANDS_FILE; then
-            echo >&2 "$0: did not find simple switch config commands in expected location ($COMMANDS_FILE)"
-            $SUDO pkill -f simple_switch_grpc
-            exit 1
-        fi
-
-        TOOLS_PATH=$SWITCH_EMULATOR_PATH/tools/
-        CLI_EXEC=$SWITCH_EMULATOR_PATH/targets/simple_switch/sswitch_CLI.py
-        chmod +x $CLI_EXEC
-
-        sed '/^#/d' $COMMANDS_FILE | PYTHONPATH=$TOOLS_PATH python3 $CLI_EXEC
+        # The controller applies $FILE_DIR/commands.txt, if present, over P4Runtime.
         ;;
 
     ofp4)
@@ -178,6 +166,7 @@ case $TARGET in
 
         # Start ofp4.
         echo "Starting ofp4..."
+        scripts/ofp4-program.sh
         (cd ofp4 && cargo build)
         ofp4/target/debug/ofp4 unix:"$sandbox"/br0.mgmt &
         echo $! > "$sandbox"/ofp4.pid